@@ -1,5 +1,8 @@
 use ray::{
-    benchmark::{run_benchmark, BenchmarkConfig, SimpleReadBenchmark, SimpleWriteBenchmark},
+    benchmark::{
+        run_benchmark, BenchmarkConfig, CsvOutput, RampConfig, SimpleReadBenchmark,
+        SimpleWriteBenchmark,
+    },
     server::Config,
 };
 
@@ -8,7 +11,7 @@ use clap::{value_t_or_exit, App, AppSettings, Arg, SubCommand};
 use log::LevelFilter;
 use simplelog::{LevelPadding, SimpleLogger};
 
-use std::time::Duration;
+use std::{path::PathBuf, time::Duration};
 
 const ABOUT: &str = "Ray benchmark tool";
 
@@ -29,7 +32,7 @@ fn parse_arguments() -> (BenchmarkConfig, BenchmarkKind) {
                 .short("a")
                 .long("address")
                 .value_name("ADDRESS")
-                .help("rayd host address")
+                .help("rayd host address, or unix:<path> for a Unix socket")
                 .takes_value(true)
                 .default_value("localhost"),
         )
@@ -75,7 +78,12 @@ fn parse_arguments() -> (BenchmarkConfig, BenchmarkKind) {
                 .value_name("LENGTH")
                 .help("length of key on bytes")
                 .takes_value(true)
-                .default_value("8"),
+                .default_value("8")
+                // rayd rejects empty keys.
+                .validator(|length| match length.parse::<usize>() {
+                    Ok(0) => Err("keys must not be empty".into()),
+                    _ => Ok(()),
+                }),
         )
         .arg(
             Arg::with_name("value_length")
@@ -94,6 +102,40 @@ fn parse_arguments() -> (BenchmarkConfig, BenchmarkKind) {
                 .takes_value(true)
                 .default_value("0"),
         )
+        .arg(
+            Arg::with_name("ramp_start")
+                .long("ramp-start")
+                .value_name("COUNT")
+                .help("start with this many tasks and add more over time")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ramp_step")
+                .long("ramp-step")
+                .value_name("COUNT")
+                .help("number of tasks added at each ramp step (with --ramp-start)")
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("ramp_interval")
+                .long("ramp-interval")
+                .value_name("SECONDS")
+                .help("time between ramp steps (with --ramp-start)")
+                .takes_value(true)
+                .default_value("1")
+                .validator(|value| match value.parse::<u64>() {
+                    Ok(0) | Err(_) => Err("must be a positive number of seconds".to_string()),
+                    Ok(_) => Ok(()),
+                }),
+        )
+        .arg(
+            Arg::with_name("csv")
+                .long("csv")
+                .value_name("PATH")
+                .help("write per-second statistics as CSV to a file (- for stdout)")
+                .takes_value(true),
+        )
         .subcommand(SubCommand::with_name("read").about(
             "Simple read benchmark: each client generates a random key-value pair \
              and fetches it in a loop",
@@ -115,6 +157,20 @@ fn parse_arguments() -> (BenchmarkConfig, BenchmarkKind) {
     let delay_micros = value_t_or_exit!(matches, "delay", u64);
     let delay = Duration::from_micros(delay_micros);
 
+    let ramp = if matches.is_present("ramp_start") {
+        Some(RampConfig {
+            start: value_t_or_exit!(matches, "ramp_start", u16),
+            step: value_t_or_exit!(matches, "ramp_step", u16),
+            interval: Duration::from_secs(value_t_or_exit!(matches, "ramp_interval", u64)),
+        })
+    } else {
+        None
+    };
+    let csv = matches.value_of("csv").map(|path| match path {
+        "-" => CsvOutput::Stdout,
+        path => CsvOutput::File(PathBuf::from(path)),
+    });
+
     let config = BenchmarkConfig {
         address,
         port,
@@ -124,6 +180,8 @@ fn parse_arguments() -> (BenchmarkConfig, BenchmarkKind) {
         key_length,
         value_length,
         delay,
+        ramp,
+        csv,
     };
 
     let kind = match matches.subcommand_name().unwrap() {
@@ -135,21 +193,26 @@ fn parse_arguments() -> (BenchmarkConfig, BenchmarkKind) {
     (config, kind)
 }
 
-fn init_logging() {
+fn init_logging(level: LevelFilter) {
     let config = simplelog::ConfigBuilder::new()
         .add_filter_allow_str("ray")
         .set_time_format_str("%T%.3f")
         .set_thread_level(LevelFilter::Off)
         .set_level_padding(LevelPadding::Off)
         .build();
-    SimpleLogger::init(LevelFilter::Info, config).unwrap();
+    SimpleLogger::init(level, config).unwrap();
 }
 
 fn main() {
-    init_logging();
-
     let (config, kind) = parse_arguments();
 
+    // Keep stdout clean for the CSV.
+    let level = match config.csv {
+        Some(CsvOutput::Stdout) => LevelFilter::Warn,
+        _ => LevelFilter::Info,
+    };
+    init_logging(level);
+
     match kind {
         BenchmarkKind::Read => {
             let benchmark = SimpleReadBenchmark::default();
@@ -10,8 +10,55 @@ const ABOUT: &str = "Ray command line interface";
 
 #[derive(Debug)]
 enum Command {
-    Get { key: Vec<u8> },
-    Set { key: Vec<u8>, value: Vec<u8> },
+    Get {
+        namespace: Vec<u8>,
+        key: Vec<u8>,
+        meta: bool,
+    },
+    Exists {
+        namespace: Vec<u8>,
+        key: Vec<u8>,
+    },
+    Set {
+        namespace: Vec<u8>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    GetSet {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Increment {
+        key: Vec<u8>,
+        delta: i64,
+    },
+    Append {
+        key: Vec<u8>,
+        suffix: Vec<u8>,
+    },
+    Dump {
+        namespace: Vec<u8>,
+        start_after: Vec<u8>,
+    },
+    Watch {
+        namespace: Vec<u8>,
+        prefix: Vec<u8>,
+    },
+    Delete {
+        namespace: Vec<u8>,
+        key: Vec<u8>,
+    },
+    Flush {
+        namespace: Vec<u8>,
+    },
+    DeletePrefix {
+        namespace: Vec<u8>,
+        prefix: Vec<u8>,
+    },
+    Clear,
+    Info,
+    Snapshot,
+    Sync,
 }
 
 #[derive(Debug)]
@@ -33,6 +80,15 @@ fn read_stdin() -> String {
     result
 }
 
+fn namespace_arg() -> Arg<'static, 'static> {
+    Arg::with_name("namespace")
+        .short("n")
+        .long("namespace")
+        .value_name("NAMESPACE")
+        .help("namespace of the key (the default one if not given)")
+        .takes_value(true)
+}
+
 fn parse_arguments() -> Arguments {
     let default_port_string = Config::default().rpc.port.to_string();
     let parser = App::new("ray")
@@ -45,7 +101,7 @@ fn parse_arguments() -> Arguments {
                 .short("a")
                 .long("address")
                 .value_name("ADDRESS")
-                .help("rayd host address")
+                .help("rayd host address, or unix:<path> for a Unix socket")
                 .takes_value(true)
                 .default_value("localhost"),
         )
@@ -61,7 +117,19 @@ fn parse_arguments() -> Arguments {
         .subcommand(
             SubCommand::with_name("get")
                 .about("Get value of given key")
-                .arg(Arg::with_name("key").help("key to get").required(true)),
+                .arg(Arg::with_name("key").help("key to get").required(true))
+                .arg(namespace_arg())
+                .arg(
+                    Arg::with_name("meta")
+                        .long("meta")
+                        .help("also print the epoch the value was read at and its size"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("exists")
+                .about("Check whether given key is set")
+                .arg(Arg::with_name("key").help("key to check").required(true))
+                .arg(namespace_arg()),
         )
         .subcommand(
             SubCommand::with_name("set")
@@ -71,7 +139,95 @@ fn parse_arguments() -> Arguments {
                         .help("key to set value for")
                         .required(true),
                 )
+                .arg(Arg::with_name("value").help("value to set"))
+                .arg(namespace_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("getset")
+                .about("Set value for given key and print the value it replaced")
+                .arg(
+                    Arg::with_name("key")
+                        .help("key to set value for")
+                        .required(true),
+                )
                 .arg(Arg::with_name("value").help("value to set")),
+        )
+        .subcommand(
+            SubCommand::with_name("increment")
+                .about("Add delta to the 64-bit integer stored at given key")
+                .setting(AppSettings::AllowLeadingHyphen)
+                .arg(
+                    Arg::with_name("key")
+                        .help("key to increment")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("delta")
+                        .help("value to add (1 by default)")
+                        .default_value("1"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("append")
+                .about("Append suffix to the value of given key")
+                .arg(
+                    Arg::with_name("key")
+                        .help("key to append to")
+                        .required(true),
+                )
+                .arg(Arg::with_name("suffix").help("suffix to append")),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Print all keys and values in key order")
+                .arg(
+                    Arg::with_name("start-after")
+                        .long("start-after")
+                        .value_name("KEY")
+                        .help("only print keys after this one")
+                        .takes_value(true),
+                )
+                .arg(namespace_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Print changes of keys starting with given prefix as they happen")
+                .arg(Arg::with_name("prefix").help("prefix of the keys to watch"))
+                .arg(namespace_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("flush")
+                .about("Remove all keys of given namespace")
+                .arg(
+                    Arg::with_name("namespace")
+                        .help("namespace to flush")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("delete")
+                .about("Remove given key")
+                .arg(Arg::with_name("key").help("key to remove").required(true))
+                .arg(namespace_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("delete-prefix")
+                .about("Remove all keys starting with given prefix")
+                .arg(
+                    Arg::with_name("prefix")
+                        .help("prefix of the keys to remove")
+                        .required(true),
+                )
+                .arg(namespace_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("clear")
+                .about("Remove all keys of all namespaces (needs rpc.allow_clear on rayd)"),
+        )
+        .subcommand(SubCommand::with_name("info").about("Show rayd version, epoch and key count"))
+        .subcommand(SubCommand::with_name("snapshot").about("Make rayd take a snapshot right away"))
+        .subcommand(
+            SubCommand::with_name("sync").about("Wait until all prior writes are persisted"),
         );
     let matches = parser.get_matches();
 
@@ -82,6 +238,15 @@ fn parse_arguments() -> Arguments {
         "get" => {
             let inner = matches.subcommand_matches("get").unwrap();
             Command::Get {
+                namespace: inner.value_of("namespace").unwrap_or("").into(),
+                key: inner.value_of("key").unwrap().into(),
+                meta: inner.is_present("meta"),
+            }
+        }
+        "exists" => {
+            let inner = matches.subcommand_matches("exists").unwrap();
+            Command::Exists {
+                namespace: inner.value_of("namespace").unwrap_or("").into(),
                 key: inner.value_of("key").unwrap().into(),
             }
         }
@@ -92,10 +257,78 @@ fn parse_arguments() -> Arguments {
                 .map(|value| value.into())
                 .unwrap_or_else(read_stdin);
             Command::Set {
+                namespace: inner.value_of("namespace").unwrap_or("").into(),
+                key: inner.value_of("key").unwrap().into(),
+                value: value.into_bytes(),
+            }
+        }
+        "getset" => {
+            let inner = matches.subcommand_matches("getset").unwrap();
+            let value: String = inner
+                .value_of("value")
+                .map(|value| value.into())
+                .unwrap_or_else(read_stdin);
+            Command::GetSet {
                 key: inner.value_of("key").unwrap().into(),
                 value: value.into_bytes(),
             }
         }
+        "increment" => {
+            let inner = matches.subcommand_matches("increment").unwrap();
+            Command::Increment {
+                key: inner.value_of("key").unwrap().into(),
+                delta: value_t_or_exit!(inner, "delta", i64),
+            }
+        }
+        "append" => {
+            let inner = matches.subcommand_matches("append").unwrap();
+            let suffix: String = inner
+                .value_of("suffix")
+                .map(|suffix| suffix.into())
+                .unwrap_or_else(read_stdin);
+            Command::Append {
+                key: inner.value_of("key").unwrap().into(),
+                suffix: suffix.into_bytes(),
+            }
+        }
+        "dump" => {
+            let inner = matches.subcommand_matches("dump").unwrap();
+            Command::Dump {
+                namespace: inner.value_of("namespace").unwrap_or("").into(),
+                start_after: inner.value_of("start-after").unwrap_or("").into(),
+            }
+        }
+        "watch" => {
+            let inner = matches.subcommand_matches("watch").unwrap();
+            Command::Watch {
+                namespace: inner.value_of("namespace").unwrap_or("").into(),
+                prefix: inner.value_of("prefix").unwrap_or("").into(),
+            }
+        }
+        "flush" => {
+            let inner = matches.subcommand_matches("flush").unwrap();
+            Command::Flush {
+                namespace: inner.value_of("namespace").unwrap().into(),
+            }
+        }
+        "delete" => {
+            let inner = matches.subcommand_matches("delete").unwrap();
+            Command::Delete {
+                namespace: inner.value_of("namespace").unwrap_or("").into(),
+                key: inner.value_of("key").unwrap().into(),
+            }
+        }
+        "delete-prefix" => {
+            let inner = matches.subcommand_matches("delete-prefix").unwrap();
+            Command::DeletePrefix {
+                namespace: inner.value_of("namespace").unwrap_or("").into(),
+                prefix: inner.value_of("prefix").unwrap().into(),
+            }
+        }
+        "clear" => Command::Clear,
+        "info" => Command::Info,
+        "snapshot" => Command::Snapshot,
+        "sync" => Command::Sync,
         _ => unreachable!(),
     };
 
@@ -112,14 +345,109 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut client = RayClient::connect(&args.address, args.port).await?;
 
     match args.command {
-        Command::Set { key, value } => {
-            client.set(key, value).await?;
+        Command::Set {
+            namespace,
+            key,
+            value,
+        } => {
+            client.set_in(namespace, key, value).await?;
         }
-        Command::Get { key } => {
-            let value = client.get(key).await?;
+        Command::Get {
+            namespace,
+            key,
+            meta: false,
+        } => {
+            let value = client.get_in(namespace, key).await?;
             let formatted = format!("{:?}", ByteStr::new(&value));
             println!("{}", &formatted[1..]);
         }
+        Command::Get {
+            namespace,
+            key,
+            meta: true,
+        } => {
+            let reply = client.get_with_meta(namespace, key).await?;
+            let formatted = format!("{:?}", ByteStr::new(&reply.value));
+            println!("{}", &formatted[1..]);
+            println!("found: {}", reply.found);
+            println!("epoch: {}", reply.epoch);
+            println!("size: {}", reply.size);
+        }
+        Command::GetSet { key, value } => {
+            let previous = client.get_set(key, value).await?;
+            let formatted = format!("{:?}", ByteStr::new(&previous));
+            println!("{}", &formatted[1..]);
+        }
+        Command::Exists { namespace, key } => {
+            let exists = client.exists_in(namespace, key).await?;
+            println!("{}", exists);
+        }
+        Command::Increment { key, delta } => {
+            let value = client.increment(key, delta).await?;
+            println!("{}", value);
+        }
+        Command::Append { key, suffix } => {
+            let length = client.append(key, suffix).await?;
+            println!("{}", length);
+        }
+        Command::Dump {
+            namespace,
+            start_after,
+        } => {
+            let mut entries = client.dump_keys_in(namespace, start_after).await?;
+            while let Some(entry) = entries.message().await? {
+                let key = format!("{:?}", ByteStr::new(&entry.key));
+                let value = format!("{:?}", ByteStr::new(&entry.value));
+                println!("{}\t{}", &key[1..], &value[1..]);
+            }
+        }
+        Command::Watch { namespace, prefix } => {
+            let mut events = client.watch_in(namespace, prefix).await?;
+            while let Some(event) = events.message().await? {
+                if event.missed > 0 {
+                    println!("Missed {} events", event.missed);
+                    continue;
+                }
+                let key = format!("{:?}", ByteStr::new(&event.key));
+                if event.deleted {
+                    println!("{}\t{}\tdeleted", event.epoch, &key[1..]);
+                } else {
+                    let value = format!("{:?}", ByteStr::new(&event.value));
+                    println!("{}\t{}\t{}", event.epoch, &key[1..], &value[1..]);
+                }
+            }
+        }
+        Command::Flush { namespace } => {
+            let count = client.flush_namespace(namespace).await?;
+            println!("Removed {} keys", count);
+        }
+        Command::Delete { namespace, key } => {
+            let found = client.delete_in(namespace, key).await?;
+            println!("{}", if found { "Removed" } else { "Not found" });
+        }
+        Command::DeletePrefix { namespace, prefix } => {
+            let count = client.delete_prefix(namespace, prefix).await?;
+            println!("Removed {} keys", count);
+        }
+        Command::Clear => {
+            let count = client.clear().await?;
+            println!("Removed {} keys", count);
+        }
+        Command::Info => {
+            let info = client.info().await?;
+            println!("version: {}", info.version);
+            println!("epoch: {}", info.epoch);
+            println!("uptime: {}s", info.uptime_seconds);
+            println!("keys: {}", info.key_count);
+        }
+        Command::Snapshot => {
+            let epoch = client.trigger_snapshot().await?;
+            println!("Snapshot taken at epoch {}", epoch);
+        }
+        Command::Sync => {
+            let epoch = client.sync().await?;
+            println!("Persisted up to epoch {}", epoch);
+        }
     };
 
     Ok(())
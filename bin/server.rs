@@ -1,13 +1,28 @@
-use ray::server::{serve_forever, Config};
+use ray::{
+    errors::*,
+    server::{check_config, dump_journal, inspect_snapshot, serve_forever, Config},
+};
 
-use clap::{App, Arg};
+use clap::{value_t_or_exit, App, AppSettings, Arg, SubCommand};
+use nix::unistd::daemon;
 
-use std::{fs::File, io::Read, process::exit};
+use std::{
+    fs::File,
+    io::{self, Read},
+    process::exit,
+};
 
 const ABOUT: &str = "Ray server";
 
 struct Arguments {
-    config: Option<String>,
+    configs: Vec<String>,
+    check_config: bool,
+    pid_file: Option<String>,
+    daemonize: bool,
+    // Directory of `rayd journal dump`.
+    dump_journal: Option<String>,
+    // File and number of keys to print of `rayd snapshot inspect`.
+    inspect_snapshot: Option<(String, usize)>,
 }
 
 fn parse_arguments() -> Arguments {
@@ -20,38 +35,191 @@ fn parse_arguments() -> Arguments {
                 .short("c")
                 .long("config")
                 .value_name("CONFIG_PATH")
-                .help("path to rayd config file")
+                .help(
+                    "path to rayd config file, or - for stdin; repeat to merge several, \
+                     later ones taking precedence",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("check-config")
+                .long("check-config")
+                .help("validate the config and exit without starting the server"),
+        )
+        .arg(
+            Arg::with_name("pid-file")
+                .long("pid-file")
+                .value_name("PID_PATH")
+                .help(
+                    "write the PID to this file once the server is up and remove it on exit; \
+                     overrides pid_file of the config",
+                )
                 .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("daemonize")
+                .long("daemonize")
+                .help("detach from the terminal and run in the background"),
+        )
+        .subcommand(
+            SubCommand::with_name("journal")
+                .about("Inspect journal files without starting the server")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("dump")
+                        .about("Print the epoch, offset and writes of every journal blob")
+                        .arg(
+                            Arg::with_name("path")
+                                .value_name("JOURNAL_PATH")
+                                .help("journal directory")
+                                .required(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("snapshot")
+                .about("Inspect snapshot files without starting the server")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("inspect")
+                        .about("Print the epoch, key count and value bytes of a full snapshot")
+                        .arg(
+                            Arg::with_name("path")
+                                .value_name("SNAPSHOT_PATH")
+                                .help(".snap file")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("keys")
+                                .long("keys")
+                                .value_name("COUNT")
+                                .help("also print the first COUNT keys in key order")
+                                .takes_value(true)
+                                .default_value("0"),
+                        ),
+                ),
         );
     let matches = parser.get_matches();
-    let config = matches.value_of("config").map(|s| s.to_string());
+    let configs = matches
+        .values_of("config")
+        .map_or_else(Vec::new, |paths| paths.map(|s| s.to_string()).collect());
+    let check_config = matches.is_present("check-config");
+    let pid_file = matches.value_of("pid-file").map(|s| s.to_string());
+    let daemonize = matches.is_present("daemonize");
+    let dump_journal = matches
+        .subcommand_matches("journal")
+        .and_then(|journal| journal.subcommand_matches("dump"))
+        .map(|dump| dump.value_of("path").unwrap().to_string());
+    let inspect_snapshot = matches
+        .subcommand_matches("snapshot")
+        .and_then(|snapshot| snapshot.subcommand_matches("inspect"))
+        .map(|inspect| {
+            let path = inspect.value_of("path").unwrap().to_string();
+            (path, value_t_or_exit!(inspect, "keys", usize))
+        });
 
-    Arguments { config }
+    Arguments {
+        configs,
+        check_config,
+        pid_file,
+        daemonize,
+        dump_journal,
+        inspect_snapshot,
+    }
 }
 
-fn read_config(path: &str) -> Config {
-    let mut file = File::open(path).unwrap_or_else(|err| {
-        eprintln!("Failed to open '{}': {}", path, err);
-        exit(1);
-    });
+// Standard input can only be read once, so it is kept for reloads.
+fn read_stdin(paths: &[String]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    if paths.iter().any(|path| path == "-") {
+        io::stdin().read_to_end(&mut buffer).unwrap_or_else(|err| {
+            eprintln!("Failed to read config from stdin: {}", err);
+            exit(1);
+        });
+    }
+    buffer
+}
 
+fn read_file(path: &str) -> Result<Vec<u8>> {
+    let mut file = File::open(path).chain_err(|| format!("failed to open '{}'", path))?;
     let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).unwrap_or_else(|err| {
-        eprintln!("Failed to read '{}': {}", path, err);
-        exit(1);
-    });
+    file.read_to_end(&mut buffer)
+        .chain_err(|| format!("failed to read '{}'", path))?;
+    Ok(buffer)
+}
 
-    serde_yaml::from_slice(&buffer).unwrap_or_else(|err| {
-        eprintln!("Failed to parse config: {}", err);
-        exit(1);
-    })
+fn read_config(args: &Arguments, stdin: &[u8]) -> Result<Config> {
+    let mut buffers: Vec<(String, Vec<u8>)> = vec![];
+    for path in &args.configs {
+        if path == "-" {
+            buffers.push(("stdin".to_string(), stdin.to_vec()));
+        } else {
+            buffers.push((format!("'{}'", path), read_file(path)?));
+        }
+    }
+    let documents: Vec<(&str, &[u8])> = buffers
+        .iter()
+        .map(|(name, buffer)| (name.as_str(), buffer.as_slice()))
+        .collect();
+    let mut config = Config::load_merged(&documents)?;
+    if args.pid_file.is_some() {
+        config.pid_file = args.pid_file.clone();
+    }
+    Ok(config)
+}
+
+fn report_problems(config: &Config) -> ! {
+    let problems = check_config(config);
+    if problems.is_empty() {
+        println!("Config is OK");
+        exit(0);
+    }
+
+    eprintln!("Config has {} problem(s):", problems.len());
+    for problem in problems {
+        let causes: Vec<String> = problem.iter().map(|cause| cause.to_string()).collect();
+        eprintln!("  - {}", causes.join(": "));
+    }
+    exit(1);
 }
 
 fn main() {
     let args = parse_arguments();
-    let config = args
-        .config
-        .map(|path| read_config(&path))
-        .unwrap_or_default();
-    serve_forever(config);
+    if let Some(path) = &args.dump_journal {
+        dump_journal(path).unwrap_or_else(|err| {
+            let causes: Vec<String> = err.iter().map(|cause| cause.to_string()).collect();
+            eprintln!("Failed to dump journal: {}", causes.join(": "));
+            exit(1);
+        });
+        exit(0);
+    }
+    if let Some((path, sample)) = &args.inspect_snapshot {
+        inspect_snapshot(path, *sample).unwrap_or_else(|err| {
+            let causes: Vec<String> = err.iter().map(|cause| cause.to_string()).collect();
+            eprintln!("Failed to inspect snapshot: {}", causes.join(": "));
+            exit(1);
+        });
+        exit(0);
+    }
+    let stdin = read_stdin(&args.configs);
+    let config = read_config(&args, &stdin).unwrap_or_else(|err| {
+        let causes: Vec<String> = err.iter().map(|cause| cause.to_string()).collect();
+        eprintln!("Failed to load config: {}", causes.join(": "));
+        exit(1);
+    });
+    if args.check_config {
+        report_problems(&config);
+    }
+
+    // Before any thread is started, as only the forking thread survives in the child.
+    if args.daemonize {
+        daemon(true, false).unwrap_or_else(|err| {
+            eprintln!("Failed to daemonize: {}", err);
+            exit(1);
+        });
+    }
+
+    serve_forever(config, Box::new(move || read_config(&args, &stdin)));
 }
@@ -1,4 +1,6 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::compile_protos("proto/ray.proto")?;
+    tonic_build::compile_protos("proto/health.proto")?;
+    tonic_build::compile_protos("proto/counter.proto")?;
     Ok(())
 }
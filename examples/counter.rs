@@ -0,0 +1,132 @@
+// A replicated counter served through the ray framework: additions are journaled, snapshotted
+// and followed by replicas just like mutations of the key-value store.
+//
+//   cargo run --example counter -- [CONFIG_PATH]
+//
+// The config is the one of rayd, except that psm.machine_service.store is ignored.
+
+use ray::{
+    errors,
+    server::{serve_forever_with, Config, HealthReporter, Machine, MachineServiceHandle, Traced},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use tonic::{Code, Request, Response, Status};
+
+use std::{
+    fmt::{self, Display},
+    fs,
+    io::{Read, Write},
+    process::exit,
+};
+
+mod proto {
+    tonic::include_proto!("counter");
+}
+
+use proto::{
+    counter_server::{Counter, CounterServer},
+    AddReply, AddRequest, GetReply, GetRequest,
+};
+
+impl Display for AddRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AddRequest {{delta: {}}}", self.delta)
+    }
+}
+
+#[derive(Default, Clone)]
+struct CounterMachine {
+    value: i64,
+}
+
+impl Machine for CounterMachine {
+    type Mutation = AddRequest;
+    type Outcome = i64;
+    type Query = ();
+    type Status = i64;
+
+    fn apply_mutation(&mut self, mutation: Self::Mutation) -> Self::Outcome {
+        self.value = self.value.wrapping_add(mutation.delta);
+        self.value
+    }
+
+    fn query_state(&self, _query: Self::Query) -> Self::Status {
+        self.value
+    }
+
+    fn write_snapshot<T: Write>(&self, writer: &mut T) -> errors::Result<()> {
+        writer.write_i64::<LittleEndian>(self.value)?;
+        Ok(())
+    }
+
+    fn from_snapshot<T: Read>(reader: &mut T, _version: u16) -> errors::Result<Self> {
+        let value = reader.read_i64::<LittleEndian>()?;
+        Ok(Self { value })
+    }
+}
+
+struct CounterService {
+    handle: MachineServiceHandle<CounterMachine>,
+    health: HealthReporter,
+}
+
+impl CounterService {
+    // Set until journal recovery is over.
+    fn not_ready_error(&self) -> Option<Status> {
+        if self.health.is_serving() {
+            None
+        } else {
+            Some(Status::new(Code::Unavailable, "counter is not ready"))
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Counter for CounterService {
+    async fn add(&self, request: Request<AddRequest>) -> Result<Response<AddReply>, Status> {
+        if let Some(err) = self.not_ready_error() {
+            return Err(err);
+        }
+        let mutation = Traced::new(request.into_inner());
+        let value = self.handle.clone().apply_mutation(mutation).await?;
+        Ok(Response::new(AddReply { value }))
+    }
+
+    async fn get(&self, _request: Request<GetRequest>) -> Result<Response<GetReply>, Status> {
+        if let Some(err) = self.not_ready_error() {
+            return Err(err);
+        }
+        let value = self.handle.clone().query_state(Traced::new(())).await?;
+        Ok(Response::new(GetReply { value }))
+    }
+}
+
+fn load_config(path: Option<&str>) -> errors::Result<Config> {
+    match path {
+        Some(path) => {
+            let yaml =
+                fs::read(path).map_err(|err| format!("failed to read '{}': {}", path, err))?;
+            Config::load(&yaml)
+        }
+        None => Config::load(&[]),
+    }
+}
+
+fn main() {
+    let path = std::env::args().nth(1);
+    let config = load_config(path.as_deref()).unwrap_or_else(|err| {
+        eprintln!("Failed to load config: {}", err);
+        exit(1);
+    });
+
+    let reload = Box::new(move || load_config(path.as_deref()));
+    serve_forever_with(
+        config,
+        reload,
+        |handle, _snapshot_handle, health, _config| {
+            CounterServer::new(CounterService { handle, health })
+        },
+    );
+}
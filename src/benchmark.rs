@@ -4,8 +4,14 @@ use tokio::{runtime, time};
 
 use futures::{channel::mpsc, select, stream::StreamExt};
 
+use chrono::{SecondsFormat, Utc};
+
 use std::{
     error::Error,
+    fs::File,
+    io::{self, Write},
+    mem,
+    path::PathBuf,
     time::{Duration, Instant},
 };
 
@@ -24,7 +30,8 @@ pub trait Benchmark: 'static {
     );
 
     fn handle_message(&mut self, message: Self::Message);
-    fn handle_tick(&mut self);
+    // Returns latencies in seconds of the requests completed since the last tick.
+    fn handle_tick(&mut self) -> Vec<f64>;
 }
 
 #[derive(Debug)]
@@ -37,6 +44,31 @@ pub struct BenchmarkConfig {
     pub key_length: usize,
     pub value_length: usize,
     pub delay: Duration,
+    // Spawn the tasks gradually instead of all at once.
+    pub ramp: Option<RampConfig>,
+    pub csv: Option<CsvOutput>,
+}
+
+// Starts with `start` tasks and adds `step` more every `interval` until there are `tasks`.
+#[derive(Debug, Clone, Copy)]
+pub struct RampConfig {
+    pub start: u16,
+    pub step: u16,
+    pub interval: Duration,
+}
+
+impl RampConfig {
+    // Tasks to add at the next step, with `active` of `tasks` already running.
+    fn step_size(&self, active: u16, tasks: u16) -> u16 {
+        self.step.min(tasks.saturating_sub(active))
+    }
+}
+
+// Where to write a line of statistics every second.
+#[derive(Debug)]
+pub enum CsvOutput {
+    Stdout,
+    File(PathBuf),
 }
 
 #[derive(Default)]
@@ -79,16 +111,8 @@ impl Benchmark for SimpleReadBenchmark {
         self.latencies.push(message);
     }
 
-    fn handle_tick(&mut self) {
-        let requests = self.latencies.len();
-        let average = if requests > 0 {
-            let sum: f64 = self.latencies.iter().sum();
-            sum / (requests as f64)
-        } else {
-            0.
-        };
-        info!("RPS: {} (average latency: {})", requests, average);
-        self.latencies.clear();
+    fn handle_tick(&mut self) -> Vec<f64> {
+        mem::take(&mut self.latencies)
     }
 }
 
@@ -128,16 +152,8 @@ impl Benchmark for SimpleWriteBenchmark {
         self.latencies.push(message);
     }
 
-    fn handle_tick(&mut self) {
-        let requests = self.latencies.len();
-        let average = if requests > 0 {
-            let sum: f64 = self.latencies.iter().sum();
-            sum / (requests as f64)
-        } else {
-            0.
-        };
-        info!("RPS: {} (average latency: {})", requests, average);
-        self.latencies.clear();
+    fn handle_tick(&mut self) -> Vec<f64> {
+        mem::take(&mut self.latencies)
     }
 }
 
@@ -172,44 +188,33 @@ async fn run_benchmark_inner<B: Benchmark>(
     for _ in 0..config.idle {
         let idle_connector = connector.clone();
         tokio::spawn(async move {
-            let mut client = idle_connector
-                .connect()
-                .await
-                .unwrap_or_else(|err| panic!("Connection failed: {}", err));
+            let mut client = idle_connector.connect_retrying().await;
             time::delay_for(Duration::from_secs(100_500)).await;
-            client.get(vec![]).await.unwrap();
+            client.ping().await.unwrap();
         });
     }
 
+    let mut csv = match config.csv {
+        Some(ref output) => Some(CsvWriter::new(output)?),
+        None => None,
+    };
+
     let (sender, mut receiver) = mpsc::unbounded();
-    for _ in 0..config.tasks {
-        let task_sender = sender.clone();
-        let task_connector = connector.clone();
-        let BenchmarkConfig {
-            key_length,
-            value_length,
-            delay,
-            ..
-        } = config;
-        tokio::spawn(async move {
-            let task_client = task_connector
-                .connect()
-                .await
-                .unwrap_or_else(|err| panic!("Connection failed: {}", err));
-            let key = random_bytes(key_length);
-            let value = random_bytes(value_length);
-            B::do_task(task_client, key, value, delay, task_sender).await
-        });
+    let initial_tasks = match config.ramp {
+        Some(ref ramp) => ramp.start.min(config.tasks),
+        None => config.tasks,
+    };
+    for _ in 0..initial_tasks {
+        spawn_task::<B>(&connector, &config, sender.clone());
     }
+    let mut active_tasks = initial_tasks;
 
-    let (interval_sender, mut interval_receiver) = mpsc::unbounded();
-    tokio::spawn(async move {
-        let mut interval = time::interval(Duration::from_secs(1));
-        loop {
-            interval.tick().await;
-            interval_sender.unbounded_send(()).unwrap();
-        }
-    });
+    let mut tick_receiver = ticker(Duration::from_secs(1));
+    let mut ramp_receiver = match config.ramp {
+        Some(ref ramp) => ticker(ramp.interval),
+        // Closed right away, so select! stops polling it.
+        None => mpsc::unbounded().1,
+    };
 
     loop {
         select! {
@@ -217,11 +222,165 @@ async fn run_benchmark_inner<B: Benchmark>(
                 let message = maybe_message.expect("All tasks are dead");
                 benchmark.handle_message(message);
             }
-            _ = interval_receiver.next() => benchmark.handle_tick(),
+            _ = tick_receiver.next() => {
+                let stats = TickStats::new(benchmark.handle_tick());
+                info!(
+                    "Tasks: {}, RPS: {} (average latency: {}, p50: {}, p99: {})",
+                    active_tasks, stats.requests, stats.average, stats.p50, stats.p99
+                );
+                if let Some(ref mut csv) = csv {
+                    csv.write_line(active_tasks, &stats)?;
+                }
+            }
+            _ = ramp_receiver.next() => {
+                let added = config
+                    .ramp
+                    .map_or(0, |ramp| ramp.step_size(active_tasks, config.tasks));
+                for _ in 0..added {
+                    spawn_task::<B>(&connector, &config, sender.clone());
+                }
+                if added > 0 {
+                    active_tasks += added;
+                    info!("Ramped up to {} tasks", active_tasks);
+                }
+            }
+        }
+    }
+}
+
+fn spawn_task<B: Benchmark>(
+    connector: &RayClientConnector,
+    config: &BenchmarkConfig,
+    sender: mpsc::UnboundedSender<B::Message>,
+) {
+    let connector = connector.clone();
+    let BenchmarkConfig {
+        key_length,
+        value_length,
+        delay,
+        ..
+    } = *config;
+    tokio::spawn(async move {
+        let client = connector.connect_retrying().await;
+        let key = random_bytes(key_length);
+        let value = random_bytes(value_length);
+        B::do_task(client, key, value, delay, sender).await
+    });
+}
+
+// Yields every period, starting one period from now.
+fn ticker(period: Duration) -> mpsc::UnboundedReceiver<()> {
+    let (sender, receiver) = mpsc::unbounded();
+    tokio::spawn(async move {
+        let mut interval = time::interval_at(time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            if sender.unbounded_send(()).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+// Latencies in seconds.
+struct TickStats {
+    requests: usize,
+    average: f64,
+    p50: f64,
+    p99: f64,
+}
+
+impl TickStats {
+    fn new(mut latencies: Vec<f64>) -> Self {
+        latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let requests = latencies.len();
+        let average = if requests > 0 {
+            latencies.iter().sum::<f64>() / (requests as f64)
+        } else {
+            0.
+        };
+        Self {
+            requests,
+            average,
+            p50: percentile(&latencies, 0.5),
+            p99: percentile(&latencies, 0.99),
         }
     }
 }
 
+fn percentile(sorted: &[f64], fraction: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.;
+    }
+    let index = ((sorted.len() - 1) as f64 * fraction).round() as usize;
+    sorted[index]
+}
+
+struct CsvWriter {
+    writer: Box<dyn Write + Send>,
+}
+
+impl CsvWriter {
+    fn new(output: &CsvOutput) -> io::Result<Self> {
+        let writer: Box<dyn Write + Send> = match output {
+            CsvOutput::Stdout => Box::new(io::stdout()),
+            CsvOutput::File(path) => Box::new(File::create(path)?),
+        };
+        let mut csv = Self { writer };
+        writeln!(csv.writer, "timestamp,tasks,rps,p50,p99")?;
+        csv.writer.flush()?;
+        Ok(csv)
+    }
+
+    // Flushed right away, so that the series can be plotted while the benchmark runs.
+    fn write_line(&mut self, tasks: u16, stats: &TickStats) -> io::Result<()> {
+        writeln!(
+            self.writer,
+            "{},{},{},{},{}",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            tasks,
+            stats.requests,
+            stats.p50,
+            stats.p99
+        )?;
+        self.writer.flush()
+    }
+}
+
 fn random_bytes(length: usize) -> Vec<u8> {
     (0..length).map(|_| rand::random::<u8>()).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ramp_adds_a_step_at_a_time_up_to_the_task_count() {
+        let ramp = RampConfig {
+            start: 2,
+            step: 2,
+            interval: Duration::from_secs(1),
+        };
+        let mut active = ramp.start;
+        let mut schedule = vec![active];
+        for _ in 0..4 {
+            active += ramp.step_size(active, 7);
+            schedule.push(active);
+        }
+        assert_eq!(schedule, [2, 4, 6, 7, 7]);
+    }
+
+    #[tokio::test]
+    async fn ticker_yields_once_per_period() {
+        let period = Duration::from_millis(100);
+        let start = Instant::now();
+        let mut ticks = ticker(period);
+        for tick in 1..=3 {
+            ticks.next().await.unwrap();
+            assert!(start.elapsed() >= period * tick);
+        }
+        assert!(start.elapsed() < period * 4);
+    }
+}
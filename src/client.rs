@@ -1,47 +1,994 @@
-use super::proto;
+mod cache;
+mod chunks;
+mod sharded;
+
+use super::proto::{self, mutation::Kind, NOT_READY_METADATA};
+
+use cache::ReadCache;
+use chunks::{Manifest, CHUNK_NAMESPACE, MANIFEST_LEN};
+pub use sharded::ShardedRayClient;
+
+use byte_string::ByteStr;
+
+use futures::{Stream, StreamExt};
+
+use tokio::{net::UnixStream, time};
 
 use tonic::{
-    transport::{Channel, Error},
-    Request, Status,
+    codec::Streaming,
+    transport::{Channel, Endpoint, Error, Uri},
+    Code, Request, Response, Status,
 };
+use tower::service_fn;
+
+use rand::Rng;
+
+use uuid::Uuid;
+
+use std::{cmp, future::Future, time::Duration};
+
+type StorageClient = proto::storage_client::StorageClient<Channel>;
+
+#[derive(Clone, Debug)]
+pub struct RayClientConfig {
+    pub pool_size: usize,
+    pub request_timeout: Option<Duration>,
+    // Retries are only ever applied to idempotent methods, or to methods made idempotent with
+    // a request id, unless rayd rejected the request as still recovering, see is_not_ready.
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    // Read your writes: gets pass the session epoch, see session_epoch, so that they wait for
+    // lagging replicas to catch up with it.
+    pub read_your_writes: bool,
+    // Values of the default namespace over this many bytes are stored in chunks, see set.
+    // Every client of such keys needs it set, to some size, to read them back.
+    pub chunk_size: Option<usize>,
+}
+
+impl Default for RayClientConfig {
+    fn default() -> Self {
+        Self {
+            pool_size: 1,
+            request_timeout: None,
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+            read_your_writes: true,
+            chunk_size: None,
+        }
+    }
+}
 
 pub struct RayClient {
-    client: proto::storage_client::StorageClient<Channel>,
+    // Each client owns a separate connection; calls are dispatched round-robin.
+    clients: Vec<StorageClient>,
+    next_client: usize,
+    config: RayClientConfig,
+    // None unless enabled with with_cache.
+    cache: Option<ReadCache>,
+    session_epoch: u64,
 }
 
 impl RayClient {
+    // Pass unix:<path> as the address to connect over a Unix socket; the port is then ignored.
     pub async fn connect(address: &str, port: u16) -> Result<Self, Error> {
+        Self::connect_with_config(address, port, RayClientConfig::default()).await
+    }
+
+    pub async fn connect_with_config(
+        address: &str,
+        port: u16,
+        config: RayClientConfig,
+    ) -> Result<Self, Error> {
         let url = format!("http://{}:{}", address, port);
-        proto::storage_client::StorageClient::connect(url)
-            .await
-            .map(|client| RayClient { client })
+        let mut clients = Vec::with_capacity(config.pool_size);
+        for _ in 0..config.pool_size.max(1) {
+            let client = match address.strip_prefix("unix:") {
+                Some(path) => StorageClient::new(connect_unix(path).await?),
+                None => StorageClient::connect(url.clone()).await?,
+            };
+            clients.push(client);
+        }
+        Ok(RayClient {
+            clients,
+            next_client: 0,
+            config,
+            cache: None,
+            session_epoch: 0,
+        })
+    }
+
+    // Serves repeated gets of up to capacity keys from memory instead of rayd. Writes made
+    // through this client drop the keys they touch, but writes of other clients are only seen
+    // once an entry is older than max_staleness; with None, never. Reads at an epoch are not
+    // cached.
+    pub fn with_cache(mut self, capacity: usize, max_staleness: Option<Duration>) -> Self {
+        self.cache = Some(ReadCache::new(capacity, max_staleness));
+        self
     }
 
+    pub fn pool_size(&self) -> usize {
+        self.clients.len()
+    }
+
+    // The highest epoch seen in replies to sets, gets and syncs. Pass it to observe_epoch of
+    // the client reading from a replica to have it see the writes made through this one.
+    pub fn session_epoch(&self) -> u64 {
+        self.session_epoch
+    }
+
+    pub fn observe_epoch(&mut self, epoch: u64) {
+        self.session_epoch = cmp::max(self.session_epoch, epoch);
+    }
+
+    // A missing key reads as an empty value, see get_opt to tell them apart.
     pub async fn get(&mut self, key: Vec<u8>) -> Result<Vec<u8>, Status> {
-        let request = Request::new(proto::GetRequest { key });
-        let response = self.client.get(request).await;
-        response.map(|resp| resp.into_inner().value)
+        Ok(self.get_opt(key).await?.unwrap_or_default())
+    }
+
+    // None if the key is missing. Servers that predate GetReply.found report keys set to an
+    // empty value as missing.
+    pub async fn get_opt(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>, Status> {
+        let value = self.get_stored(key.clone()).await?;
+        if self.config.chunk_size.is_none() {
+            return Ok(value);
+        }
+        self.reassemble(key, value).await
+    }
+
+    // Reads the value as of the given epoch, see GetRequest in ray.proto. Chunked values are
+    // returned as their manifest.
+    pub async fn get_at(&mut self, key: Vec<u8>, epoch: u64) -> Result<Vec<u8>, Status> {
+        let value = self
+            .get_request(proto::GetRequest {
+                key,
+                at_epoch: epoch,
+                namespace: vec![],
+                min_epoch: 0,
+            })
+            .await?;
+        Ok(value.unwrap_or_default())
+    }
+
+    // Namespaces are separate keyspaces, see SetRequest in ray.proto.
+    pub async fn get_in(&mut self, namespace: Vec<u8>, key: Vec<u8>) -> Result<Vec<u8>, Status> {
+        let value = self
+            .get_request(proto::GetRequest {
+                key,
+                at_epoch: 0,
+                namespace,
+                min_epoch: 0,
+            })
+            .await?;
+        Ok(value.unwrap_or_default())
+    }
+
+    // Also tells the epoch the value was read at, see GetWithMetaReply in ray.proto. Never
+    // served from the cache.
+    pub async fn get_with_meta(
+        &mut self,
+        namespace: Vec<u8>,
+        key: Vec<u8>,
+    ) -> Result<proto::GetWithMetaReply, Status> {
+        let min_epoch = self.min_epoch();
+        let reply = self
+            .call(true, move |mut client| {
+                let request = Request::new(proto::GetRequest {
+                    key: key.clone(),
+                    at_epoch: 0,
+                    namespace: namespace.clone(),
+                    min_epoch,
+                });
+                async move { client.get_with_meta(request).await }
+            })
+            .await?;
+        self.observe_epoch(reply.epoch);
+        Ok(reply)
+    }
+
+    async fn get_stored(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>, Status> {
+        self.get_request(proto::GetRequest {
+            key,
+            at_epoch: 0,
+            namespace: vec![],
+            min_epoch: 0,
+        })
+        .await
+    }
+
+    // Reads the chunks of a manifest. Chunks go missing when the value is replaced meanwhile,
+    // in which case the new value is read instead.
+    async fn reassemble(
+        &mut self,
+        key: Vec<u8>,
+        mut value: Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>, Status> {
+        for _ in 0..=self.config.max_retries {
+            let manifest = match value.as_deref().and_then(Manifest::decode) {
+                Some(manifest) => manifest,
+                None => return Ok(value),
+            };
+            match self.read_chunks(&manifest).await? {
+                Some(assembled) => return Ok(Some(assembled)),
+                None => {
+                    let current = self.get_stored(key.clone()).await?;
+                    if current.as_deref().and_then(Manifest::decode) == Some(manifest) {
+                        let message = format!("chunks of {:?} are missing", ByteStr::new(&key));
+                        return Err(Status::new(Code::DataLoss, message));
+                    }
+                    value = current;
+                }
+            }
+        }
+        let message = format!("{:?} kept changing while read", ByteStr::new(&key));
+        Err(Status::new(Code::Aborted, message))
     }
 
+    // None if a chunk is missing. Chunks are never cached, as they are large.
+    async fn read_chunks(&mut self, manifest: &Manifest) -> Result<Option<Vec<u8>>, Status> {
+        let min_epoch = self.min_epoch();
+        let mut value = Vec::with_capacity(manifest.len as usize);
+        for key in manifest.chunk_keys() {
+            let reply = self
+                .call(true, move |mut client| {
+                    let request = Request::new(proto::GetRequest {
+                        key: key.clone(),
+                        at_epoch: 0,
+                        namespace: CHUNK_NAMESPACE.to_vec(),
+                        min_epoch,
+                    });
+                    async move { client.get(request).await }
+                })
+                .await?;
+            self.observe_epoch(reply.epoch);
+            if !reply.found && reply.value.is_empty() {
+                return Ok(None);
+            }
+            value.extend_from_slice(&reply.value);
+        }
+        if value.len() as u64 != manifest.len {
+            return Ok(None);
+        }
+        Ok(Some(value))
+    }
+
+    // Errors are ignored: chunks left behind only take up space.
+    async fn remove_chunks(&mut self, manifest: &Manifest) {
+        let mutations = manifest
+            .chunk_keys()
+            .map(|key| proto::Mutation {
+                kind: Some(Kind::Delete(proto::DeleteRequest {
+                    key,
+                    namespace: CHUNK_NAMESPACE.to_vec(),
+                    request_id: vec![],
+                })),
+            })
+            .collect();
+        self.transaction(vec![], mutations).await.ok();
+    }
+
+    async fn remove_chunks_of(&mut self, previous: &[u8]) {
+        if let Some(manifest) = Manifest::decode(previous) {
+            self.remove_chunks(&manifest).await;
+        }
+    }
+
+    async fn get_request(
+        &mut self,
+        mut request: proto::GetRequest,
+    ) -> Result<Option<Vec<u8>>, Status> {
+        let cache_key = match self.cache {
+            Some(ref mut cache) if request.at_epoch == 0 => {
+                if let Some(value) = cache.get(&request.namespace, &request.key) {
+                    return Ok(value);
+                }
+                Some((request.namespace.clone(), request.key.clone()))
+            }
+            _ => None,
+        };
+
+        request.min_epoch = self.min_epoch();
+        let reply = self
+            .call(true, move |mut client| {
+                let request = Request::new(request.clone());
+                async move { client.get(request).await }
+            })
+            .await?;
+        self.observe_epoch(reply.epoch);
+        // A non-empty value is there even if the server does not set found.
+        let value = if reply.found || !reply.value.is_empty() {
+            Some(reply.value)
+        } else {
+            None
+        };
+        if let (Some(cache), Some((namespace, key))) = (self.cache.as_mut(), cache_key) {
+            cache.insert(namespace, key, value.clone());
+        }
+        Ok(value)
+    }
+
+    // Sets all entries at once with a single journal write.
+    pub async fn batch_set(&mut self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), Status> {
+        for (key, _) in &entries {
+            self.invalidate(&[], key);
+        }
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|(key, value)| proto::KeyValue { key, value })
+            .collect();
+        self.call(true, move |mut client| {
+            let request = Request::new(proto::BatchSetRequest {
+                entries: entries.clone(),
+                request_id: vec![],
+            });
+            async move { client.batch_set(request).await }
+        })
+        .await?;
+        Ok(())
+    }
+
+    // Applies the mutations only if all conditions hold, see TransactionRequest in ray.proto.
+    // Retried under the same request id. Returns whether the transaction was committed.
+    pub async fn transaction(
+        &mut self,
+        conditions: Vec<proto::Condition>,
+        mutations: Vec<proto::Mutation>,
+    ) -> Result<bool, Status> {
+        if let Some(ref mut cache) = self.cache {
+            for mutation in &mutations {
+                cache.remove_mutation(mutation);
+            }
+        }
+        let request_id = new_request_id();
+        let reply = self
+            .call(true, move |mut client| {
+                let request = Request::new(proto::TransactionRequest {
+                    conditions: conditions.clone(),
+                    mutations: mutations.clone(),
+                    request_id: request_id.clone(),
+                });
+                async move { client.transaction(request).await }
+            })
+            .await?;
+        Ok(reply.committed)
+    }
+
+    // Streams the entries to rayd, which journals them in batches as they arrive, and returns
+    // how many were set. Neither retried nor subject to the request timeout, as the entries
+    // are consumed as they are sent.
+    pub async fn bulk_set<S>(&mut self, entries: S) -> Result<u64, Status>
+    where
+        S: Stream<Item = (Vec<u8>, Vec<u8>)> + Send + Sync + 'static,
+    {
+        // The keys are not known up front.
+        if let Some(ref mut cache) = self.cache {
+            cache.clear();
+        }
+        let requests = entries.map(|(key, value)| proto::SetRequest {
+            key,
+            value,
+            request_id: vec![],
+            namespace: vec![],
+        });
+        let reply = self.pick_client().bulk_set(Request::new(requests)).await?;
+        Ok(reply.into_inner().count)
+    }
+
+    pub async fn exists(&mut self, key: Vec<u8>) -> Result<bool, Status> {
+        self.exists_in(vec![], key).await
+    }
+
+    pub async fn exists_in(&mut self, namespace: Vec<u8>, key: Vec<u8>) -> Result<bool, Status> {
+        let reply = self
+            .call(true, move |mut client| {
+                let request = Request::new(proto::ExistsRequest {
+                    key: key.clone(),
+                    namespace: namespace.clone(),
+                });
+                async move { client.exists(request).await }
+            })
+            .await?;
+        Ok(reply.exists)
+    }
+
+    // With chunk_size set, a larger value is written in chunks of that size, then a manifest
+    // listing them is set in its place. Values of any size replace the previous one with
+    // get_set, to remove its chunks if it was a manifest. A set that fails before the manifest
+    // is in place removes the chunks written so far; chunks are left behind only if it is
+    // unknown whether it was set.
     pub async fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Status> {
-        let request = Request::new(proto::SetRequest { key, value });
-        let response = self.client.set(request).await;
-        response.map(|_| ())
+        let chunk_size = match self.config.chunk_size {
+            Some(chunk_size) => chunk_size.max(1),
+            None => return self.set_in(vec![], key, value).await,
+        };
+        if value.len() <= chunk_size && Manifest::decode(&value).is_none() {
+            let previous = self.replace(key, value).await?;
+            self.remove_chunks_of(&previous).await;
+            return Ok(());
+        }
+
+        let manifest = Manifest {
+            id: new_request_id(),
+            len: value.len() as u64,
+            chunk_size: chunk_size.min(u32::MAX as usize) as u32,
+        };
+        let chunks = value.chunks(manifest.chunk_size as usize);
+        for (chunk_key, chunk) in manifest.chunk_keys().zip(chunks) {
+            let result = self
+                .set_in(CHUNK_NAMESPACE.to_vec(), chunk_key, chunk.to_vec())
+                .await;
+            if let Err(status) = result {
+                self.remove_chunks(&manifest).await;
+                return Err(status);
+            }
+        }
+        match self.replace(key, manifest.encode()).await {
+            Ok(previous) => {
+                self.remove_chunks_of(&previous).await;
+                Ok(())
+            }
+            Err(status) => {
+                if !is_transient(&status) {
+                    self.remove_chunks(&manifest).await;
+                }
+                Err(status)
+            }
+        }
+    }
+
+    pub async fn set_in(
+        &mut self,
+        namespace: Vec<u8>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<(), Status> {
+        self.invalidate(&namespace, &key);
+        let reply = self
+            .call(true, move |mut client| {
+                let request = Request::new(proto::SetRequest {
+                    key: key.clone(),
+                    value: value.clone(),
+                    request_id: vec![],
+                    namespace: namespace.clone(),
+                });
+                async move { client.set(request).await }
+            })
+            .await?;
+        self.observe_epoch(reply.epoch);
+        Ok(())
+    }
+
+    // The chunks of a chunked value are removed after it. A value set in between by another
+    // client is deleted without its chunks, which are then left behind.
+    pub async fn delete(&mut self, key: Vec<u8>) -> Result<bool, Status> {
+        if self.config.chunk_size.is_none() {
+            return self.delete_in(vec![], key).await;
+        }
+        let previous = self.get_stored(key.clone()).await?;
+        let found = self.delete_in(vec![], key).await?;
+        if let Some(previous) = previous {
+            self.remove_chunks_of(&previous).await;
+        }
+        Ok(found)
+    }
+
+    // Returns whether the key was set. Retried like increment, so that a retry still tells.
+    pub async fn delete_in(&mut self, namespace: Vec<u8>, key: Vec<u8>) -> Result<bool, Status> {
+        self.invalidate(&namespace, &key);
+        let request_id = new_request_id();
+        let reply = self
+            .call(true, move |mut client| {
+                let request = Request::new(proto::DeleteRequest {
+                    key: key.clone(),
+                    namespace: namespace.clone(),
+                    request_id: request_id.clone(),
+                });
+                async move { client.delete(request).await }
+            })
+            .await?;
+        Ok(reply.found)
+    }
+
+    // Removes every key of the namespace at once and returns how many there were. Retried
+    // like increment.
+    pub async fn flush_namespace(&mut self, namespace: Vec<u8>) -> Result<u64, Status> {
+        if let Some(ref mut cache) = self.cache {
+            cache.remove_namespace(&namespace);
+        }
+        let request_id = new_request_id();
+        let reply = self
+            .call(true, move |mut client| {
+                let request = Request::new(proto::FlushNamespaceRequest {
+                    namespace: namespace.clone(),
+                    request_id: request_id.clone(),
+                });
+                async move { client.flush_namespace(request).await }
+            })
+            .await?;
+        Ok(reply.count)
+    }
+
+    // Removes every key of the namespace starting with the prefix, which must not be empty,
+    // and returns how many there were. Retried like increment.
+    pub async fn delete_prefix(
+        &mut self,
+        namespace: Vec<u8>,
+        prefix: Vec<u8>,
+    ) -> Result<u64, Status> {
+        if let Some(ref mut cache) = self.cache {
+            cache.remove_prefix(&namespace, &prefix);
+        }
+        let request_id = new_request_id();
+        let reply = self
+            .call(true, move |mut client| {
+                let request = Request::new(proto::DeletePrefixRequest {
+                    prefix: prefix.clone(),
+                    namespace: namespace.clone(),
+                    request_id: request_id.clone(),
+                });
+                async move { client.delete_prefix(request).await }
+            })
+            .await?;
+        Ok(reply.count)
+    }
+
+    // Removes every key of every namespace and returns how many there were. Fails unless the
+    // server allows clears. Retried like increment.
+    pub async fn clear(&mut self) -> Result<u64, Status> {
+        if let Some(ref mut cache) = self.cache {
+            cache.clear();
+        }
+        let request_id = new_request_id();
+        let reply = self
+            .call(true, move |mut client| {
+                let request = Request::new(proto::ClearRequest {
+                    request_id: request_id.clone(),
+                });
+                async move { client.clear(request).await }
+            })
+            .await?;
+        Ok(reply.count)
+    }
+
+    // Sets the value and returns the one it replaced, with no other write in between. Retried
+    // like increment, so a retry returns the value replaced by the original call.
+    pub async fn get_set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<Vec<u8>, Status> {
+        self.get_set_within(key, value, 0).await
+    }
+
+    // Same as get_set, but the value replaced is only sent back if it could be a manifest, so
+    // that overwriting a plain value downloads nothing.
+    async fn replace(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<Vec<u8>, Status> {
+        self.get_set_within(key, value, MANIFEST_LEN as u64).await
     }
+
+    async fn get_set_within(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        max_previous_size: u64,
+    ) -> Result<Vec<u8>, Status> {
+        self.invalidate(&[], &key);
+        let request_id = new_request_id();
+        let reply = self
+            .call(true, move |mut client| {
+                let request = Request::new(proto::GetSetRequest {
+                    key: key.clone(),
+                    value: value.clone(),
+                    request_id: request_id.clone(),
+                    max_previous_size,
+                });
+                async move { client.get_set(request).await }
+            })
+            .await?;
+        self.observe_epoch(reply.epoch);
+        Ok(reply.value)
+    }
+
+    // Retried under the same request id, so that rayd applies the delta only once.
+    pub async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<i64, Status> {
+        self.invalidate(&[], &key);
+        let request_id = new_request_id();
+        let reply = self
+            .call(true, move |mut client| {
+                let request = Request::new(proto::IncrementRequest {
+                    key: key.clone(),
+                    delta,
+                    request_id: request_id.clone(),
+                });
+                async move { client.increment(request).await }
+            })
+            .await?;
+        Ok(reply.value)
+    }
+
+    // Retried like increment. Returns the new length of the value.
+    pub async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<u64, Status> {
+        self.invalidate(&[], &key);
+        let request_id = new_request_id();
+        let reply = self
+            .call(true, move |mut client| {
+                let request = Request::new(proto::AppendRequest {
+                    key: key.clone(),
+                    suffix: suffix.clone(),
+                    request_id: request_id.clone(),
+                });
+                async move { client.append(request).await }
+            })
+            .await?;
+        Ok(reply.length)
+    }
+
+    // Streams all entries in key order. To resume a dropped dump, pass the last key received.
+    pub async fn dump_keys(
+        &mut self,
+        start_after: Vec<u8>,
+    ) -> Result<Streaming<proto::KeyValue>, Status> {
+        self.dump_keys_in(vec![], start_after).await
+    }
+
+    // Streams the entries of a single namespace, with keys as they were set in it.
+    pub async fn dump_keys_in(
+        &mut self,
+        namespace: Vec<u8>,
+        start_after: Vec<u8>,
+    ) -> Result<Streaming<proto::KeyValue>, Status> {
+        self.call(true, move |mut client| {
+            let request = Request::new(proto::DumpKeysRequest {
+                start_after: start_after.clone(),
+                namespace: namespace.clone(),
+            });
+            async move { client.dump_keys(request).await }
+        })
+        .await
+    }
+
+    // Streams changes of the keys starting with the prefix from now on, in epoch order. An
+    // event with missed set stands for the events this client fell too far behind to receive.
+    pub async fn watch(
+        &mut self,
+        prefix: Vec<u8>,
+    ) -> Result<Streaming<proto::ChangeEvent>, Status> {
+        self.watch_in(vec![], prefix).await
+    }
+
+    pub async fn watch_in(
+        &mut self,
+        namespace: Vec<u8>,
+        prefix: Vec<u8>,
+    ) -> Result<Streaming<proto::ChangeEvent>, Status> {
+        self.call(true, move |mut client| {
+            let request = Request::new(proto::WatchRequest {
+                prefix: prefix.clone(),
+                namespace: namespace.clone(),
+            });
+            async move { client.watch(request).await }
+        })
+        .await
+    }
+
+    pub async fn ping(&mut self) -> Result<(), Status> {
+        self.call(true, |mut client| {
+            let request = Request::new(proto::PingRequest {});
+            async move { client.ping(request).await }
+        })
+        .await?;
+        Ok(())
+    }
+
+    pub async fn info(&mut self) -> Result<proto::InfoReply, Status> {
+        self.call(true, |mut client| {
+            let request = Request::new(proto::InfoRequest {});
+            async move { client.info(request).await }
+        })
+        .await
+    }
+
+    pub async fn trigger_snapshot(&mut self) -> Result<u64, Status> {
+        let reply = self
+            .call(false, |mut client| {
+                let request = Request::new(proto::TriggerSnapshotRequest {});
+                async move { client.trigger_snapshot(request).await }
+            })
+            .await?;
+        Ok(reply.epoch)
+    }
+
+    // Returns the persisted epoch, which covers every write acknowledged before the call.
+    pub async fn sync(&mut self) -> Result<u64, Status> {
+        let reply = self
+            .call(true, |mut client| {
+                let request = Request::new(proto::SyncRequest {});
+                async move { client.sync(request).await }
+            })
+            .await?;
+        self.observe_epoch(reply.persisted_epoch);
+        Ok(reply.persisted_epoch)
+    }
+
+    async fn call<R, F, T>(&mut self, idempotent: bool, make_call: F) -> Result<R, Status>
+    where
+        F: Fn(StorageClient) -> T,
+        T: Future<Output = Result<Response<R>, Status>>,
+    {
+        let mut attempt = 0;
+        let mut backoff = self.config.initial_backoff;
+        loop {
+            let call = make_call(self.pick_client().clone());
+            let result = match self.config.request_timeout {
+                Some(timeout) => match time::timeout(timeout, call).await {
+                    Ok(result) => result,
+                    Err(_) => Err(Status::new(Code::DeadlineExceeded, "request timed out")),
+                },
+                None => call.await,
+            };
+
+            match result {
+                Err(ref status)
+                    if ((idempotent && is_transient(status)) || is_not_ready(status))
+                        && attempt < self.config.max_retries =>
+                {
+                    attempt += 1;
+                    time::delay_for(backoff).await;
+                    backoff = cmp::min(backoff * 2, self.config.max_backoff);
+                }
+                result => return result.map(Response::into_inner),
+            }
+        }
+    }
+
+    // Done before the write is sent rather than after it succeeds, as a write that fails may
+    // still have been applied.
+    fn invalidate(&mut self, namespace: &[u8], key: &[u8]) {
+        if let Some(ref mut cache) = self.cache {
+            cache.remove(namespace, key);
+        }
+    }
+
+    fn min_epoch(&self) -> u64 {
+        if self.config.read_your_writes {
+            self.session_epoch
+        } else {
+            0
+        }
+    }
+
+    fn pick_client(&mut self) -> &mut StorageClient {
+        let index = self.next_client;
+        self.next_client = (self.next_client + 1) % self.clients.len();
+        &mut self.clients[index]
+    }
+}
+
+fn new_request_id() -> Vec<u8> {
+    Uuid::new_v4().as_bytes().to_vec()
+}
+
+// Whether rayd rejected the request as it is still recovering, in which case the request was
+// not applied and is safe to send again.
+pub fn is_not_ready(status: &Status) -> bool {
+    status.code() == Code::Unavailable && status.metadata().contains_key(NOT_READY_METADATA)
+}
+
+fn is_transient(status: &Status) -> bool {
+    matches!(status.code(), Code::Unavailable | Code::DeadlineExceeded)
+}
+
+async fn connect_unix(path: &str) -> Result<Channel, Error> {
+    let path = path.to_string();
+    // The URI is required but unused: every connection dials the socket.
+    Endpoint::from_static("http://localhost")
+        .connect_with_connector(service_fn(move |_: Uri| UnixStream::connect(path.clone())))
+        .await
 }
 
 #[derive(Clone)]
 pub struct RayClientConnector {
     address: String,
     port: u16,
+    config: RayClientConfig,
+    connect_retries: u32,
+    connect_initial_backoff: Duration,
+    connect_max_backoff: Duration,
 }
 
 impl RayClientConnector {
     pub fn new(address: String, port: u16) -> Self {
-        Self { address, port }
+        Self {
+            address,
+            port,
+            config: RayClientConfig::default(),
+            connect_retries: 3,
+            connect_initial_backoff: Duration::from_millis(100),
+            connect_max_backoff: Duration::from_secs(5),
+        }
+    }
+
+    pub fn with_config(mut self, config: RayClientConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn with_pool_size(mut self, pool_size: usize) -> Self {
+        self.config.pool_size = pool_size.max(1);
+        self
+    }
+
+    // Number of times connect() retries a failed connection; connect_retrying() ignores it.
+    pub fn with_connect_retries(mut self, retries: u32) -> Self {
+        self.connect_retries = retries;
+        self
+    }
+
+    pub fn with_connect_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.connect_initial_backoff = initial;
+        self.connect_max_backoff = max.max(initial);
+        self
     }
 
     pub async fn connect(&self) -> Result<RayClient, Error> {
-        RayClient::connect(&self.address, self.port).await
+        self.connect_with_retries(Some(self.connect_retries)).await
+    }
+
+    // Keeps retrying until rayd accepts the connection.
+    pub async fn connect_retrying(&self) -> RayClient {
+        match self.connect_with_retries(None).await {
+            Ok(client) => client,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    async fn connect_with_retries(&self, retries: Option<u32>) -> Result<RayClient, Error> {
+        let mut attempt = 0;
+        let mut backoff = self.connect_initial_backoff;
+        loop {
+            let err =
+                match RayClient::connect_with_config(&self.address, self.port, self.config.clone())
+                    .await
+                {
+                    Ok(client) => return Ok(client),
+                    Err(err) => err,
+                };
+            if retries.is_some_and(|retries| attempt >= retries) {
+                return Err(err);
+            }
+            attempt += 1;
+
+            // Clients cut off by a restart of rayd would otherwise all come back at once.
+            let delay = jittered(backoff);
+            debug!(
+                "Connection to {}:{} failed, retrying in {:?}: {}",
+                self.address, self.port, delay, err
+            );
+            time::delay_for(delay).await;
+            backoff = cmp::min(backoff * 2, self.connect_max_backoff);
+        }
+    }
+}
+
+// Somewhere between half of the backoff and all of it.
+fn jittered(backoff: Duration) -> Duration {
+    let half = backoff / 2;
+    half + half.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::net::TcpListener;
+
+    use std::{
+        collections::HashSet,
+        net::SocketAddr,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    // A client whose calls never reach a server, for tests that stub the calls out.
+    fn unconnected_client(config: RayClientConfig) -> RayClient {
+        let channel = Endpoint::from_static("http://127.0.0.1:1")
+            .connect_lazy()
+            .unwrap();
+        RayClient {
+            clients: vec![StorageClient::new(channel)],
+            next_client: 0,
+            config,
+            cache: None,
+            session_epoch: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn pool_opens_a_connection_per_client() {
+        let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut listener = TcpListener::bind(address).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accepted = tokio::spawn(async move {
+            let mut peers = HashSet::new();
+            let mut sockets = vec![];
+            while peers.len() < 4 {
+                let (socket, peer) = listener.accept().await.unwrap();
+                peers.insert(peer);
+                sockets.push(socket);
+            }
+            (peers, sockets)
+        });
+
+        let config = RayClientConfig {
+            pool_size: 4,
+            ..Default::default()
+        };
+        let client = RayClient::connect_with_config("127.0.0.1", port, config)
+            .await
+            .unwrap();
+        assert_eq!(client.pool_size(), 4);
+        let (peers, _sockets) = accepted.await.unwrap();
+        assert_eq!(peers.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn connections_are_retried_with_backoff_until_rayd_is_up() {
+        // Nothing listens on the port until the listener is bound again below.
+        let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let port = TcpListener::bind(address)
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let connector = RayClientConnector::new("127.0.0.1".into(), port)
+            .with_connect_retries(3)
+            .with_connect_backoff(Duration::from_millis(40), Duration::from_millis(80));
+
+        // Retries wait at least half of the backoffs of 40, 80 and 80 ms.
+        let started = time::Instant::now();
+        assert!(connector.connect().await.is_err());
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        let client = tokio::spawn(async move { connector.connect_retrying().await });
+        time::delay_for(Duration::from_millis(300)).await;
+        let address: SocketAddr = ([127, 0, 0, 1], port).into();
+        let mut listener = TcpListener::bind(address).await.unwrap();
+        let (_socket, _) = listener.accept().await.unwrap();
+        let client = time::timeout(Duration::from_secs(5), client).await.unwrap();
+        assert_eq!(client.unwrap().pool_size(), 1);
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried() {
+        let mut client = unconnected_client(RayClientConfig {
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        });
+        let attempts = AtomicU32::new(0);
+        let reply = client
+            .call(true, |_| {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if attempt < 2 {
+                        Err(Status::unavailable("down"))
+                    } else {
+                        Ok(Response::new(attempt))
+                    }
+                }
+            })
+            .await;
+        assert_eq!(reply.unwrap(), 2);
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn non_idempotent_calls_are_not_retried() {
+        let mut client = unconnected_client(RayClientConfig::default());
+        let attempts = AtomicU32::new(0);
+        let reply: Result<(), Status> = client
+            .call(false, |_| {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                async { Err(Status::unavailable("down")) }
+            })
+            .await;
+        assert_eq!(reply.unwrap_err().code(), Code::Unavailable);
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
     }
 }
@@ -0,0 +1,226 @@
+use crate::proto::{self, mutation::Kind};
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+// Namespace and key.
+type CacheKey = (Vec<u8>, Vec<u8>);
+
+// Values read by a single client, dropped when the client writes their keys. Writes of other
+// clients go unnoticed, so entries also expire after max_staleness, if set. Once there are
+// more than capacity entries, the least recently used one is evicted.
+pub struct ReadCache {
+    capacity: usize,
+    max_staleness: Option<Duration>,
+    entries: HashMap<CacheKey, CacheEntry>,
+    // Keys by the tick of their last use, least recent first.
+    recency: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+struct CacheEntry {
+    // None for a missing key.
+    value: Option<Vec<u8>>,
+    fetched: Instant,
+    used: u64,
+}
+
+impl ReadCache {
+    pub fn new(capacity: usize, max_staleness: Option<Duration>) -> Self {
+        Self {
+            capacity,
+            max_staleness,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    // None if the key is not cached, Some(None) if it is cached as missing.
+    pub fn get(&mut self, namespace: &[u8], key: &[u8]) -> Option<Option<Vec<u8>>> {
+        let cache_key = (namespace.to_vec(), key.to_vec());
+        let entry = self.entries.get_mut(&cache_key)?;
+        if let Some(max_staleness) = self.max_staleness {
+            if entry.fetched.elapsed() > max_staleness {
+                self.remove(namespace, key);
+                return None;
+            }
+        }
+
+        self.tick += 1;
+        self.recency.remove(&entry.used);
+        entry.used = self.tick;
+        let value = entry.value.clone();
+        self.recency.insert(self.tick, cache_key);
+        Some(value)
+    }
+
+    pub fn insert(&mut self, namespace: Vec<u8>, key: Vec<u8>, value: Option<Vec<u8>>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&namespace, &key);
+
+        self.tick += 1;
+        let entry = CacheEntry {
+            value,
+            fetched: Instant::now(),
+            used: self.tick,
+        };
+        let cache_key = (namespace, key);
+        self.recency.insert(self.tick, cache_key.clone());
+        self.entries.insert(cache_key, entry);
+
+        if self.entries.len() > self.capacity {
+            let oldest = *self.recency.keys().next().unwrap();
+            let cache_key = self.recency.remove(&oldest).unwrap();
+            self.entries.remove(&cache_key);
+        }
+    }
+
+    pub fn remove(&mut self, namespace: &[u8], key: &[u8]) {
+        let cache_key = (namespace.to_vec(), key.to_vec());
+        if let Some(entry) = self.entries.remove(&cache_key) {
+            self.recency.remove(&entry.used);
+        }
+    }
+
+    pub fn remove_namespace(&mut self, namespace: &[u8]) {
+        let entries = &mut self.entries;
+        self.recency.retain(|_, (key_namespace, key)| {
+            if key_namespace[..] != *namespace {
+                return true;
+            }
+            entries.remove(&(key_namespace.clone(), key.clone()));
+            false
+        });
+    }
+
+    pub fn remove_prefix(&mut self, namespace: &[u8], prefix: &[u8]) {
+        let entries = &mut self.entries;
+        self.recency.retain(|_, (key_namespace, key)| {
+            if key_namespace[..] != *namespace || !key.starts_with(prefix) {
+                return true;
+            }
+            entries.remove(&(key_namespace.clone(), key.clone()));
+            false
+        });
+    }
+
+    // Drops the keys the mutation may write.
+    pub fn remove_mutation(&mut self, mutation: &proto::Mutation) {
+        match mutation.kind {
+            Some(Kind::Set(ref set)) => self.remove(&set.namespace, &set.key),
+            Some(Kind::GetSet(ref get_set)) => self.remove(&[], &get_set.key),
+            Some(Kind::Increment(ref increment)) => self.remove(&[], &increment.key),
+            Some(Kind::Append(ref append)) => self.remove(&[], &append.key),
+            Some(Kind::BatchSet(ref batch_set)) => {
+                for entry in &batch_set.entries {
+                    self.remove(&[], &entry.key);
+                }
+            }
+            Some(Kind::Transaction(ref transaction)) => {
+                for nested in &transaction.mutations {
+                    self.remove_mutation(nested);
+                }
+            }
+            Some(Kind::FlushNamespace(ref flush)) => self.remove_namespace(&flush.namespace),
+            Some(Kind::Clear(_)) => self.clear(),
+            Some(Kind::Delete(ref delete)) => self.remove(&delete.namespace, &delete.key),
+            Some(Kind::DeletePrefix(ref delete)) => {
+                self.remove_prefix(&delete.namespace, &delete.prefix)
+            }
+            None => (),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    fn cached(cache: &mut ReadCache, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        cache.get(b"", key)
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let mut cache = ReadCache::new(2, None);
+        cache.insert(vec![], b"a".to_vec(), Some(b"1".to_vec()));
+        cache.insert(vec![], b"b".to_vec(), None);
+        assert_eq!(cached(&mut cache, b"a"), Some(Some(b"1".to_vec())));
+        assert_eq!(cached(&mut cache, b"b"), Some(None));
+
+        // a was used before b, so it goes first.
+        cache.insert(vec![], b"c".to_vec(), Some(b"3".to_vec()));
+        assert_eq!(cached(&mut cache, b"a"), None);
+        assert_eq!(cached(&mut cache, b"b"), Some(None));
+        assert_eq!(cached(&mut cache, b"c"), Some(Some(b"3".to_vec())));
+        assert_eq!((cache.entries.len(), cache.recency.len()), (2, 2));
+    }
+
+    #[test]
+    fn writes_drop_the_keys_they_touch() {
+        let mut cache = ReadCache::new(10, None);
+        for namespace in &[&b""[..], b"ns"] {
+            for key in &[&b"a"[..], b"ab", b"b"] {
+                cache.insert(namespace.to_vec(), key.to_vec(), Some(b"value".to_vec()));
+            }
+        }
+
+        let set = proto::Mutation {
+            kind: Some(Kind::Set(proto::SetRequest {
+                key: b"a".to_vec(),
+                namespace: b"ns".to_vec(),
+                ..Default::default()
+            })),
+        };
+        let increment = proto::Mutation {
+            kind: Some(Kind::Increment(proto::IncrementRequest {
+                key: b"b".to_vec(),
+                ..Default::default()
+            })),
+        };
+        let transaction = proto::Mutation {
+            kind: Some(Kind::Transaction(proto::TransactionRequest {
+                mutations: vec![set, increment],
+                ..Default::default()
+            })),
+        };
+        cache.remove_mutation(&transaction);
+        assert!(cache.get(b"ns", b"a").is_none());
+        assert!(cached(&mut cache, b"b").is_none());
+        assert!(cached(&mut cache, b"a").is_some());
+        assert!(cache.get(b"ns", b"b").is_some());
+
+        cache.remove_prefix(b"", b"a");
+        assert!(cached(&mut cache, b"a").is_none());
+        assert!(cached(&mut cache, b"ab").is_none());
+        assert!(cache.get(b"ns", b"ab").is_some());
+
+        cache.remove_namespace(b"ns");
+        assert!(cache.entries.is_empty());
+        assert!(cache.recency.is_empty());
+    }
+
+    #[test]
+    fn entries_expire_after_max_staleness() {
+        let mut cache = ReadCache::new(10, Some(Duration::from_millis(50)));
+        cache.insert(vec![], b"a".to_vec(), Some(b"1".to_vec()));
+        assert!(cached(&mut cache, b"a").is_some());
+
+        // Using an entry does not make it any fresher.
+        thread::sleep(Duration::from_millis(60));
+        assert!(cached(&mut cache, b"a").is_none());
+        assert!(cache.entries.is_empty());
+    }
+}
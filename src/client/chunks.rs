@@ -0,0 +1,64 @@
+use std::convert::TryInto;
+
+// Chunks of large values are kept in their own namespace, under the id of the value they belong
+// to followed by their index, so that they never collide with the keys of the application.
+pub const CHUNK_NAMESPACE: &[u8] = b"ray.chunks";
+
+// Stored under the key of a chunked value in place of the value:
+//   [MAGIC][id: 16 bytes][value length: u64][chunk size: u32]
+// Integers are little-endian. Every chunk is chunk size bytes long but the last one.
+const MAGIC: &[u8] = b"\0ray-chunked\0";
+const ID_LEN: usize = 16;
+pub const MANIFEST_LEN: usize = MAGIC.len() + ID_LEN + 8 + 4;
+
+#[derive(PartialEq)]
+pub struct Manifest {
+    pub id: Vec<u8>,
+    pub len: u64,
+    pub chunk_size: u32,
+}
+
+impl Manifest {
+    // None unless the value is a manifest. Values that happen to look like one are stored in
+    // chunks themselves, so that they read back as they were set.
+    pub fn decode(value: &[u8]) -> Option<Self> {
+        if value.len() != MANIFEST_LEN || !value.starts_with(MAGIC) {
+            return None;
+        }
+        let (id, rest) = value[MAGIC.len()..].split_at(ID_LEN);
+        let (len, chunk_size) = rest.split_at(8);
+        let manifest = Manifest {
+            id: id.to_vec(),
+            len: u64::from_le_bytes(len.try_into().unwrap()),
+            chunk_size: u32::from_le_bytes(chunk_size.try_into().unwrap()),
+        };
+        if manifest.chunk_size == 0 {
+            return None;
+        }
+        Some(manifest)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut value = Vec::with_capacity(MANIFEST_LEN);
+        value.extend_from_slice(MAGIC);
+        value.extend_from_slice(&self.id);
+        value.extend_from_slice(&self.len.to_le_bytes());
+        value.extend_from_slice(&self.chunk_size.to_le_bytes());
+        value
+    }
+
+    pub fn chunk_count(&self) -> u64 {
+        self.len.div_ceil(u64::from(self.chunk_size))
+    }
+
+    pub fn chunk_keys(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        (0..self.chunk_count()).map(move |index| chunk_key(&self.id, index))
+    }
+}
+
+fn chunk_key(id: &[u8], index: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(id.len() + 8);
+    key.extend_from_slice(id);
+    key.extend_from_slice(&index.to_be_bytes());
+    key
+}
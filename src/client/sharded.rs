@@ -0,0 +1,206 @@
+use super::{RayClient, RayClientConnector};
+
+use hmac_sha256::HMAC;
+
+use tonic::{Code, Status};
+
+use std::convert::TryInto;
+
+// Spreads keys over several rayd instances, each holding a part of them, with rendezvous
+// hashing: a key goes to the shard whose name scores highest for it. Adding a shard only moves
+// the keys it wins over to it, and removing one only moves the keys it held. Clients sharing
+// the data must list the same endpoints, in any order.
+pub struct ShardedRayClient {
+    shards: Vec<Shard>,
+}
+
+struct Shard {
+    // address:port, scored against the keys.
+    name: String,
+    connector: RayClientConnector,
+    // Connected on first use, so that a shard that is down only fails the keys it holds.
+    client: Option<RayClient>,
+}
+
+impl ShardedRayClient {
+    pub fn new(endpoints: Vec<(String, u16)>) -> Self {
+        let connectors = endpoints
+            .into_iter()
+            .map(|(address, port)| RayClientConnector::new(address, port))
+            .collect();
+        Self::with_connectors(connectors)
+    }
+
+    // Shards are named after the address and port of their connectors, whatever the config.
+    pub fn with_connectors(connectors: Vec<RayClientConnector>) -> Self {
+        assert!(!connectors.is_empty(), "no shards given");
+        let shards = connectors
+            .into_iter()
+            .map(|connector| Shard {
+                name: format!("{}:{}", connector.address, connector.port),
+                connector,
+                client: None,
+            })
+            .collect();
+        Self { shards }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    // Index of the endpoint the key is routed to.
+    pub fn shard_of(&self, namespace: &[u8], key: &[u8]) -> usize {
+        let mut message = Vec::with_capacity(8 + namespace.len() + key.len());
+        message.extend_from_slice(&(namespace.len() as u64).to_le_bytes());
+        message.extend_from_slice(namespace);
+        message.extend_from_slice(key);
+        // Names break ties, so that the order of the endpoints never matters.
+        (0..self.shards.len())
+            .max_by_key(|&index| {
+                let name = &self.shards[index].name;
+                (score(name, &message), name)
+            })
+            .unwrap()
+    }
+
+    pub async fn get(&mut self, key: Vec<u8>) -> Result<Vec<u8>, Status> {
+        self.get_in(vec![], key).await
+    }
+
+    pub async fn get_opt(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>, Status> {
+        self.client_for(&[], &key).await?.get_opt(key).await
+    }
+
+    pub async fn get_in(&mut self, namespace: Vec<u8>, key: Vec<u8>) -> Result<Vec<u8>, Status> {
+        let client = self.client_for(&namespace, &key).await?;
+        client.get_in(namespace, key).await
+    }
+
+    pub async fn exists(&mut self, key: Vec<u8>) -> Result<bool, Status> {
+        self.exists_in(vec![], key).await
+    }
+
+    pub async fn exists_in(&mut self, namespace: Vec<u8>, key: Vec<u8>) -> Result<bool, Status> {
+        let client = self.client_for(&namespace, &key).await?;
+        client.exists_in(namespace, key).await
+    }
+
+    pub async fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Status> {
+        self.set_in(vec![], key, value).await
+    }
+
+    pub async fn set_in(
+        &mut self,
+        namespace: Vec<u8>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<(), Status> {
+        let client = self.client_for(&namespace, &key).await?;
+        client.set_in(namespace, key, value).await
+    }
+
+    pub async fn delete(&mut self, key: Vec<u8>) -> Result<bool, Status> {
+        self.delete_in(vec![], key).await
+    }
+
+    pub async fn delete_in(&mut self, namespace: Vec<u8>, key: Vec<u8>) -> Result<bool, Status> {
+        let client = self.client_for(&namespace, &key).await?;
+        client.delete_in(namespace, key).await
+    }
+
+    pub async fn increment(&mut self, key: Vec<u8>, delta: i64) -> Result<i64, Status> {
+        self.client_for(&[], &key)
+            .await?
+            .increment(key, delta)
+            .await
+    }
+
+    pub async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<u64, Status> {
+        self.client_for(&[], &key).await?.append(key, suffix).await
+    }
+
+    // A shard that cannot be connected to is tried again on its next key.
+    async fn client_for(&mut self, namespace: &[u8], key: &[u8]) -> Result<&mut RayClient, Status> {
+        let index = self.shard_of(namespace, key);
+        let shard = &mut self.shards[index];
+        if shard.client.is_none() {
+            let client = shard.connector.connect().await.map_err(|err| {
+                let message = format!("shard {} is unreachable: {}", shard.name, err);
+                Status::new(Code::Unavailable, message)
+            })?;
+            shard.client = Some(client);
+        }
+        Ok(shard.client.as_mut().unwrap())
+    }
+}
+
+// The keyed hash is stable across builds and platforms, unlike the hashers of std, so every
+// client routes a key the same way.
+fn score(name: &str, message: &[u8]) -> u64 {
+    let mac = HMAC::mac(message, name.as_bytes());
+    u64::from_le_bytes(mac[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sharded(ports: &[u16]) -> ShardedRayClient {
+        let endpoints = ports
+            .iter()
+            .map(|&port| ("10.0.0.1".to_string(), port))
+            .collect();
+        ShardedRayClient::new(endpoints)
+    }
+
+    fn key(index: usize) -> Vec<u8> {
+        format!("key{}", index).into_bytes()
+    }
+
+    #[test]
+    fn keys_are_spread_evenly() {
+        let client = sharded(&[1, 2, 3, 4]);
+        let mut counts = [0; 4];
+        for index in 0..4000 {
+            counts[client.shard_of(b"", &key(index))] += 1;
+        }
+        for &count in counts.iter() {
+            assert!(count > 800 && count < 1200, "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn routing_does_not_depend_on_the_order_of_endpoints() {
+        let client = sharded(&[1, 2, 3]);
+        let reversed = sharded(&[3, 2, 1]);
+        for index in 0..1000 {
+            let shard = client.shard_of(b"", &key(index));
+            let other = reversed.shard_of(b"", &key(index));
+            assert_eq!(client.shards[shard].name, reversed.shards[other].name);
+        }
+    }
+
+    #[test]
+    fn an_added_shard_only_takes_keys_over() {
+        let client = sharded(&[1, 2, 3]);
+        let grown = sharded(&[1, 2, 3, 4]);
+        let mut moved = 0;
+        for index in 0..1000 {
+            let before = client.shard_of(b"", &key(index));
+            let after = grown.shard_of(b"", &key(index));
+            if after != before {
+                assert_eq!(after, 3);
+                moved += 1;
+            }
+        }
+        assert!(moved > 150 && moved < 350, "{}", moved);
+    }
+
+    #[test]
+    fn routing_is_stable_across_builds() {
+        // The first 8 bytes of HMAC-SHA256 keyed by the shard name, as clients built with any
+        // other version must route keys the same way.
+        assert_eq!(score("10.0.0.1:1", b"key"), 15_917_103_873_128_492_876);
+    }
+}
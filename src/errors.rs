@@ -7,6 +7,59 @@ use tonic::{Code, Status};
 use std::{fmt, io};
 
 error_chain! {
+    errors {
+        QueueOverflow(queue: &'static str) {
+            description("queue overflow")
+            display("{} queue is full", queue)
+        }
+
+        ReadOnlyReplica {
+            description("read-only replica")
+            display("this rayd is a read-only replica")
+        }
+
+        NotAnInteger(len: usize) {
+            description("value is not an integer")
+            display("value is not a 64-bit integer (length: {})", len)
+        }
+
+        ValueTooLarge(size: u64, limit: u64) {
+            description("value is too large")
+            display("value of {} bytes would exceed the limit of {} bytes", size, limit)
+        }
+
+        FailedRequest {
+            description("request failed before")
+            display("a mutation with this request id was already applied and failed")
+        }
+
+        SnapshotFailed(epoch: u64) {
+            description("failed to make snapshot")
+            display("failed to make snapshot for epoch {}", epoch)
+        }
+
+        EpochUnavailable(epoch: u64) {
+            description("epoch is unavailable")
+            display("state at epoch {} is not persisted yet or no longer retained", epoch)
+        }
+
+        DeadlineExceeded {
+            description("deadline exceeded")
+            display("request deadline passed before it could be served")
+        }
+
+        InvalidArgument(reason: String) {
+            description("invalid argument")
+            display("invalid argument: {}", reason)
+        }
+
+        // The service stopped, e.g. because rayd is shutting down; the request may be retried.
+        ServiceUnavailable(service: &'static str) {
+            description("service unavailable")
+            display("{} service is unavailable", service)
+        }
+    }
+
     foreign_links {
         Io(io::Error);
         ProtoEncode(prost::EncodeError);
@@ -46,9 +99,84 @@ impl WithFancyChain for Error {
     }
 }
 
+// Use the code of the first error in the chain that has a meaningful one.
+fn status_code(err: &Error) -> Code {
+    let mut current = Some(err);
+    while let Some(err) = current {
+        match err.kind() {
+            ErrorKind::QueueOverflow(_) => return Code::ResourceExhausted,
+            ErrorKind::InvalidArgument(_) => return Code::InvalidArgument,
+            ErrorKind::ServiceUnavailable(_) => return Code::Unavailable,
+            ErrorKind::ReadOnlyReplica
+            | ErrorKind::NotAnInteger(_)
+            | ErrorKind::ValueTooLarge(..)
+            | ErrorKind::FailedRequest => return Code::FailedPrecondition,
+            ErrorKind::EpochUnavailable(_) => return Code::OutOfRange,
+            ErrorKind::DeadlineExceeded => return Code::DeadlineExceeded,
+            _ => (),
+        }
+        current = err
+            .1
+            .next_error
+            .as_ref()
+            .and_then(|next| next.downcast_ref::<Error>());
+    }
+    Code::Internal
+}
+
 impl From<Error> for Status {
     fn from(err: Error) -> Self {
         let message = format!("{}", err.display_chain());
-        Self::new(Code::Internal, message)
+        Self::new(status_code(&err), message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code_of(err: Error) -> Code {
+        Status::from(err).code()
+    }
+
+    #[test]
+    fn error_kinds_map_to_their_codes() {
+        let cases: Vec<(ErrorKind, Code)> = vec![
+            (ErrorKind::QueueOverflow("test"), Code::ResourceExhausted),
+            (
+                ErrorKind::InvalidArgument("test".into()),
+                Code::InvalidArgument,
+            ),
+            (ErrorKind::ServiceUnavailable("test"), Code::Unavailable),
+            (ErrorKind::ReadOnlyReplica, Code::FailedPrecondition),
+            (ErrorKind::NotAnInteger(3), Code::FailedPrecondition),
+            (ErrorKind::ValueTooLarge(2, 1), Code::FailedPrecondition),
+            (ErrorKind::FailedRequest, Code::FailedPrecondition),
+            (ErrorKind::EpochUnavailable(1), Code::OutOfRange),
+            (ErrorKind::DeadlineExceeded, Code::DeadlineExceeded),
+            (ErrorKind::SnapshotFailed(1), Code::Internal),
+            (ErrorKind::Msg("test".into()), Code::Internal),
+        ];
+        for (kind, code) in cases {
+            let description = kind.to_string();
+            assert_eq!(code_of(kind.into()), code, "{}", description);
+        }
+    }
+
+    #[test]
+    fn first_meaningful_code_in_the_chain_wins() {
+        let err: Result<()> = Err(ErrorKind::ServiceUnavailable("journal").into());
+        let err = err.chain_err(|| "failed to apply mutation").unwrap_err();
+        assert_eq!(code_of(err), Code::Unavailable);
+
+        let err: Result<()> = Err(ErrorKind::QueueOverflow("test").into());
+        let err = err
+            .chain_err(|| ErrorKind::ServiceUnavailable("machine"))
+            .unwrap_err();
+        assert_eq!(code_of(err), Code::Unavailable);
+
+        let err: Result<()> = Err(io::Error::from(io::ErrorKind::NotFound).into());
+        let err = err.chain_err(|| "journal write failed").unwrap_err();
+        assert_eq!(code_of(err), Code::Internal);
     }
 }
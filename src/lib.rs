@@ -3,10 +3,10 @@ extern crate log;
 
 pub mod benchmark;
 pub mod client;
+pub mod errors;
 pub mod proto;
 pub mod server;
 
-mod errors;
 mod util;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -4,11 +4,20 @@ use std::fmt::{self, Display, Formatter};
 
 tonic::include_proto!("ray");
 
+// Set on the UNAVAILABLE status of requests rejected because rayd is still recovering. They
+// never reach the PSM, so clients may retry them whether or not they are idempotent.
+pub const NOT_READY_METADATA: &str = "ray-not-ready";
+
+pub mod health {
+    tonic::include_proto!("grpc.health.v1");
+}
+
 impl Display for SetRequest {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "SetRequest {{key: {:?}, value: {:?}}}",
+            "SetRequest {{namespace: {:?}, key: {:?}, value: {:?}}}",
+            ByteStr::new(&self.namespace),
             ByteStr::new(&self.key),
             ByteStr::new(&self.value),
         )
@@ -17,18 +26,318 @@ impl Display for SetRequest {
 
 impl Display for SetReply {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "SetOk")
+        write!(f, "SetOk {{epoch: {}}}", self.epoch)
+    }
+}
+
+impl Display for BatchSetRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "BatchSetRequest {{entries: {}}}", self.entries.len())
+    }
+}
+
+impl Display for BatchSetReply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "BatchSetOk")
+    }
+}
+
+impl Display for TransactionRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "TransactionRequest {{conditions: {}, mutations: {}}}",
+            self.conditions.len(),
+            self.mutations.len(),
+        )
+    }
+}
+
+impl Display for TransactionReply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "TransactionReply {{committed: {}}}", self.committed)
+    }
+}
+
+impl Display for BulkSetReply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "BulkSetOk {{count: {}}}", self.count)
     }
 }
 
 impl Display for GetRequest {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "GetRequest {{key: {:?}}}", ByteStr::new(&self.key),)
+        write!(
+            f,
+            "GetRequest {{namespace: {:?}, key: {:?}, at_epoch: {}, min_epoch: {}}}",
+            ByteStr::new(&self.namespace),
+            ByteStr::new(&self.key),
+            self.at_epoch,
+            self.min_epoch,
+        )
     }
 }
 
 impl Display for GetReply {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "GetReply {{value: {:?}}}", ByteStr::new(&self.value),)
+        write!(
+            f,
+            "GetReply {{value: {:?}, found: {}, epoch: {}}}",
+            ByteStr::new(&self.value),
+            self.found,
+            self.epoch,
+        )
+    }
+}
+
+impl Display for GetWithMetaReply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "GetWithMetaReply {{value: {:?}, found: {}, epoch: {}, size: {}}}",
+            ByteStr::new(&self.value),
+            self.found,
+            self.epoch,
+            self.size,
+        )
+    }
+}
+
+impl Display for DumpKeysRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "DumpKeysRequest {{namespace: {:?}, start_after: {:?}}}",
+            ByteStr::new(&self.namespace),
+            ByteStr::new(&self.start_after),
+        )
+    }
+}
+
+impl Display for WatchRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "WatchRequest {{namespace: {:?}, prefix: {:?}}}",
+            ByteStr::new(&self.namespace),
+            ByteStr::new(&self.prefix),
+        )
+    }
+}
+
+impl Display for ExistsRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ExistsRequest {{namespace: {:?}, key: {:?}}}",
+            ByteStr::new(&self.namespace),
+            ByteStr::new(&self.key),
+        )
+    }
+}
+
+impl Display for ExistsReply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "ExistsReply {{exists: {}}}", self.exists)
+    }
+}
+
+impl Display for FlushNamespaceRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "FlushNamespaceRequest {{namespace: {:?}}}",
+            ByteStr::new(&self.namespace),
+        )
+    }
+}
+
+impl Display for FlushNamespaceReply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "FlushNamespaceReply {{count: {}}}", self.count)
+    }
+}
+
+impl Display for DeleteRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "DeleteRequest {{namespace: {:?}, key: {:?}}}",
+            ByteStr::new(&self.namespace),
+            ByteStr::new(&self.key),
+        )
+    }
+}
+
+impl Display for DeleteReply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "DeleteReply {{found: {}}}", self.found)
+    }
+}
+
+impl Display for DeletePrefixRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "DeletePrefixRequest {{namespace: {:?}, prefix: {:?}}}",
+            ByteStr::new(&self.namespace),
+            ByteStr::new(&self.prefix),
+        )
+    }
+}
+
+impl Display for DeletePrefixReply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "DeletePrefixReply {{count: {}}}", self.count)
+    }
+}
+
+impl Display for ClearRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "ClearRequest")
+    }
+}
+
+impl Display for ClearReply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "ClearReply {{count: {}}}", self.count)
+    }
+}
+
+impl Display for TriggerSnapshotRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "TriggerSnapshotRequest")
+    }
+}
+
+impl Display for PingRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "PingRequest")
+    }
+}
+
+impl Display for PongReply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Pong")
+    }
+}
+
+impl Display for InfoRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "InfoRequest")
+    }
+}
+
+impl Display for InfoReply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "InfoReply {{version: {}, epoch: {}, uptime_seconds: {}, key_count: {}}}",
+            self.version, self.epoch, self.uptime_seconds, self.key_count,
+        )
+    }
+}
+
+impl Display for TriggerSnapshotReply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "TriggerSnapshotReply {{epoch: {}}}", self.epoch)
+    }
+}
+
+impl Display for SyncRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "SyncRequest")
+    }
+}
+
+impl Display for SyncReply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "SyncReply {{persisted_epoch: {}}}", self.persisted_epoch)
+    }
+}
+
+impl Display for GetSetRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "GetSetRequest {{key: {:?}, value: {:?}}}",
+            ByteStr::new(&self.key),
+            ByteStr::new(&self.value),
+        )
+    }
+}
+
+impl Display for GetSetReply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "GetSetReply {{value: {:?}, epoch: {}}}",
+            ByteStr::new(&self.value),
+            self.epoch
+        )
+    }
+}
+
+impl Display for IncrementRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "IncrementRequest {{key: {:?}, delta: {}}}",
+            ByteStr::new(&self.key),
+            self.delta,
+        )
+    }
+}
+
+impl Display for IncrementReply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "IncrementReply {{value: {}}}", self.value)
+    }
+}
+
+impl Display for AppendRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "AppendRequest {{key: {:?}, suffix: {:?}}}",
+            ByteStr::new(&self.key),
+            ByteStr::new(&self.suffix),
+        )
+    }
+}
+
+impl Display for AppendReply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "AppendReply {{length: {}}}", self.length)
+    }
+}
+
+impl Display for AppendMutation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "AppendMutation {{key: {:?}, suffix: {:?}, max_value_size: {}}}",
+            ByteStr::new(&self.key),
+            ByteStr::new(&self.suffix),
+            self.max_value_size,
+        )
+    }
+}
+
+impl Display for Mutation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            Some(mutation::Kind::Set(ref set)) => set.fmt(f),
+            Some(mutation::Kind::Increment(ref increment)) => increment.fmt(f),
+            Some(mutation::Kind::Append(ref append)) => append.fmt(f),
+            Some(mutation::Kind::BatchSet(ref batch_set)) => batch_set.fmt(f),
+            Some(mutation::Kind::Transaction(ref transaction)) => transaction.fmt(f),
+            Some(mutation::Kind::FlushNamespace(ref flush)) => flush.fmt(f),
+            Some(mutation::Kind::GetSet(ref get_set)) => get_set.fmt(f),
+            Some(mutation::Kind::Clear(ref clear)) => clear.fmt(f),
+            Some(mutation::Kind::DeletePrefix(ref delete)) => delete.fmt(f),
+            Some(mutation::Kind::Delete(ref delete)) => delete.fmt(f),
+            None => write!(f, "EmptyMutation"),
+        }
     }
 }
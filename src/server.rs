@@ -1,48 +1,162 @@
+mod bloom_filter;
 mod config;
 mod directory_journal;
 mod directory_snapshot_storage;
+mod health_service;
 mod journal_service;
+mod kv_store;
+mod listener;
 mod logging_service;
 mod machine_service;
+mod null_storage;
+mod object_store;
+mod object_store_snapshot_storage;
+mod pid_file;
+mod rate_limiter;
 mod rpc;
 mod snapshot_service;
+mod span_logger;
 mod storage_machine;
 
-pub use config::Config;
+pub use config::{Config, MachineServiceConfig, SnapshotServiceConfig};
+pub use health_service::HealthReporter;
+pub use machine_service::{AuditedWrite, JournalCodec, Machine, MachineServiceHandle};
+pub use snapshot_service::SnapshotServiceHandle;
 
-use config::{LoggingConfig, MetricsConfig, PsmConfig};
-use directory_journal::DirectoryJournalReader;
+pub use crate::util::Traced;
+
+use config::{
+    changed_fields, copy_field, HealthConfig, JournalServiceConfig, LoggingConfig, LoggingTarget,
+    MetricsConfig, Persistence, PsmConfig, Role, RpcConfig, SnapshotBackend, StoreKind,
+};
+use directory_journal::{DirectoryJournalReader, DirectoryJournalTailer};
 use directory_snapshot_storage::DirectorySnapshotStorage;
-use journal_service::{JournalReader, JournalServiceRestorer};
-use logging_service::{fastlog_queue_size, FastlogService, LoggingService, LoggingServiceFacade};
-use machine_service::{Machine, MachineService, MachineServiceHandle};
+use health_service::{GrpcHealthService, HealthService};
+use journal_service::{
+    JournalFollower, JournalReader, JournalService, JournalServiceRequest, JournalServiceRestorer,
+    JournalTailer,
+};
+use kv_store::{HashStore, KvStore, OrderedStore};
+use logging_service::{
+    fastlog_queue_size, init_audit, target_levels, FastlogService, LoggingService,
+    LoggingServiceFacade,
+};
+use machine_service::{MachineService, MachineServiceRequest};
+use null_storage::{NullJournalReader, NullSnapshotStorage};
+use object_store::ObjectStoreClient;
+use object_store_snapshot_storage::ObjectStoreSnapshotStorage;
+use pid_file::PidFile;
 use rpc::RayStorageService;
-use snapshot_service::{read_snapshot, SnapshotService, SnapshotStorage};
+use snapshot_service::{read_last_snapshot, SnapshotRequest, SnapshotService, SnapshotStorage};
+use span_logger::SpanLogger;
+use storage_machine::StorageMachine;
 
 use crate::{
     errors::*,
     fatal,
-    proto::storage_server::StorageServer,
-    util::{do_and_die, get_thread_cpu_times, profiled_channel, profiled_unbounded_channel},
+    proto::{health::health_server::HealthServer, storage_server::StorageServer},
+    util::{
+        do_and_die, profiled_channel, profiled_unbounded_channel, ProfiledReceiver, ProfiledSender,
+        ProfiledUnboundedReceiver, ProfiledUnboundedSender, QueueStats, ThreadCpuTimes,
+    },
 };
 
-use tokio::{runtime, sync::oneshot};
-use tonic::transport::Server;
+use nix::unistd::{access, AccessFlags};
+#[cfg(target_os = "linux")]
+use nix::{
+    sched::{sched_setaffinity, CpuSet},
+    unistd::Pid,
+};
+
+use tokio::{
+    runtime,
+    signal::unix::{signal, Signal, SignalKind},
+    sync::{broadcast, oneshot},
+};
+use tonic::{
+    body::BoxBody,
+    codegen::{http, Service, StdError},
+    transport::{Body, NamedService, Server},
+};
 
-use metrics::{labels, Key};
+use futures::FutureExt;
+
+use metrics::{labels, Key, Label, Recorder};
 use metrics_runtime::{
     exporters::HttpExporter, observers::PrometheusBuilder, Measurement, Receiver,
 };
+use serde_yaml::Value;
 
 use std::{
+    cmp,
+    fmt::{self, Display},
+    fs::remove_file,
     future::Future,
+    io,
     net::SocketAddr,
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
     process::exit,
-    sync::{atomic::AtomicU64, Arc},
+    sync::{atomic::AtomicU64, Arc, Mutex},
     thread,
+    time::Duration,
 };
 
-pub fn serve_forever(config: Config) -> ! {
+// Queues whose peak sizes and overflows are reported when metrics are scraped.
+static QUEUES: Mutex<Vec<QueueMetrics>> = Mutex::new(Vec::new());
+
+struct QueueMetrics {
+    service: &'static str,
+    // Set for services with several queues.
+    queue: Option<&'static str>,
+    // Only bounded queues can overflow.
+    bounded: bool,
+    stats: Arc<QueueStats>,
+}
+
+// Loads the config anew, for it to be reloaded on SIGHUP.
+pub type ConfigLoader = Box<dyn FnMut() -> Result<Config> + Send>;
+
+pub fn serve_forever(config: Config, load_config: ConfigLoader) -> ! {
+    match config.psm.machine_service.store {
+        StoreKind::Hash => serve_forever_with(config, load_config, storage_service::<HashStore>),
+        StoreKind::Ordered => {
+            serve_forever_with(config, load_config, storage_service::<OrderedStore>)
+        }
+    }
+}
+
+fn storage_service<K: KvStore>(
+    handle: MachineServiceHandle<StorageMachine<K>>,
+    snapshot_handle: SnapshotServiceHandle,
+    health: HealthReporter,
+    config: &Config,
+) -> StorageServer<RayStorageService<K>> {
+    StorageServer::new(RayStorageService::new(
+        handle,
+        snapshot_handle,
+        health,
+        &config.rpc,
+    ))
+}
+
+// Runs the PSM services for the machine M and serves the RPC service built by make_service
+// next to the gRPC health service. The service is given handles to query and mutate the
+// machine, and the health reporter, which is serving once recovery is over; requests that
+// touch the machine are best turned away until then, as the storage service does. On SIGHUP,
+// the config is loaded again and the fields that can change at runtime are applied.
+pub fn serve_forever_with<M, S, F>(config: Config, load_config: ConfigLoader, make_service: F) -> !
+where
+    M: Machine,
+    S: Service<http::Request<Body>, Response = http::Response<BoxBody>>
+        + NamedService
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<StdError> + Send,
+    F: FnOnce(MachineServiceHandle<M>, SnapshotServiceHandle, HealthReporter, &Config) -> S,
+{
     init_logging(&config.logging).unwrap_or_else(|err| {
         eprintln!(
             "Failed to initialize logging (error chain below)\n{}",
@@ -58,7 +172,7 @@ pub fn serve_forever(config: Config) -> ! {
         );
     });
 
-    start_server(config).unwrap_or_else(|err| {
+    start_server(config, load_config, make_service).unwrap_or_else(|err| {
         fatal!(
             "Failed to start server (error chain below)\n{}",
             err.display_fancy_chain()
@@ -70,14 +184,19 @@ pub fn serve_forever(config: Config) -> ! {
 
 fn init_logging(config: &LoggingConfig) -> Result<()> {
     let (log_sender, log_receiver) = profiled_unbounded_channel();
+    register_queue("logging_service", None, false, log_receiver.stats());
 
     let mut logging_service = LoggingService::new(log_receiver, config)
         .chain_err(|| "failed to create logging service")?;
-    run_in_dedicated_thread("rayd-logging", RuntimeKind::Basic, async move {
+    run_in_dedicated_thread("rayd-logging", RuntimeKind::Basic, None, async move {
         logging_service.serve().await
     })?;
 
     LoggingServiceFacade::init(log_sender.clone(), config)?;
+    SpanLogger::init()?;
+    if config.audit.is_some() {
+        init_audit(log_sender.clone());
+    }
     FastlogService::init(log_sender, config.fastlog_threads)?;
     log_panics::init();
 
@@ -89,14 +208,20 @@ fn init_metrics(config: &MetricsConfig) -> Result<()> {
         return Ok(());
     }
 
+    let static_labels = static_labels(config).chain_err(|| "invalid metrics.static_labels")?;
     let receiver = Receiver::builder()
         .build()
         .chain_err(|| "failed to create metrics receiver")?;
+    let controller = receiver.controller();
+    let proxy_labels = static_labels.clone();
 
     // Collect thread cpu usage info
-    let main_pid = std::process::id();
+    let thread_cpu_times = ThreadCpuTimes::new(
+        std::process::id(),
+        Duration::from_millis(config.cpu_time_refresh_ms),
+    );
     receiver.sink().proxy("rayd", move || {
-        let cpu_times = match get_thread_cpu_times(main_pid) {
+        let cpu_times = match thread_cpu_times.get() {
             Ok(times) => times,
             Err(err) => {
                 warn!(
@@ -121,23 +246,46 @@ fn init_metrics(config: &MetricsConfig) -> Result<()> {
         let value = Measurement::Gauge(fastlog_queue_size() as i64);
         metrics.push((key, value));
 
+        // The peaks restart on every scrape, so they cover the time since the previous one.
+        for queue in QUEUES.lock().unwrap().iter() {
+            let labels = || match queue.queue {
+                Some(name) => labels!("queue" => name),
+                None => Vec::new(),
+            };
+            let name = format!("{}.queue_peak_size", queue.service);
+            let key = Key::from_name_and_labels(name, labels());
+            metrics.push((key, Measurement::Gauge(queue.stats.take_peak_size())));
+            if queue.bounded {
+                let name = format!("{}.queue_overflow_count", queue.service);
+                let key = Key::from_name_and_labels(name, labels());
+                metrics.push((key, Measurement::Counter(queue.stats.overflow_count())));
+            }
+        }
+
+        for (key, _) in &mut metrics {
+            key.add_labels(proxy_labels.clone());
+        }
         metrics
     });
 
-    let address = config
-        .address
-        .parse()
-        .chain_err(|| format!("not a valid IP address: {}", config.address))?;
-
     let server = HttpExporter::new(
-        receiver.controller(),
+        controller,
         PrometheusBuilder::new(),
-        SocketAddr::new(address, config.port),
+        metrics_address(config)?,
     );
 
-    receiver.install();
+    if static_labels.is_empty() {
+        receiver.install();
+    } else {
+        let recorder = LabeledRecorder {
+            receiver,
+            labels: static_labels,
+        };
+        metrics::set_boxed_recorder(Box::new(recorder))
+            .chain_err(|| "failed to install metrics recorder")?;
+    }
 
-    run_in_dedicated_thread("rayd-metrics", RuntimeKind::WithIo, async move {
+    run_in_dedicated_thread("rayd-metrics", RuntimeKind::WithIo, None, async move {
         server
             .async_run()
             .await
@@ -147,27 +295,292 @@ fn init_metrics(config: &MetricsConfig) -> Result<()> {
     Ok(())
 }
 
-fn start_server(config: Config) -> Result<()> {
+// Labels rayd sets on its own metrics, which static labels would clash with.
+const RESERVED_LABELS: &[&str] = &["pid", "name", "queue", "method", "ip"];
+
+// Sorted by name, so that every series lists them in the same order.
+fn static_labels(config: &MetricsConfig) -> Result<Vec<Label>> {
+    let mut labels: Vec<_> = config.static_labels.iter().collect();
+    labels.sort();
+    for (name, _) in &labels {
+        if RESERVED_LABELS.contains(&name.as_str()) {
+            bail!("label {:?} is set by rayd itself", name);
+        }
+        let mut chars = name.chars();
+        let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && !name.starts_with("__");
+        if !valid {
+            bail!("{:?} is not a valid label name", name);
+        }
+    }
+    Ok(labels
+        .into_iter()
+        .map(|(name, value)| Label::new(name.clone(), value.clone()))
+        .collect())
+}
+
+// Adds the static labels to the metrics recorded with the macros; those of the proxy get them
+// where they are made.
+struct LabeledRecorder {
+    receiver: Receiver,
+    labels: Vec<Label>,
+}
+
+impl Recorder for LabeledRecorder {
+    fn increment_counter(&self, mut key: Key, value: u64) {
+        key.add_labels(self.labels.clone());
+        self.receiver.increment_counter(key, value);
+    }
+
+    fn update_gauge(&self, mut key: Key, value: i64) {
+        key.add_labels(self.labels.clone());
+        self.receiver.update_gauge(key, value);
+    }
+
+    fn record_histogram(&self, mut key: Key, value: u64) {
+        key.add_labels(self.labels.clone());
+        self.receiver.record_histogram(key, value);
+    }
+}
+
+fn register_queue(
+    service: &'static str,
+    queue: Option<&'static str>,
+    bounded: bool,
+    stats: Arc<QueueStats>,
+) {
+    QUEUES.lock().unwrap().push(QueueMetrics {
+        service,
+        queue,
+        bounded,
+        stats,
+    });
+}
+
+fn metrics_address(config: &MetricsConfig) -> Result<SocketAddr> {
     let ip_address = config
-        .rpc
         .address
         .parse()
-        .chain_err(|| format!("not a valid IP address: {}", config.rpc.address))?;
-    let socket_address = SocketAddr::new(ip_address, config.rpc.port);
+        .chain_err(|| format!("not a valid IP address: {}", config.address))?;
+    Ok(SocketAddr::new(ip_address, config.port))
+}
 
-    let journal_reader = DirectoryJournalReader::new(&config.journal_storage)
-        .chain_err(|| "failed to initialize journal reader")?;
+fn init_health(config: &HealthConfig, reporter: HealthReporter) -> Result<()> {
+    if !config.enable {
+        return Ok(());
+    }
 
-    let snapshot_storage = DirectorySnapshotStorage::new(&config.snapshot_storage.path)
-        .chain_err(|| "failed to initialize snapshot storage")?;
+    let health_service = HealthService::new(config, reporter)?;
+    run_in_dedicated_thread("rayd-health", RuntimeKind::WithIo, None, async move {
+        health_service.serve().await
+    })?;
 
-    let (handle, ready) = run_psm(journal_reader, snapshot_storage, &config.psm)
-        .chain_err(|| "failed to run PSM services")?;
+    Ok(())
+}
 
-    let storage_service = RayStorageService::new(handle);
-    let server = Server::builder()
-        .add_service(StorageServer::new(storage_service))
-        .serve(socket_address);
+enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenAddress {
+    // The address is either an IP address to be used with the port, or unix:<path>.
+    fn from_config(config: &RpcConfig) -> Result<Self> {
+        if let Some(path) = config.address.strip_prefix("unix:") {
+            return Ok(ListenAddress::Unix(PathBuf::from(path)));
+        }
+        let ip_address = config
+            .address
+            .parse()
+            .chain_err(|| format!("not a valid IP address: {}", config.address))?;
+        Ok(ListenAddress::Tcp(SocketAddr::new(ip_address, config.port)))
+    }
+}
+
+impl Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(address) => write!(f, "{}", address),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// Prints the journal files of the directory without touching them, for `rayd journal dump`.
+pub fn dump_journal(path: &str) -> Result<()> {
+    let stdout = io::stdout();
+    directory_journal::dump_journal::<StorageMachine<HashStore>, _>(
+        Path::new(path),
+        &mut stdout.lock(),
+    )
+}
+
+// Prints what the full snapshot holds, with its first sample keys in key order, for
+// `rayd snapshot inspect`.
+pub fn inspect_snapshot(path: &str, sample: usize) -> Result<()> {
+    let stdout = io::stdout();
+    storage_machine::inspect_snapshot::<OrderedStore, _>(
+        Path::new(path),
+        sample,
+        &mut stdout.lock(),
+    )
+}
+
+// Checks what can be checked without starting anything or touching the disk: addresses, port
+// conflicts, and whether the directories and log files rayd writes to could be created or
+// opened for writing. Returns every problem found.
+pub fn check_config(config: &Config) -> Vec<Error> {
+    let mut results = vec![check_role(config)];
+
+    let mut listeners = vec![];
+    match ListenAddress::from_config(&config.rpc) {
+        Ok(ListenAddress::Tcp(address)) => listeners.push(("rpc", address)),
+        Ok(ListenAddress::Unix(path)) => results.push(
+            check_creatable_file(&path).chain_err(|| "rpc.address is not a usable socket path"),
+        ),
+        Err(err) => results.push(Err(err).chain_err(|| "invalid rpc.address")),
+    }
+    if config.metrics.enable {
+        match metrics_address(&config.metrics) {
+            Ok(address) => listeners.push(("metrics", address)),
+            Err(err) => results.push(Err(err).chain_err(|| "invalid metrics.address")),
+        }
+        let labels = static_labels(&config.metrics).map(|_| ());
+        results.push(labels.chain_err(|| "invalid metrics.static_labels"));
+    }
+    if config.health.enable {
+        match HealthService::listen_address(&config.health) {
+            Ok(address) => listeners.push(("health", address)),
+            Err(err) => results.push(Err(err).chain_err(|| "invalid health.address")),
+        }
+    }
+    for (index, (name, address)) in listeners.iter().enumerate() {
+        for (other_name, other_address) in &listeners[index + 1..] {
+            if addresses_conflict(address, other_address) {
+                results.push(Err(format!(
+                    "{} ({}) and {} ({}) listen on the same port",
+                    name, address, other_name, other_address
+                )
+                .into()));
+            }
+        }
+    }
+
+    // Replicas only read the journal and snapshots of the primary.
+    if config.role != Role::Replica && config.persistence == Persistence::Journal {
+        let journal_path = Path::new(&config.journal_storage.path);
+        results.push(
+            check_writable_dir(journal_path).chain_err(|| "journal_storage.path is not usable"),
+        );
+        match config.snapshot_storage.backend {
+            SnapshotBackend::Directory => {
+                let snapshot_path = Path::new(&config.snapshot_storage.path);
+                results.push(
+                    check_writable_dir(snapshot_path)
+                        .chain_err(|| "snapshot_storage.path is not usable"),
+                );
+            }
+            SnapshotBackend::ObjectStore => results.push(
+                ObjectStoreClient::check_config(&config.snapshot_storage.object_store)
+                    .map(|_| ())
+                    .chain_err(|| "invalid snapshot_storage.object_store"),
+            ),
+        }
+    }
+
+    for (index, target) in config.logging.targets.iter().enumerate() {
+        let result = target_levels(target).and_then(|_| match &target.target {
+            LoggingTarget::Stderr => Ok(()),
+            LoggingTarget::File { path, .. } => check_creatable_file(Path::new(path)),
+        });
+        results.push(result.chain_err(|| format!("logging target #{} is not usable", index + 1)));
+    }
+    if let Some(audit) = &config.logging.audit {
+        if let LoggingTarget::File { path, .. } = &audit.target {
+            let result = check_creatable_file(Path::new(path));
+            results.push(result.chain_err(|| "audit target is not usable"));
+        }
+    }
+    let compression = &config.psm.machine_service.compression;
+    if compression.enable && !(1..=22).contains(&compression.level) {
+        results.push(Err(
+            "psm.machine_service.compression.level must be from 1 to 22".into(),
+        ));
+    }
+    if let Some(path) = &config.pid_file {
+        let result = check_creatable_file(Path::new(path));
+        results.push(result.chain_err(|| "pid_file is not usable"));
+    }
+
+    results.into_iter().filter_map(Result::err).collect()
+}
+
+fn check_role(config: &Config) -> Result<()> {
+    if config.role != Role::Primary && config.persistence == Persistence::None {
+        bail!("replicas and standbys need a journal to follow, persistence cannot be none");
+    }
+    Ok(())
+}
+
+// The same port can only be shared by listeners bound to different specific IP addresses.
+fn addresses_conflict(address: &SocketAddr, other: &SocketAddr) -> bool {
+    address.port() != 0
+        && address.port() == other.port()
+        && (address.ip() == other.ip()
+            || address.ip().is_unspecified()
+            || other.ip().is_unspecified())
+}
+
+// The directory must be writable, or creatable if it does not exist yet.
+fn check_writable_dir(path: &Path) -> Result<()> {
+    if path.exists() {
+        if !path.is_dir() {
+            bail!("{:?} is not a directory", path);
+        }
+        return access(path, AccessFlags::W_OK | AccessFlags::X_OK)
+            .chain_err(|| format!("{:?} is not writable", path));
+    }
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => check_writable_dir(parent),
+        _ => check_writable_dir(Path::new(".")),
+    }
+}
+
+// The file must be writable, or creatable in its directory if it does not exist yet.
+fn check_creatable_file(path: &Path) -> Result<()> {
+    if path.exists() {
+        if path.is_dir() {
+            bail!("{:?} is a directory", path);
+        }
+        return access(path, AccessFlags::W_OK).chain_err(|| format!("{:?} is not writable", path));
+    }
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => check_writable_dir(parent),
+        _ => check_writable_dir(Path::new(".")),
+    }
+}
+
+fn start_server<M, S, F>(config: Config, load_config: ConfigLoader, make_service: F) -> Result<()>
+where
+    M: Machine,
+    S: Service<http::Request<Body>, Response = http::Response<BoxBody>>
+        + NamedService
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<StdError> + Send,
+    F: FnOnce(MachineServiceHandle<M>, SnapshotServiceHandle, HealthReporter, &Config) -> S,
+{
+    check_role(&config)?;
+    let listen_address = ListenAddress::from_config(&config.rpc)?;
+    let pid_file = match &config.pid_file {
+        Some(path) => Some(Arc::new(
+            PidFile::acquire(path).chain_err(|| "failed to acquire PID file")?,
+        )),
+        None => None,
+    };
 
     let num_threads = if config.rpc.threads > 0 {
         config.rpc.threads as usize
@@ -182,44 +595,374 @@ fn start_server(config: Config) -> Result<()> {
         .build()
         .chain_err(|| "failed to start Tokio runtime")?;
 
-    // Wait for PSM services to become initialized.
+    let mut terminate = runtime
+        .block_on(async { signal(SignalKind::terminate()) })
+        .chain_err(|| "failed to install SIGTERM handler")?;
+    let hangup = runtime
+        .block_on(async { signal(SignalKind::hangup()) })
+        .chain_err(|| "failed to install SIGHUP handler")?;
+    let mut promotion_sender = None;
+
+    let health = HealthReporter::new();
+    init_health(&config.health, health.clone())
+        .chain_err(|| "failed to initialize health service")?;
+
+    let (handle, snapshot_handle, ready) = match config.persistence {
+        Persistence::None => {
+            warn!("Persistence is off, all data will be lost on shutdown");
+            let journal = PsmRole::<_, DirectoryJournalTailer>::Primary(NullJournalReader);
+            run_psm(journal, NullSnapshotStorage, &config.psm, health.clone())
+        }
+        Persistence::Journal => {
+            let journal = match config.role {
+                Role::Primary => PsmRole::Primary(
+                    DirectoryJournalReader::new(&config.journal_storage)
+                        .chain_err(|| "failed to initialize journal reader")?,
+                ),
+                Role::Replica => {
+                    PsmRole::Replica(DirectoryJournalTailer::new(&config.journal_storage))
+                }
+                Role::Standby => {
+                    let (sender, promotion) = oneshot::channel();
+                    promotion_sender = Some(sender);
+                    let journal_storage = config.journal_storage.clone();
+                    PsmRole::Standby {
+                        tailer: DirectoryJournalTailer::new(&config.journal_storage),
+                        open_reader: Box::new(move || {
+                            DirectoryJournalReader::new(&journal_storage)
+                        }),
+                        promotion,
+                    }
+                }
+            };
+
+            match config.snapshot_storage.backend {
+                SnapshotBackend::Directory => {
+                    let snapshot_storage = DirectorySnapshotStorage::new(&config.snapshot_storage)
+                        .chain_err(|| "failed to initialize snapshot storage")?;
+                    run_psm(journal, snapshot_storage, &config.psm, health.clone())
+                }
+                SnapshotBackend::ObjectStore => {
+                    let snapshot_storage =
+                        ObjectStoreSnapshotStorage::new(&config.snapshot_storage)
+                            .chain_err(|| "failed to initialize snapshot storage")?;
+                    run_psm(journal, snapshot_storage, &config.psm, health.clone())
+                }
+            }
+        }
+    }
+    .chain_err(|| "failed to run PSM services")?;
+
+    // The PID file tells init systems that rayd is up, so it is written once recovery is over.
+    let (started_sender, mut started) = oneshot::channel();
+    let started_pid_file = pid_file.clone();
+    runtime.spawn(async move {
+        if ready.await.is_err() {
+            return;
+        }
+        if let Some(pid_file) = started_pid_file {
+            pid_file.write().unwrap_or_else(|err| {
+                fatal!(
+                    "Failed to write PID file (error chain below)\n{}",
+                    err.display_fancy_chain()
+                );
+            });
+        }
+        started_sender.send(()).ok();
+    });
+
+    let current_config =
+        serde_yaml::to_value(&config).chain_err(|| "failed to serialize config")?;
+    runtime.spawn(reload_on_hangup(
+        hangup,
+        load_config,
+        current_config,
+        promotion_sender,
+    ));
+
+    // The server is up during recovery to answer health checks; the storage service rejects
+    // requests with Unavailable until the PSM services are ready.
+    let service = make_service(
+        handle.clone(),
+        snapshot_handle.clone(),
+        health.clone(),
+        &config,
+    );
+    let health_service = GrpcHealthService::new(health.clone(), S::NAME);
+    let router = Server::builder()
+        .add_service(service)
+        .add_service(HealthServer::new(health_service));
+    let shutdown = async move {
+        terminate.recv().await;
+        health.set_serving(false);
+        info!("Received SIGTERM, draining in-flight requests");
+    };
+
+    match listen_address {
+        ListenAddress::Tcp(address) => {
+            let incoming = runtime.block_on(listener::bind_tcp(address, &config.rpc.tcp))?;
+            info!("Serving rayd on {}", listen_address);
+            runtime.block_on(router.serve_with_incoming_shutdown(incoming, shutdown))
+        }
+        ListenAddress::Unix(ref path) => {
+            let incoming = runtime.enter(|| listener::bind_unix(path))?;
+            info!("Serving rayd on {}", listen_address);
+            let result = runtime.block_on(router.serve_with_incoming_shutdown(incoming, shutdown));
+            remove_file(path).ok(); // Ignore error
+            result
+        }
+    }
+    .chain_err(|| "RPC service failed")?;
+
+    // Recovery only reads the journal, so it is safe to exit without a snapshot.
+    if started.try_recv().is_err() {
+        info!("Shut down during recovery, exiting");
+        return Ok(());
+    }
+
+    if config.role == Role::Replica || config.persistence == Persistence::None {
+        info!("Shutdown complete");
+        return Ok(());
+    }
+
+    let epoch = handle.persisted_epoch();
+    info!("Taking final snapshot (epoch: {})", epoch);
     runtime
-        .block_on(ready)
-        .chain_err(|| "wait on PSM initialization failed")?;
+        .block_on(snapshot_handle.make_snapshot(epoch))
+        .chain_err(|| "failed to make final snapshot")?;
+
+    info!("Shutdown complete");
+    Ok(())
+}
+
+// Applies the reloaded config, keeping the current one if it fails to load or apply.
+async fn reload_on_hangup(
+    mut hangup: Signal,
+    mut load_config: ConfigLoader,
+    mut current: Value,
+    mut promotion: Option<oneshot::Sender<()>>,
+) {
+    while hangup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading config");
+        let result = load_config()
+            .chain_err(|| "failed to load config")
+            .and_then(|config| reload_config(&current, &config, &mut promotion));
+        match result {
+            Ok(config) => current = config,
+            Err(err) => warn!(
+                "Failed to reload config, keeping the current one (error chain below)\n{}",
+                err.display_fancy_chain()
+            ),
+        }
+    }
+}
 
-    info!("Serving rayd on {}", socket_address);
+// Applies the fields that can change at runtime: logging levels and modules, enabling metrics,
+// and the role of a standby, which is promoted once it is a primary. Other changes are logged
+// and take effect on restart. Returns the config now in effect.
+fn reload_config(
+    current: &Value,
+    config: &Config,
+    promotion: &mut Option<oneshot::Sender<()>>,
+) -> Result<Value> {
+    let old_config: Config =
+        serde_yaml::from_value(current.clone()).chain_err(|| "invalid current config")?;
+    let new = serde_yaml::to_value(config).chain_err(|| "failed to serialize config")?;
+
+    let mut effective = current.clone();
+    for field in changed_fields(current, &new) {
+        let reloadable = match field.split('.').collect::<Vec<_>>()[..] {
+            ["logging", "modules"] => true,
+            ["logging", "targets", _, "level"] | ["logging", "targets", _, "min_level"] => true,
+            // Metrics cannot be turned off once their recorder is installed.
+            ["metrics", "enable"] => config.metrics.enable,
+            ["role"] => old_config.role == Role::Standby && config.role == Role::Primary,
+            _ => false,
+        };
+        if reloadable {
+            copy_field(&new, &mut effective, &field);
+            info!("Reloaded {}", field);
+        } else {
+            warn!("Changed {} ignored, requires restart", field);
+        }
+    }
+
+    let effective_config: Config =
+        serde_yaml::from_value(effective.clone()).chain_err(|| "invalid reloaded config")?;
+    LoggingServiceFacade::reload(&effective_config.logging)
+        .chain_err(|| "failed to reload logging")?;
+    if effective_config.metrics.enable && !old_config.metrics.enable {
+        init_metrics(&effective_config.metrics).chain_err(|| "failed to initialize metrics")?;
+    }
+    if effective_config.role == Role::Primary {
+        if let Some(sender) = promotion.take() {
+            info!("Promoting to primary");
+            sender.send(()).ok();
+        }
+    }
+    Ok(effective)
+}
 
-    runtime.block_on(server).chain_err(|| "RPC service failed")
+// A primary writes the journal, a replica only follows it. A standby follows it until
+// promoted, then writes it like a primary.
+enum PsmRole<R: JournalReader, T: JournalTailer> {
+    Primary(R),
+    Replica(T),
+    Standby {
+        tailer: T,
+        // The reader is opened on promotion, when the primary no longer writes the journal.
+        open_reader: Box<dyn FnOnce() -> Result<R> + Send>,
+        promotion: oneshot::Receiver<()>,
+    },
 }
 
-fn run_psm<M: Machine, R: JournalReader, S: SnapshotStorage>(
-    journal_reader: R,
+// Follows the journal until promoted, then recovers the journal service of a primary. The
+// machine is current by then, so recovery only reads the journal to find its end; the
+// snapshot service counts mutations towards the next snapshot from the promotion on.
+async fn promote_standby<M, R, T, S>(
+    mut follower: JournalFollower<T, M>,
+    open_reader: Box<dyn FnOnce() -> Result<R> + Send>,
+    promotion: oneshot::Receiver<()>,
+    start: PrimaryStart<M, S>,
+) -> Result<JournalService<R::Writer, M>>
+where
+    M: Machine,
+    R: JournalReader,
+    T: JournalTailer,
+    S: SnapshotStorage,
+{
+    follower.catch_up().await?;
+    info!("Standing by, reload the config with role: primary to promote");
+    let machine_epoch = follower.serve_until(promotion).await?;
+    drop(follower);
+
+    let journal_reader = open_reader().chain_err(|| "failed to initialize journal reader")?;
+    let journal_service = start.start(journal_reader, machine_epoch).await?;
+    info!("Promoted to primary (epoch: {})", machine_epoch);
+    Ok(journal_service)
+}
+
+// Everything the journal and snapshot services of a primary are started with, so that a
+// standby can start them once promoted.
+struct PrimaryStart<M: Machine, S: SnapshotStorage> {
+    storage: S,
+    machine_sender: ProfiledSender<MachineServiceRequest<M>>,
+    journal_receiver: ProfiledReceiver<JournalServiceRequest<M>>,
+    snapshot_sender: ProfiledSender<u64>,
+    snapshot_receiver: ProfiledReceiver<u64>,
+    snapshot_request_receiver: ProfiledUnboundedReceiver<SnapshotRequest>,
+    min_epoch_sender: ProfiledUnboundedSender<u64>,
+    min_epoch_receiver: ProfiledUnboundedReceiver<u64>,
+    persisted_epoch: Arc<AtomicU64>,
+    journal_bytes: Arc<AtomicU64>,
+    journal_config: JournalServiceConfig,
+    snapshot_config: SnapshotServiceConfig,
+}
+
+impl<M: Machine, S: SnapshotStorage> PrimaryStart<M, S> {
+    // Runs the snapshot service on a thread of its own and recovers the journal service, which
+    // is to be served on the calling thread.
+    async fn start<R: JournalReader>(
+        self,
+        journal_reader: R,
+        epoch: u64,
+    ) -> Result<JournalService<R::Writer, M>> {
+        let journal_config = self.journal_config;
+        let snapshot_config = self.snapshot_config;
+
+        let storage = self.storage;
+        let snapshot_machine_sender = self.machine_sender.clone();
+        let snapshot_receiver = self.snapshot_receiver;
+        let snapshot_request_receiver = self.snapshot_request_receiver;
+        let min_epoch_sender = self.min_epoch_sender;
+        let snapshot_journal_bytes = self.journal_bytes.clone();
+        let snapshot_interval_time = match snapshot_config.snapshot_interval_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        };
+        // Journal and machine services stay on a single thread, as they keep the order of
+        // mutations. The snapshot service may get more for the blocking work it spawns.
+        let snapshot_runtime = match snapshot_config.worker_threads {
+            0 => RuntimeKind::WithTime,
+            threads => RuntimeKind::ThreadedWithTime(threads),
+        };
+        run_in_dedicated_thread(
+            "rayd-snapshot",
+            snapshot_runtime,
+            snapshot_config.core_id,
+            async move {
+                let snapshot_service = SnapshotService::<S, M>::new(
+                    storage,
+                    snapshot_machine_sender,
+                    snapshot_receiver,
+                    snapshot_request_receiver,
+                    min_epoch_sender,
+                    epoch,
+                    snapshot_config.snapshot_interval,
+                    snapshot_config.snapshot_interval_bytes,
+                    snapshot_interval_time,
+                    snapshot_journal_bytes,
+                    snapshot_config.batch_size,
+                    snapshot_config.deltas_per_full,
+                );
+                serve_snapshots(snapshot_service).await
+            },
+        )?;
+
+        let recovery_threads = match journal_config.recovery_threads {
+            0 => num_cpus::get(),
+            threads => threads,
+        };
+        let restorer = JournalServiceRestorer::<R, M>::new(
+            journal_reader,
+            self.machine_sender,
+            self.snapshot_sender,
+            self.journal_receiver,
+            self.min_epoch_receiver,
+            journal_config.batch_size,
+            journal_config.batch_max_bytes,
+            recovery_threads,
+            Duration::from_millis(journal_config.recovery_progress_interval_ms),
+            journal_config.dispatch_queue_size,
+            epoch,
+            self.persisted_epoch,
+            self.journal_bytes,
+        );
+        restorer.restore().await
+    }
+}
+
+fn run_psm<M: Machine, R: JournalReader, T: JournalTailer, S: SnapshotStorage>(
+    journal: PsmRole<R, T>,
     storage: S,
     config: &PsmConfig,
-) -> Result<(MachineServiceHandle<M>, oneshot::Receiver<()>)> {
+    health: HealthReporter,
+) -> Result<(
+    MachineServiceHandle<M>,
+    SnapshotServiceHandle,
+    oneshot::Receiver<()>,
+)> {
     let journal_config = &config.journal_service;
     let machine_config = &config.machine_service;
     let snapshot_config = &config.snapshot_service;
 
-    let (journal_sender, journal_receiver) = profiled_channel(journal_config.request_queue_size);
     let (machine_sender, machine_receiver) = profiled_channel(machine_config.request_queue_size);
-    let (snapshot_sender, snapshot_receiver) = profiled_unbounded_channel();
-    let (min_epoch_sender, min_epoch_receiver) = profiled_unbounded_channel();
+    // The channel cannot be empty.
+    let (watch_sender, _) = broadcast::channel(cmp::max(machine_config.watch_buffer_size, 1));
+    register_queue("machine_service", None, true, machine_receiver.stats());
     let persisted_epoch = Arc::new(AtomicU64::new(0));
+    let journal_bytes = Arc::new(AtomicU64::new(0));
 
-    let handle = MachineServiceHandle::new(
-        journal_sender,
-        machine_sender.clone(),
-        persisted_epoch.clone(),
-    );
-    let snapshot = storage
-        .open_last_snapshot()
-        .chain_err(|| "failed to open the last snapshot")?;
+    let journal_start = match journal {
+        PsmRole::Primary(ref reader) => reader.first_epoch(),
+        PsmRole::Replica(ref tailer) | PsmRole::Standby { ref tailer, .. } => tailer.first_epoch(),
+    }
+    .chain_err(|| "failed to read journal")?;
+    let snapshot =
+        read_last_snapshot(&storage, journal_start).chain_err(|| "failed to read snapshot")?;
 
     let (machine, epoch) = match snapshot {
-        Some(mut reader) => {
-            let (machine, epoch) =
-                read_snapshot(&mut reader).chain_err(|| "failed to read snapshot")?;
+        Some((machine, epoch)) => {
             info!("Recovered state from snapshot (epoch: {})", epoch);
             (machine, epoch)
         }
@@ -230,64 +973,226 @@ fn run_psm<M: Machine, R: JournalReader, S: SnapshotStorage>(
     };
 
     let (ready_sender, ready_receiver) = oneshot::channel();
-    let journal_batch_size = journal_config.batch_size;
-    run_in_dedicated_thread("rayd-journal", RuntimeKind::Basic, async move {
-        let restorer = JournalServiceRestorer::<R, M>::new(
-            journal_reader,
-            machine_sender,
-            snapshot_sender,
-            journal_receiver,
-            min_epoch_receiver,
-            journal_batch_size,
-            epoch,
-            persisted_epoch,
-        );
-        let mut journal_service = restorer.restore().await?;
-        ready_sender.send(()).ok();
-        journal_service.serve().await
-    })?;
 
-    let snapshot_machine = machine.clone();
-    let snapshot_interval = snapshot_config.snapshot_interval;
-    let snapshot_batch_size = snapshot_config.batch_size;
-    run_in_dedicated_thread("rayd-snapshot", RuntimeKind::Basic, async move {
-        let mut snapshot_service = SnapshotService::<S, M>::new(
-            storage,
-            snapshot_machine,
-            snapshot_receiver,
-            min_epoch_sender,
-            epoch,
-            snapshot_interval,
-            snapshot_batch_size,
-        );
-        snapshot_service.serve().await
-    })?;
+    let is_replica = matches!(journal, PsmRole::Replica(_));
+    let (handle, snapshot_handle) = match journal {
+        PsmRole::Primary(_) | PsmRole::Standby { .. } => {
+            let (journal_sender, journal_receiver) =
+                profiled_channel(journal_config.request_queue_size);
+            let (snapshot_sender, snapshot_receiver) =
+                profiled_channel(snapshot_config.request_queue_size);
+            let (snapshot_request_sender, snapshot_request_receiver) = profiled_unbounded_channel();
+            let (min_epoch_sender, min_epoch_receiver) = profiled_unbounded_channel();
+            register_queue(
+                "journal_service",
+                Some("request"),
+                true,
+                journal_receiver.stats(),
+            );
+            register_queue(
+                "journal_service",
+                Some("min_epoch"),
+                false,
+                min_epoch_receiver.stats(),
+            );
+            register_queue("snapshot_service", None, true, snapshot_receiver.stats());
 
-    run_in_dedicated_thread("rayd-machine", RuntimeKind::Basic, async move {
-        let mut machine_service = MachineService::new(machine, machine_receiver, epoch);
-        machine_service.serve().await
-    })?;
+            let handle = MachineServiceHandle::new(
+                Some(journal_sender),
+                machine_sender.clone(),
+                persisted_epoch.clone(),
+                journal_config.reject_when_full,
+                watch_sender.clone(),
+            );
+            let snapshot_handle = SnapshotServiceHandle::new(snapshot_request_sender);
+
+            let start = PrimaryStart {
+                storage,
+                machine_sender: machine_sender.clone(),
+                journal_receiver,
+                snapshot_sender,
+                snapshot_receiver,
+                snapshot_request_receiver,
+                min_epoch_sender,
+                min_epoch_receiver,
+                persisted_epoch: persisted_epoch.clone(),
+                journal_bytes,
+                journal_config: journal_config.clone(),
+                snapshot_config: snapshot_config.clone(),
+            };
+
+            match journal {
+                PsmRole::Primary(journal_reader) => {
+                    run_in_dedicated_thread(
+                        "rayd-journal",
+                        RuntimeKind::Basic,
+                        journal_config.core_id,
+                        async move {
+                            let mut journal_service = start.start(journal_reader, epoch).await?;
+                            ready_sender.send(()).ok();
+                            health.set_serving(true);
+                            journal_service.serve().await
+                        },
+                    )?;
+                }
+                PsmRole::Standby {
+                    tailer,
+                    open_reader,
+                    promotion,
+                } => {
+                    let poll_interval = Duration::from_millis(journal_config.poll_interval_ms);
+                    run_in_dedicated_thread(
+                        "rayd-journal",
+                        RuntimeKind::WithTime,
+                        journal_config.core_id,
+                        async move {
+                            let follower = JournalFollower::<T, M>::new(
+                                tailer,
+                                machine_sender,
+                                epoch,
+                                persisted_epoch,
+                                poll_interval,
+                            );
+                            let mut journal_service =
+                                promote_standby(follower, open_reader, promotion, start).await?;
+                            ready_sender.send(()).ok();
+                            health.set_serving(true);
+                            journal_service.serve().await
+                        },
+                    )?;
+                }
+                PsmRole::Replica(_) => unreachable!(),
+            }
+
+            (handle, snapshot_handle)
+        }
+        PsmRole::Replica(journal_tailer) => {
+            let handle = MachineServiceHandle::new(
+                None,
+                machine_sender.clone(),
+                persisted_epoch.clone(),
+                journal_config.reject_when_full,
+                watch_sender.clone(),
+            );
+
+            let poll_interval = Duration::from_millis(journal_config.poll_interval_ms);
+            run_in_dedicated_thread(
+                "rayd-journal",
+                RuntimeKind::WithTime,
+                journal_config.core_id,
+                async move {
+                    let mut follower = JournalFollower::<T, M>::new(
+                        journal_tailer,
+                        machine_sender,
+                        epoch,
+                        persisted_epoch,
+                        poll_interval,
+                    );
+                    follower.catch_up().await?;
+                    ready_sender.send(()).ok();
+                    health.set_serving(true);
+                    follower.serve().await
+                },
+            )?;
 
-    Ok((handle, ready_receiver))
+            (handle, SnapshotServiceHandle::disabled())
+        }
+    };
+
+    let max_pending_queries = machine_config.max_pending_queries;
+    let retained_epochs = machine_config.retained_epochs;
+    let mut machine = machine;
+    machine.configure(machine_config);
+    if !is_replica {
+        machine.configure_snapshots(snapshot_config);
+    }
+    run_in_dedicated_thread(
+        "rayd-machine",
+        RuntimeKind::Basic,
+        machine_config.core_id,
+        async move {
+            let mut machine_service = MachineService::new(
+                machine,
+                machine_receiver,
+                epoch,
+                max_pending_queries,
+                retained_epochs,
+                watch_sender,
+            );
+            machine_service.serve().await
+        },
+    )?;
+
+    Ok((handle, snapshot_handle, ready_receiver))
+}
+
+// Unlike the journal and the machine, snapshots are not needed to keep serving: they only
+// spare replaying the whole journal on startup. So the snapshot thread is not critical: the
+// service retries failed snapshots itself, and is restarted if it panics.
+async fn serve_snapshots<S: SnapshotStorage, M: Machine>(
+    mut snapshot_service: SnapshotService<S, M>,
+) -> Result<()> {
+    loop {
+        match AssertUnwindSafe(snapshot_service.serve())
+            .catch_unwind()
+            .await
+        {
+            Ok(result) => return result,
+            Err(_) => {
+                // The panic is already logged by the panic hook.
+                error!("Snapshot service panicked, restarting it");
+                snapshot_service.recover_from_failure();
+            }
+        }
+    }
 }
 
 enum RuntimeKind {
     Basic,
     WithIo,
+    WithTime,
+    // Multi-threaded with the given number of worker threads, named after the thread.
+    ThreadedWithTime(usize),
 }
 
 fn run_in_dedicated_thread<T: Future<Output = Result<()>> + Send + 'static>(
     thread_name: &'static str,
     kind: RuntimeKind,
+    core_id: Option<usize>,
     task: T,
 ) -> Result<()> {
     let thread = thread::Builder::new()
         .name(thread_name.to_string())
         .spawn(move || {
+            if let Some(core_id) = core_id {
+                pin_to_core(core_id).unwrap_or_else(|err| {
+                    fatal!(
+                        "Failed to pin thread '{}' to core {}: {}",
+                        thread_name,
+                        core_id,
+                        err
+                    );
+                });
+            }
+
             let mut builder = runtime::Builder::new();
-            builder.basic_scheduler();
-            if let RuntimeKind::WithIo = kind {
-                builder.enable_io();
+            match kind {
+                RuntimeKind::Basic => {
+                    builder.basic_scheduler();
+                }
+                RuntimeKind::WithIo => {
+                    builder.basic_scheduler().enable_io();
+                }
+                RuntimeKind::WithTime => {
+                    builder.basic_scheduler().enable_time();
+                }
+                RuntimeKind::ThreadedWithTime(threads) => {
+                    builder
+                        .threaded_scheduler()
+                        .core_threads(threads)
+                        .thread_name(thread_name)
+                        .enable_time();
+                }
             }
 
             let mut runtime = builder.build().unwrap_or_else(|err| {
@@ -300,3 +1205,84 @@ fn run_in_dedicated_thread<T: Future<Output = Result<()>> + Send + 'static>(
     thread.chain_err(|| "failed to spawn thread")?;
     Ok(())
 }
+
+#[cfg(target_os = "linux")]
+fn pin_to_core(core_id: usize) -> nix::Result<()> {
+    let mut cpu_set = CpuSet::new();
+    cpu_set.set(core_id)?;
+    sched_setaffinity(Pid::from_raw(0), &cpu_set)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to_core(core_id: usize) -> nix::Result<()> {
+    warn!(
+        "Pinning threads to cores is only supported on Linux, not pinning to core {}",
+        core_id
+    );
+    Ok(())
+}
+
+// Pinning is only supported on Linux.
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    use futures::future;
+
+    use std::sync::mpsc;
+
+    fn affinity() -> Vec<usize> {
+        let cpu_set = nix::sched::sched_getaffinity(Pid::from_raw(0)).unwrap();
+        (0..CpuSet::count())
+            .filter(|&core_id| cpu_set.is_set(core_id).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn dedicated_thread_is_pinned_to_its_core() {
+        let core_id = affinity()[0];
+        let (sender, receiver) = mpsc::channel();
+        // The thread is left running, as the process exits once a dedicated thread finishes.
+        run_in_dedicated_thread(
+            "rayd-pinned",
+            RuntimeKind::Basic,
+            Some(core_id),
+            async move {
+                sender.send(affinity()).unwrap();
+                future::pending().await
+            },
+        )
+        .unwrap();
+        assert_eq!(receiver.recv().unwrap(), vec![core_id]);
+    }
+
+    #[test]
+    fn static_labels_are_sorted_and_checked() {
+        let mut config = MetricsConfig::default();
+        for &(name, value) in &[("zone", "b"), ("cluster", "main"), ("_tier", "hot")] {
+            config.static_labels.insert(name.into(), value.into());
+        }
+        let labels = static_labels(&config).unwrap();
+        let pairs: Vec<_> = labels
+            .iter()
+            .map(|label| (label.key(), label.value()))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![("_tier", "hot"), ("cluster", "main"), ("zone", "b")]
+        );
+
+        for &(name, error) in &[
+            ("queue", "label \"queue\" is set by rayd itself"),
+            ("1st", "\"1st\" is not a valid label name"),
+            ("__name__", "\"__name__\" is not a valid label name"),
+            ("with-dash", "\"with-dash\" is not a valid label name"),
+            ("", "\"\" is not a valid label name"),
+        ] {
+            config.static_labels.clear();
+            config.static_labels.insert(name.into(), "value".into());
+            let err = static_labels(&config).err().unwrap();
+            assert_eq!(err.to_string(), error);
+        }
+    }
+}
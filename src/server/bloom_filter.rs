@@ -0,0 +1,60 @@
+use std::{collections::hash_map::DefaultHasher, hash::Hasher};
+
+// Bloom filter with a small counter per cell instead of a bit, so that keys can be removed.
+// A counter that reaches the maximum sticks there: it may then give false positives for
+// removed keys, but never a false negative.
+#[derive(Clone)]
+pub struct CountingBloomFilter {
+    counters: Box<[u8]>,
+    hash_count: u32,
+}
+
+impl CountingBloomFilter {
+    pub fn new(expected_keys: usize, false_positive_rate: f64) -> Self {
+        let expected_keys = expected_keys.max(1) as f64;
+        let false_positive_rate = false_positive_rate.clamp(1e-9, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+
+        let size = (-expected_keys * false_positive_rate.ln() / (ln2 * ln2)).ceil();
+        let hash_count = (size / expected_keys * ln2).round().max(1.0);
+        Self {
+            counters: vec![0; size as usize].into_boxed_slice(),
+            hash_count: hash_count as u32,
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for index in self.indices(key) {
+            let counter = &mut self.counters[index];
+            *counter = counter.saturating_add(1);
+        }
+    }
+
+    // Must only be called for keys that were inserted.
+    pub fn remove(&mut self, key: &[u8]) {
+        for index in self.indices(key) {
+            let counter = &mut self.counters[index];
+            if *counter != u8::MAX {
+                *counter -= 1;
+            }
+        }
+    }
+
+    // False means the key was definitely not inserted.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.indices(key).all(|index| self.counters[index] > 0)
+    }
+
+    // Double hashing: the i-th index is h1 + i * h2.
+    fn indices(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(key);
+        let first = hasher.finish();
+        hasher.write_u8(0xff);
+        let second = hasher.finish() | 1;
+
+        let size = self.counters.len() as u64;
+        (0..self.hash_count as u64)
+            .map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % size) as usize)
+    }
+}
@@ -1,23 +1,235 @@
-use log::LevelFilter;
+use crate::errors::*;
+
+use log::{Level, LevelFilter};
 use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+
+use std::{collections::HashMap, env};
+
+// Environment variables with this prefix override config fields, with double underscores
+// between nested fields: RAYD_RPC__PORT=9000 sets rpc.port.
+const ENV_PREFIX: &str = "RAYD_";
 
 #[derive(Default, Serialize, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct Config {
+    pub role: Role,
+    pub persistence: Persistence,
     pub rpc: RpcConfig,
     pub psm: PsmConfig,
     pub journal_storage: JournalStorageConfig,
     pub snapshot_storage: SnapshotStorageConfig,
     pub logging: LoggingConfig,
     pub metrics: MetricsConfig,
+    pub health: HealthConfig,
+    // Holds the PID once recovery is over, removed on a clean exit; none if not set.
+    pub pid_file: Option<String>,
+}
+
+impl Config {
+    // Parses the YAML config and applies the RAYD_* environment variables on top of it. Fields
+    // set in neither place keep their defaults.
+    pub fn load(yaml: &[u8]) -> Result<Self> {
+        Self::load_merged(&[("YAML", yaml)])
+    }
+
+    // Like load, but with several YAML documents, each named for error messages. Later
+    // documents override earlier ones field by field, so that an override only needs to state
+    // the fields it changes.
+    pub fn load_merged(documents: &[(&str, &[u8])]) -> Result<Self> {
+        let mut value = Value::Mapping(Mapping::new());
+        for (name, yaml) in documents {
+            if yaml.is_empty() {
+                continue;
+            }
+            let document =
+                serde_yaml::from_slice(yaml).chain_err(|| format!("failed to parse {}", name))?;
+            merge_yaml(&mut value, document);
+        }
+        let vars = env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        });
+        apply_env_overrides(&mut value, vars)?;
+        serde_yaml::from_value(value).chain_err(|| "invalid config")
+    }
+}
+
+// Sections are merged key by key; anything else, lists included, is replaced as a whole. An
+// empty section or document overrides nothing.
+fn merge_yaml(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (_, Value::Null) => (),
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(field) => merge_yaml(field, value),
+                    None if value.is_null() => (),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn apply_env_overrides(
+    config: &mut Value,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<()> {
+    for (name, raw) in vars {
+        let path = match name.strip_prefix(ENV_PREFIX) {
+            Some(path) => path.to_lowercase(),
+            None => continue,
+        };
+        let fields: Vec<&str> = path.split("__").collect();
+        if fields.iter().any(|field| field.is_empty()) {
+            bail!("malformed config variable {}", name);
+        }
+
+        // Values are YAML too, so that numbers and booleans keep their types.
+        let override_value = match serde_yaml::from_str(&raw) {
+            Ok(value) if !raw.is_empty() => value,
+            _ => Value::String(raw),
+        };
+
+        let mut node = &mut *config;
+        for field in fields {
+            if let Value::Null = node {
+                *node = Value::Mapping(Mapping::new());
+            }
+            node = match node {
+                Value::Mapping(mapping) => mapping
+                    .entry(Value::String(field.into()))
+                    .or_insert(Value::Null),
+                _ => bail!("{} overrides a field that is not a section", name),
+            };
+        }
+        *node = override_value;
+    }
+    Ok(())
+}
+
+// Paths of the fields that differ between two serialized configs, such as rpc.port, with list
+// items numbered: logging.targets.0.level. Lists of different lengths differ as a whole.
+pub fn changed_fields(old: &Value, new: &Value) -> Vec<String> {
+    let mut fields = vec![];
+    collect_changed_fields(old, new, &mut vec![], &mut fields);
+    fields
+}
+
+fn collect_changed_fields(
+    old: &Value,
+    new: &Value,
+    path: &mut Vec<String>,
+    fields: &mut Vec<String>,
+) {
+    match (old, new) {
+        (Value::Mapping(old_mapping), Value::Mapping(new_mapping)) => {
+            let added = new_mapping
+                .iter()
+                .filter(|(key, _)| !old_mapping.contains_key(key));
+            for (key, _) in old_mapping.iter().chain(added) {
+                let name = match key {
+                    Value::String(name) => name.clone(),
+                    _ => serde_yaml::to_string(key).unwrap_or_default(),
+                };
+                path.push(name);
+                collect_changed_fields(
+                    old_mapping.get(key).unwrap_or(&Value::Null),
+                    new_mapping.get(key).unwrap_or(&Value::Null),
+                    path,
+                    fields,
+                );
+                path.pop();
+            }
+        }
+        (Value::Sequence(old_items), Value::Sequence(new_items))
+            if old_items.len() == new_items.len() =>
+        {
+            for (index, (old_item, new_item)) in old_items.iter().zip(new_items).enumerate() {
+                path.push(index.to_string());
+                collect_changed_fields(old_item, new_item, path, fields);
+                path.pop();
+            }
+        }
+        (old, new) if old != new => fields.push(path.join(".")),
+        _ => (),
+    }
+}
+
+// Sets the field at a path given by changed_fields to its value in another config.
+pub fn copy_field(from: &Value, to: &mut Value, path: &str) {
+    let mut source = from;
+    let mut target = to;
+    for name in path.split('.') {
+        let next = match name.parse::<usize>() {
+            Ok(index) if source.is_sequence() => source.get(index).zip(target.get_mut(index)),
+            _ => source.get(name).zip(target.get_mut(name)),
+        };
+        match next {
+            Some((next_source, next_target)) => {
+                source = next_source;
+                target = next_target;
+            }
+            None => return,
+        }
+    }
+    *target = source.clone();
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Role {
+    // Accepts writes and owns the journal and snapshot directories.
+    #[default]
+    #[serde(rename = "primary")]
+    Primary,
+    // Serves reads only, tailing the journal of a primary on shared storage.
+    #[serde(rename = "replica")]
+    Replica,
+    // Tails the journal like a replica without serving, until reloaded as a primary.
+    #[serde(rename = "standby")]
+    Standby,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Persistence {
+    // Mutations are journaled and snapshotted according to journal_storage and snapshot_storage.
+    #[default]
+    #[serde(rename = "journal")]
+    Journal,
+    // Nothing is written anywhere and all data is lost on restart. For tests and caches.
+    #[serde(rename = "none")]
+    None,
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct RpcConfig {
     pub threads: u16,
+    // IP address to listen on with the port, or unix:<path> to listen on a Unix socket.
     pub address: String,
     pub port: u16,
+    // Largest key that mutations may carry and largest value that may be set or built by
+    // appends, in bytes (0 for no limit). Oversized sets are rejected before they are journaled.
+    pub max_key_size: usize,
+    pub max_value_size: u64,
+    // Limits on the keys and values carried by a single request or reply, in bytes (0 for no
+    // limit). Requests are still decoded in full before they are rejected.
+    pub max_recv_message_size: usize,
+    pub max_send_message_size: usize,
+    // Count requests per client IP. Every distinct IP becomes a separate series, so this is
+    // best left off for servers with many short-lived clients.
+    pub per_ip_metrics: bool,
+    // Requests taking longer are logged as a warning, along with where the time went (0 to
+    // never log them).
+    pub slow_request_threshold_ms: u64,
+    // Accept Clear requests, which remove every key. Off so that a stray request cannot wipe
+    // a production store.
+    pub allow_clear: bool,
+    pub rate_limit: RateLimitConfig,
+    pub tcp: TcpConfig,
 }
 
 impl Default for RpcConfig {
@@ -26,6 +238,64 @@ impl Default for RpcConfig {
             threads: 0,
             address: "127.0.0.1".into(),
             port: 39172,
+            max_key_size: 64 * 1024,
+            max_value_size: 16 * 1024 * 1024,
+            max_recv_message_size: 64 * 1024 * 1024,
+            max_send_message_size: 64 * 1024 * 1024,
+            per_ip_metrics: false,
+            slow_request_threshold_ms: 0,
+            allow_clear: false,
+            rate_limit: RateLimitConfig::default(),
+            tcp: TcpConfig::default(),
+        }
+    }
+}
+
+// Limits are global rather than per client: requests over the rate are rejected, not queued.
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    // Requests per second (0 for no limit).
+    pub read_rate: u32,
+    // Requests accepted at once after a quiet period.
+    pub read_burst: u32,
+    // Mutations and snapshot requests.
+    pub write_rate: u32,
+    pub write_burst: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            read_rate: 0,
+            read_burst: 1000,
+            write_rate: 0,
+            write_burst: 1000,
+        }
+    }
+}
+
+// Options of the listening socket and of the connections it accepts. Ignored when listening on
+// a Unix socket. The defaults are what Tokio does on its own.
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpConfig {
+    pub reuse_address: bool,
+    // Connections the OS queues for rayd to accept before it starts refusing them. The OS may
+    // cap it, e.g. with net.core.somaxconn on Linux.
+    pub listen_backlog: u32,
+    pub nodelay: bool,
+    // Idle time before keepalive probes are sent, in milliseconds (0 to disable keepalive).
+    pub keepalive_ms: u64,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            reuse_address: true,
+            listen_backlog: 1024,
+            nodelay: false,
+            keepalive_ms: 0,
         }
     }
 }
@@ -41,24 +311,123 @@ pub struct PsmConfig {
 #[derive(Serialize, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct MachineServiceConfig {
+    pub store: StoreKind,
     pub request_queue_size: usize,
     pub mutation_queue_size: usize,
+    pub max_pending_queries: usize,
+    // Number of past states kept for reads at a given epoch. Each one is a full copy of the
+    // state, and the oldest is replaced on every mutation, so keep this small.
+    pub retained_epochs: usize,
+    pub bloom_filter: BloomFilterConfig,
+    pub compression: CompressionConfig,
+    pub eviction: EvictionConfig,
+    // Events a subscriber of the Watch RPC may fall behind by before it misses some.
+    pub watch_buffer_size: usize,
+    // CPU core to pin the service thread to; not pinned if unset. Only supported on Linux.
+    pub core_id: Option<usize>,
 }
 
 impl Default for MachineServiceConfig {
     fn default() -> Self {
         Self {
+            store: StoreKind::Hash,
             request_queue_size: 10000,
             mutation_queue_size: 10000,
+            max_pending_queries: 100_000,
+            retained_epochs: 0,
+            bloom_filter: BloomFilterConfig::default(),
+            compression: CompressionConfig::default(),
+            eviction: EvictionConfig::default(),
+            watch_buffer_size: 10000,
+            core_id: None,
         }
     }
 }
 
+// Lets lookups of keys that were never set skip the store.
 #[derive(Serialize, Deserialize)]
 #[serde(default, deny_unknown_fields)]
+pub struct BloomFilterConfig {
+    pub enable: bool,
+    // The filter takes about expected_keys * 1.44 * log2(1 / false_positive_rate) bytes.
+    pub expected_keys: usize,
+    pub false_positive_rate: f64,
+}
+
+impl Default for BloomFilterConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            expected_keys: 1_000_000,
+            false_positive_rate: 0.01,
+        }
+    }
+}
+
+// Keeps large values compressed with zstd, trading CPU time for memory and snapshot size.
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    pub enable: bool,
+    // Smaller values are kept as they are.
+    pub min_value_size: usize,
+    // From 1 (fastest) to 22 (smallest).
+    pub level: i32,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            min_value_size: 4096,
+            level: 3,
+        }
+    }
+}
+
+// Bounds the map for cache-style use by evicting the least recently written keys. Replicas and
+// restarts only evict the same keys with the same limits.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EvictionConfig {
+    // 0 for no limit.
+    pub max_keys: usize,
+    // Keys and values as stored, without the overhead of the store; 0 for no limit.
+    pub max_bytes: u64,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum StoreKind {
+    // Faster point lookups.
+    #[serde(rename = "hash")]
+    Hash,
+    // Keeps keys sorted, so snapshots of the same state are identical.
+    #[serde(rename = "ordered")]
+    Ordered,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct JournalServiceConfig {
     pub request_queue_size: usize,
     pub batch_size: usize,
+    // Cap on the encoded size of a batch in bytes (0 for no limit). A larger mutation is
+    // persisted in a batch of its own.
+    pub batch_max_bytes: usize,
+    // Reject mutations with ResourceExhausted instead of waiting when the queue is full.
+    pub reject_when_full: bool,
+    // How often a replica checks the journal for new mutations.
+    pub poll_interval_ms: u64,
+    // Threads applying the journal on startup (0 for the number of CPUs). Only machines that
+    // support it apply mutations in parallel.
+    pub recovery_threads: usize,
+    // How often progress of a long recovery is logged.
+    pub recovery_progress_interval_ms: u64,
+    // Persisted batches waiting to be handed to the machine service. Batches are persisted
+    // ahead of the machine service until this many are waiting.
+    pub dispatch_queue_size: usize,
+    // CPU core to pin the service thread to; not pinned if unset. Only supported on Linux.
+    pub core_id: Option<usize>,
 }
 
 impl Default for JournalServiceConfig {
@@ -66,31 +435,69 @@ impl Default for JournalServiceConfig {
         Self {
             request_queue_size: 10000,
             batch_size: 100,
+            batch_max_bytes: 0,
+            reject_when_full: false,
+            poll_interval_ms: 100,
+            recovery_threads: 1,
+            recovery_progress_interval_ms: 10_000,
+            dispatch_queue_size: 100,
+            core_id: None,
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct SnapshotServiceConfig {
+    // A snapshot is taken after this many mutations, or earlier if one of the triggers below
+    // fires first.
     pub snapshot_interval: u64,
+    // Journal bytes written since the last snapshot (0 disables the trigger).
+    pub snapshot_interval_bytes: u64,
+    // Time since the last snapshot, checked about once a second (0 disables the trigger).
+    pub snapshot_interval_ms: u64,
+    // Epochs the journal service may run ahead of this service. Once as many are waiting, the
+    // journal service waits too, so a stalled snapshot service slows down writes instead of
+    // piling up memory.
+    pub request_queue_size: usize,
     pub batch_size: usize,
+    // Number of incremental snapshots taken between full ones (0 disables them).
+    pub deltas_per_full: u32,
+    // Number of parts full snapshots of the key-value store are split into, each serialized on
+    // a thread of its own and deserialized in parallel on startup. 0 and 1 write a single part.
+    pub snapshot_segments: u32,
+    // Worker threads of a multi-threaded runtime to run the service on. 0 runs it on a single
+    // thread, like the journal and machine services.
+    pub worker_threads: usize,
+    // CPU core to pin the service thread to; not pinned if unset. Only supported on Linux.
+    // Worker threads are pinned to the same core.
+    pub core_id: Option<usize>,
 }
 
 impl Default for SnapshotServiceConfig {
     fn default() -> Self {
         Self {
             snapshot_interval: 10000,
+            snapshot_interval_bytes: 0,
+            snapshot_interval_ms: 0,
+            request_queue_size: 100_000,
             batch_size: 100_000,
+            deltas_per_full: 0,
+            snapshot_segments: 1,
+            worker_threads: 0,
+            core_id: None,
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct JournalStorageConfig {
     pub path: String,
     pub file_size_soft_limit: usize,
+    // Once a snapshot is taken, also rewrite the oldest file kept without the blobs it covers,
+    // merging the next files into it until it reaches file_size_soft_limit.
+    pub compact: bool,
 }
 
 impl Default for JournalStorageConfig {
@@ -98,6 +505,7 @@ impl Default for JournalStorageConfig {
         Self {
             path: String::from("./journal"),
             file_size_soft_limit: 100_000_000,
+            compact: false,
         }
     }
 }
@@ -105,13 +513,60 @@ impl Default for JournalStorageConfig {
 #[derive(Serialize, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct SnapshotStorageConfig {
+    pub backend: SnapshotBackend,
+    // Directory for the directory backend.
     pub path: String,
+    // Number of full snapshots (with their deltas) to keep, 0 to keep all.
+    pub keep_snapshots: usize,
+    pub object_store: ObjectStoreConfig,
 }
 
 impl Default for SnapshotStorageConfig {
     fn default() -> Self {
         Self {
+            backend: SnapshotBackend::Directory,
             path: String::from("./snapshots"),
+            keep_snapshots: 0,
+            object_store: ObjectStoreConfig::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum SnapshotBackend {
+    #[serde(rename = "directory")]
+    Directory,
+    // S3 or a compatible service such as MinIO.
+    #[serde(rename = "object_store")]
+    ObjectStore,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ObjectStoreConfig {
+    // Base URL, e.g. https://s3.us-east-1.amazonaws.com; buckets are addressed path-style.
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    // Snapshot objects are named <prefix>/<timestamp>_<epoch>.snap or .delta.
+    pub prefix: String,
+    // Taken from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY when empty.
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    // Snapshots are uploaded in parts of this size, in bytes (at least 5 MiB).
+    pub part_size: usize,
+}
+
+impl Default for ObjectStoreConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: String::from("http://localhost:9000"),
+            region: String::from("us-east-1"),
+            bucket: String::new(),
+            prefix: String::from("snapshots"),
+            access_key_id: String::new(),
+            secret_access_key: String::new(),
+            part_size: 64 * 1024 * 1024,
         }
     }
 }
@@ -123,6 +578,8 @@ pub struct LoggingConfig {
     pub fastlog_threads: u16,
     pub modules: Vec<String>,
     pub targets: Vec<LoggingTargetConfig>,
+    // Record of every persisted mutation, kept apart from the logs; off if not set.
+    pub audit: Option<AuditConfig>,
 }
 
 impl Default for LoggingConfig {
@@ -134,16 +591,34 @@ impl Default for LoggingConfig {
             targets: vec![LoggingTargetConfig {
                 target: LoggingTarget::Stderr,
                 level: LogLevel::Info,
+                min_level: None,
+                format: LogFormat::Text,
             }],
+            audit: None,
         }
     }
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditConfig {
+    pub target: LoggingTarget,
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct LoggingTargetConfig {
     pub target: LoggingTarget,
+    // Most verbose level written to the target.
+    #[serde(alias = "max_level")]
     pub level: LogLevel,
+    // Most severe level written to the target (error if not set), to select a band of levels.
+    #[serde(default)]
+    pub min_level: Option<LogLevel>,
+    #[serde(default)]
+    pub format: LogFormat,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -152,7 +627,40 @@ pub enum LoggingTarget {
     #[serde(rename = "stderr")]
     Stderr,
     #[serde(rename = "file")]
-    File { path: String },
+    File {
+        path: String,
+        #[serde(default)]
+        rotation: LogRotationConfig,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogRotationConfig {
+    // Size in bytes at which the file is archived and a fresh one is started (0 to never rotate).
+    pub max_file_size: u64,
+    // Number of archives kept next to the active file; older ones are removed.
+    pub keep_files: usize,
+}
+
+impl Default for LogRotationConfig {
+    fn default() -> Self {
+        Self {
+            max_file_size: 0,
+            keep_files: 5,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub enum LogFormat {
+    // One human-readable line per message.
+    #[default]
+    #[serde(rename = "text")]
+    Text,
+    // One JSON object per line with timestamp, level, module and message fields.
+    #[serde(rename = "json")]
+    Json,
 }
 
 #[derive(Clone, Copy, Serialize, Deserialize)]
@@ -168,6 +676,17 @@ pub enum LogLevel {
     Error,
 }
 
+impl From<LogLevel> for Level {
+    fn from(level: LogLevel) -> log::Level {
+        match level {
+            LogLevel::Debug => Level::Debug,
+            LogLevel::Info => Level::Info,
+            LogLevel::Warn => Level::Warn,
+            LogLevel::Error => Level::Error,
+        }
+    }
+}
+
 impl From<LogLevel> for LevelFilter {
     fn from(level: LogLevel) -> log::LevelFilter {
         match level {
@@ -185,6 +704,10 @@ pub struct MetricsConfig {
     pub enable: bool,
     pub address: String,
     pub port: u16,
+    // Thread cpu times are read from /proc at most this often, however often they are scraped.
+    pub cpu_time_refresh_ms: u64,
+    // Added to every metric, e.g. to tell apart the instances reporting to one Prometheus.
+    pub static_labels: HashMap<String, String>,
 }
 
 impl Default for MetricsConfig {
@@ -193,6 +716,123 @@ impl Default for MetricsConfig {
             enable: true,
             address: "127.0.0.1".into(),
             port: 40000,
+            cpu_time_refresh_ms: 1000,
+            static_labels: HashMap::new(),
         }
     }
 }
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    pub enable: bool,
+    pub address: String,
+    pub port: u16,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            address: "127.0.0.1".into(),
+            port: 40001,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Same as Config::load_merged, with the given variables instead of the environment.
+    fn load_with_vars(yaml: &str, vars: &[(&str, &str)]) -> Result<Config> {
+        let mut value = serde_yaml::from_str(yaml).unwrap();
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        apply_env_overrides(&mut value, vars)?;
+        serde_yaml::from_value(value).chain_err(|| "invalid config")
+    }
+
+    #[test]
+    fn variables_override_the_file() {
+        let yaml = "rpc:\n    port: 1000\n    threads: 3\n";
+        let vars = [
+            ("RAYD_RPC__PORT", "9000"),
+            ("RAYD_PSM__MACHINE_SERVICE__STORE", "ordered"),
+            ("RAYD_HEALTH__ENABLE", "true"),
+            ("RAYD_PID_FILE", "rayd.pid"),
+            ("OTHER_RPC__PORT", "1"),
+        ];
+        let config = load_with_vars(yaml, &vars).unwrap();
+        assert_eq!(config.rpc.port, 9000);
+        assert_eq!(config.rpc.threads, 3);
+        assert!(matches!(
+            config.psm.machine_service.store,
+            StoreKind::Ordered
+        ));
+        assert!(config.health.enable);
+        assert_eq!(config.pid_file.as_deref(), Some("rayd.pid"));
+        assert_eq!(config.metrics.port, MetricsConfig::default().port);
+    }
+
+    #[test]
+    fn malformed_variables_are_rejected() {
+        for &name in &["RAYD_RPC__", "RAYD_RPC__PORT__X", "RAYD_NO_SUCH_FIELD"] {
+            assert!(load_with_vars("{}", &[(name, "1")]).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn load_applies_the_environment() {
+        // No other test looks at this field, so setting it for the whole process is harmless.
+        env::set_var("RAYD_PSM__MACHINE_SERVICE__WATCH_BUFFER_SIZE", "123");
+        let config = Config::load(b"psm:\n    machine_service:\n        watch_buffer_size: 5\n");
+        assert_eq!(config.unwrap().psm.machine_service.watch_buffer_size, 123);
+    }
+
+    #[test]
+    fn later_documents_override_nested_fields() {
+        let base = b"rpc:
+    port: 1000
+    rate_limit:
+        read_rate: 10
+        read_burst: 20
+logging:
+    targets:
+      - target:
+            type: stderr
+        level: info
+      - target:
+            type: stderr
+        level: warn
+";
+        let overlay = b"rpc:
+    rate_limit:
+        read_burst: 30
+logging:
+    targets:
+      - target:
+            type: file
+            path: rayd.log
+        level: debug
+metrics:
+";
+        let documents: [(&str, &[u8]); 3] = [("base", base), ("empty", b""), ("overlay", overlay)];
+        let config = Config::load_merged(&documents).unwrap();
+        assert_eq!(config.rpc.port, 1000);
+        assert_eq!(config.rpc.rate_limit.read_rate, 10);
+        assert_eq!(config.rpc.rate_limit.read_burst, 30);
+        // Lists are replaced whole, and an empty section changes nothing.
+        assert_eq!(config.logging.targets.len(), 1);
+        assert!(matches!(
+            config.logging.targets[0].target,
+            LoggingTarget::File { .. }
+        ));
+        assert!(config.metrics.enable);
+
+        let documents: [(&str, &[u8]); 2] = [("base", base), ("broken.yml", b"rpc: [")];
+        let err = Config::load_merged(&documents).err().unwrap();
+        assert_eq!(err.to_string(), "failed to parse broken.yml");
+    }
+}
@@ -1,6 +1,9 @@
 use super::{
     config::JournalStorageConfig,
-    journal_service::{JournalReader, JournalWriter, ReadResult},
+    journal_service::{
+        blob_epoch, decode_blob, JournalReader, JournalTailer, JournalWriter, ReadResult,
+    },
+    machine_service::Machine,
 };
 
 use crate::{errors::*, util::try_read_u32};
@@ -9,29 +12,73 @@ use chrono::Utc;
 
 use byteorder::{LittleEndian, WriteBytesExt};
 
+use byte_string::ByteStr;
+
+use metrics::{counter, gauge};
+
 use std::{
+    cmp,
     collections::VecDeque,
-    fs::{create_dir_all, read_dir, remove_file, File, OpenOptions},
-    io::{self, BufReader, BufWriter, Read, Write},
+    fs::{create_dir_all, read_dir, read_to_string, remove_file, rename, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
+    sync::mpsc::{self, TryRecvError},
+    thread,
 };
 
+// A compaction writes the blobs it keeps to COMPACTED_FILE, lists the files it merges but the
+// oldest one in COMPACTION_MARKER, and commits by renaming COMPACTED_FILE over the oldest one.
+// Only then are the other merged files removed, and the marker last. Neither name ends with
+// .jnl, so readers and tailers never see them.
+const COMPACTED_FILE: &str = "compacted.tmp";
+const COMPACTION_MARKER: &str = "compaction";
+
+// A compaction running on its own thread, and the number of blobs it drops.
+struct PendingCompaction {
+    receiver: mpsc::Receiver<Result<(usize, usize, usize)>>,
+    dropped: usize,
+}
+
 struct DirectoryJournalBase {
     directory_path: PathBuf,
-    previous_files: VecDeque<(PathBuf, usize)>,
+    // Path, blob count and size in bytes of every file before the current one.
+    previous_files: VecDeque<(PathBuf, usize, usize)>,
     total_blob_count: usize,
+    total_size: usize,
     file_size_soft_limit: usize,
+    compact: bool,
+    compaction: Option<PendingCompaction>,
 }
 
 impl DirectoryJournalBase {
-    fn push_file(&mut self, path: PathBuf, blob_count: usize) {
+    fn push_file(&mut self, path: PathBuf, blob_count: usize, size: usize) {
         self.total_blob_count += blob_count;
-        self.previous_files.push_back((path, blob_count));
+        self.total_size += size;
+        self.previous_files.push_back((path, blob_count, size));
+    }
+
+    fn pop_last_file(&mut self) -> Option<(PathBuf, usize, usize)> {
+        let (path, blob_count, size) = self.previous_files.pop_back()?;
+        self.total_blob_count -= blob_count;
+        self.total_size -= size;
+        Some((path, blob_count, size))
     }
 
-    fn dispose_oldest_blobs(&mut self, mut blob_count: usize) -> Result<()> {
+    fn report_disk_usage(&self, current_file_size: usize) {
+        gauge!(
+            "rayd.journal_storage.file_count",
+            self.previous_files.len() as i64 + 1
+        );
+        gauge!(
+            "rayd.journal_storage.size",
+            (self.total_size + current_file_size) as i64
+        );
+    }
+
+    // Returns how many of the blobs are left, as only whole files are removed.
+    fn dispose_oldest_blobs(&mut self, mut blob_count: usize) -> Result<usize> {
         while !self.previous_files.is_empty() && blob_count >= self.previous_files[0].1 {
-            let (ref path, file_blob_count) = self.previous_files[0];
+            let (ref path, file_blob_count, file_size) = self.previous_files[0];
 
             if let Err(err) = remove_file(path) {
                 if err.kind() == io::ErrorKind::NotFound {
@@ -44,17 +91,216 @@ impl DirectoryJournalBase {
             }
 
             self.total_blob_count -= file_blob_count;
+            self.total_size -= file_size;
             blob_count -= file_blob_count;
             self.previous_files.pop_front();
         }
+        Ok(blob_count)
+    }
+
+    // Rewrites the oldest file without its first blob_count blobs on a thread of its own,
+    // merging the files after it into it until it reaches the size limit, like the writer
+    // does. The current file is left alone, so writes go on meanwhile.
+    fn spawn_compaction(&mut self, blob_count: usize) -> Result<()> {
+        let limit = self.file_size_soft_limit;
+        let mergeable = self.previous_files.len() > 1 && self.previous_files[0].2 < limit;
+        if self.previous_files.is_empty() || (blob_count == 0 && !mergeable) {
+            return Ok(());
+        }
+
+        let directory_path = self.directory_path.clone();
+        let files = self.previous_files.clone();
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("rayd-compaction".to_string())
+            .spawn(move || {
+                let result = compact_files(&directory_path, &files, blob_count, limit);
+                sender.send(result).ok();
+            })
+            .chain_err(|| "failed to spawn thread")?;
+        self.compaction = Some(PendingCompaction {
+            receiver,
+            dropped: blob_count,
+        });
         Ok(())
     }
+
+    // Accounts for the files replaced by a compaction that is done, waiting for it if told
+    // to. A failed compaction leaves the files as they were, so it is only logged.
+    fn apply_compaction(&mut self, wait: bool) {
+        let result = match self.compaction {
+            None => return,
+            Some(ref compaction) if wait => compaction.receiver.recv().ok(),
+            Some(ref compaction) => match compaction.receiver.try_recv() {
+                Ok(result) => Some(result),
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => None,
+            },
+        };
+        let dropped = self.compaction.take().unwrap().dropped;
+        let (merged, compacted_blob_count, compacted_size) =
+            match result.unwrap_or_else(|| Err("journal compaction thread terminated".into())) {
+                Ok(compacted) => compacted,
+                Err(err) => {
+                    warn!(
+                        "Failed to compact the journal (error chain below)\n{}",
+                        err.display_fancy_chain()
+                    );
+                    return;
+                }
+            };
+
+        let oldest_path = self.previous_files[0].0.clone();
+        for (_, file_blob_count, file_size) in self.previous_files.drain(..merged) {
+            self.total_blob_count -= file_blob_count;
+            self.total_size -= file_size;
+        }
+        self.total_blob_count += compacted_blob_count;
+        self.total_size += compacted_size;
+        info!(
+            "Compacted {} journal file(s) into {:?} ({} blobs dropped, {} left)",
+            merged, oldest_path, dropped, compacted_blob_count
+        );
+        self.previous_files
+            .push_front((oldest_path, compacted_blob_count, compacted_size));
+        counter!("rayd.journal_storage.compaction_count", 1);
+    }
+}
+
+// Compacts the oldest of the files, see DirectoryJournalBase::spawn_compaction, and commits
+// the compaction. Returns the number of files merged, and the blob count and size of the
+// compacted file.
+fn compact_files(
+    directory_path: &Path,
+    files: &VecDeque<(PathBuf, usize, usize)>,
+    skipped: usize,
+    size_limit: usize,
+) -> Result<(usize, usize, usize)> {
+    let compacted_path = directory_path.join(COMPACTED_FILE);
+    let (merged, blob_count, size) =
+        write_compacted_file(&compacted_path, files, skipped, size_limit)?;
+
+    let mut marker = String::new();
+    for (path, _, _) in files.iter().take(merged).skip(1) {
+        marker.push_str(&path.to_string_lossy());
+        marker.push('\n');
+    }
+    let marker_path = directory_path.join(COMPACTION_MARKER);
+    File::create(&marker_path)
+        .and_then(|mut file| {
+            file.write_all(marker.as_bytes())
+                .and_then(|_| file.sync_all())
+        })
+        .chain_err(|| format!("failed to write {:?}", marker_path))?;
+    sync_directory(directory_path)?;
+
+    let oldest_path = &files[0].0;
+    rename(&compacted_path, oldest_path)
+        .chain_err(|| format!("failed to replace {:?}", oldest_path))?;
+    sync_directory(directory_path)?;
+    finish_compaction(directory_path, &marker_path)?;
+    Ok((merged, blob_count, size))
+}
+
+// Copies the blobs of the oldest files to a new file at the path, but the first skipped ones,
+// and persists it. Files are taken until the new file reaches size_limit. Returns the number
+// of files taken, and the blob count and size of the new file.
+fn write_compacted_file(
+    path: &Path,
+    files: &VecDeque<(PathBuf, usize, usize)>,
+    skipped: usize,
+    size_limit: usize,
+) -> Result<(usize, usize, usize)> {
+    let mut writer = File::create(path)
+        .map(BufWriter::new)
+        .chain_err(|| format!("failed to create {:?}", path))?;
+    let mut merged = 0;
+    let mut blob_count = 0;
+    let mut size = 0;
+    let mut skipped = skipped;
+    for (file_path, _, file_size) in files {
+        if merged > 0 && size >= size_limit {
+            break;
+        }
+        merged += 1;
+        let mut reader = DirectoryJournalReader::open_file(file_path)?;
+        let mut remaining = *file_size;
+        while let Some(blob) = read_blob_within(&mut reader, remaining)
+            .chain_err(|| format!("failed to read from {:?}", file_path))?
+        {
+            remaining -= 4 + blob.len();
+            if skipped > 0 {
+                skipped -= 1;
+                continue;
+            }
+            writer
+                .write_u32::<LittleEndian>(blob.len() as u32)
+                .and_then(|_| writer.write_all(&blob))
+                .chain_err(|| format!("failed to write to {:?}", path))?;
+            blob_count += 1;
+            size += 4 + blob.len();
+        }
+    }
+    writer
+        .into_inner()
+        .map_err(|err| err.into_error())
+        .and_then(|file| file.sync_all())
+        .chain_err(|| format!("failed to persist {:?}", path))?;
+    Ok((merged, blob_count, size))
+}
+
+// Removes the merged files listed in the marker of a committed compaction, then the marker.
+fn finish_compaction(directory_path: &Path, marker_path: &Path) -> Result<()> {
+    let marker =
+        read_to_string(marker_path).chain_err(|| format!("failed to read {:?}", marker_path))?;
+    for line in marker.lines() {
+        match remove_file(line) {
+            Ok(()) => debug!("Removed compacted journal file: {:?}", line),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err).chain_err(|| format!("failed to remove {:?}", line)),
+        }
+    }
+    sync_directory(directory_path)?;
+    remove_file(marker_path).chain_err(|| format!("failed to remove {:?}", marker_path))?;
+    sync_directory(directory_path)
+}
+
+// A compaction interrupted before its rename is rolled back, one interrupted after it is
+// finished, so that every blob is in exactly one file either way.
+fn recover_compaction(directory_path: &Path) -> Result<()> {
+    let compacted_path = directory_path.join(COMPACTED_FILE);
+    let marker_path = directory_path.join(COMPACTION_MARKER);
+    if compacted_path.exists() {
+        warn!("Rolling back an interrupted journal compaction");
+        remove_file(&compacted_path)
+            .chain_err(|| format!("failed to remove {:?}", compacted_path))?;
+        if marker_path.exists() {
+            remove_file(&marker_path)
+                .chain_err(|| format!("failed to remove {:?}", marker_path))?;
+        }
+        sync_directory(directory_path)
+    } else if marker_path.exists() {
+        warn!("Finishing an interrupted journal compaction");
+        finish_compaction(directory_path, &marker_path)
+    } else {
+        Ok(())
+    }
+}
+
+// Makes creations, renames and removals of files in the directory durable.
+fn sync_directory(path: &Path) -> Result<()> {
+    File::open(path)
+        .and_then(|directory| directory.sync_all())
+        .chain_err(|| format!("failed to sync directory {:?}", path))
 }
 
 pub struct DirectoryJournalReader {
     file_paths: VecDeque<PathBuf>,
     current_file: Option<BufReader<File>>,
     current_file_blob_count: usize,
+    // Bytes of complete blobs read from the current file.
+    current_file_size: usize,
+    current_file_len: usize,
     base: DirectoryJournalBase,
 }
 
@@ -63,37 +309,33 @@ impl DirectoryJournalReader {
         let directory_path = PathBuf::from(&config.path);
         create_dir_all(directory_path.as_path())
             .chain_err(|| format!("failed to create directory {:?}", directory_path))?;
+        recover_compaction(&directory_path)?;
 
-        let mut file_paths = vec![];
-        let dir_entries = read_dir(&directory_path)
-            .chain_err(|| format!("failed to read directory {:?}", directory_path))?;
-
-        for entry in dir_entries {
-            let file_path = entry.chain_err(|| "failed to resolve entry")?.path();
-            if file_path.to_string_lossy().ends_with(".jnl") {
-                file_paths.push(file_path.to_owned());
-            }
-        }
-
-        file_paths.sort();
+        let file_paths = journal_file_paths(&directory_path)?;
 
-        let current_file = if file_paths.is_empty() {
-            None
+        let (current_file, current_file_len) = if file_paths.is_empty() {
+            (None, 0)
         } else {
-            Some(Self::open_file(&file_paths[0])?)
+            let (file, len) = Self::open_file_with_len(&file_paths[0])?;
+            (Some(file), len)
         };
 
         let base = DirectoryJournalBase {
             directory_path,
             previous_files: VecDeque::new(),
             total_blob_count: 0,
+            total_size: 0,
             file_size_soft_limit: config.file_size_soft_limit,
+            compact: config.compact,
+            compaction: None,
         };
 
         let reader = Self {
             file_paths: file_paths.into(),
             current_file,
             current_file_blob_count: 0,
+            current_file_size: 0,
+            current_file_len,
             base,
         };
 
@@ -108,47 +350,143 @@ impl DirectoryJournalReader {
         Ok(BufReader::new(file))
     }
 
-    fn read_len(&mut self) -> Result<Option<usize>> {
+    fn open_file_with_len(path: &Path) -> Result<(BufReader<File>, usize)> {
+        let file = Self::open_file(path)?;
+        let metadata = file
+            .get_ref()
+            .metadata()
+            .chain_err(|| format!("failed to stat {:?}", path))?;
+        Ok((file, metadata.len() as usize))
+    }
+
+    fn read_next(&mut self) -> Result<Option<Vec<u8>>> {
         while let Some(ref mut file) = self.current_file {
-            match try_read_u32(file)? {
-                None => {
-                    let path = self.file_paths.pop_front().unwrap();
-                    self.base.push_file(path, self.current_file_blob_count);
-
-                    if self.file_paths.is_empty() {
-                        self.current_file = None;
-                        break;
-                    } else {
-                        self.current_file = Some(Self::open_file(&self.file_paths[0])?);
-                        self.current_file_blob_count = 0;
-                    }
-                }
-                Some(len) => return Ok(Some(len as usize)),
+            let path = &self.file_paths[0];
+            let remaining = self.current_file_len - self.current_file_size;
+            let maybe_blob = read_blob_within(file, remaining)
+                .chain_err(|| format!("failed to read from {:?}", path))?;
+            if let Some(blob) = maybe_blob {
+                self.current_file_blob_count += 1;
+                self.current_file_size += 4 + blob.len();
+                return Ok(Some(blob));
+            }
+
+            if self.current_file_size < self.current_file_len {
+                self.discard_incomplete_blob()?;
+            }
+
+            let path = self.file_paths.pop_front().unwrap();
+            self.base
+                .push_file(path, self.current_file_blob_count, self.current_file_size);
+
+            if self.file_paths.is_empty() {
+                self.current_file = None;
+            } else {
+                let (file, len) = Self::open_file_with_len(&self.file_paths[0])?;
+                self.current_file = Some(file);
+                self.current_file_blob_count = 0;
+                self.current_file_size = 0;
+                self.current_file_len = len;
             }
         }
 
         Ok(None)
     }
+
+    // A crash in the middle of an append leaves an incomplete blob at the end of the last
+    // file, which is cut off so that the file stays valid once newer files follow it.
+    // Anywhere else it means that persisted mutations are lost.
+    fn discard_incomplete_blob(&mut self) -> Result<()> {
+        let path = &self.file_paths[0];
+        if self.file_paths.len() > 1 {
+            bail!(
+                "journal file {:?} is truncated at offset {}",
+                path,
+                self.current_file_size
+            );
+        }
+
+        warn!(
+            "Discarding incomplete blob at the end of {:?} (offset: {}, file length: {})",
+            path, self.current_file_size, self.current_file_len
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_len(self.current_file_size as u64))
+            .chain_err(|| format!("failed to truncate {:?}", path))?;
+        self.current_file_len = self.current_file_size;
+        Ok(())
+    }
+}
+
+// Journal files of the directory, oldest first.
+fn journal_file_paths(directory_path: &Path) -> Result<Vec<PathBuf>> {
+    let mut file_paths = vec![];
+    let dir_entries = read_dir(directory_path)
+        .chain_err(|| format!("failed to read directory {:?}", directory_path))?;
+
+    for entry in dir_entries {
+        let file_path = entry.chain_err(|| "failed to resolve entry")?.path();
+        if file_path.to_string_lossy().ends_with(".jnl") {
+            file_paths.push(file_path.to_owned());
+        }
+    }
+
+    file_paths.sort();
+    Ok(file_paths)
+}
+
+// Epoch of the first blob of the given files, None if they hold no blobs.
+fn first_blob_epoch<'a, I: IntoIterator<Item = &'a PathBuf>>(file_paths: I) -> Result<Option<u64>> {
+    for path in file_paths {
+        let (mut file, len) = DirectoryJournalReader::open_file_with_len(path)?;
+        let blob = read_blob_within(&mut file, len)
+            .chain_err(|| format!("failed to read from {:?}", path))?;
+        if let Some(blob) = blob {
+            return blob_epoch(&blob)
+                .map(Some)
+                .chain_err(|| format!("failed to read the first blob of {:?}", path));
+        }
+    }
+    Ok(None)
+}
+
+// Reads the next blob unless the end of the file is reached, possibly in the middle of the
+// blob. Never reads past the given number of bytes, so a corrupted length can't cause a
+// huge allocation.
+fn read_blob_within<T: Read>(reader: &mut T, remaining: usize) -> io::Result<Option<Vec<u8>>> {
+    if remaining < 4 {
+        return Ok(None);
+    }
+    let len = match try_read_u32(reader)? {
+        Some(len) => len as usize,
+        None => return Ok(None),
+    };
+    if len > remaining - 4 {
+        return Ok(None);
+    }
+
+    let mut blob = vec![0; len];
+    reader.read_exact(&mut blob)?;
+    Ok(Some(blob))
 }
 
 impl JournalReader for DirectoryJournalReader {
     type Writer = DirectoryJournalWriter;
 
     fn read_blob(mut self) -> Result<ReadResult<Self, Self::Writer>> {
-        let len = match self.read_len()? {
-            Some(len) => len,
+        match self.read_next()? {
+            Some(blob) => Ok(ReadResult::Blob(blob, self)),
             None => {
                 let writer = DirectoryJournalWriter::new(self.base)?;
-                return Ok(ReadResult::End(writer));
+                Ok(ReadResult::End(writer))
             }
-        };
-
-        let mut blob = vec![0; len as usize];
-        self.current_file.as_mut().unwrap().read_exact(&mut blob)?;
-
-        self.current_file_blob_count += 1;
+        }
+    }
 
-        Ok(ReadResult::Blob(blob, self))
+    fn first_epoch(&self) -> Result<Option<u64>> {
+        first_blob_epoch(&self.file_paths)
     }
 }
 
@@ -161,18 +499,46 @@ pub struct DirectoryJournalWriter {
 }
 
 impl DirectoryJournalWriter {
-    fn new(base: DirectoryJournalBase) -> Result<Self> {
-        let (file, file_path) = Self::open_new_file(&base.directory_path)?;
-        let writer = Self {
-            file,
-            file_path,
-            current_file_size: 0,
-            current_file_blob_count: 0,
-            base,
+    fn new(mut base: DirectoryJournalBase) -> Result<Self> {
+        // After a restart, keep appending to the last file unless it is full already. The
+        // reader has cut off any incomplete blob at its end.
+        let last_file_size = base.previous_files.back().map(|(_, _, size)| *size);
+        let writer = match last_file_size {
+            Some(size) if size < base.file_size_soft_limit => {
+                let (file_path, blob_count, size) = base.pop_last_file().unwrap();
+                let file = Self::open_last_file(&file_path)?;
+                Self {
+                    file,
+                    file_path,
+                    current_file_size: size,
+                    current_file_blob_count: blob_count,
+                    base,
+                }
+            }
+            _ => {
+                let (file, file_path) = Self::open_new_file(&base.directory_path)?;
+                Self {
+                    file,
+                    file_path,
+                    current_file_size: 0,
+                    current_file_blob_count: 0,
+                    base,
+                }
+            }
         };
+        writer.base.report_disk_usage(writer.current_file_size);
         Ok(writer)
     }
 
+    fn open_last_file(path: &Path) -> Result<BufWriter<File>> {
+        debug!("Appending to journal file: {:?}", path);
+        let file = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .chain_err(|| format!("failed to open file for append: {:?}", path))?;
+        Ok(BufWriter::new(file))
+    }
+
     fn open_new_file(directory_path: &Path) -> Result<(BufWriter<File>, PathBuf)> {
         let file_name = format!("{}.jnl", Utc::now().format("%+"));
         let path = Path::new(&directory_path).join(file_name);
@@ -195,35 +561,494 @@ impl JournalWriter for DirectoryJournalWriter {
             .write_u32::<LittleEndian>(blob.len() as u32)
             .and_then(|_| self.file.write_all(blob))
             .chain_err(|| format!("failed to write to {:?}", self.file_path))?;
+        counter!("rayd.journal_storage.bytes_written", blob.len() as u64 + 4);
         Ok(())
     }
 
     fn persist(&mut self) -> Result<()> {
         self.file.flush()?;
         self.file.get_ref().sync_data()?;
+        counter!("rayd.journal_storage.fsync_count", 1);
         if self.current_file_size >= self.base.file_size_soft_limit {
             let (new_file, new_file_path) = Self::open_new_file(&self.base.directory_path)?;
             self.base.push_file(
                 std::mem::replace(&mut self.file_path, new_file_path),
                 self.current_file_blob_count,
+                self.current_file_size,
             );
             self.file = new_file;
             self.current_file_size = 0;
             self.current_file_blob_count = 0;
         }
+        self.base.apply_compaction(false);
+        self.base.report_disk_usage(self.current_file_size);
         Ok(())
     }
 
+    // Blobs a running compaction drops are not counted, as they are gone once it is done.
     fn get_blob_count(&self) -> usize {
-        self.base.total_blob_count + self.current_file_blob_count
+        let dropped = self
+            .base
+            .compaction
+            .as_ref()
+            .map_or(0, |compaction| compaction.dropped);
+        self.base.total_blob_count - dropped + self.current_file_blob_count
     }
 
+    // The blobs are the oldest ones, so they are those of the previous files first. Blobs of
+    // the current file are never disposed of, as it is still being written.
     fn dispose_oldest_blobs(&mut self, blob_count: usize) -> Result<()> {
-        if blob_count > self.current_file_blob_count {
-            self.base
-                .dispose_oldest_blobs(blob_count - self.current_file_blob_count)
-        } else {
-            Ok(())
+        self.base.apply_compaction(true);
+        let previous_blob_count = cmp::min(blob_count, self.base.total_blob_count);
+        if previous_blob_count == 0 {
+            return Ok(());
         }
+        let left = self.base.dispose_oldest_blobs(previous_blob_count)?;
+        if self.base.compact {
+            self.base.spawn_compaction(left)?;
+        }
+        self.base.report_disk_usage(self.current_file_size);
+        Ok(())
+    }
+}
+
+// A compaction left running could race with the next reader of the directory.
+impl Drop for DirectoryJournalWriter {
+    fn drop(&mut self) {
+        self.base.apply_compaction(true);
+    }
+}
+
+#[derive(Default)]
+struct DumpedFile {
+    blob_count: usize,
+    // Epochs of the first and the last blob that could be decoded.
+    epochs: Option<(u64, u64)>,
+    // Offset of the incomplete blob parsing stopped at.
+    stopped_at: Option<usize>,
+}
+
+// Prints every blob of the journal files with its offset, epoch and the writes of its
+// mutation, then the epoch range of every file. Unlike the reader, it never modifies the
+// files: an incomplete blob is reported and ends the file.
+pub fn dump_journal<M: Machine, W: Write>(directory_path: &Path, out: &mut W) -> Result<()> {
+    let mut dumped = vec![];
+    for path in journal_file_paths(directory_path)? {
+        writeln!(out, "{}", path.display())?;
+        let file = dump_journal_file::<M, W>(&path, out)?;
+        dumped.push((path, file));
+    }
+
+    writeln!(out, "Summary:")?;
+    if dumped.is_empty() {
+        writeln!(out, "  no journal files in {:?}", directory_path)?;
+    }
+    for (path, file) in dumped {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let epochs = match file.epochs {
+            Some((first, last)) => format!("epochs {} to {}", first, last),
+            None => "no epochs".to_string(),
+        };
+        write!(out, "  {}: {} blobs, {}", name, file.blob_count, epochs)?;
+        match file.stopped_at {
+            Some(offset) => writeln!(out, ", stopped at offset {}", offset)?,
+            None => writeln!(out)?,
+        }
+    }
+    Ok(())
+}
+
+fn dump_journal_file<M: Machine, W: Write>(path: &Path, out: &mut W) -> Result<DumpedFile> {
+    let (mut reader, len) = DirectoryJournalReader::open_file_with_len(path)?;
+    let mut file = DumpedFile::default();
+    let mut offset = 0;
+    while let Some(blob) = read_blob_within(&mut reader, len - offset)
+        .chain_err(|| format!("failed to read from {:?}", path))?
+    {
+        let blob_len = blob.len();
+        file.blob_count += 1;
+        match decode_blob::<M>(blob) {
+            Ok((mutation, epoch)) => {
+                write!(out, "  offset {}: epoch {}", offset, epoch)?;
+                for (index, write) in M::audited_writes(&mutation).iter().enumerate() {
+                    let separator = if index == 0 { ", " } else { "; " };
+                    write!(out, "{}{}", separator, write.operation)?;
+                    if !write.namespace.is_empty() {
+                        write!(out, " namespace={:?}", ByteStr::new(&write.namespace))?;
+                    }
+                    write!(
+                        out,
+                        " key={:?} value_len={}",
+                        ByteStr::new(&write.key),
+                        write.value_len
+                    )?;
+                }
+                writeln!(out)?;
+                file.epochs = match file.epochs {
+                    Some((first, _)) => Some((first, epoch)),
+                    None => Some((epoch, epoch)),
+                };
+            }
+            Err(err) => {
+                let causes: Vec<String> = err.iter().map(|cause| cause.to_string()).collect();
+                writeln!(out, "  offset {}: {}", offset, causes.join(": "))?;
+            }
+        }
+        offset += 4 + blob_len;
+    }
+
+    if offset < len {
+        writeln!(
+            out,
+            "  offset {}: incomplete blob, parsing stopped ({} bytes left)",
+            offset,
+            len - offset
+        )?;
+        file.stopped_at = Some(offset);
+    }
+    Ok(file)
+}
+
+// Follows journal files as another process writes them, oldest first.
+pub struct DirectoryJournalTailer {
+    directory_path: PathBuf,
+    current_file: Option<(PathBuf, BufReader<File>)>,
+    // Offset of the first blob not read from the current file yet.
+    offset: u64,
+}
+
+impl DirectoryJournalTailer {
+    pub fn new(config: &JournalStorageConfig) -> Self {
+        Self {
+            directory_path: PathBuf::from(&config.path),
+            current_file: None,
+            offset: 0,
+        }
+    }
+
+    fn next_file_path(&self) -> Result<Option<PathBuf>> {
+        let dir_entries = read_dir(&self.directory_path)
+            .chain_err(|| format!("failed to read directory {:?}", self.directory_path))?;
+
+        let mut newer_paths = vec![];
+        for entry in dir_entries {
+            let file_path = entry.chain_err(|| "failed to resolve entry")?.path();
+            if !file_path.to_string_lossy().ends_with(".jnl") {
+                continue;
+            }
+            let is_newer = match self.current_file {
+                Some((ref current_path, _)) => file_path > *current_path,
+                None => true,
+            };
+            if is_newer {
+                newer_paths.push(file_path);
+            }
+        }
+
+        Ok(newer_paths.into_iter().min())
+    }
+
+    // A blob that is only partially written is left for the next attempt.
+    fn read_current_file(&mut self) -> Result<Option<Vec<u8>>> {
+        let (path, file) = match self.current_file {
+            Some((ref path, ref mut file)) => (path, file),
+            None => return Ok(None),
+        };
+
+        match read_complete_blob(file) {
+            Ok(Some(blob)) => {
+                self.offset += 4 + blob.len() as u64;
+                Ok(Some(blob))
+            }
+            Ok(None) => {
+                file.seek(SeekFrom::Start(self.offset))
+                    .chain_err(|| format!("failed to seek in {:?}", path))?;
+                Ok(None)
+            }
+            Err(err) => Err(err).chain_err(|| format!("failed to read from {:?}", path)),
+        }
+    }
+}
+
+fn read_complete_blob<T: Read>(reader: &mut T) -> io::Result<Option<Vec<u8>>> {
+    let len = match try_read_u32(reader) {
+        Ok(Some(len)) => len,
+        Ok(None) => return Ok(None),
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let mut blob = vec![0; len as usize];
+    match reader.read_exact(&mut blob) {
+        Ok(()) => Ok(Some(blob)),
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+impl JournalTailer for DirectoryJournalTailer {
+    fn next_blob(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(blob) = self.read_current_file()? {
+                return Ok(Some(blob));
+            }
+
+            // The writer only starts a new file once it is done with the current one.
+            let next_path = match self.next_file_path()? {
+                Some(path) => path,
+                None => return Ok(None),
+            };
+
+            // The current file may have been appended to since we last hit its end.
+            if let Some(blob) = self.read_current_file()? {
+                return Ok(Some(blob));
+            }
+
+            debug!("Following journal file: {:?}", next_path);
+            let file = DirectoryJournalReader::open_file(&next_path)?;
+            self.current_file = Some((next_path, file));
+            self.offset = 0;
+        }
+    }
+
+    // The writer may not have created the directory yet.
+    fn first_epoch(&self) -> Result<Option<u64>> {
+        if !self.directory_path.exists() {
+            return Ok(None);
+        }
+        first_blob_epoch(&journal_file_paths(&self.directory_path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    use std::fs;
+
+    // Recovers the journal of the directory: its blobs and the writer continuing it.
+    fn recover(
+        dir: &TempDir,
+        file_size_soft_limit: usize,
+    ) -> Result<(Vec<Vec<u8>>, DirectoryJournalWriter)> {
+        let config = JournalStorageConfig {
+            path: dir.path().to_string_lossy().into_owned(),
+            file_size_soft_limit,
+            ..Default::default()
+        };
+        let mut reader = DirectoryJournalReader::new(&config)?;
+        let mut blobs = vec![];
+        loop {
+            reader = match reader.read_blob()? {
+                ReadResult::Blob(blob, reader) => {
+                    blobs.push(blob);
+                    reader
+                }
+                ReadResult::End(writer) => return Ok((blobs, writer)),
+            }
+        }
+    }
+
+    fn open_writer(dir: &TempDir, file_size_soft_limit: usize) -> DirectoryJournalWriter {
+        recover(dir, file_size_soft_limit).unwrap().1
+    }
+
+    // Three files: two of two blobs each, and the last one of a single blob.
+    fn write_blobs(dir: &TempDir) {
+        let mut writer = open_writer(dir, 20);
+        for index in 0..5 {
+            writer.append_blob(&[index; 8]).unwrap();
+            writer.persist().unwrap();
+        }
+    }
+
+    fn truncate(path: &Path, cut: u64) {
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - cut).unwrap();
+    }
+
+    fn disk_usage(writer: &DirectoryJournalWriter) -> usize {
+        writer.base.total_size + writer.current_file_size
+    }
+
+    fn file_sizes(dir: &TempDir) -> usize {
+        journal_file_paths(dir.path())
+            .unwrap()
+            .iter()
+            .map(|path| path.metadata().unwrap().len() as usize)
+            .sum()
+    }
+
+    #[test]
+    fn size_grows_by_the_appended_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = open_writer(&dir, 30);
+        for len in &[10, 20, 30] {
+            writer.append_blob(&vec![0; *len]).unwrap();
+            writer.persist().unwrap();
+        }
+        // Each blob takes its length prefix on top of its bytes.
+        assert_eq!(disk_usage(&writer), 72);
+        assert_eq!(file_sizes(&dir), 72);
+        assert!(writer.base.previous_files.len() > 1);
+
+        drop(writer);
+        assert_eq!(disk_usage(&open_writer(&dir, 30)), 72);
+    }
+
+    #[test]
+    fn truncated_tail_is_cut_off() {
+        let dir = tempfile::tempdir().unwrap();
+        write_blobs(&dir);
+        let paths = journal_file_paths(dir.path()).unwrap();
+        assert_eq!(paths.len(), 3);
+        truncate(&paths[2], 3);
+
+        let (blobs, mut writer) = recover(&dir, 20).unwrap();
+        let expected: Vec<_> = (0..4).map(|index| vec![index; 8]).collect();
+        assert_eq!(blobs, expected);
+        writer.append_blob(&[9; 8]).unwrap();
+        writer.persist().unwrap();
+        drop(writer);
+
+        let (blobs, _) = recover(&dir, 20).unwrap();
+        assert_eq!(blobs.len(), 5);
+        assert_eq!(blobs[4], vec![9; 8]);
+    }
+
+    #[test]
+    fn truncated_middle_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        write_blobs(&dir);
+        let paths = journal_file_paths(dir.path()).unwrap();
+        truncate(&paths[1], 3);
+
+        let err = recover(&dir, 20)
+            .err()
+            .expect("recovered a truncated journal");
+        assert!(
+            err.to_string().contains("is truncated at offset"),
+            "{}",
+            err
+        );
+        assert_eq!(file_sizes(&dir), 5 * 12 - 3);
+    }
+
+    #[test]
+    fn restart_appends_to_a_small_last_file() {
+        let dir = tempfile::tempdir().unwrap();
+        write_blobs(&dir);
+        let mut writer = open_writer(&dir, 20);
+        assert_eq!(writer.get_blob_count(), 5);
+        writer.append_blob(&[9; 8]).unwrap();
+        writer.persist().unwrap();
+
+        // The blob went into the third file, which then filled up and was rolled over.
+        let paths = journal_file_paths(dir.path()).unwrap();
+        assert_eq!(paths.len(), 4);
+        assert_eq!(paths[2].metadata().unwrap().len(), 24);
+        assert_eq!(paths[3].metadata().unwrap().len(), 0);
+        // The last file is not counted twice.
+        assert_eq!(writer.get_blob_count(), 6);
+        assert_eq!(disk_usage(&writer), file_sizes(&dir));
+        drop(writer);
+        assert_eq!(recover(&dir, 20).unwrap().0.len(), 6);
+    }
+
+    #[test]
+    fn restart_starts_a_new_file_after_a_full_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = open_writer(&dir, 100);
+        writer.append_blob(&[0; 8]).unwrap();
+        writer.append_blob(&[1; 8]).unwrap();
+        writer.persist().unwrap();
+        drop(writer);
+
+        // The last file is full under the new limit.
+        let mut writer = open_writer(&dir, 20);
+        writer.append_blob(&[2; 8]).unwrap();
+        writer.persist().unwrap();
+        let paths = journal_file_paths(dir.path()).unwrap();
+        assert_eq!(paths.len(), 2);
+        assert_eq!(paths[0].metadata().unwrap().len(), 24);
+        assert_eq!(writer.get_blob_count(), 3);
+        assert_eq!(disk_usage(&writer), file_sizes(&dir));
+    }
+
+    #[test]
+    fn disposal_takes_the_previous_files_first() {
+        let dir = tempfile::tempdir().unwrap();
+        write_blobs(&dir);
+        let mut writer = open_writer(&dir, 20);
+        writer.append_blob(&[5; 8]).unwrap();
+
+        // The four oldest blobs fill the first two files, which go even though the current
+        // file holds two blobs.
+        writer.dispose_oldest_blobs(4).unwrap();
+        assert_eq!(journal_file_paths(dir.path()).unwrap().len(), 1);
+        assert_eq!(writer.get_blob_count(), 2);
+        writer.persist().unwrap();
+        drop(writer);
+        let (blobs, _) = recover(&dir, 20).unwrap();
+        assert_eq!(blobs, vec![vec![4; 8], vec![5; 8]]);
+    }
+
+    #[test]
+    fn compaction_merges_the_oldest_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = open_writer(&dir, 20);
+        writer.base.compact = true;
+        for index in 0..7 {
+            writer.append_blob(&[index; 8]).unwrap();
+            writer.persist().unwrap();
+        }
+        assert_eq!(journal_file_paths(dir.path()).unwrap().len(), 4);
+
+        // The first file goes, the second one loses a blob and takes the third one in.
+        writer.dispose_oldest_blobs(3).unwrap();
+        assert_eq!(writer.get_blob_count(), 4);
+        writer.base.apply_compaction(true);
+        let paths = journal_file_paths(dir.path()).unwrap();
+        assert_eq!(paths.len(), 2);
+        assert_eq!(paths[0].metadata().unwrap().len(), 36);
+        assert_eq!(writer.get_blob_count(), 4);
+        assert_eq!(disk_usage(&writer), file_sizes(&dir));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+        drop(writer);
+
+        let (blobs, _) = recover(&dir, 20).unwrap();
+        let expected: Vec<_> = (3..7).map(|index| vec![index; 8]).collect();
+        assert_eq!(blobs, expected);
+    }
+
+    #[test]
+    fn interrupted_compactions_are_rolled_back_or_finished() {
+        let dir = tempfile::tempdir().unwrap();
+        write_blobs(&dir);
+        let paths = journal_file_paths(dir.path()).unwrap();
+        let marker = format!("{}\n", paths[1].display());
+        let expected: Vec<_> = (0..5).map(|index| vec![index; 8]).collect();
+
+        // Before the rename, the oldest file is still the original one.
+        fs::write(dir.path().join(COMPACTED_FILE), [0; 12]).unwrap();
+        fs::write(dir.path().join(COMPACTION_MARKER), &marker).unwrap();
+        let (blobs, writer) = recover(&dir, 20).unwrap();
+        assert_eq!(blobs, expected);
+        drop(writer);
+        assert_eq!(journal_file_paths(dir.path()).unwrap(), paths);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+
+        // After it, the oldest file holds the blobs of the merged ones as well.
+        let merged = [fs::read(&paths[0]).unwrap(), fs::read(&paths[1]).unwrap()].concat();
+        fs::write(&paths[0], merged).unwrap();
+        fs::write(dir.path().join(COMPACTION_MARKER), &marker).unwrap();
+        let (blobs, writer) = recover(&dir, 20).unwrap();
+        assert_eq!(blobs, expected);
+        drop(writer);
+        assert_eq!(journal_file_paths(dir.path()).unwrap().len(), 2);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
     }
 }
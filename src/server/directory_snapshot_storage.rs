@@ -1,11 +1,16 @@
-use super::snapshot_service::{PersistentWrite, SnapshotStorage};
+use super::{
+    config::SnapshotStorageConfig,
+    snapshot_service::{
+        parse_snapshot_epoch, PersistentWrite, SnapshotChain, SnapshotKind, SnapshotStorage,
+    },
+};
 
 use crate::errors::*;
 
 use chrono::Utc;
 
 use std::{
-    fs::{create_dir_all, read_dir, File, OpenOptions},
+    fs::{create_dir_all, read_dir, remove_file, File, OpenOptions},
     io::{self, BufReader, BufWriter, Write},
     path::{Path, PathBuf},
 };
@@ -32,15 +37,76 @@ impl PersistentWrite for SnapshotWriter {
     }
 }
 
+// Epoch of the snapshot and the path to it.
+type SnapshotFile = (u64, PathBuf);
+
 pub struct DirectorySnapshotStorage {
     path: PathBuf,
+    keep_snapshots: usize,
 }
 
 impl DirectorySnapshotStorage {
-    pub fn new(path: &str) -> io::Result<Self> {
-        let path = PathBuf::from(path);
+    pub fn new(config: &SnapshotStorageConfig) -> io::Result<Self> {
+        let path = PathBuf::from(&config.path);
         create_dir_all(path.as_path())?;
-        Ok(Self { path })
+        Ok(Self {
+            path,
+            keep_snapshots: config.keep_snapshots,
+        })
+    }
+
+    // Returns full snapshots and deltas, both sorted from oldest to newest.
+    fn list_snapshots(&self) -> Result<(Vec<SnapshotFile>, Vec<SnapshotFile>)> {
+        let mut full = vec![];
+        let mut deltas = vec![];
+        let dir_entries = read_dir(&self.path)
+            .chain_err(|| format!("failed to read directory {:?}", self.path))?;
+        for entry in dir_entries {
+            let path = entry
+                .chain_err(|| "failed to resolve directory entry")?
+                .path();
+            if !path.is_file() {
+                continue;
+            }
+            let name = match path.file_name() {
+                Some(name) => name.to_string_lossy(),
+                None => continue,
+            };
+            for (kind, files) in [
+                (SnapshotKind::Full, &mut full),
+                (SnapshotKind::Delta, &mut deltas),
+            ] {
+                if !name.ends_with(extension(kind)) {
+                    continue;
+                }
+                match parse_snapshot_epoch(&name, extension(kind)) {
+                    Some(epoch) => files.push((epoch, path.to_owned())),
+                    None => warn!(
+                        "Ignoring snapshot file with no epoch in its name: {:?}",
+                        path
+                    ),
+                }
+            }
+        }
+        // Names only break ties, e.g. when a snapshot was retaken after a corrupted one.
+        full.sort();
+        deltas.sort();
+        Ok((full, deltas))
+    }
+
+    fn open_file(path: &Path) -> Result<BufReader<File>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .open(path)
+            .chain_err(|| format!("failed to open file for read: {:?}", path))?;
+        Ok(BufReader::new(file))
+    }
+}
+
+fn extension(kind: SnapshotKind) -> &'static str {
+    match kind {
+        SnapshotKind::Full => ".snap",
+        SnapshotKind::Delta => ".delta",
     }
 }
 
@@ -48,8 +114,8 @@ impl SnapshotStorage for DirectorySnapshotStorage {
     type Writer = SnapshotWriter;
     type Reader = BufReader<File>;
 
-    fn create_snapshot(&mut self, name: &str) -> Result<Self::Writer> {
-        let file_name = format!("{}_{}.snap", Utc::now().format("%+"), name);
+    fn create_snapshot(&mut self, name: &str, kind: SnapshotKind) -> Result<Self::Writer> {
+        let file_name = format!("{}_{}{}", Utc::now().format("%+"), name, extension(kind));
         let path = Path::new(&self.path).join(file_name);
         debug!("Creating snapshot file: {:?}", path);
 
@@ -64,31 +130,152 @@ impl SnapshotStorage for DirectorySnapshotStorage {
         Ok(writer)
     }
 
-    fn open_last_snapshot(&self) -> Result<Option<Self::Reader>> {
-        let mut latest = None;
-        let dir_entries = read_dir(&self.path)
-            .chain_err(|| format!("failed to read directory {:?}", self.path))?;
-        for entry in dir_entries {
-            let path = entry
-                .chain_err(|| "failed to resolve directory entry")?
-                .path();
-            if path.is_file()
-                && path.to_string_lossy().ends_with(".snap")
-                && latest.as_ref().map(|prev| *prev < path).unwrap_or(true)
-            {
-                latest = Some(path.to_owned());
-            }
+    fn open_snapshot(&self, age: usize) -> Result<Option<SnapshotChain<Self::Reader>>> {
+        let (full, deltas) = self.list_snapshots()?;
+        if age >= full.len() {
+            return Ok(None);
         }
-        if let Some(ref path) = latest {
-            debug!("Latest snapshot found: {:?}", path);
-            let file = OpenOptions::new()
-                .read(true)
-                .open(path)
-                .chain_err(|| format!("failed to open file for read: {:?}", path))?;
-            let reader = BufReader::new(file);
-            Ok(Some(reader))
-        } else {
-            Ok(None)
+
+        let index = full.len() - 1 - age;
+        let (epoch, path) = &full[index];
+        let next = full.get(index + 1).map(|(epoch, _)| *epoch);
+        debug!("Snapshot found (age: {}): {:?}", age, path);
+        let base = Self::open_file(path)?;
+
+        let deltas = deltas
+            .iter()
+            .filter(|(delta_epoch, _)| {
+                delta_epoch > epoch && next.iter().all(|next| delta_epoch < next)
+            })
+            .map(|(_, delta)| {
+                debug!("Snapshot delta found: {:?}", delta);
+                Self::open_file(delta)
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Some(SnapshotChain { base, deltas }))
+    }
+
+    fn dispose_old_snapshots(&mut self) -> Result<()> {
+        if self.keep_snapshots == 0 {
+            return Ok(());
         }
+
+        let (full, deltas) = self.list_snapshots()?;
+        if full.len() <= self.keep_snapshots {
+            return Ok(());
+        }
+
+        // Deltas are only useful on top of a full snapshot that is kept.
+        let (oldest_kept, _) = full[full.len() - self.keep_snapshots];
+        let disposed = full[..full.len() - self.keep_snapshots]
+            .iter()
+            .chain(deltas.iter().filter(|(epoch, _)| *epoch < oldest_kept));
+
+        for (_, path) in disposed {
+            remove_file(path).chain_err(|| format!("failed to remove {:?}", path))?;
+            debug!("Removed snapshot file: {:?}", path);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{fs, io::Read};
+
+    fn create(storage: &mut DirectorySnapshotStorage, epoch: u64, kind: SnapshotKind) {
+        let mut writer = storage
+            .create_snapshot(&format!("{:020}", epoch), kind)
+            .unwrap();
+        writer.write_all(b"snapshot").unwrap();
+        writer.persist().unwrap();
+    }
+
+    fn epochs(files: Vec<SnapshotFile>) -> Vec<u64> {
+        files.into_iter().map(|(epoch, _)| epoch).collect()
+    }
+
+    #[test]
+    fn disposal_keeps_the_newest_snapshots_and_their_deltas() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = SnapshotStorageConfig {
+            path: dir.path().to_string_lossy().into_owned(),
+            keep_snapshots: 2,
+            ..Default::default()
+        };
+        let mut storage = DirectorySnapshotStorage::new(&config).unwrap();
+        for epoch in &[10, 20, 30, 40, 50] {
+            create(&mut storage, *epoch, SnapshotKind::Full);
+        }
+        create(&mut storage, 35, SnapshotKind::Delta);
+        create(&mut storage, 45, SnapshotKind::Delta);
+
+        storage.dispose_old_snapshots().unwrap();
+        let (full, deltas) = storage.list_snapshots().unwrap();
+        assert_eq!(epochs(full), vec![40, 50]);
+        assert_eq!(epochs(deltas), vec![45]);
+    }
+
+    #[test]
+    fn disposal_keeps_everything_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = SnapshotStorageConfig {
+            path: dir.path().to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        let mut storage = DirectorySnapshotStorage::new(&config).unwrap();
+        for epoch in 1..=5 {
+            create(&mut storage, epoch, SnapshotKind::Full);
+        }
+
+        storage.dispose_old_snapshots().unwrap();
+        let (full, _) = storage.list_snapshots().unwrap();
+        assert_eq!(epochs(full), vec![1, 2, 3, 4, 5]);
+    }
+
+    fn contents(mut reader: BufReader<File>) -> String {
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn snapshots_are_ordered_by_epoch_rather_than_timestamp() {
+        let dir = tempfile::tempdir().unwrap();
+        // The clock went backwards twice. The unpadded name is from before epochs were padded.
+        for (name, epoch) in &[
+            ("2030-01-01T00:00:00+00:00_00000000000000000005.snap", 5),
+            ("2010-01-01T00:00:00+00:00_00000000000000000010.snap", 10),
+            ("2020-01-01T00:00:00+00:00_7.snap", 7),
+            ("2030-01-01T00:00:00+00:00_00000000000000000006.delta", 6),
+            ("2000-01-01T00:00:00+00:00_00000000000000000012.delta", 12),
+        ] {
+            fs::write(dir.path().join(name), epoch.to_string()).unwrap();
+        }
+        let config = SnapshotStorageConfig {
+            path: dir.path().to_string_lossy().into_owned(),
+            keep_snapshots: 1,
+            ..Default::default()
+        };
+        let mut storage = DirectorySnapshotStorage::new(&config).unwrap();
+
+        for &(age, base, ref deltas) in
+            &[(0, "10", vec!["12"]), (1, "7", vec![]), (2, "5", vec!["6"])]
+        {
+            let chain = storage.open_snapshot(age).unwrap().unwrap();
+            assert_eq!(contents(chain.base), base, "age: {}", age);
+            let chain_deltas: Vec<_> = chain.deltas.into_iter().map(contents).collect();
+            assert_eq!(chain_deltas, *deltas, "age: {}", age);
+        }
+        assert!(storage.open_snapshot(3).unwrap().is_none());
+
+        storage.dispose_old_snapshots().unwrap();
+        let (full, deltas) = storage.list_snapshots().unwrap();
+        assert_eq!(epochs(full), vec![10]);
+        assert_eq!(epochs(deltas), vec![12]);
     }
 }
@@ -0,0 +1,280 @@
+use super::config::HealthConfig;
+
+use crate::{
+    errors::*,
+    proto::health::{
+        health_check_response::ServingStatus, health_server::Health, HealthCheckRequest,
+        HealthCheckResponse,
+    },
+    util::threads_alive,
+};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+
+use tokio::sync::{mpsc, watch};
+
+use tonic::{Request as GrpcRequest, Response as GrpcResponse, Status};
+
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+// Shared serving status: set once journal recovery is over, cleared on shutdown.
+#[derive(Clone)]
+pub struct HealthReporter {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+    // Set along with the serving status and never cleared, so that shutdown and recovery
+    // can be told apart.
+    recovered: Arc<AtomicBool>,
+}
+
+impl HealthReporter {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Self {
+            sender: Arc::new(sender),
+            receiver,
+            recovered: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn set_serving(&self, serving: bool) {
+        if serving {
+            self.recovered.store(true, Ordering::SeqCst);
+        }
+        // Can't fail, as the reporter holds a receiver itself.
+        self.sender.broadcast(serving).ok();
+    }
+
+    pub fn is_serving(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    pub fn is_recovered(&self) -> bool {
+        self.recovered.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for HealthReporter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Serves plain HTTP probes:
+//   /healthz - 200 while every dedicated service thread is alive;
+//   /readyz  - 200 once journal recovery is over and until shutdown begins.
+pub struct HealthService {
+    address: SocketAddr,
+    reporter: HealthReporter,
+}
+
+impl HealthService {
+    pub fn new(config: &HealthConfig, reporter: HealthReporter) -> Result<Self> {
+        Ok(Self {
+            address: Self::listen_address(config)?,
+            reporter,
+        })
+    }
+
+    pub fn listen_address(config: &HealthConfig) -> Result<SocketAddr> {
+        let ip_address = config
+            .address
+            .parse()
+            .chain_err(|| format!("not a valid IP address: {}", config.address))?;
+        Ok(SocketAddr::new(ip_address, config.port))
+    }
+
+    pub async fn serve(self) -> Result<()> {
+        let reporter = self.reporter;
+        let make_service = make_service_fn(move |_| {
+            let reporter = reporter.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let response = respond(&request, &reporter);
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+
+        Server::bind(&self.address)
+            .serve(make_service)
+            .await
+            .chain_err(|| "health server failed")
+    }
+}
+
+fn respond(request: &Request<Body>, reporter: &HealthReporter) -> Response<Body> {
+    let healthy = match request.uri().path() {
+        "/healthz" => threads_alive(),
+        "/readyz" => threads_alive() && reporter.is_serving(),
+        _ => return reply(StatusCode::NOT_FOUND, "not found\n"),
+    };
+
+    if healthy {
+        reply(StatusCode::OK, "ok\n")
+    } else {
+        reply(StatusCode::SERVICE_UNAVAILABLE, "unavailable\n")
+    }
+}
+
+fn reply(status: StatusCode, text: &'static str) -> Response<Body> {
+    let mut response = Response::new(Body::from(text));
+    *response.status_mut() = status;
+    response
+}
+
+// Implements grpc.health.v1.Health on top of the same serving status.
+pub struct GrpcHealthService {
+    reporter: HealthReporter,
+    // The one service known besides the empty name, which stands for the whole server.
+    service_name: &'static str,
+}
+
+impl GrpcHealthService {
+    pub fn new(reporter: HealthReporter, service_name: &'static str) -> Self {
+        Self {
+            reporter,
+            service_name,
+        }
+    }
+
+    fn is_known(&self, service: &str) -> bool {
+        service.is_empty() || service == self.service_name
+    }
+}
+
+fn serving_status(serving: bool) -> ServingStatus {
+    if serving {
+        ServingStatus::Serving
+    } else {
+        ServingStatus::NotServing
+    }
+}
+
+fn health_response(status: ServingStatus) -> HealthCheckResponse {
+    HealthCheckResponse {
+        status: status as i32,
+    }
+}
+
+#[tonic::async_trait]
+impl Health for GrpcHealthService {
+    type WatchStream = mpsc::Receiver<std::result::Result<HealthCheckResponse, Status>>;
+
+    async fn check(
+        &self,
+        request: GrpcRequest<HealthCheckRequest>,
+    ) -> std::result::Result<GrpcResponse<HealthCheckResponse>, Status> {
+        let service = &request.get_ref().service;
+        if !self.is_known(service) {
+            return Err(Status::not_found(format!("unknown service: {}", service)));
+        }
+
+        let status = serving_status(self.reporter.is_serving());
+        Ok(GrpcResponse::new(health_response(status)))
+    }
+
+    async fn watch(
+        &self,
+        request: GrpcRequest<HealthCheckRequest>,
+    ) -> std::result::Result<GrpcResponse<Self::WatchStream>, Status> {
+        let (mut sender, receiver) = mpsc::channel(1);
+
+        if !self.is_known(&request.get_ref().service) {
+            let response = health_response(ServingStatus::ServiceUnknown);
+            sender.send(Ok(response)).await.ok();
+            return Ok(GrpcResponse::new(receiver));
+        }
+
+        // The first recv returns the current status, later ones wait for a change.
+        let mut updates = self.reporter.receiver.clone();
+        tokio::spawn(async move {
+            let mut last = None;
+            while let Some(serving) = updates.recv().await {
+                if last == Some(serving) {
+                    continue;
+                }
+                last = Some(serving);
+                let response = health_response(serving_status(serving));
+                if sender.send(Ok(response)).await.is_err() {
+                    break; // Client went away
+                }
+            }
+        });
+
+        Ok(GrpcResponse::new(receiver))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::proto::health::{health_client::HealthClient, health_server::HealthServer};
+
+    use tokio::time::delay_for;
+
+    use std::{net::TcpListener, time::Duration};
+
+    fn probe(path: &str, reporter: &HealthReporter) -> StatusCode {
+        let request = Request::get(path).body(Body::empty()).unwrap();
+        respond(&request, reporter).status()
+    }
+
+    #[test]
+    fn readiness_follows_the_serving_status() {
+        let reporter = HealthReporter::new();
+        assert_eq!(probe("/healthz", &reporter), StatusCode::OK);
+        assert_eq!(probe("/readyz", &reporter), StatusCode::SERVICE_UNAVAILABLE);
+
+        reporter.set_serving(true);
+        assert_eq!(probe("/healthz", &reporter), StatusCode::OK);
+        assert_eq!(probe("/readyz", &reporter), StatusCode::OK);
+
+        reporter.set_serving(false);
+        assert_eq!(probe("/readyz", &reporter), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(probe("/other", &reporter), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn watch_observes_the_server_becoming_ready() {
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let reporter = HealthReporter::new();
+        let service = GrpcHealthService::new(reporter.clone(), "ray.Storage");
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(HealthServer::new(service))
+                .serve(address),
+        );
+
+        let mut client = loop {
+            match HealthClient::connect(format!("http://{}", address)).await {
+                Ok(client) => break client,
+                Err(_) => delay_for(Duration::from_millis(10)).await,
+            }
+        };
+        let request = HealthCheckRequest {
+            service: "ray.Storage".into(),
+        };
+        let mut updates = client.watch(request).await.unwrap().into_inner();
+        let status = updates.message().await.unwrap().unwrap().status;
+        assert_eq!(status, ServingStatus::NotServing as i32);
+
+        reporter.set_serving(true);
+        let status = updates.message().await.unwrap().unwrap().status;
+        assert_eq!(status, ServingStatus::Serving as i32);
+    }
+}
@@ -1,25 +1,21 @@
 use super::{
-    logging_service::FastlogMessage,
-    machine_service::{Machine, MachineServiceRequest},
-    snapshot_service::MutationProposal,
+    logging_service::{audit, audit_enabled, AuditRecord, FastlogMessage},
+    machine_service::{JournalCodec, Machine, MachineServiceRequest},
 };
 
 use crate::{
     errors::*,
     fastlog,
-    util::{
-        ProfiledReceiver, ProfiledSender, ProfiledUnboundedReceiver, ProfiledUnboundedSender,
-        Traced,
-    },
+    util::{profiled_channel, ProfiledReceiver, ProfiledSender, ProfiledUnboundedReceiver, Traced},
 };
 
 use prost::Message;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
-use tokio::sync::oneshot;
+use tokio::{sync::oneshot, time};
 
-use futures::{select, FutureExt};
+use futures::{future, select, FutureExt};
 
 use metrics::{gauge, timing, value};
 
@@ -29,7 +25,7 @@ use std::{
         atomic::{AtomicU64, Ordering},
         Arc,
     },
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 pub enum ReadResult<R, W> {
@@ -41,6 +37,8 @@ pub trait JournalReader: Sized + Send + 'static {
     type Writer: JournalWriter;
 
     fn read_blob(self) -> Result<ReadResult<Self, Self::Writer>>;
+    // Epoch of the oldest retained blob, None if the journal is empty.
+    fn first_epoch(&self) -> Result<Option<u64>>;
 }
 
 pub trait JournalWriter: Send + 'static {
@@ -50,48 +48,160 @@ pub trait JournalWriter: Send + 'static {
     fn dispose_oldest_blobs(&mut self, blob_count: usize) -> Result<()>;
 }
 
-pub struct JournalServiceRequest<U: Message> {
-    pub mutation: Traced<U>,
-    pub notify: oneshot::Sender<()>,
+// Reads a journal that is being written by another process.
+pub trait JournalTailer: Send + 'static {
+    // Returns None if there is no complete blob yet.
+    fn next_blob(&mut self) -> Result<Option<Vec<u8>>>;
+    // Epoch of the oldest retained blob, None if the journal is empty.
+    fn first_epoch(&self) -> Result<Option<u64>>;
+}
+
+pub enum JournalServiceRequest<M: Machine> {
+    Mutation {
+        mutation: Traced<M::Mutation>,
+        // Passed along with the proposal to the machine service, which sends the outcome and
+        // the epoch of the mutation.
+        result: oneshot::Sender<(M::Outcome, u64)>,
+    },
+    // Barrier that is sent the persisted epoch once every mutation queued before it is
+    // persisted.
+    Sync {
+        result: oneshot::Sender<u64>,
+    },
+}
+
+impl<M: Machine> JournalServiceRequest<M> {
+    fn encoded_len(&self) -> usize {
+        match self {
+            JournalServiceRequest::Mutation { mutation, .. } => mutation.payload.encoded_len(),
+            JournalServiceRequest::Sync { .. } => 0,
+        }
+    }
 }
 
 // Only need Debug to make tokio::sync::mpsc::errors::SendError<_> implement Error.
-impl<U: Message> Debug for JournalServiceRequest<U> {
+impl<M: Machine> Debug for JournalServiceRequest<M> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "JournalServiceRequest")
     }
 }
 
-struct BatchResult<U> {
-    mutations: Vec<Traced<U>>,
-    notifiers: Vec<oneshot::Sender<()>>,
+struct BatchResult<M: Machine> {
+    mutations: Vec<Traced<M::Mutation>>,
+    results: Vec<oneshot::Sender<(M::Outcome, u64)>>,
+    syncs: Vec<oneshot::Sender<u64>>,
     min_epoch: Option<u64>,
 }
 
-struct JournalServiceBase<M: Machine> {
+// Proposals persisted together, along with the senders of their outcomes.
+struct PersistedBatch<M: Machine> {
+    proposals: Vec<(Traced<M::Mutation>, u64)>,
+    results: Vec<oneshot::Sender<(M::Outcome, u64)>>,
+}
+
+// Only need Debug to make tokio::sync::mpsc::errors::SendError<_> implement Error.
+impl<M: Machine> Debug for PersistedBatch<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PersistedBatch")
+    }
+}
+
+// Hands mutations to the machine service and their epochs to the snapshot service.
+struct ProposalSender<M: Machine> {
     machine_sender: ProfiledSender<MachineServiceRequest<M>>,
-    snapshot_sender: ProfiledUnboundedSender<MutationProposal<M::Mutation>>,
-    request_receiver: ProfiledReceiver<JournalServiceRequest<M::Mutation>>,
-    min_epoch_receiver: ProfiledUnboundedReceiver<u64>,
-    batch_size: usize,
-    external_epoch: Arc<AtomicU64>,
+    // Epochs of the mutations sent to the machine service. Bounded, so a lagging snapshot
+    // service holds up proposals rather than letting the epochs pile up.
+    snapshot_sender: ProfiledSender<u64>,
 }
 
-impl<M: Machine> JournalServiceBase<M> {
-    async fn send_proposal(&mut self, mutation: Traced<M::Mutation>, epoch: u64) -> Result<()> {
+// Can't derive Clone since it puts Clone trait bound on M.
+impl<M: Machine> Clone for ProposalSender<M> {
+    fn clone(&self) -> Self {
+        Self {
+            machine_sender: self.machine_sender.clone(),
+            snapshot_sender: self.snapshot_sender.clone(),
+        }
+    }
+}
+
+impl<M: Machine> ProposalSender<M> {
+    async fn send_proposal(
+        &mut self,
+        mutation: Traced<M::Mutation>,
+        epoch: u64,
+        result: Option<oneshot::Sender<(M::Outcome, u64)>>,
+    ) -> Result<()> {
         self.snapshot_sender
-            .send(MutationProposal {
-                mutation: mutation.clone(),
+            .send(epoch)
+            .await
+            .chain_err(|| "snapshot_sender failed")?;
+        self.machine_sender
+            .send(MachineServiceRequest::Proposal {
+                mutation,
                 epoch,
+                result,
             })
+            .await
+            .chain_err(|| "machine_sender failed")
+    }
+
+    async fn send_recovered(
+        &mut self,
+        mutations: Vec<M::Mutation>,
+        epoch: u64,
+        threads: usize,
+    ) -> Result<()> {
+        let last_epoch = epoch + mutations.len() as u64 - 1;
+        self.snapshot_sender
+            .send(last_epoch)
+            .await
             .chain_err(|| "snapshot_sender failed")?;
         self.machine_sender
-            .send(MachineServiceRequest::Proposal { mutation, epoch })
+            .send(MachineServiceRequest::Recovered {
+                mutations,
+                epoch,
+                threads,
+            })
             .await
             .chain_err(|| "machine_sender failed")
     }
 
-    async fn serve_batch(&mut self) -> Result<BatchResult<M::Mutation>> {
+    // Runs alongside the journal service, so that a machine service slow to take proposals
+    // holds up only this loop rather than the next persist. Batches come in epoch order and
+    // are sent on one by one, so the machine still sees the epochs in order.
+    async fn dispatch(mut self, mut receiver: ProfiledReceiver<PersistedBatch<M>>) -> Result<()> {
+        loop {
+            gauge!(
+                "rayd.journal_service.queue_size",
+                receiver.approx_len(),
+                "queue" => "dispatch"
+            );
+            let PersistedBatch { proposals, results } = receiver
+                .recv()
+                .await
+                .chain_err(|| "dispatch_receiver failed")?;
+            for ((mutation, epoch), result) in proposals.into_iter().zip(results) {
+                self.send_proposal(mutation, epoch, Some(result)).await?;
+            }
+        }
+    }
+}
+
+struct JournalServiceBase<M: Machine> {
+    proposal_sender: ProposalSender<M>,
+    request_receiver: ProfiledReceiver<JournalServiceRequest<M>>,
+    min_epoch_receiver: ProfiledUnboundedReceiver<u64>,
+    batch_size: usize,
+    batch_max_bytes: usize,
+    // Request that did not fit into the previous batch.
+    pending_request: Option<JournalServiceRequest<M>>,
+    external_epoch: Arc<AtomicU64>,
+    // Bytes of the blobs journaled after the snapshot epoch, recovered ones included.
+    journal_bytes: Arc<AtomicU64>,
+}
+
+impl<M: Machine> JournalServiceBase<M> {
+    async fn serve_batch(&mut self) -> Result<BatchResult<M>> {
         gauge!(
             "rayd.journal_service.queue_size",
             self.request_receiver.approx_len(),
@@ -103,12 +213,17 @@ impl<M: Machine> JournalServiceBase<M> {
             "queue" => "min_epoch"
         );
 
+        if let Some(request) = self.pending_request.take() {
+            return self.process_request_batch(request);
+        }
+
         select! {
             maybe_min_epoch = self.min_epoch_receiver.recv().fuse() => {
                 let min_epoch = maybe_min_epoch.chain_err(|| "min_epoch_receiver failed")?;
                 return Ok(BatchResult {
                     mutations: vec![],
-                    notifiers: vec![],
+                    results: vec![],
+                    syncs: vec![],
                     min_epoch: Some(min_epoch),
                 })
             },
@@ -119,18 +234,23 @@ impl<M: Machine> JournalServiceBase<M> {
         }
     }
 
-    fn process_request_batch(
-        &mut self,
-        first: JournalServiceRequest<M::Mutation>,
-    ) -> Result<BatchResult<M::Mutation>> {
+    fn process_request_batch(&mut self, first: JournalServiceRequest<M>) -> Result<BatchResult<M>> {
         let mut mutations = vec![];
-        let mut notifiers = vec![];
+        let mut results = vec![];
+        let mut syncs = vec![];
         let mut request = first;
         let mut processed_requests = 0;
+        let mut batch_bytes = 0;
 
         loop {
-            mutations.push(request.mutation);
-            notifiers.push(request.notify);
+            batch_bytes += request.encoded_len();
+            match request {
+                JournalServiceRequest::Mutation { mutation, result } => {
+                    mutations.push(mutation);
+                    results.push(result);
+                }
+                JournalServiceRequest::Sync { result } => syncs.push(result),
+            }
             processed_requests += 1;
 
             if processed_requests < self.batch_size {
@@ -141,24 +261,88 @@ impl<M: Machine> JournalServiceBase<M> {
             } else {
                 break;
             }
+
+            let request_bytes = request.encoded_len();
+            if self.batch_max_bytes > 0 && batch_bytes + request_bytes > self.batch_max_bytes {
+                self.pending_request = Some(request);
+                break;
+            }
         }
 
         Ok(BatchResult {
             mutations,
-            notifiers,
+            results,
+            syncs,
             min_epoch: None,
         })
     }
 
+    fn add_journal_bytes(&self, bytes: usize) {
+        self.journal_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
     fn update_persisted_epoch(&self, persisted_epoch: u64) {
         self.external_epoch
             .store(persisted_epoch, Ordering::Release);
     }
 }
 
+// Number of recovered mutations handed to the machines at once when recovering in parallel.
+const RECOVERY_BATCH_SIZE: usize = 100_000;
+
+// Progress of a long recovery is reported after this many mutations at the latest.
+const PROGRESS_MUTATIONS: usize = 1_000_000;
+
+struct RecoveryProgress {
+    started: Instant,
+    interval: Duration,
+    last_report: Instant,
+    last_report_count: usize,
+}
+
+impl RecoveryProgress {
+    fn new(interval: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            interval,
+            last_report: now,
+            last_report_count: 0,
+        }
+    }
+
+    fn update(&mut self, mutation_count: usize, epoch: u64) {
+        if mutation_count - self.last_report_count < PROGRESS_MUTATIONS
+            && self.last_report.elapsed() < self.interval
+        {
+            return;
+        }
+
+        gauge!(
+            "rayd.journal_service.recovered_mutations",
+            mutation_count as i64
+        );
+        info!(
+            "Journal recovery in progress: {} mutations so far (epoch: {}, elapsed: {:.1?})",
+            mutation_count,
+            epoch,
+            self.started.elapsed()
+        );
+        self.last_report = Instant::now();
+        self.last_report_count = mutation_count;
+    }
+}
+
 pub struct JournalServiceRestorer<R: JournalReader, M: Machine> {
     reader: R,
+    // Epoch the machine service is at: that of the snapshot recovered from, or on a promoted
+    // standby, the one its machine followed the journal to.
     snapshot_epoch: u64,
+    // With more than one thread, mutations are applied in batches by Machine::apply_recovered.
+    recovery_threads: usize,
+    progress_interval: Duration,
+    dispatch_queue_size: usize,
     base: JournalServiceBase<M>,
 }
 
@@ -167,33 +351,51 @@ impl<R: JournalReader, M: Machine> JournalServiceRestorer<R, M> {
     pub fn new(
         reader: R,
         machine_sender: ProfiledSender<MachineServiceRequest<M>>,
-        snapshot_sender: ProfiledUnboundedSender<MutationProposal<M::Mutation>>,
-        request_receiver: ProfiledReceiver<JournalServiceRequest<M::Mutation>>,
+        snapshot_sender: ProfiledSender<u64>,
+        request_receiver: ProfiledReceiver<JournalServiceRequest<M>>,
         min_epoch_receiver: ProfiledUnboundedReceiver<u64>,
         batch_size: usize,
+        batch_max_bytes: usize,
+        recovery_threads: usize,
+        progress_interval: Duration,
+        dispatch_queue_size: usize,
         snapshot_epoch: u64,
         external_epoch: Arc<AtomicU64>,
+        journal_bytes: Arc<AtomicU64>,
     ) -> Self {
         let base = JournalServiceBase {
-            machine_sender,
-            snapshot_sender,
+            proposal_sender: ProposalSender {
+                machine_sender,
+                snapshot_sender,
+            },
             request_receiver,
             min_epoch_receiver,
             batch_size,
+            batch_max_bytes,
+            pending_request: None,
             external_epoch,
+            journal_bytes,
         };
         Self {
             reader,
             snapshot_epoch,
+            recovery_threads,
+            progress_interval,
+            dispatch_queue_size,
             base,
         }
     }
 
     pub async fn restore(mut self) -> Result<JournalService<R::Writer, M>> {
         info!("Starting journal recovery");
+        let mut progress = RecoveryProgress::new(self.progress_interval);
 
         let mut mutation_count = 0usize;
+        let mut first_epoch = None;
         let mut last_epoch = None;
+        // First epoch past the snapshot, the first one applied.
+        let mut first_applied = None;
+        let mut recovered = vec![];
 
         let mut maybe_reader = Some(self.reader);
         let mut maybe_writer = None;
@@ -201,20 +403,40 @@ impl<R: JournalReader, M: Machine> JournalServiceRestorer<R, M> {
         while let Some(reader) = maybe_reader {
             maybe_reader = match reader.read_blob().chain_err(|| "failed to read blob")? {
                 ReadResult::Blob(data, reader) => {
-                    let (mutation, epoch) = Self::decode_blob(data)?;
-                    Self::validate_blob_epoch(epoch, self.snapshot_epoch, last_epoch)?;
-
+                    let blob_len = data.len();
+                    let (mutation, epoch) = decode_blob::<M>(data)?;
+                    validate_blob_epoch(epoch, self.snapshot_epoch, last_epoch)?;
                     if epoch > self.snapshot_epoch {
+                        self.base.add_journal_bytes(blob_len);
+                        first_applied.get_or_insert(epoch);
+                    }
+
+                    if epoch > self.snapshot_epoch && self.recovery_threads > 1 {
+                        recovered.push(mutation);
+                        if recovered.len() == RECOVERY_BATCH_SIZE {
+                            let first_epoch = epoch + 1 - recovered.len() as u64;
+                            self.base
+                                .proposal_sender
+                                .send_recovered(recovered, first_epoch, self.recovery_threads)
+                                .await?;
+                            recovered = vec![];
+                        }
+                    } else if epoch > self.snapshot_epoch {
                         let traced = Traced::new(mutation);
                         fastlog!(FastlogMessage::RecoveredMutation {
                             id: traced.id,
                             epoch: epoch,
                         });
-                        self.base.send_proposal(traced, epoch).await?;
+                        self.base
+                            .proposal_sender
+                            .send_proposal(traced, epoch, None)
+                            .await?;
                     }
 
+                    first_epoch.get_or_insert(epoch);
                     last_epoch = Some(epoch);
                     mutation_count += 1;
+                    progress.update(mutation_count, epoch);
 
                     Some(reader)
                 }
@@ -225,24 +447,36 @@ impl<R: JournalReader, M: Machine> JournalServiceRestorer<R, M> {
             };
         }
 
-        let last_epoch = last_epoch.unwrap_or(0);
-        if last_epoch > 0 && last_epoch < self.snapshot_epoch {
-            bail!(
-                "Missing mutation(s): snapshot epoch {}, got mutations only up to epoch {}",
-                self.snapshot_epoch,
-                last_epoch
-            );
+        if !recovered.is_empty() {
+            let first_epoch = last_epoch.unwrap() + 1 - recovered.len() as u64;
+            self.base
+                .proposal_sender
+                .send_recovered(recovered, first_epoch, self.recovery_threads)
+                .await?;
         }
 
-        if mutation_count > 0 {
-            let first_epoch = last_epoch + 1 - mutation_count as u64;
+        gauge!(
+            "rayd.journal_service.recovered_mutations",
+            mutation_count as i64
+        );
+        timing!(
+            "rayd.journal_service.recovery_duration",
+            progress.started,
+            Instant::now()
+        );
+        if let (Some(first_epoch), Some(last_epoch)) = (first_epoch, last_epoch) {
             info!(
-                "Recovered {} mutations from journal (epoch range: [{}, {}])",
-                mutation_count, first_epoch, last_epoch
+                "Recovered {} mutations from journal in {:.1?} (epoch range: [{}, {}])",
+                mutation_count,
+                progress.started.elapsed(),
+                first_epoch,
+                last_epoch
             );
         } else {
             info!("No mutations recovered from journal");
         }
+        check_journal_continuity(self.snapshot_epoch, first_applied, last_epoch)?;
+        let last_epoch = bridge_journal_end(self.snapshot_epoch, last_epoch);
 
         // Notice: before this point, the value of the external_epoch atomic was zero.
         // It is crucially important that no requests are served based on it's value before
@@ -252,73 +486,203 @@ impl<R: JournalReader, M: Machine> JournalServiceRestorer<R, M> {
         Ok(JournalService {
             writer: maybe_writer.unwrap(),
             persisted_epoch: last_epoch,
+            dispatch_queue_size: self.dispatch_queue_size,
             base: self.base,
         })
     }
+}
 
-    fn decode_blob(blob: Vec<u8>) -> Result<(M::Mutation, u64)> {
-        if blob.len() < 9 {
-            bail!(
-                "Journal blob is too short: expected at least 9 bytes, got {}",
-                blob.len()
-            );
-        }
+// Blobs start with the epoch as a little-endian u64, whose top byte holds the codec of the
+// mutation that follows. Journals written before codecs were introduced have a zero top byte
+// and hold the protobuf encoding of the mutation type of the time, which may differ from the
+// current one, see Machine::decode_legacy_mutation.
+const CODEC_SHIFT: u32 = 56;
+const EPOCH_MASK: u64 = (1 << CODEC_SHIFT) - 1;
+const LEGACY_CODEC: u64 = 0;
+const CUSTOM_CODEC: u64 = 1;
+const PROTOBUF_CODEC: u64 = 2;
 
-        let epoch = (&blob[..8]).read_u64::<LittleEndian>().unwrap();
-        let mutation = M::Mutation::decode(&blob[8..]).chain_err(|| "failed to decode mutation")?;
+fn encode_blob<M: Machine>(mutation: &M::Mutation, epoch: u64) -> Result<Vec<u8>> {
+    if epoch >> CODEC_SHIFT != 0 {
+        bail!("Epoch {} does not fit into a journal blob", epoch);
+    }
+    let mut blob = Vec::with_capacity(8 + mutation.encoded_len());
+    let codec = match M::journal_codec() {
+        JournalCodec::Protobuf => PROTOBUF_CODEC,
+        JournalCodec::Custom => CUSTOM_CODEC,
+    };
+    blob.write_u64::<LittleEndian>(epoch | codec << CODEC_SHIFT)
+        .unwrap();
+    match M::journal_codec() {
+        JournalCodec::Protobuf => mutation.encode(&mut blob).map_err(Error::from),
+        JournalCodec::Custom => M::encode_mutation(mutation, &mut blob),
+    }
+    .chain_err(|| "failed to encode mutation")?;
+    Ok(blob)
+}
 
-        Ok((mutation, epoch))
+pub fn decode_blob<M: Machine>(blob: Vec<u8>) -> Result<(M::Mutation, u64)> {
+    // An empty message encodes to nothing.
+    if blob.len() < 8 {
+        bail!(
+            "Journal blob is too short: expected at least 8 bytes, got {}",
+            blob.len()
+        );
     }
 
-    fn validate_blob_epoch(epoch: u64, snapshot_epoch: u64, last_epoch: Option<u64>) -> Result<()> {
-        if last_epoch
-            .as_ref()
-            .map(|last| last + 1 != epoch)
-            .unwrap_or(false)
-        {
-            bail!(
-                "Missing mutation(s): expected epoch {}, got {}",
-                last_epoch.unwrap() + 1,
-                epoch
-            );
-        }
+    let header = (&blob[..8]).read_u64::<LittleEndian>().unwrap();
+    let epoch = header & EPOCH_MASK;
+    let mutation = match header >> CODEC_SHIFT {
+        PROTOBUF_CODEC => M::Mutation::decode(&blob[8..]).map_err(Error::from),
+        CUSTOM_CODEC => M::decode_mutation(&blob[8..]),
+        LEGACY_CODEC => M::decode_legacy_mutation(&blob[8..]),
+        codec => bail!(
+            "Mutation of epoch {} has unknown journal codec {}",
+            epoch,
+            codec
+        ),
+    };
+    let mutation =
+        mutation.chain_err(|| format!("failed to decode mutation of epoch {}", epoch))?;
+
+    Ok((mutation, epoch))
+}
+
+// Reads only the header, so that the mutation itself needn't be decodable.
+pub fn blob_epoch(blob: &[u8]) -> Result<u64> {
+    match blob.get(..8) {
+        Some(mut header) => Ok(header.read_u64::<LittleEndian>().unwrap() & EPOCH_MASK),
+        None => bail!("Journal blob is too short: {} bytes", blob.len()),
+    }
+}
 
-        if last_epoch.is_none() && epoch > snapshot_epoch + 1 {
-            bail!(
-                "Missing mutation(s): expected epoch {}, got epoch {}",
-                snapshot_epoch + 1,
-                epoch
+// Gaps in the journal are fine as long as the snapshot covers them: the journal may have been
+// trimmed by another node, or continued from a snapshot newer than its end.
+fn validate_blob_epoch(epoch: u64, snapshot_epoch: u64, last_epoch: Option<u64>) -> Result<()> {
+    match last_epoch {
+        Some(last_epoch) if epoch <= last_epoch => bail!(
+            "Journal epochs are out of order: epoch {} follows epoch {}",
+            epoch,
+            last_epoch
+        ),
+        Some(last_epoch) if last_epoch + 1 != epoch && epoch > snapshot_epoch + 1 => bail!(
+            "Missing mutation(s): epochs [{}, {}] are neither in the journal nor in the snapshot \
+             (snapshot epoch: {})",
+            last_epoch.max(snapshot_epoch) + 1,
+            epoch - 1,
+            snapshot_epoch
+        ),
+        Some(last_epoch) if last_epoch + 1 != epoch => warn!(
+            "Journal skips epochs [{}, {}], bridged by the snapshot (snapshot epoch: {})",
+            last_epoch + 1,
+            epoch - 1,
+            snapshot_epoch
+        ),
+        None if epoch > snapshot_epoch + 1 => bail!(
+            "Missing mutation(s): the journal starts at epoch {}, but the snapshot only covers \
+             epochs up to {}, so epochs [{}, {}] are lost; a newer snapshot is needed to recover",
+            epoch,
+            snapshot_epoch,
+            snapshot_epoch + 1,
+            epoch - 1
+        ),
+        _ => (),
+    }
+
+    Ok(())
+}
+
+// Confirms that recovery picks up right where the snapshot ends, and tells a journal continuing
+// the snapshot from one with nothing past it. Gaps between blobs are caught by
+// validate_blob_epoch, and a journal ending before the snapshot is reported by
+// bridge_journal_end.
+fn check_journal_continuity(
+    snapshot_epoch: u64,
+    first_applied: Option<u64>,
+    last_epoch: Option<u64>,
+) -> Result<()> {
+    match (first_applied, last_epoch) {
+        (Some(first_applied), _) if first_applied != snapshot_epoch + 1 => bail!(
+            "Gap detected: the journal resumes at epoch {} after snapshot epoch {}",
+            first_applied,
+            snapshot_epoch
+        ),
+        (Some(first_applied), last_epoch) => info!(
+            "Journal continues the snapshot: epochs [{}, {}] applied on top of snapshot epoch {}",
+            first_applied,
+            last_epoch.unwrap_or(first_applied),
+            snapshot_epoch
+        ),
+        // The file being written is never disposed, so the journal is only empty if it was lost
+        // or had just moved on to a new file when the files before were disposed.
+        (None, None) if snapshot_epoch > 0 => warn!(
+            "Journal is empty at snapshot epoch {}: fine if the journal had just moved on to a \
+             new file, otherwise any mutations persisted after the snapshot were lost",
+            snapshot_epoch
+        ),
+        (None, None) => info!("Journal is empty and there is no snapshot, starting from scratch"),
+        (None, Some(last_epoch)) if last_epoch == snapshot_epoch => info!(
+            "Journal ends at snapshot epoch {}, nothing to apply",
+            snapshot_epoch
+        ),
+        (None, Some(_)) => (),
+    }
+    Ok(())
+}
+
+// Returns the epoch to continue from. A journal ending before the snapshot has lost its tail,
+// but the snapshot still has those mutations, so new ones are appended after the snapshot.
+fn bridge_journal_end(snapshot_epoch: u64, last_epoch: Option<u64>) -> u64 {
+    match last_epoch {
+        Some(last_epoch) if last_epoch < snapshot_epoch => {
+            warn!(
+                "Journal ends at epoch {} before the snapshot, continuing from snapshot epoch {}",
+                last_epoch, snapshot_epoch
             );
+            snapshot_epoch
         }
-
-        Ok(())
+        Some(last_epoch) => last_epoch,
+        None => snapshot_epoch,
     }
 }
 
 pub struct JournalService<W: JournalWriter, M: Machine> {
     writer: W,
     persisted_epoch: u64,
+    // Persisted batches waiting to be handed to the machine service, see
+    // ProposalSender::dispatch.
+    dispatch_queue_size: usize,
     base: JournalServiceBase<M>,
 }
 
 impl<W: JournalWriter, M: Machine> JournalService<W, M> {
     fn write_mutation(&mut self, mutation: &M::Mutation, epoch: u64) -> Result<()> {
-        let mut blob = vec![0u8; 8 + mutation.encoded_len()];
-        (&mut blob[..8]).write_u64::<LittleEndian>(epoch).unwrap();
-        mutation
-            .encode(&mut &mut blob[8..])
-            .chain_err(|| "failed to encode mutation")?;
+        let blob = encode_blob::<M>(mutation, epoch)?;
         self.writer
             .append_blob(&blob)
             .chain_err(|| "journal write failed")?;
+        self.base.add_journal_bytes(blob.len());
         Ok(())
     }
 
     pub async fn serve(&mut self) -> Result<()> {
+        let (dispatch_sender, dispatch_receiver) = profiled_channel(self.dispatch_queue_size);
+        let proposal_sender = self.base.proposal_sender.clone();
+        let dispatcher = proposal_sender.dispatch(dispatch_receiver);
+        future::try_join(self.serve_batches(dispatch_sender), dispatcher).await?;
+        Ok(())
+    }
+
+    // Once persisted, a batch is queued for dispatch and the next one is taken right away.
+    async fn serve_batches(
+        &mut self,
+        mut dispatch_sender: ProfiledSender<PersistedBatch<M>>,
+    ) -> Result<()> {
         loop {
             let BatchResult {
                 mutations,
-                notifiers,
+                results,
+                syncs,
                 min_epoch,
             } = self.base.serve_batch().await?;
 
@@ -326,7 +690,10 @@ impl<W: JournalWriter, M: Machine> JournalService<W, M> {
                 self.handle_new_min_epoch(min_epoch)?;
             }
 
+            // Every batch is persisted before the next one is taken, so a sync with no
+            // mutations before it in its batch is satisfied right away.
             if mutations.is_empty() {
+                self.notify_syncs(syncs);
                 continue;
             }
 
@@ -346,11 +713,13 @@ impl<W: JournalWriter, M: Machine> JournalService<W, M> {
             self.writer
                 .persist()
                 .chain_err(|| "failed to persist journal")?;
-            timing!(
-                "rayd.journal_service.persist_duration",
-                start,
-                Instant::now()
-            );
+            let persisted = Instant::now();
+            timing!("rayd.journal_service.persist_duration", start, persisted);
+            for (mutation, _) in proposals.iter() {
+                if let Some(timings) = &mutation.timings {
+                    timings.mark_persisted(persisted);
+                }
+            }
 
             self.persisted_epoch += proposals.len() as u64;
             self.base.update_persisted_epoch(self.persisted_epoch);
@@ -358,6 +727,7 @@ impl<W: JournalWriter, M: Machine> JournalService<W, M> {
                 "rayd.journal_service.persisted_epoch",
                 self.persisted_epoch as i64
             );
+            self.notify_syncs(syncs);
 
             let now = chrono::Utc::now();
             for (mutation, epoch) in proposals.iter() {
@@ -370,13 +740,27 @@ impl<W: JournalWriter, M: Machine> JournalService<W, M> {
                 );
             }
 
-            for notify in notifiers.into_iter() {
-                notify.send(()).ok(); // Ignore error
+            if audit_enabled() {
+                for (mutation, epoch) in proposals.iter() {
+                    audit(AuditRecord {
+                        datetime: now,
+                        epoch: *epoch,
+                        id: mutation.id,
+                        writes: M::audited_writes(&mutation.payload),
+                    });
+                }
             }
 
-            for (mutation, epoch) in proposals.into_iter() {
-                self.base.send_proposal(mutation, epoch).await?;
-            }
+            dispatch_sender
+                .send(PersistedBatch { proposals, results })
+                .await
+                .chain_err(|| "dispatch_sender failed")?;
+        }
+    }
+
+    fn notify_syncs(&self, syncs: Vec<oneshot::Sender<u64>>) {
+        for sync in syncs {
+            sync.send(self.persisted_epoch).ok(); // Ignore error
         }
     }
 
@@ -400,3 +784,675 @@ impl<W: JournalWriter, M: Machine> JournalService<W, M> {
         Ok(())
     }
 }
+
+// Runs instead of the journal service on read-only replicas and standbys: tails the journal
+// written by the primary and feeds its mutations to the machine service.
+pub struct JournalFollower<T: JournalTailer, M: Machine> {
+    tailer: T,
+    machine_sender: ProfiledSender<MachineServiceRequest<M>>,
+    snapshot_epoch: u64,
+    last_epoch: Option<u64>,
+    external_epoch: Arc<AtomicU64>,
+    poll_interval: Duration,
+}
+
+impl<T: JournalTailer, M: Machine> JournalFollower<T, M> {
+    pub fn new(
+        tailer: T,
+        machine_sender: ProfiledSender<MachineServiceRequest<M>>,
+        snapshot_epoch: u64,
+        external_epoch: Arc<AtomicU64>,
+        poll_interval: Duration,
+    ) -> Self {
+        Self {
+            tailer,
+            machine_sender,
+            snapshot_epoch,
+            last_epoch: None,
+            external_epoch,
+            poll_interval,
+        }
+    }
+
+    // Applies the journal written so far.
+    pub async fn catch_up(&mut self) -> Result<()> {
+        info!("Starting to follow the journal");
+        self.apply_available_blobs().await?;
+
+        let last_epoch = bridge_journal_end(self.snapshot_epoch, self.last_epoch);
+        self.external_epoch.store(last_epoch, Ordering::Release);
+
+        info!("Caught up with the journal (epoch: {})", last_epoch);
+        Ok(())
+    }
+
+    pub async fn serve(&mut self) -> Result<()> {
+        loop {
+            time::delay_for(self.poll_interval).await;
+            self.apply_available_blobs().await?;
+        }
+    }
+
+    // Follows the journal until promoted, then applies the rest of it and returns the epoch
+    // the machine is at.
+    pub async fn serve_until(&mut self, promotion: oneshot::Receiver<()>) -> Result<u64> {
+        let mut promotion = promotion.fuse();
+        loop {
+            select! {
+                _ = time::delay_for(self.poll_interval).fuse() => {
+                    self.apply_available_blobs().await?;
+                },
+                // A dropped sender means shutdown, not promotion.
+                result = promotion => if result.is_ok() {
+                    break;
+                },
+            }
+        }
+        self.apply_available_blobs().await?;
+
+        let last_epoch = bridge_journal_end(self.snapshot_epoch, self.last_epoch);
+        info!("Caught up with the journal (epoch: {})", last_epoch);
+        Ok(last_epoch)
+    }
+
+    async fn apply_available_blobs(&mut self) -> Result<()> {
+        while let Some(blob) = self
+            .tailer
+            .next_blob()
+            .chain_err(|| "failed to read blob")?
+        {
+            self.apply_blob(blob).await?;
+        }
+        gauge!(
+            "rayd.journal_follower.epoch",
+            self.last_epoch.unwrap_or(0) as i64
+        );
+        Ok(())
+    }
+
+    async fn apply_blob(&mut self, blob: Vec<u8>) -> Result<()> {
+        let (mutation, epoch) = decode_blob::<M>(blob)?;
+        validate_blob_epoch(epoch, self.snapshot_epoch, self.last_epoch)?;
+        self.last_epoch = Some(epoch);
+
+        if epoch <= self.snapshot_epoch {
+            return Ok(());
+        }
+
+        let mutation = Traced::new(mutation);
+        fastlog!(FastlogMessage::RecoveredMutation {
+            id: mutation.id,
+            epoch,
+        });
+        self.machine_sender
+            .send(MachineServiceRequest::Proposal {
+                mutation,
+                epoch,
+                result: None,
+            })
+            .await
+            .chain_err(|| "machine_sender failed")?;
+
+        // Published per blob, so that reads keep up even if the journal never runs dry.
+        self.external_epoch.store(epoch, Ordering::Release);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::{
+        config::JournalStorageConfig, directory_journal::DirectoryJournalReader,
+        kv_store::HashStore, storage_machine::StorageMachine,
+    };
+
+    use crate::{
+        proto::{self, mutation::Kind},
+        util::{profiled_unbounded_channel, ProfiledUnboundedSender},
+    };
+
+    use std::{
+        fs,
+        io::{Read, Write},
+        sync::{atomic::AtomicUsize, Mutex},
+        thread,
+    };
+
+    type TestMachine = StorageMachine<HashStore>;
+
+    // Journal kept in memory, whose blobs are read back by the next reader. Persists are only
+    // counted.
+    #[derive(Clone, Default)]
+    struct MemoryJournal {
+        blobs: Arc<Mutex<Vec<Vec<u8>>>>,
+        persist_count: Arc<AtomicUsize>,
+    }
+
+    struct MemoryReader {
+        journal: MemoryJournal,
+        next: usize,
+    }
+
+    impl JournalReader for MemoryReader {
+        type Writer = MemoryJournal;
+
+        fn read_blob(mut self) -> Result<ReadResult<Self, Self::Writer>> {
+            let blob = self.journal.blobs.lock().unwrap().get(self.next).cloned();
+            match blob {
+                Some(blob) => {
+                    self.next += 1;
+                    Ok(ReadResult::Blob(blob, self))
+                }
+                None => Ok(ReadResult::End(self.journal)),
+            }
+        }
+
+        fn first_epoch(&self) -> Result<Option<u64>> {
+            let blobs = self.journal.blobs.lock().unwrap();
+            blobs.first().map(|blob| blob_epoch(blob)).transpose()
+        }
+    }
+
+    impl JournalWriter for MemoryJournal {
+        fn append_blob(&mut self, blob: &[u8]) -> Result<()> {
+            self.blobs.lock().unwrap().push(blob.to_vec());
+            Ok(())
+        }
+
+        fn persist(&mut self) -> Result<()> {
+            self.persist_count.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn get_blob_count(&self) -> usize {
+            self.blobs.lock().unwrap().len()
+        }
+
+        fn dispose_oldest_blobs(&mut self, blob_count: usize) -> Result<()> {
+            self.blobs.lock().unwrap().drain(..blob_count);
+            Ok(())
+        }
+    }
+
+    // A journal service recovering from and writing to a memory journal, whose proposals are
+    // taken from machine_receiver instead of being applied.
+    struct Journal {
+        request_sender: ProfiledSender<JournalServiceRequest<TestMachine>>,
+        machine_receiver: ProfiledReceiver<MachineServiceRequest<TestMachine>>,
+        persisted_epoch: Arc<AtomicU64>,
+        snapshot_receiver: ProfiledReceiver<u64>,
+        _min_epoch_sender: ProfiledUnboundedSender<u64>,
+    }
+
+    impl Journal {
+        fn start(memory: &MemoryJournal, batch_size: usize, batch_max_bytes: usize) -> Self {
+            Self::start_with_queues(memory, batch_size, batch_max_bytes, 1000, 1000, 1000)
+        }
+
+        fn start_with_queues(
+            memory: &MemoryJournal,
+            batch_size: usize,
+            batch_max_bytes: usize,
+            machine_queue_size: usize,
+            dispatch_queue_size: usize,
+            snapshot_queue_size: usize,
+        ) -> Self {
+            let (request_sender, request_receiver) = profiled_channel(1000);
+            let (machine_sender, machine_receiver) = profiled_channel(machine_queue_size);
+            let (snapshot_sender, snapshot_receiver) = profiled_channel(snapshot_queue_size);
+            let (min_epoch_sender, min_epoch_receiver) = profiled_unbounded_channel();
+            let persisted_epoch = Arc::new(AtomicU64::new(0));
+            let reader = MemoryReader {
+                journal: memory.clone(),
+                next: 0,
+            };
+            let restorer = JournalServiceRestorer::new(
+                reader,
+                machine_sender,
+                snapshot_sender,
+                request_receiver,
+                min_epoch_receiver,
+                batch_size,
+                batch_max_bytes,
+                1,
+                Duration::from_secs(3600),
+                dispatch_queue_size,
+                0,
+                persisted_epoch.clone(),
+                Arc::new(AtomicU64::new(0)),
+            );
+            tokio::spawn(async move { restorer.restore().await?.serve().await });
+            Self {
+                request_sender,
+                machine_receiver,
+                persisted_epoch,
+                snapshot_receiver,
+                _min_epoch_sender: min_epoch_sender,
+            }
+        }
+
+        async fn propose(&mut self, mutation: proto::Mutation) {
+            let (result, _) = oneshot::channel();
+            let request = JournalServiceRequest::Mutation {
+                mutation: Traced::new(mutation),
+                result,
+            };
+            self.request_sender.send(request).await.unwrap();
+        }
+
+        // Epochs of the next count proposals handed to the machine service.
+        async fn proposed_epochs(&mut self, count: usize) -> Vec<u64> {
+            let mut epochs = vec![];
+            while epochs.len() < count {
+                match self.machine_receiver.recv().await.unwrap() {
+                    MachineServiceRequest::Proposal { epoch, .. } => epochs.push(epoch),
+                    _ => panic!("unexpected machine service request"),
+                }
+            }
+            epochs
+        }
+    }
+
+    fn set(key: &[u8], value: &[u8]) -> proto::SetRequest {
+        proto::SetRequest {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn mutations_round_trip_through_blobs() {
+        let mutation = proto::Mutation {
+            kind: Some(Kind::Increment(proto::IncrementRequest {
+                key: b"counter".to_vec(),
+                delta: -3,
+                ..Default::default()
+            })),
+        };
+        let blob = encode_blob::<TestMachine>(&mutation, 7).unwrap();
+        assert_eq!(blob_epoch(&blob).unwrap(), 7);
+        assert_eq!(decode_blob::<TestMachine>(blob).unwrap(), (mutation, 7));
+    }
+
+    #[test]
+    fn legacy_blobs_decode_as_sets() {
+        for request in &[set(b"key", b"value"), set(b"", b"")] {
+            let mut blob = vec![];
+            blob.write_u64::<LittleEndian>(7).unwrap();
+            request.encode(&mut blob).unwrap();
+
+            let mutation = proto::Mutation {
+                kind: Some(Kind::Set(request.clone())),
+            };
+            assert_eq!(decode_blob::<TestMachine>(blob).unwrap(), (mutation, 7));
+        }
+    }
+
+    #[test]
+    fn unknown_codecs_are_refused() {
+        let mut blob = vec![];
+        blob.write_u64::<LittleEndian>(7 | 9 << CODEC_SHIFT)
+            .unwrap();
+        set(b"key", b"value").encode(&mut blob).unwrap();
+
+        let err = decode_blob::<TestMachine>(blob).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Mutation of epoch 7 has unknown journal codec 9"
+        );
+    }
+
+    // Journals increments, the only mutations it takes, with bincode.
+    #[derive(Clone, Default)]
+    struct BincodeMachine(TestMachine);
+
+    impl Machine for BincodeMachine {
+        type Mutation = proto::Mutation;
+        type Outcome = <TestMachine as Machine>::Outcome;
+        type Query = <TestMachine as Machine>::Query;
+        type Status = <TestMachine as Machine>::Status;
+
+        fn apply_mutation(&mut self, mutation: Self::Mutation) -> Self::Outcome {
+            self.0.apply_mutation(mutation)
+        }
+
+        fn query_state(&self, query: Self::Query) -> Self::Status {
+            self.0.query_state(query)
+        }
+
+        fn write_snapshot<T: Write>(&self, writer: &mut T) -> Result<()> {
+            self.0.write_snapshot(writer)
+        }
+
+        fn from_snapshot<T: Read>(reader: &mut T, version: u16) -> Result<Self> {
+            TestMachine::from_snapshot(reader, version).map(Self)
+        }
+
+        fn journal_codec() -> JournalCodec {
+            JournalCodec::Custom
+        }
+
+        fn encode_mutation(mutation: &Self::Mutation, buffer: &mut Vec<u8>) -> Result<()> {
+            match &mutation.kind {
+                Some(Kind::Increment(request)) => {
+                    bincode::serialize_into(buffer, &(&request.key, request.delta))
+                        .chain_err(|| "bincode failed")
+                }
+                _ => bail!("not an increment"),
+            }
+        }
+
+        fn decode_mutation(data: &[u8]) -> Result<Self::Mutation> {
+            let (key, delta) = bincode::deserialize(data).chain_err(|| "bincode failed")?;
+            Ok(increment(key, delta))
+        }
+    }
+
+    fn increment(key: Vec<u8>, delta: i64) -> proto::Mutation {
+        proto::Mutation {
+            kind: Some(Kind::Increment(proto::IncrementRequest {
+                key,
+                delta,
+                ..Default::default()
+            })),
+        }
+    }
+
+    #[test]
+    fn mutations_round_trip_through_a_custom_codec() {
+        let mutation = increment(b"counter".to_vec(), -3);
+        let blob = encode_blob::<BincodeMachine>(&mutation, 7).unwrap();
+        let protobuf_blob = encode_blob::<TestMachine>(&mutation, 7).unwrap();
+        assert_ne!(blob, protobuf_blob);
+        assert_eq!(blob_epoch(&blob).unwrap(), 7);
+        assert_eq!(decode_blob::<BincodeMachine>(blob).unwrap(), (mutation, 7));
+    }
+
+    #[test]
+    fn blobs_decode_with_the_codec_they_were_written_with() {
+        // A journal written by the protobuf machine and continued by the custom one.
+        let first = increment(b"counter".to_vec(), 1);
+        let second = increment(b"counter".to_vec(), 2);
+        let protobuf_blob = encode_blob::<TestMachine>(&first, 1).unwrap();
+        let custom_blob = encode_blob::<BincodeMachine>(&second, 2).unwrap();
+
+        let decoded = decode_blob::<BincodeMachine>(protobuf_blob.clone()).unwrap();
+        assert_eq!(decoded, (first, 1));
+        let decoded = decode_blob::<BincodeMachine>(custom_blob.clone()).unwrap();
+        assert_eq!(decoded, (second, 2));
+
+        // Going back to a machine without the custom codec fails on its blobs.
+        decode_blob::<TestMachine>(protobuf_blob).unwrap();
+        let err = decode_blob::<TestMachine>(custom_blob).err().unwrap();
+        assert_eq!(err.to_string(), "failed to decode mutation of epoch 2");
+    }
+
+    #[tokio::test]
+    async fn journal_files_round_trip_through_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = JournalStorageConfig {
+            path: dir.path().to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        let mutations: Vec<_> = (1..=3)
+            .map(|delta| increment(b"counter".to_vec(), delta))
+            .collect();
+        let blobs: Vec<_> = (1..)
+            .zip(&mutations)
+            .map(|(epoch, mutation)| encode_blob::<TestMachine>(mutation, epoch).unwrap())
+            .collect();
+        let mut writer = match DirectoryJournalReader::new(&config).unwrap().read_blob() {
+            Ok(ReadResult::End(writer)) => writer,
+            _ => panic!("the journal is not empty"),
+        };
+        for blob in &blobs {
+            writer.append_blob(blob).unwrap();
+        }
+        writer.persist().unwrap();
+        drop(writer);
+
+        // Lengths and epochs are little-endian whatever the byte order of the host.
+        let path = fs::read_dir(dir.path())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let bytes = fs::read(path).unwrap();
+        assert_eq!(bytes[..4], (blobs[0].len() as u32).to_le_bytes());
+        assert_eq!(
+            bytes[4..12],
+            (1 | PROTOBUF_CODEC << CODEC_SHIFT).to_le_bytes()
+        );
+
+        let (machine_sender, mut machine_receiver) = profiled_channel(10);
+        let (snapshot_sender, _snapshot_receiver) = profiled_channel(10);
+        let (_request_sender, request_receiver) = profiled_channel(10);
+        let (_min_epoch_sender, min_epoch_receiver) = profiled_unbounded_channel();
+        let restorer = JournalServiceRestorer::<_, TestMachine>::new(
+            DirectoryJournalReader::new(&config).unwrap(),
+            machine_sender,
+            snapshot_sender,
+            request_receiver,
+            min_epoch_receiver,
+            100,
+            1 << 20,
+            1,
+            Duration::from_secs(3600),
+            1000,
+            0,
+            Arc::new(AtomicU64::new(0)),
+            Arc::new(AtomicU64::new(0)),
+        );
+        let restore = tokio::spawn(restorer.restore());
+        for (epoch, expected) in (1..).zip(mutations) {
+            match machine_receiver.recv().await.unwrap() {
+                MachineServiceRequest::Proposal {
+                    mutation,
+                    epoch: proposed_epoch,
+                    ..
+                } => assert_eq!((mutation.payload, proposed_epoch), (expected, epoch)),
+                _ => panic!("unexpected machine service request"),
+            }
+        }
+        assert_eq!(restore.await.unwrap().unwrap().persisted_epoch, 3);
+    }
+
+    fn set_of_size(value_len: usize) -> JournalServiceRequest<TestMachine> {
+        let mutation = proto::Mutation {
+            kind: Some(Kind::Set(set(b"key", &vec![0; value_len]))),
+        };
+        let (result, _) = oneshot::channel();
+        JournalServiceRequest::Mutation {
+            mutation: Traced::new(mutation),
+            result,
+        }
+    }
+
+    #[test]
+    fn journal_gaps_covered_by_the_snapshot_are_bridged() {
+        // Epochs 3 and 4 were trimmed from the journal, but snapshot epoch 4 has them.
+        validate_blob_epoch(5, 4, Some(2)).unwrap();
+        validate_blob_epoch(3, 4, None).unwrap();
+        validate_blob_epoch(5, 4, None).unwrap();
+
+        assert_eq!(bridge_journal_end(10, Some(7)), 10);
+        assert_eq!(bridge_journal_end(10, Some(12)), 12);
+        assert_eq!(bridge_journal_end(10, None), 10);
+    }
+
+    #[test]
+    fn journal_gaps_past_the_snapshot_are_refused() {
+        for &last_epoch in &[Some(2), None] {
+            let err = validate_blob_epoch(7, 4, last_epoch).unwrap_err();
+            assert!(err.to_string().contains("epochs [5, 6]"), "{}", err);
+        }
+        let err = validate_blob_epoch(3, 4, Some(3)).unwrap_err();
+        assert!(err.to_string().contains("out of order"), "{}", err);
+    }
+
+    #[test]
+    fn progress_is_reported_every_interval_or_million_mutations() {
+        let mut progress = RecoveryProgress::new(Duration::from_secs(3600));
+        for &(count, reported) in &[
+            (1, 0),
+            (PROGRESS_MUTATIONS - 1, 0),
+            (PROGRESS_MUTATIONS, PROGRESS_MUTATIONS),
+            (2 * PROGRESS_MUTATIONS - 1, PROGRESS_MUTATIONS),
+            (2 * PROGRESS_MUTATIONS, 2 * PROGRESS_MUTATIONS),
+        ] {
+            progress.update(count, count as u64);
+            assert_eq!(progress.last_report_count, reported, "count: {}", count);
+        }
+
+        let mut progress = RecoveryProgress::new(Duration::from_millis(50));
+        progress.update(1, 1);
+        assert_eq!(progress.last_report_count, 0);
+        thread::sleep(Duration::from_millis(60));
+        progress.update(2, 2);
+        assert_eq!(progress.last_report_count, 2);
+        progress.update(3, 3);
+        assert_eq!(progress.last_report_count, 2);
+    }
+
+    #[tokio::test]
+    async fn batches_stay_within_the_byte_cap() {
+        let (machine_sender, _) = profiled_channel(1);
+        let (snapshot_sender, _) = profiled_channel(1);
+        let (mut request_sender, request_receiver) = profiled_channel(100);
+        let (_min_epoch_sender, min_epoch_receiver) = profiled_unbounded_channel::<u64>();
+        let mut base = JournalServiceBase::<TestMachine> {
+            proposal_sender: ProposalSender {
+                machine_sender,
+                snapshot_sender,
+            },
+            request_receiver,
+            min_epoch_receiver,
+            batch_size: 100,
+            batch_max_bytes: 100,
+            pending_request: None,
+            external_epoch: Arc::new(AtomicU64::new(0)),
+            journal_bytes: Arc::new(AtomicU64::new(0)),
+        };
+
+        // The one over the cap goes alone.
+        let value_lens = [10, 60, 30, 5, 200, 80, 20, 1];
+        let mut sizes = vec![];
+        for value_len in value_lens.iter() {
+            let request = set_of_size(*value_len);
+            sizes.push(request.encoded_len());
+            request_sender.send(request).await.unwrap();
+        }
+
+        let mut batches = vec![];
+        let mut batched = 0;
+        while batched < sizes.len() {
+            let batch = base.serve_batch().await.unwrap().mutations.len();
+            batches.push(sizes[batched..batched + batch].to_vec());
+            batched += batch;
+        }
+        for (index, batch) in batches.iter().enumerate() {
+            let bytes: usize = batch.iter().sum();
+            assert!(bytes <= 100 || batch.len() == 1, "{:?}", batches);
+            // Batches only end where the next request would not fit.
+            if let Some(next) = batches.get(index + 1) {
+                assert!(bytes + next[0] > 100, "{:?}", batches);
+            }
+        }
+        assert_eq!(
+            batches.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![2, 2, 1, 1, 2]
+        );
+    }
+
+    #[tokio::test]
+    async fn batch_set_is_persisted_at_once() {
+        let memory = MemoryJournal::default();
+        let mut journal = Journal::start(&memory, 1000, 0);
+        let entries = (0..100)
+            .map(|index| proto::KeyValue {
+                key: format!("key{}", index).into_bytes(),
+                value: b"value".to_vec(),
+            })
+            .collect();
+        let mutation = proto::Mutation {
+            kind: Some(Kind::BatchSet(proto::BatchSetRequest {
+                entries,
+                ..Default::default()
+            })),
+        };
+        journal.propose(mutation).await;
+
+        assert_eq!(journal.proposed_epochs(1).await, vec![1]);
+        assert_eq!(memory.persist_count.load(Ordering::SeqCst), 1);
+        assert_eq!(memory.blobs.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_slow_machine_does_not_hold_up_persisting() {
+        // The machine service takes a single proposal until the end, and two persisted batches
+        // may wait for it.
+        let memory = MemoryJournal::default();
+        let mut journal = Journal::start_with_queues(&memory, 1, 0, 1, 2, 1000);
+        for index in 0..20u8 {
+            let mutation = proto::Mutation {
+                kind: Some(Kind::Set(set(&[index], b"value"))),
+            };
+            journal.propose(mutation).await;
+        }
+
+        // One batch taken by the machine, one waiting to be, two in the queue and one waiting
+        // to get into it. Reads may already wait for all of them.
+        let persisted_epoch = || journal.persisted_epoch.load(Ordering::SeqCst);
+        while persisted_epoch() < 5 {
+            time::delay_for(Duration::from_millis(10)).await;
+        }
+        time::delay_for(Duration::from_millis(100)).await;
+        assert_eq!(persisted_epoch(), 5);
+        assert_eq!(memory.persist_count.load(Ordering::SeqCst), 5);
+
+        let epochs = journal.proposed_epochs(20).await;
+        assert_eq!(epochs, (1..=20).collect::<Vec<_>>());
+        assert_eq!(memory.persist_count.load(Ordering::SeqCst), 20);
+    }
+
+    #[tokio::test]
+    async fn a_stalled_snapshot_service_throttles_persisting() {
+        // The snapshot service takes two epochs until the end, and two persisted batches may
+        // wait for the dispatch loop.
+        let memory = MemoryJournal::default();
+        let mut journal = Journal::start_with_queues(&memory, 1, 0, 1000, 2, 2);
+        for index in 0..20u8 {
+            let mutation = proto::Mutation {
+                kind: Some(Kind::Set(set(&[index], b"value"))),
+            };
+            journal.propose(mutation).await;
+        }
+
+        // The machine service gets the two mutations whose epochs went through. The dispatch
+        // loop holds the third batch, two are in its queue and one is waiting to get into it.
+        assert_eq!(journal.proposed_epochs(2).await, vec![1, 2]);
+        let epoch = journal.persisted_epoch.clone();
+        let persisted_epoch = || epoch.load(Ordering::SeqCst);
+        while persisted_epoch() < 6 {
+            time::delay_for(Duration::from_millis(10)).await;
+        }
+        time::delay_for(Duration::from_millis(100)).await;
+        assert_eq!(persisted_epoch(), 6);
+        assert_eq!(memory.blobs.lock().unwrap().len(), 6);
+
+        let mut epochs = vec![];
+        while epochs.len() < 20 {
+            epochs.push(journal.snapshot_receiver.recv().await.unwrap());
+        }
+        assert_eq!(epochs, (1..=20).collect::<Vec<_>>());
+        assert_eq!(
+            journal.proposed_epochs(18).await,
+            (3..=20).collect::<Vec<_>>()
+        );
+        assert_eq!(persisted_epoch(), 20);
+    }
+}
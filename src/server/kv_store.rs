@@ -0,0 +1,175 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+    sync::Arc,
+};
+
+// Values are never mutated in place, so reads and clones of the store, such as the one a
+// snapshot is written from, share them instead of copying.
+pub type Value = Arc<[u8]>;
+
+// The flag telling compressed values apart is the tag of the enum rather than a byte in front
+// of the value, so that plain values are still shared on reads.
+#[derive(Clone, Debug)]
+pub enum StoredValue {
+    Plain(Value),
+    Zstd(Value),
+}
+
+#[derive(Clone, Copy)]
+pub struct Compression {
+    pub min_value_size: usize,
+    pub level: i32,
+}
+
+impl StoredValue {
+    // Values are only kept compressed if that makes them smaller.
+    pub fn new(value: Value, compression: Option<Compression>) -> Self {
+        if let Some(compression) = compression {
+            if value.len() >= compression.min_value_size {
+                match zstd::encode_all(&value[..], compression.level) {
+                    Ok(compressed) if compressed.len() < value.len() => {
+                        return StoredValue::Zstd(compressed.into());
+                    }
+                    _ => (),
+                }
+            }
+        }
+        StoredValue::Plain(value)
+    }
+
+    pub fn plain(&self) -> Cow<'_, [u8]> {
+        match self {
+            StoredValue::Plain(value) => Cow::Borrowed(value),
+            StoredValue::Zstd(compressed) => Cow::Owned(decompress(compressed)),
+        }
+    }
+
+    pub fn to_plain(&self) -> Value {
+        match self {
+            StoredValue::Plain(value) => value.clone(),
+            StoredValue::Zstd(compressed) => decompress(compressed).into(),
+        }
+    }
+
+    // The bytes as they are held, compressed or not.
+    pub fn stored(&self) -> &[u8] {
+        match self {
+            StoredValue::Plain(value) | StoredValue::Zstd(value) => value,
+        }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        matches!(self, StoredValue::Zstd(_))
+    }
+}
+
+// Compressed values are only made by this process or read from checksummed snapshots.
+fn decompress(compressed: &[u8]) -> Vec<u8> {
+    zstd::decode_all(compressed).expect("failed to decompress a stored value")
+}
+
+// Backing store of the storage machine.
+pub trait KvStore: Default + Clone + Send + Sync + 'static {
+    fn get(&self, key: &[u8]) -> Option<&StoredValue>;
+    fn insert(&mut self, key: Box<[u8]>, value: StoredValue);
+    fn remove(&mut self, key: &[u8]) -> Option<StoredValue>;
+    // Removes all keys starting with the prefix and returns their entries.
+    fn remove_prefix(&mut self, prefix: &[u8]) -> Vec<(Box<[u8]>, StoredValue)>;
+    // Moves every entry of other into the store, replacing values of keys it already holds.
+    fn append(&mut self, other: Self);
+    fn len(&self) -> usize;
+    fn iter(&self) -> Box<dyn Iterator<Item = (&[u8], &StoredValue)> + '_>;
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+}
+
+// Fast point lookups, arbitrary iteration order.
+pub type HashStore = HashMap<Box<[u8]>, StoredValue>;
+
+// Iterates in key order, which makes snapshots of equal states byte-for-byte identical.
+pub type OrderedStore = BTreeMap<Box<[u8]>, StoredValue>;
+
+impl KvStore for HashStore {
+    fn get(&self, key: &[u8]) -> Option<&StoredValue> {
+        HashMap::get(self, key)
+    }
+
+    fn insert(&mut self, key: Box<[u8]>, value: StoredValue) {
+        HashMap::insert(self, key, value);
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<StoredValue> {
+        HashMap::remove(self, key)
+    }
+
+    // Has to scan the whole table.
+    fn remove_prefix(&mut self, prefix: &[u8]) -> Vec<(Box<[u8]>, StoredValue)> {
+        let mut removed = vec![];
+        self.retain(|key, value| {
+            if key.starts_with(prefix) {
+                removed.push((key.clone(), value.clone()));
+                false
+            } else {
+                true
+            }
+        });
+        removed
+    }
+
+    fn append(&mut self, other: Self) {
+        self.extend(other);
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&[u8], &StoredValue)> + '_> {
+        Box::new(HashMap::iter(self).map(|(key, value)| (&key[..], value)))
+    }
+}
+
+impl KvStore for OrderedStore {
+    fn get(&self, key: &[u8]) -> Option<&StoredValue> {
+        BTreeMap::get(self, key)
+    }
+
+    fn insert(&mut self, key: Box<[u8]>, value: StoredValue) {
+        BTreeMap::insert(self, key, value);
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<StoredValue> {
+        BTreeMap::remove(self, key)
+    }
+
+    // Only visits the keys that are removed.
+    fn remove_prefix(&mut self, prefix: &[u8]) -> Vec<(Box<[u8]>, StoredValue)> {
+        let keys: Vec<Box<[u8]>> = self
+            .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.into_iter()
+            .map(|key| {
+                let value = BTreeMap::remove(self, &key).unwrap();
+                (key, value)
+            })
+            .collect()
+    }
+
+    fn append(&mut self, mut other: Self) {
+        BTreeMap::append(self, &mut other);
+    }
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&[u8], &StoredValue)> + '_> {
+        Box::new(BTreeMap::iter(self).map(|(key, value)| (&key[..], value)))
+    }
+}
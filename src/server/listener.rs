@@ -0,0 +1,224 @@
+use super::config::TcpConfig;
+
+use crate::errors::*;
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UnixListener},
+    stream::{Stream, StreamExt},
+};
+use tonic::transport::server::Connected;
+
+use metrics::{counter, gauge};
+
+use net2::TcpBuilder;
+
+use std::{
+    fs::{metadata, remove_file},
+    io,
+    net::SocketAddr,
+    os::unix::{fs::FileTypeExt, net::UnixStream},
+    path::Path,
+    pin::Pin,
+    sync::atomic::{AtomicI64, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+static OPEN_CONNECTIONS: AtomicI64 = AtomicI64::new(0);
+
+// A client connection, counted in the rayd.rpc.open_connections gauge while it is open.
+pub struct Connection<S> {
+    stream: S,
+    // None for Unix sockets.
+    remote_addr: Option<SocketAddr>,
+}
+
+impl<S> Connection<S> {
+    fn new(stream: S, remote_addr: Option<SocketAddr>) -> Self {
+        counter!("rayd.rpc.accepted_connections", 1);
+        let open = OPEN_CONNECTIONS.fetch_add(1, Ordering::Relaxed) + 1;
+        gauge!("rayd.rpc.open_connections", open);
+        Self {
+            stream,
+            remote_addr,
+        }
+    }
+}
+
+impl<S> Drop for Connection<S> {
+    fn drop(&mut self) {
+        let open = OPEN_CONNECTIONS.fetch_sub(1, Ordering::Relaxed) - 1;
+        gauge!("rayd.rpc.open_connections", open);
+    }
+}
+
+impl<S> Connected for Connection<S> {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Connection<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Connection<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+pub async fn bind_tcp(
+    address: SocketAddr,
+    config: &TcpConfig,
+) -> Result<impl Stream<Item = io::Result<Connection<tokio::net::TcpStream>>>> {
+    let listener =
+        listen_tcp(address, config).chain_err(|| format!("failed to bind {}", address))?;
+    let listener = TcpListener::from_std(listener)
+        .chain_err(|| format!("failed to register listener on {}", address))?;
+
+    let nodelay = config.nodelay;
+    let keepalive = match config.keepalive_ms {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    };
+    Ok(listener.map(move |stream| {
+        let stream = stream?;
+        // The connection is still usable with default options, so don't drop it.
+        if let Err(err) = stream.set_nodelay(nodelay) {
+            warn!("Failed to set TCP_NODELAY on accepted connection: {}", err);
+        }
+        if let Err(err) = stream.set_keepalive(keepalive) {
+            warn!("Failed to set SO_KEEPALIVE on accepted connection: {}", err);
+        }
+        let remote_addr = stream.peer_addr().ok();
+        Ok(Connection::new(stream, remote_addr))
+    }))
+}
+
+fn listen_tcp(address: SocketAddr, config: &TcpConfig) -> io::Result<std::net::TcpListener> {
+    let builder = match address {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+    };
+    builder.reuse_address(config.reuse_address)?;
+    builder.bind(address)?;
+    let backlog = config.listen_backlog.min(i32::MAX as u32) as i32;
+    builder.listen(backlog)
+}
+
+// Must be called within a Tokio runtime. A socket file left behind by a rayd that is no longer
+// running is replaced, but one that still accepts connections is not.
+pub fn bind_unix(
+    path: &Path,
+) -> Result<impl Stream<Item = io::Result<Connection<tokio::net::UnixStream>>>> {
+    if let Ok(metadata) = metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!("{:?} exists and is not a socket", path);
+        }
+        if UnixStream::connect(path).is_ok() {
+            bail!("{:?} is in use by another process", path);
+        }
+        remove_file(path).chain_err(|| format!("failed to remove stale socket {:?}", path))?;
+        info!("Removed stale socket {:?}", path);
+    }
+
+    let listener =
+        UnixListener::bind(path).chain_err(|| format!("failed to bind socket {:?}", path))?;
+    Ok(listener.map(|stream| Ok(Connection::new(stream?, None))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use lazy_static::lazy_static;
+    use tokio::{net::TcpStream, sync::Mutex};
+
+    lazy_static! {
+        // Held by the tests that accept connections, as they share the open connection count.
+        static ref ACCEPTING: Mutex<()> = Mutex::new(());
+    }
+
+    async fn accepting(
+        config: &TcpConfig,
+    ) -> (
+        SocketAddr,
+        impl Stream<Item = io::Result<Connection<tokio::net::TcpStream>>>,
+    ) {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        (address, bind_tcp(address, config).await.unwrap())
+    }
+
+    fn open_connections() -> i64 {
+        OPEN_CONNECTIONS.load(Ordering::Relaxed)
+    }
+
+    #[tokio::test]
+    async fn open_connections_are_counted_until_dropped() {
+        let _accepting = ACCEPTING.lock().await;
+        let (address, incoming) = accepting(&TcpConfig::default()).await;
+        tokio::pin!(incoming);
+        let before = open_connections();
+
+        let _first_client = TcpStream::connect(address).await.unwrap();
+        let first = incoming.next().await.unwrap().unwrap();
+        let _second_client = TcpStream::connect(address).await.unwrap();
+        let second = incoming.next().await.unwrap().unwrap();
+        assert_eq!(open_connections(), before + 2);
+        assert_eq!(first.remote_addr().unwrap().ip(), address.ip());
+
+        drop(first);
+        assert_eq!(open_connections(), before + 1);
+        drop(second);
+        assert_eq!(open_connections(), before);
+    }
+
+    #[tokio::test]
+    async fn accepted_sockets_get_the_configured_options() {
+        let _accepting = ACCEPTING.lock().await;
+        let config = TcpConfig {
+            nodelay: true,
+            keepalive_ms: 30_000,
+            ..TcpConfig::default()
+        };
+        let (address, incoming) = accepting(&config).await;
+        tokio::pin!(incoming);
+
+        let _client = TcpStream::connect(address).await.unwrap();
+        let connection = incoming.next().await.unwrap().unwrap();
+        assert!(connection.stream.nodelay().unwrap());
+        assert!(connection.stream.keepalive().unwrap().is_some());
+        drop(connection);
+
+        // Both are off by default.
+        let (address, incoming) = accepting(&TcpConfig::default()).await;
+        tokio::pin!(incoming);
+        let _client = TcpStream::connect(address).await.unwrap();
+        let connection = incoming.next().await.unwrap().unwrap();
+        assert!(!connection.stream.nodelay().unwrap());
+        assert_eq!(connection.stream.keepalive().unwrap(), None);
+    }
+}
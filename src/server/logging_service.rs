@@ -1,17 +1,26 @@
-use super::config::{LoggingConfig, LoggingTarget};
+use super::{
+    config::{
+        AuditConfig, LogFormat, LogRotationConfig, LoggingConfig, LoggingTarget,
+        LoggingTargetConfig,
+    },
+    machine_service::AuditedWrite,
+};
 use crate::{
     errors::*,
-    util::{do_and_die, ProfiledUnboundedReceiver, ProfiledUnboundedSender},
+    util::{do_and_die, hex, ProfiledUnboundedReceiver, ProfiledUnboundedSender},
 };
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, SecondsFormat, Utc};
 use crossbeam::channel::{unbounded, Receiver, Sender};
+use hmac_sha256::Hash;
 use lazy_static::lazy_static;
 use uuid::Uuid;
 
 use libc::STDERR_FILENO;
 use nix::unistd::dup;
 
+use serde::Serialize;
+
 use log::{Level, LevelFilter, Log, Metadata, Record};
 use metrics::gauge;
 
@@ -19,10 +28,13 @@ use tokio::sync::mpsc::error::TryRecvError;
 
 use std::{
     fmt::{self, Display},
-    fs::{File, OpenOptions},
-    io::{BufWriter, Write},
+    fs::{read_dir, remove_file, rename, File, OpenOptions},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
     os::unix::io::FromRawFd,
+    path::{Path, PathBuf},
+    sync::{OnceLock, RwLock},
     thread,
+    time::{Duration, Instant},
 };
 
 lazy_static! {
@@ -31,7 +43,20 @@ lazy_static! {
     static ref FASTLOG_RECEIVER: Receiver<FastlogRecord> = FASTLOG_CHANNEL.1.clone();
 }
 
+// Kept apart from the logger installed, so that its filter can be swapped on reload.
+static FACADE: OnceLock<LoggingServiceFacade> = OnceLock::new();
+
+// Only set if an audit target is configured.
+static AUDIT_SENDER: OnceLock<ProfiledUnboundedSender<LoggingServiceMessage>> = OnceLock::new();
+
 const DATETIME_FORMAT: &str = "%F %T%.3f";
+// Suffix of rotated log files; sorts in creation order.
+const ARCHIVE_SUFFIX_FORMAT: &str = "%Y%m%d-%H%M%S%.3f";
+// Bounds the time spent writing out queued messages before exiting; must stay well below the
+// grace period of fatal! and clean_exit, after which the process exits anyway.
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+// Length of the SHA-256 hashes chaining audit records.
+const AUDIT_HASH_LEN: usize = 32;
 
 #[derive(Debug)]
 enum ShutdownType {
@@ -39,16 +64,180 @@ enum ShutdownType {
     ExitZero,
 }
 
+// Formatted by the logging service, as each target may use its own format.
 #[derive(Debug)]
 pub struct LoggingServiceMessage {
-    text: String,
+    datetime: DateTime<Utc>,
     level: Level,
+    module: String,
+    text: String,
     shutdown: Option<ShutdownType>,
+    // Written to the audit target only.
+    audit: Option<AuditRecord>,
+    // New levels of the targets, in order; nothing is written.
+    levels: Option<Vec<(LevelFilter, Level)>>,
+}
+
+impl LoggingServiceMessage {
+    fn format_text(&self) -> String {
+        format!(
+            "{} [{}] {}: {}\n",
+            self.datetime.format(DATETIME_FORMAT),
+            self.level,
+            self.module,
+            self.text,
+        )
+    }
+
+    fn format_json(&self) -> String {
+        let record = JsonRecord {
+            timestamp: self.datetime.to_rfc3339_opts(SecondsFormat::Millis, true),
+            level: self.level.as_str(),
+            module: &self.module,
+            message: &self.text,
+        };
+        let mut line = serde_json::to_string(&record).expect("failed to serialize log record");
+        line.push('\n');
+        line
+    }
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: &'a str,
+    module: &'a str,
+    message: &'a str,
 }
 
 pub struct LoggingService {
     receiver: ProfiledUnboundedReceiver<LoggingServiceMessage>,
-    writers: Vec<(BufWriter<File>, LevelFilter)>,
+    targets: Vec<LogTarget>,
+    audit: Option<AuditLog>,
+}
+
+struct LogTarget {
+    writer: BufWriter<File>,
+    max_level: LevelFilter,
+    min_level: Level,
+    format: LogFormat,
+    // Only set for file targets that rotate.
+    rotation: Option<FileRotation>,
+}
+
+struct FileRotation {
+    path: PathBuf,
+    max_file_size: u64,
+    keep_files: usize,
+    file_size: u64,
+}
+
+impl LogTarget {
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        self.writer.write_all(line.as_bytes())?;
+
+        let rotation = match self.rotation {
+            Some(ref mut rotation) => rotation,
+            None => return Ok(()),
+        };
+        rotation.file_size += line.len() as u64;
+        if rotation.file_size < rotation.max_file_size {
+            return Ok(());
+        }
+
+        // The line that crossed the limit goes to the archive.
+        self.writer.flush()?;
+        let mut archive_name = rotation.path.clone().into_os_string();
+        archive_name.push(format!(".{}", Utc::now().format(ARCHIVE_SUFFIX_FORMAT)));
+        rename(&rotation.path, &archive_name)
+            .chain_err(|| format!("failed to rename {:?}", rotation.path))?;
+
+        let capacity = self.writer.capacity();
+        self.writer = BufWriter::with_capacity(capacity, open_log_file(&rotation.path)?);
+        rotation.file_size = 0;
+
+        remove_old_archives(&rotation.path, rotation.keep_files)
+    }
+}
+
+fn open_log_file(path: &Path) -> Result<File> {
+    let maybe_file = OpenOptions::new().append(true).create(true).open(path);
+    maybe_file.chain_err(|| format!("failed to open {:?}", path))
+}
+
+fn open_target(target: &LoggingTarget) -> Result<(File, Option<FileRotation>)> {
+    match target {
+        LoggingTarget::Stderr => {
+            let stderr_fd =
+                dup(STDERR_FILENO).chain_err(|| "failed to dup stderr file descriptor")?;
+            Ok((unsafe { File::from_raw_fd(stderr_fd) }, None))
+        }
+        LoggingTarget::File { path, rotation } => {
+            let file = open_log_file(Path::new(path))?;
+            let rotation = file_rotation(path, rotation, &file)?;
+            Ok((file, rotation))
+        }
+    }
+}
+
+fn file_rotation(
+    path: &str,
+    config: &LogRotationConfig,
+    file: &File,
+) -> Result<Option<FileRotation>> {
+    if config.max_file_size == 0 {
+        return Ok(None);
+    }
+    let metadata = file
+        .metadata()
+        .chain_err(|| format!("failed to stat {}", path))?;
+    Ok(Some(FileRotation {
+        path: PathBuf::from(path),
+        max_file_size: config.max_file_size,
+        keep_files: config.keep_files,
+        file_size: metadata.len(),
+    }))
+}
+
+fn remove_old_archives(path: &Path, keep_files: usize) -> Result<()> {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut prefix = path.file_name().unwrap_or_default().to_os_string();
+    prefix.push(".");
+    let prefix = prefix.to_string_lossy();
+
+    let mut archives = vec![];
+    let dir_entries =
+        read_dir(directory).chain_err(|| format!("failed to read directory {:?}", directory))?;
+    for entry in dir_entries {
+        let archive_path = entry.chain_err(|| "failed to resolve entry")?.path();
+        let is_archive = archive_path
+            .file_name()
+            .map(|name| name.to_string_lossy().starts_with(prefix.as_ref()))
+            .unwrap_or(false);
+        if is_archive {
+            archives.push(archive_path);
+        }
+    }
+
+    archives.sort();
+    let excess = archives.len().saturating_sub(keep_files);
+    for archive_path in &archives[..excess] {
+        remove_file(archive_path).chain_err(|| format!("failed to remove {:?}", archive_path))?;
+    }
+    Ok(())
+}
+
+// Most verbose and most severe levels written to the target.
+pub fn target_levels(config: &LoggingTargetConfig) -> Result<(LevelFilter, Level)> {
+    let max_level = LevelFilter::from(config.level);
+    let min_level = config.min_level.map_or(Level::Error, Level::from);
+    if min_level > max_level {
+        bail!("min_level of a logging target is more verbose than its level");
+    }
+    Ok((max_level, min_level))
 }
 
 impl LoggingService {
@@ -56,24 +245,32 @@ impl LoggingService {
         receiver: ProfiledUnboundedReceiver<LoggingServiceMessage>,
         config: &LoggingConfig,
     ) -> Result<Self> {
-        let mut writers = vec![];
+        let mut targets = vec![];
         for target_config in &config.targets {
-            let file = match &target_config.target {
-                LoggingTarget::Stderr => {
-                    let stderr_fd =
-                        dup(STDERR_FILENO).chain_err(|| "failed to dup stderr file descriptor")?;
-                    unsafe { File::from_raw_fd(stderr_fd) }
-                }
-                LoggingTarget::File { path } => {
-                    let maybe_file = OpenOptions::new().append(true).create(true).open(path);
-                    maybe_file.chain_err(|| format!("failed to open {}", path))?
-                }
-            };
-            let writer = BufWriter::with_capacity(config.buffer_size, file);
-            writers.push((writer, target_config.level.into()));
+            let (max_level, min_level) = target_levels(target_config)?;
+            let (file, rotation) = open_target(&target_config.target)?;
+            targets.push(LogTarget {
+                writer: BufWriter::with_capacity(config.buffer_size, file),
+                max_level,
+                min_level,
+                format: target_config.format,
+                rotation,
+            });
         }
 
-        Ok(Self { receiver, writers })
+        let audit = match &config.audit {
+            Some(audit_config) => Some(
+                AuditLog::open(audit_config, config.buffer_size)
+                    .chain_err(|| "failed to open audit target")?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            receiver,
+            targets,
+            audit,
+        })
     }
 
     pub async fn serve(&mut self) -> Result<()> {
@@ -92,47 +289,316 @@ impl LoggingService {
                     bail!("receiver is closed");
                 }
             };
-            if !message.text.is_empty() {
-                for (writer, filter) in self.writers.iter_mut() {
-                    if message.level <= *filter {
-                        writer
-                            .write(message.text.as_bytes())
-                            .chain_err(|| format!("failed to write message '{}'", message.text))?;
-                    }
-                }
+            if let Some(ref shutdown_type) = message.shutdown {
+                self.shut_down(&message)?;
+                let exit_code = match shutdown_type {
+                    ShutdownType::Abort => 1,
+                    ShutdownType::ExitZero => 0,
+                };
+                std::process::exit(exit_code);
             }
-            match message.shutdown {
-                Some(shutdown_type) => {
-                    self.flush().chain_err(|| "failed to flush writers")?;
-                    let exit_code = match shutdown_type {
-                        ShutdownType::Abort => 1,
-                        ShutdownType::ExitZero => 0,
-                    };
-                    std::process::exit(exit_code);
-                },
-                None => (),
+            self.write_message(&message)?;
+        }
+    }
+
+    // Writes out what was queued before the shutdown message, then the message itself, so
+    // that everything is on disk by the time the process exits.
+    fn shut_down(&mut self, message: &LoggingServiceMessage) -> Result<()> {
+        self.drain()?;
+        self.write_message(message)?;
+        self.flush().chain_err(|| "failed to flush writers")
+    }
+
+    fn write_message(&mut self, message: &LoggingServiceMessage) -> Result<()> {
+        if let Some(levels) = &message.levels {
+            for (target, &(max_level, min_level)) in self.targets.iter_mut().zip(levels) {
+                target.max_level = max_level;
+                target.min_level = min_level;
             }
+            return Ok(());
+        }
+        if let Some(record) = &message.audit {
+            return match self.audit {
+                Some(ref mut audit) => audit.write_record(record).chain_err(|| {
+                    format!("failed to write audit record of epoch {}", record.epoch)
+                }),
+                None => Ok(()),
+            };
+        }
+        if message.text.is_empty() {
+            return Ok(());
         }
+        let mut text = None;
+        let mut json = None;
+        for target in self.targets.iter_mut() {
+            if message.level > target.max_level || message.level < target.min_level {
+                continue;
+            }
+            let line = match target.format {
+                LogFormat::Text => text.get_or_insert_with(|| message.format_text()),
+                LogFormat::Json => json.get_or_insert_with(|| message.format_json()),
+            };
+            target
+                .write_line(line)
+                .chain_err(|| format!("failed to write message '{}'", message.text))?;
+        }
+        Ok(())
+    }
+
+    // Writes out the messages and fastlog records queued so far, so that the lines leading up
+    // to an exit are not lost. Fastlog workers are bypassed, as they may be gone already.
+    fn drain(&mut self) -> Result<()> {
+        let deadline = Instant::now() + SHUTDOWN_DRAIN_TIMEOUT;
+        while Instant::now() < deadline {
+            let message = match FASTLOG_RECEIVER.try_recv() {
+                Ok(record) => LoggingServiceMessage::from(record),
+                Err(_) => match self.receiver.try_recv() {
+                    Ok(message) => message,
+                    Err(_) => break,
+                },
+            };
+            self.write_message(&message)?;
+        }
+        Ok(())
     }
 
     fn flush(&mut self) -> Result<()> {
-        for (writer, _) in self.writers.iter_mut() {
-            writer.flush()?;
+        for target in self.targets.iter_mut() {
+            target.writer.flush()?;
+        }
+        if let Some(ref mut audit) = self.audit {
+            audit.target.writer.flush()?;
         }
         Ok(())
     }
 }
 
+// Records of persisted mutations. Each record is chained to the previous one by a SHA-256 hash
+// of the previous hash followed by the record line up to its own hash, so that records cannot
+// be altered, removed or reordered without breaking the chain after them. The chain continues
+// from the last record of an existing file, or starts from a hash of zeros.
+struct AuditLog {
+    target: LogTarget,
+    last_hash: [u8; AUDIT_HASH_LEN],
+}
+
+impl AuditLog {
+    fn open(config: &AuditConfig, buffer_size: usize) -> Result<Self> {
+        let (file, rotation) = open_target(&config.target)?;
+        let last_hash = match &config.target {
+            LoggingTarget::Stderr => [0; AUDIT_HASH_LEN],
+            LoggingTarget::File { path, .. } => last_audit_hash(Path::new(path), config.format)?,
+        };
+        let target = LogTarget {
+            writer: BufWriter::with_capacity(buffer_size, file),
+            max_level: LevelFilter::Trace,
+            min_level: Level::Error,
+            format: config.format,
+            rotation,
+        };
+        Ok(Self { target, last_hash })
+    }
+
+    fn write_record(&mut self, record: &AuditRecord) -> Result<()> {
+        let (prefix, suffix) = audit_hash_affixes(self.target.format);
+        let mut line = match self.target.format {
+            LogFormat::Text => record.format_text(),
+            LogFormat::Json => record.format_json(),
+        };
+
+        let mut hasher = Hash::new();
+        hasher.update(self.last_hash);
+        hasher.update(&line);
+        self.last_hash = hasher.finalize();
+
+        line.push_str(prefix);
+        line.push_str(&hex(&self.last_hash));
+        line.push_str(suffix);
+        self.target.write_line(&line)
+    }
+}
+
+// Text surrounding the hash at the end of an audit line.
+fn audit_hash_affixes(format: LogFormat) -> (&'static str, &'static str) {
+    match format {
+        LogFormat::Text => (" hash=", "\n"),
+        LogFormat::Json => (",\"hash\":\"", "\"}\n"),
+    }
+}
+
+fn last_audit_hash(path: &Path, format: LogFormat) -> Result<[u8; AUDIT_HASH_LEN]> {
+    let mut file = File::open(path).chain_err(|| format!("failed to open {:?}", path))?;
+    let file_len = file
+        .metadata()
+        .chain_err(|| format!("failed to stat {:?}", path))?
+        .len();
+    if file_len == 0 {
+        return Ok([0; AUDIT_HASH_LEN]);
+    }
+
+    let (prefix, suffix) = audit_hash_affixes(format);
+    let tail_len = (prefix.len() + AUDIT_HASH_LEN * 2 + suffix.len()) as u64;
+    let mut tail = vec![];
+    file.seek(SeekFrom::Start(file_len.saturating_sub(tail_len)))
+        .and_then(|_| file.read_to_end(&mut tail))
+        .chain_err(|| format!("failed to read {:?}", path))?;
+
+    let hash = tail
+        .strip_suffix(suffix.as_bytes())
+        .and_then(|rest| rest.strip_prefix(prefix.as_bytes()))
+        .and_then(parse_audit_hash);
+    hash.ok_or_else(|| format!("last line of {:?} does not end with an audit hash", path).into())
+}
+
+fn parse_audit_hash(hex: &[u8]) -> Option<[u8; AUDIT_HASH_LEN]> {
+    if hex.len() != AUDIT_HASH_LEN * 2 {
+        return None;
+    }
+    let mut hash = [0; AUDIT_HASH_LEN];
+    for (byte, digits) in hash.iter_mut().zip(hex.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+#[derive(Debug)]
+pub struct AuditRecord {
+    pub datetime: DateTime<Utc>,
+    pub epoch: u64,
+    pub id: Uuid,
+    pub writes: Vec<AuditedWrite>,
+}
+
+impl AuditRecord {
+    // Both formats leave out the hash, and JSON leaves out the closing brace after it.
+    fn format_text(&self) -> String {
+        let mut line = format!(
+            "{} epoch={} id={}",
+            self.datetime.format(DATETIME_FORMAT),
+            self.epoch,
+            self.id
+        );
+        for (index, write) in self.writes.iter().enumerate() {
+            line.push_str(if index == 0 { " " } else { ", " });
+            line.push_str(write.operation);
+            if !write.namespace.is_empty() {
+                line.push_str(&format!(" namespace=\"{}\"", escape(&write.namespace)));
+            }
+            line.push_str(&format!(
+                " key=\"{}\" value_len={}",
+                escape(&write.key),
+                write.value_len
+            ));
+        }
+        line
+    }
+
+    fn format_json(&self) -> String {
+        let writes = self
+            .writes
+            .iter()
+            .map(|write| JsonAuditedWrite {
+                operation: write.operation,
+                namespace: escape(&write.namespace),
+                key: escape(&write.key),
+                value_len: write.value_len,
+            })
+            .collect();
+        let record = JsonAuditRecord {
+            timestamp: self.datetime.to_rfc3339_opts(SecondsFormat::Millis, true),
+            epoch: self.epoch,
+            id: self.id.to_string(),
+            writes,
+        };
+        let mut line = serde_json::to_string(&record).expect("failed to serialize audit record");
+        line.pop();
+        line
+    }
+}
+
+#[derive(Serialize)]
+struct JsonAuditRecord {
+    timestamp: String,
+    epoch: u64,
+    id: String,
+    writes: Vec<JsonAuditedWrite>,
+}
+
+#[derive(Serialize)]
+struct JsonAuditedWrite {
+    operation: &'static str,
+    namespace: String,
+    key: String,
+    value_len: usize,
+}
+
+// Keys may be arbitrary bytes.
+fn escape(bytes: &[u8]) -> String {
+    let escaped: Vec<u8> = bytes
+        .iter()
+        .flat_map(|byte| std::ascii::escape_default(*byte))
+        .collect();
+    String::from_utf8(escaped).unwrap()
+}
+
+// Makes the journal service hand records of persisted mutations to the logging service.
+pub fn init_audit(sender: ProfiledUnboundedSender<LoggingServiceMessage>) {
+    AUDIT_SENDER.set(sender).ok(); // Ignore error
+}
+
+// Checked before building audit records, so that they cost nothing unless audit is enabled.
+pub fn audit_enabled() -> bool {
+    AUDIT_SENDER.get().is_some()
+}
+
+pub fn audit(record: AuditRecord) {
+    if let Some(sender) = AUDIT_SENDER.get() {
+        let message = LoggingServiceMessage {
+            datetime: record.datetime,
+            level: Level::Info,
+            module: String::new(),
+            text: String::new(),
+            shutdown: None,
+            audit: Some(record),
+            levels: None,
+        };
+        sender.send(message).expect("logging service is dead");
+    }
+}
+
 pub struct LoggingServiceFacade {
     sender: ProfiledUnboundedSender<LoggingServiceMessage>,
+    // Swapped on reload.
+    filter: RwLock<FacadeFilter>,
+}
+
+struct FacadeFilter {
     modules: Vec<String>,
     max_level: LevelFilter,
 }
 
+impl FacadeFilter {
+    fn new(config: &LoggingConfig) -> Self {
+        let max_level = config
+            .targets
+            .iter()
+            .map(|target| LevelFilter::from(target.level))
+            .max()
+            .unwrap_or(LevelFilter::Off);
+
+        let mut modules = config.modules.clone();
+        modules.push("abort".to_string());
+        modules.push("exit".to_string());
+
+        Self { modules, max_level }
+    }
+}
+
 impl Log for LoggingServiceFacade {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= self.max_level
-            && self
+        let filter = self.filter.read().unwrap();
+        metadata.level() <= filter.max_level
+            && filter
                 .modules
                 .iter()
                 .any(|module| metadata.target().starts_with(module))
@@ -140,22 +606,21 @@ impl Log for LoggingServiceFacade {
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            let text = format!(
-                "{} [{}] {}: {}\n",
-                Utc::now().format(DATETIME_FORMAT),
-                record.level(),
-                record.module_path().unwrap_or("unknown"),
-                record.args(),
-            );
-            let level = record.level();
             let shutdown = match record.metadata().target() {
                 "abort" => Some(ShutdownType::Abort),
                 "exit" => Some(ShutdownType::ExitZero),
                 _ => None,
             };
-            self.sender
-                .send(LoggingServiceMessage { text, level, shutdown })
-                .expect("logging service is dead");
+            let message = LoggingServiceMessage {
+                datetime: Utc::now(),
+                level: record.level(),
+                module: record.module_path().unwrap_or("unknown").to_string(),
+                text: record.args().to_string(),
+                shutdown,
+                audit: None,
+                levels: None,
+            };
+            self.sender.send(message).expect("logging service is dead");
         }
     }
 
@@ -167,27 +632,56 @@ impl LoggingServiceFacade {
         sender: ProfiledUnboundedSender<LoggingServiceMessage>,
         config: &LoggingConfig,
     ) -> Result<()> {
-        let max_level = config
-            .targets
-            .iter()
-            .map(|target| LevelFilter::from(target.level))
-            .max()
-            .unwrap_or(LevelFilter::Off);
-
-        let mut modules = config.modules.clone();
-        modules.push("abort".to_string());
-        modules.push("exit".to_string());
-
-        let facade = Box::new(LoggingServiceFacade {
+        let filter = FacadeFilter::new(config);
+        let max_level = filter.max_level;
+        let facade = LoggingServiceFacade {
             sender,
-            max_level,
-            modules,
-        });
-        log::set_boxed_logger(facade)
+            filter: RwLock::new(filter),
+        };
+        if FACADE.set(facade).is_err() {
+            bail!("logger is already set");
+        }
+        log::set_logger(FACADE.get().unwrap())
             .map(|_| log::set_max_level(max_level))
             .chain_err(|| "failed to set logger")
     }
 
+    // Swaps the levels of the targets and the modules logged. Targets are not reopened, so the
+    // config is expected to list the same targets as the one logging was initialized with.
+    pub fn reload(config: &LoggingConfig) -> Result<()> {
+        let levels = config
+            .targets
+            .iter()
+            .map(target_levels)
+            .collect::<Result<Vec<_>>>()?;
+        let facade = match FACADE.get() {
+            Some(facade) => facade,
+            None => bail!("logger is not set"),
+        };
+
+        // Messages sent from now on are filtered by the new levels of the targets.
+        let message = LoggingServiceMessage {
+            datetime: Utc::now(),
+            level: Level::Info,
+            module: module_path!().to_string(),
+            text: String::new(),
+            shutdown: None,
+            audit: None,
+            levels: Some(levels),
+        };
+        facade
+            .sender
+            .send(message)
+            .chain_err(|| "logging service is dead")?;
+
+        let filter = FacadeFilter::new(config);
+        log::set_max_level(filter.max_level);
+        *facade.filter.write().unwrap() = filter;
+        // Tracing caches whether each callsite is enabled, see SpanLogger::register_callsite.
+        tracing::callsite::rebuild_interest_cache();
+        Ok(())
+    }
+
     pub fn clean_exit() -> ! {
         info!(target: "exit", "");
         std::thread::sleep(std::time::Duration::from_secs(5));
@@ -210,14 +704,17 @@ pub struct FastlogRecord {
     pub message: FastlogMessage,
 }
 
-impl ToString for FastlogRecord {
-    fn to_string(&self) -> String {
-        format!(
-            "{} [DEBUG] {}: {}\n",
-            self.datetime.format(DATETIME_FORMAT),
-            self.module,
-            self.message,
-        )
+impl From<FastlogRecord> for LoggingServiceMessage {
+    fn from(record: FastlogRecord) -> Self {
+        Self {
+            datetime: record.datetime,
+            level: Level::Debug,
+            module: record.module.to_string(),
+            text: record.message.to_string(),
+            shutdown: None,
+            audit: None,
+            levels: None,
+        }
     }
 }
 
@@ -278,11 +775,7 @@ impl FastlogService {
 
     fn run(&mut self) -> Result<()> {
         for record in self.receiver.iter() {
-            let message = LoggingServiceMessage {
-                text: record.to_string(),
-                level: Level::Debug,
-                shutdown: None,
-            };
+            let message = LoggingServiceMessage::from(record);
             self.sender
                 .send(message)
                 .chain_err(|| "sender failed")?;
@@ -316,3 +809,195 @@ macro_rules! fastlog {
             .expect("fastlog sender failed")
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::config::LogLevel;
+    use crate::util::profiled_unbounded_channel;
+
+    use std::{fs, thread};
+
+    fn rotated_target(path: &Path, max_file_size: u64, keep_files: usize) -> LogTarget {
+        let target = LoggingTarget::File {
+            path: path.to_string_lossy().into_owned(),
+            rotation: LogRotationConfig {
+                max_file_size,
+                keep_files,
+            },
+        };
+        let (file, rotation) = open_target(&target).unwrap();
+        LogTarget {
+            writer: BufWriter::new(file),
+            max_level: LevelFilter::Trace,
+            min_level: Level::Error,
+            format: LogFormat::Text,
+            rotation,
+        }
+    }
+
+    // Contents of the active file and of the archives, oldest first.
+    fn log_files(directory: &Path) -> (String, Vec<String>) {
+        let mut archives: Vec<_> = read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| !path.ends_with("rayd.log"))
+            .collect();
+        archives.sort();
+        let archives = archives
+            .iter()
+            .map(|path| fs::read_to_string(path).unwrap())
+            .collect();
+        let active = fs::read_to_string(directory.join("rayd.log")).unwrap();
+        (active, archives)
+    }
+
+    #[test]
+    fn rotation_archives_the_full_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut target = rotated_target(&dir.path().join("rayd.log"), 10, 5);
+        target.write_line("first\n").unwrap();
+        target.write_line("second\n").unwrap();
+        target.write_line("third\n").unwrap();
+        target.writer.flush().unwrap();
+
+        let (active, archives) = log_files(dir.path());
+        assert_eq!(active, "third\n");
+        assert_eq!(archives, vec!["first\nsecond\n".to_string()]);
+    }
+
+    #[test]
+    fn rotation_keeps_the_newest_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut target = rotated_target(&dir.path().join("rayd.log"), 1, 2);
+        for line in &["1\n", "2\n", "3\n"] {
+            target.write_line(line).unwrap();
+            // Archives are named after the time of the rotation, to the millisecond.
+            thread::sleep(Duration::from_millis(2));
+        }
+
+        let (active, archives) = log_files(dir.path());
+        assert_eq!(active, "");
+        assert_eq!(archives, vec!["2\n".to_string(), "3\n".to_string()]);
+    }
+
+    fn message(level: Level, text: &str) -> LoggingServiceMessage {
+        LoggingServiceMessage {
+            datetime: Utc::now(),
+            level,
+            module: "ray::server".to_string(),
+            text: text.to_string(),
+            shutdown: None,
+            audit: None,
+            levels: None,
+        }
+    }
+
+    fn file_target(
+        path: &Path,
+        level: LogLevel,
+        min_level: Option<LogLevel>,
+    ) -> LoggingTargetConfig {
+        LoggingTargetConfig {
+            target: LoggingTarget::File {
+                path: path.to_string_lossy().into_owned(),
+                rotation: LogRotationConfig::default(),
+            },
+            level,
+            min_level,
+            format: LogFormat::Text,
+        }
+    }
+
+    #[test]
+    fn targets_only_get_the_levels_of_their_band() {
+        let dir = tempfile::tempdir().unwrap();
+        let (warn_path, all_path) = (dir.path().join("warn.log"), dir.path().join("all.log"));
+        let config = LoggingConfig {
+            targets: vec![
+                file_target(&warn_path, LogLevel::Warn, Some(LogLevel::Warn)),
+                file_target(&all_path, LogLevel::Debug, None),
+            ],
+            ..Default::default()
+        };
+        let (_sender, receiver) = profiled_unbounded_channel();
+        let mut service = LoggingService::new(receiver, &config).unwrap();
+        for &level in &[Level::Debug, Level::Info, Level::Warn, Level::Error] {
+            service
+                .write_message(&message(level, &level.to_string()))
+                .unwrap();
+        }
+        service.flush().unwrap();
+
+        let lines = |path: &Path| -> Vec<String> {
+            let text = fs::read_to_string(path).unwrap();
+            text.lines()
+                .map(|line| line.rsplit(' ').next().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(lines(&warn_path), vec!["WARN"]);
+        assert_eq!(lines(&all_path), vec!["DEBUG", "INFO", "WARN", "ERROR"]);
+    }
+
+    #[test]
+    fn queued_records_are_written_before_exiting() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rayd.log");
+        let config = LoggingConfig {
+            targets: vec![file_target(&path, LogLevel::Debug, None)],
+            ..Default::default()
+        };
+        let (sender, receiver) = profiled_unbounded_channel();
+        let mut service = LoggingService::new(receiver, &config).unwrap();
+
+        // Nothing forwards fastlog records here, as if the fastlog workers were gone.
+        let id = Uuid::new_v4();
+        for epoch in 1..=3 {
+            let message = FastlogMessage::PersistedMutation { epoch, id };
+            crate::fastlog!(message);
+        }
+        sender.send(message(Level::Info, "queued")).unwrap();
+        let mut shutdown = message(Level::Info, "exiting");
+        shutdown.shutdown = Some(ShutdownType::ExitZero);
+        service.shut_down(&shutdown).unwrap();
+
+        // Other tests may have queued fastlog records too.
+        let text = fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = text
+            .lines()
+            .filter(|line| line.contains(&id.to_string()) || !line.contains("(id: "))
+            .map(|line| line.split_once(": ").unwrap().1)
+            .collect();
+        let mut expected: Vec<_> = (1..=3)
+            .map(|epoch| FastlogMessage::PersistedMutation { epoch, id }.to_string())
+            .collect();
+        expected.push("queued".to_string());
+        expected.push("exiting".to_string());
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn inverted_band_is_rejected() {
+        let target = file_target(Path::new("rayd.log"), LogLevel::Warn, Some(LogLevel::Info));
+        assert!(target_levels(&target).is_err());
+    }
+
+    #[test]
+    fn json_records_are_parseable() {
+        let message = message(
+            Level::Warn,
+            "quoted \"text\"\nover two lines, with a tab\t and ünicode",
+        );
+        let line = message.format_json();
+        assert!(line.ends_with('\n'));
+        assert_eq!(line.matches('\n').count(), 1);
+
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(record["level"], "WARN");
+        assert_eq!(record["module"], "ray::server");
+        assert_eq!(record["message"], message.text.as_str());
+        let timestamp = record["timestamp"].as_str().unwrap();
+        assert!(DateTime::parse_from_rfc3339(timestamp).is_ok());
+    }
+}
@@ -1,51 +1,213 @@
-use super::{journal_service::JournalServiceRequest, logging_service::FastlogMessage};
+use super::{
+    config::{MachineServiceConfig, SnapshotServiceConfig},
+    journal_service::JournalServiceRequest,
+    logging_service::FastlogMessage,
+};
 
 use crate::{
     errors::*,
     fastlog,
-    util::{ProfiledReceiver, ProfiledSender, Traced},
+    util::{ProfiledReceiver, ProfiledSender, RequestTimings, Traced},
 };
 
 use prost::Message;
 
-use tokio::sync::oneshot;
+use tokio::sync::{broadcast, mpsc::error::TrySendError, oneshot};
+
+use metrics::{counter, gauge, timing};
 
-use metrics::{counter, gauge};
+use tracing::debug_span;
+use tracing_futures::Instrument;
 
 use std::{
     cmp,
-    collections::BinaryHeap,
+    collections::{BinaryHeap, VecDeque},
     fmt::{self, Debug, Display},
     io::{Read, Write},
     sync::{
         atomic::{self, AtomicU64},
         Arc,
     },
+    time::Instant,
 };
 
+// Snapshots are written from clones of the machine serving queries, taken between two of its
+// mutations, so cloning should be cheap: the storage machine shares its values with the clone.
 pub trait Machine: Default + Clone + Send + 'static {
     type Mutation: Message + Default + Clone + Display;
+    type Outcome: Send;
     type Query: Send;
     type Status: Send;
 
-    fn apply_mutation(&mut self, mutation: Self::Mutation);
+    // Must be deterministic: replicas and journal recovery apply the same mutations.
+    fn apply_mutation(&mut self, mutation: Self::Mutation) -> Self::Outcome;
     fn query_state(&self, query: Self::Query) -> Self::Status;
     fn write_snapshot<T: Write>(&self, writer: &mut T) -> Result<()>;
-    fn from_snapshot<T: Read>(reader: &mut T) -> Result<Self>;
+    // Snapshots of newer versions than SNAPSHOT_VERSION are rejected before they get here.
+    fn from_snapshot<T: Read>(reader: &mut T, version: u16) -> Result<Self>;
+
+    // Version of the layout written by write_snapshot and write_delta, recorded in every
+    // snapshot. Bump it whenever the layout changes in a way older versions would misread.
+    const SNAPSHOT_VERSION: u16 = 1;
+
+    // Applies mutations recovered from the journal, whose outcomes nobody waits for. Machines
+    // whose mutations of different keys commute may spread them over several threads.
+    fn apply_recovered(&mut self, mutations: Vec<Self::Mutation>, _threads: usize) {
+        for mutation in mutations {
+            self.apply_mutation(mutation);
+        }
+    }
+
+    // Called on the machine serving queries before it applies any mutations.
+    fn configure(&mut self, _config: &MachineServiceConfig) {}
+
+    // Called before the machine applies any mutations if it is to take snapshots, i.e. unless
+    // it is a read-only replica.
+    fn configure_snapshots(&mut self, _config: &SnapshotServiceConfig) {}
+
+    // Incremental snapshots are optional. A machine supporting them remembers what changed
+    // since tracking was enabled, and can write and apply these changes. Changes are only
+    // cleared once the snapshot of a clone is persisted, and only those the clone had, so
+    // that a failed snapshot loses none of them.
+    fn track_changes(&mut self) {}
+
+    // Number of changes made so far, to tell the changes a clone taken now has from those
+    // made after it.
+    fn change_count(&self) -> u64 {
+        0
+    }
+
+    // Forgets the changes among the first count made.
+    fn clear_changes(&mut self, _count: u64) {}
+
+    fn write_delta<T: Write>(&self, _writer: &mut T) -> Result<()> {
+        bail!("incremental snapshots are not supported by this machine")
+    }
+
+    fn apply_delta<T: Read>(&mut self, _reader: &mut T, _version: u16) -> Result<()> {
+        bail!("incremental snapshots are not supported by this machine")
+    }
+
+    // Encoding of mutations in the journal. Every journaled mutation records the codec it was
+    // written with, so the choice may change between restarts. Machines choosing Custom encode
+    // and decode mutations themselves, e.g. with bincode, which is more compact than protobuf
+    // for small fixed-shape mutations.
+    fn journal_codec() -> JournalCodec {
+        JournalCodec::Protobuf
+    }
+
+    fn encode_mutation(_mutation: &Self::Mutation, _buffer: &mut Vec<u8>) -> Result<()> {
+        bail!("custom journal codec is not supported by this machine")
+    }
+
+    fn decode_mutation(_data: &[u8]) -> Result<Self::Mutation> {
+        bail!("custom journal codec is not supported by this machine")
+    }
+
+    // Decodes the mutations of journals written before codecs were recorded, which hold the
+    // protobuf encoding of the mutation type of the time. Machines whose mutation type has
+    // changed since convert them here, or refuse them.
+    fn decode_legacy_mutation(data: &[u8]) -> Result<Self::Mutation> {
+        Self::Mutation::decode(data).map_err(Error::from)
+    }
+
+    // Keys a mutation writes, for the audit log. Mutations of machines that do not tell are
+    // audited by their epoch and id only.
+    fn audited_writes(_mutation: &Self::Mutation) -> Vec<AuditedWrite> {
+        vec![]
+    }
+
+    // Watching is optional. A watched machine records the keys each mutation changes, which
+    // are taken right after it is applied and sent to the subscribers of the Watch RPC.
+    fn watch_changes(&mut self, _watched: bool) {}
+
+    fn take_key_changes(&mut self) -> Vec<KeyChange> {
+        vec![]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalCodec {
+    Protobuf,
+    // Machine::encode_mutation and Machine::decode_mutation.
+    Custom,
+}
+
+// A key written by a mutation, as recorded in the audit log.
+#[derive(Debug)]
+pub struct AuditedWrite {
+    // Kind of the write, e.g. "set".
+    pub operation: &'static str,
+    // Empty for machines without namespaces.
+    pub namespace: Vec<u8>,
+    // Empty for writes to a whole namespace.
+    pub key: Vec<u8>,
+    // Bytes written to the key, e.g. the length of the value set or of the suffix appended.
+    pub value_len: usize,
+}
+
+// A key changed by a mutation, as sent to watchers.
+#[derive(Clone, Debug)]
+pub struct KeyChange {
+    // Empty for machines without namespaces.
+    pub namespace: Vec<u8>,
+    pub key: Vec<u8>,
+    // None if the key was removed.
+    pub value: Option<Arc<[u8]>>,
+}
+
+// Keys changed by the mutation at the epoch. Events are sent in epoch order, one per mutation
+// that changed any key.
+#[derive(Clone, Debug)]
+pub struct WatchEvent {
+    pub epoch: u64,
+    pub changes: Arc<Vec<KeyChange>>,
 }
 
 pub enum MachineServiceRequest<M: Machine> {
     Query {
         query: Traced<M::Query>,
         min_epoch: u64,
-        result: oneshot::Sender<M::Status>,
+        // Serve the state right after this epoch rather than the latest one.
+        at_epoch: Option<u64>,
+        // Queries still waiting for their epoch by then are dropped unserved.
+        deadline: Option<Instant>,
+        // Along with the epoch of the state the query was served at.
+        result: oneshot::Sender<Result<(M::Status, u64)>>,
     },
     Proposal {
         mutation: Traced<M::Mutation>,
         epoch: u64,
+        // None for mutations recovered from the journal.
+        result: Option<oneshot::Sender<(M::Outcome, u64)>>,
+    },
+    // Consecutive mutations recovered from the journal, the first one at the given epoch.
+    Recovered {
+        mutations: Vec<M::Mutation>,
+        epoch: u64,
+        threads: usize,
+    },
+    // A clone of the machine to write a snapshot from, at an epoch of at least min_epoch.
+    Snapshot {
+        min_epoch: u64,
+        // Start tracking changes, if not yet, for deltas to follow this snapshot.
+        track_changes: bool,
+        result: oneshot::Sender<SnapshotClone<M>>,
+    },
+    // The snapshot of a clone is persisted, so the next delta starts where the clone was
+    // taken.
+    ClearChanges {
+        change_count: u64,
     },
 }
 
+pub struct SnapshotClone<M> {
+    pub machine: M,
+    pub epoch: u64,
+    // Changes the clone has, see Machine::change_count.
+    pub change_count: u64,
+}
+
 // Only need Debug to make tokio::sync::mpsc::errors::SendError<_> implement Error.
 impl<M: Machine> Debug for MachineServiceRequest<M> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -55,57 +217,190 @@ impl<M: Machine> Debug for MachineServiceRequest<M> {
 
 #[derive(Clone)]
 pub struct MachineServiceHandle<M: Machine> {
-    journal_sender: ProfiledSender<JournalServiceRequest<M::Mutation>>,
+    // None on read-only replicas.
+    journal_sender: Option<ProfiledSender<JournalServiceRequest<M>>>,
     machine_sender: ProfiledSender<MachineServiceRequest<M>>,
     persisted_epoch: Arc<AtomicU64>,
+    reject_when_full: bool,
+    watch_sender: broadcast::Sender<WatchEvent>,
 }
 
 impl<M: Machine> MachineServiceHandle<M> {
     pub fn new(
-        journal_sender: ProfiledSender<JournalServiceRequest<M::Mutation>>,
+        journal_sender: Option<ProfiledSender<JournalServiceRequest<M>>>,
         machine_sender: ProfiledSender<MachineServiceRequest<M>>,
         persisted_epoch: Arc<AtomicU64>,
+        reject_when_full: bool,
+        watch_sender: broadcast::Sender<WatchEvent>,
     ) -> Self {
         Self {
             journal_sender,
             machine_sender,
             persisted_epoch,
+            reject_when_full,
+            watch_sender,
         }
     }
 
-    pub async fn apply_mutation(&mut self, mutation: Traced<M::Mutation>) -> Result<()> {
+    // Events of the mutations applied from now on. A subscriber falling more than the buffer
+    // behind misses the oldest events and is told how many it missed.
+    pub fn watch(&self) -> broadcast::Receiver<WatchEvent> {
+        self.watch_sender.subscribe()
+    }
+
+    pub fn persisted_epoch(&self) -> u64 {
+        self.persisted_epoch.load(atomic::Ordering::Acquire)
+    }
+
+    // Resolves once the mutation is persisted and applied by the serving replica.
+    pub async fn apply_mutation(&mut self, mutation: Traced<M::Mutation>) -> Result<M::Outcome> {
+        let (outcome, _) = self.apply_mutation_with_epoch(mutation).await?;
+        Ok(outcome)
+    }
+
+    // Also returns the epoch of the mutation, which is persisted by then.
+    pub async fn apply_mutation_with_epoch(
+        &mut self,
+        mutation: Traced<M::Mutation>,
+    ) -> Result<(M::Outcome, u64)> {
+        let journal_sender = match self.journal_sender {
+            Some(ref mut sender) => sender,
+            None => bail!(ErrorKind::ReadOnlyReplica),
+        };
+
         let (sender, receiver) = oneshot::channel();
-        let request = JournalServiceRequest {
+        let request = JournalServiceRequest::Mutation {
             mutation,
-            notify: sender,
+            result: sender,
         };
-        self.journal_sender
-            .send(request)
+        if self.reject_when_full {
+            match journal_sender.try_send(request) {
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) => {
+                    counter!("rayd.machine_service.rejected_mutation_count", 1);
+                    bail!(ErrorKind::QueueOverflow("journal request"));
+                }
+                Err(err) => return Err(err).chain_err(|| ErrorKind::ServiceUnavailable("journal")),
+            }
+        } else {
+            journal_sender
+                .send(request)
+                .await
+                .chain_err(|| ErrorKind::ServiceUnavailable("journal"))?;
+        }
+        receiver
             .await
-            .chain_err(|| "journal_sender failed")?;
-        receiver.await.chain_err(|| "sender dropped")
+            .chain_err(|| ErrorKind::ServiceUnavailable("journal"))
+    }
+
+    // Resolves with the persisted epoch once every mutation proposed before is persisted.
+    pub async fn sync(&mut self) -> Result<u64> {
+        let journal_sender = match self.journal_sender {
+            Some(ref mut sender) => sender,
+            None => bail!(ErrorKind::ReadOnlyReplica),
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        journal_sender
+            .send(JournalServiceRequest::Sync { result: sender })
+            .await
+            .chain_err(|| ErrorKind::ServiceUnavailable("journal"))?;
+        receiver
+            .await
+            .chain_err(|| ErrorKind::ServiceUnavailable("journal"))
     }
 
     pub async fn query_state(&mut self, query: Traced<M::Query>) -> Result<M::Status> {
-        let epoch = self.persisted_epoch.load(atomic::Ordering::Acquire);
+        let (status, _) = self.query_state_after(query, 0).await?;
+        Ok(status)
+    }
+
+    // Also returns the epoch of the state the query was served at, which is at least
+    // min_epoch on replicas: they wait to catch up with a write the primary acknowledged at
+    // that epoch. Every epoch a primary acknowledged is persisted, so a higher one is of no use
+    // there and is ignored rather than waited for.
+    pub async fn query_state_after(
+        &mut self,
+        query: Traced<M::Query>,
+        min_epoch: u64,
+    ) -> Result<(M::Status, u64)> {
+        let persisted_epoch = self.persisted_epoch();
+        let min_epoch = match self.journal_sender {
+            Some(_) => persisted_epoch,
+            None => cmp::max(persisted_epoch, min_epoch),
+        };
+        self.send_query(query, min_epoch, None).await
+    }
+
+    // Serves the query at exactly the given epoch, which must be persisted and still
+    // retained by the machine service.
+    pub async fn query_state_at(
+        &mut self,
+        query: Traced<M::Query>,
+        epoch: u64,
+    ) -> Result<M::Status> {
+        if epoch > self.persisted_epoch() {
+            bail!(ErrorKind::EpochUnavailable(epoch));
+        }
+        let (status, _) = self.send_query(query, epoch, Some(epoch)).await?;
+        Ok(status)
+    }
+
+    async fn send_query(
+        &mut self,
+        query: Traced<M::Query>,
+        min_epoch: u64,
+        at_epoch: Option<u64>,
+    ) -> Result<(M::Status, u64)> {
+        let deadline = query.deadline;
         let (sender, receiver) = oneshot::channel();
         let request = MachineServiceRequest::Query {
             query,
-            min_epoch: epoch,
+            min_epoch,
+            at_epoch,
+            deadline,
             result: sender,
         };
         self.machine_sender
             .send(request)
             .await
-            .chain_err(|| "machine_receiver dropped")?;
-        receiver.await.chain_err(|| "sender dropped")
+            .chain_err(|| ErrorKind::ServiceUnavailable("machine"))?;
+        match receiver.await {
+            Ok(status) => status,
+            // The machine service drops queries whose deadline has passed.
+            Err(_) if deadline_passed(deadline, Instant::now()) => {
+                bail!(ErrorKind::DeadlineExceeded)
+            }
+            Err(err) => Err(err).chain_err(|| ErrorKind::ServiceUnavailable("machine")),
+        }
     }
 }
 
 struct QueryPqItem<M: Machine> {
     query: M::Query,
     min_epoch: u64,
-    result: oneshot::Sender<M::Status>,
+    at_epoch: Option<u64>,
+    deadline: Option<Instant>,
+    // When the query reached the machine service, to time how long it waits in the queue.
+    received: Instant,
+    timings: Option<Arc<RequestTimings>>,
+    result: oneshot::Sender<Result<(M::Status, u64)>>,
+}
+
+impl<M: Machine> QueryPqItem<M> {
+    fn is_expired(&self, now: Instant) -> bool {
+        deadline_passed(self.deadline, now)
+    }
+
+    fn record_wait(&self, now: Instant) {
+        if let Some(timings) = &self.timings {
+            timings.record_query_wait(now.saturating_duration_since(self.received));
+        }
+    }
+}
+
+fn deadline_passed(deadline: Option<Instant>, now: Instant) -> bool {
+    matches!(deadline, Some(deadline) if deadline <= now)
 }
 
 impl<M: Machine> cmp::PartialEq for QueryPqItem<M> {
@@ -134,6 +429,23 @@ pub struct MachineService<M: Machine> {
     request_receiver: ProfiledReceiver<MachineServiceRequest<M>>,
     epoch: u64,
     query_queue: BinaryHeap<QueryPqItem<M>>,
+    max_pending_queries: usize,
+    // States right after the epochs preceding the current one, oldest first.
+    history: VecDeque<(u64, M)>,
+    retained_epochs: usize,
+    // Snapshot requests waiting for their min_epoch.
+    snapshot_requests: Vec<SnapshotItem<M>>,
+    tracking_changes: bool,
+    // Sending never blocks: the channel overwrites the oldest events once its buffer is full.
+    watch_sender: broadcast::Sender<WatchEvent>,
+    // Whether the machine is watched, i.e. there were subscribers at the last mutation.
+    watched: bool,
+}
+
+struct SnapshotItem<M: Machine> {
+    min_epoch: u64,
+    track_changes: bool,
+    result: oneshot::Sender<SnapshotClone<M>>,
 }
 
 impl<M: Machine> MachineService<M> {
@@ -141,12 +453,22 @@ impl<M: Machine> MachineService<M> {
         machine: M,
         request_receiver: ProfiledReceiver<MachineServiceRequest<M>>,
         epoch: u64,
+        max_pending_queries: usize,
+        retained_epochs: usize,
+        watch_sender: broadcast::Sender<WatchEvent>,
     ) -> Self {
         Self {
             machine,
             request_receiver,
             epoch,
             query_queue: BinaryHeap::new(),
+            max_pending_queries,
+            history: VecDeque::with_capacity(retained_epochs),
+            retained_epochs,
+            snapshot_requests: Vec::new(),
+            tracking_changes: false,
+            watch_sender,
+            watched: false,
         }
     }
 
@@ -162,18 +484,39 @@ impl<M: Machine> MachineService<M> {
                 .await
                 .chain_err(|| "request_receiver failed")?
             {
-                MachineServiceRequest::Proposal { mutation, epoch } => {
+                MachineServiceRequest::Proposal {
+                    mutation,
+                    epoch,
+                    result,
+                } => {
                     fastlog!(FastlogMessage::ApplyingMutation {
                         epoch: self.epoch + 1,
                         id: mutation.id
                     });
                     counter!("rayd.machine_service.proposal_count", 1);
-                    self.handle_proposal(mutation.into_payload(), epoch).await;
+                    let span = debug_span!("request", id = %mutation.id);
+                    self.handle_proposal(mutation.into_payload(), epoch, result)
+                        .instrument(span)
+                        .await;
+                    gauge!("rayd.machine_service.epoch", self.epoch as i64);
+                }
+                MachineServiceRequest::Recovered {
+                    mutations,
+                    epoch,
+                    threads,
+                } => {
+                    counter!(
+                        "rayd.machine_service.proposal_count",
+                        mutations.len() as u64
+                    );
+                    self.handle_recovered(mutations, epoch, threads);
                     gauge!("rayd.machine_service.epoch", self.epoch as i64);
                 }
                 MachineServiceRequest::Query {
                     query,
                     min_epoch,
+                    at_epoch,
+                    deadline,
                     result,
                 } => {
                     fastlog!(FastlogMessage::ServingQuery {
@@ -181,42 +524,540 @@ impl<M: Machine> MachineService<M> {
                         id: query.id
                     });
                     counter!("rayd.machine_service.query_count", 1);
-                    self.handle_query(query.into_payload(), min_epoch, result);
+                    let span = debug_span!("request", id = %query.id);
+                    let timings = query.timings.clone();
+                    let item = QueryPqItem {
+                        query: query.into_payload(),
+                        min_epoch,
+                        at_epoch,
+                        deadline,
+                        received: Instant::now(),
+                        timings,
+                        result,
+                    };
+                    span.in_scope(|| self.handle_query(item));
+                }
+                MachineServiceRequest::Snapshot {
+                    min_epoch,
+                    track_changes,
+                    result,
+                } => {
+                    self.snapshot_requests.push(SnapshotItem {
+                        min_epoch,
+                        track_changes,
+                        result,
+                    });
+                    self.serve_snapshot_requests();
+                }
+                MachineServiceRequest::ClearChanges { change_count } => {
+                    self.machine.clear_changes(change_count);
                 }
             }
         }
     }
 
-    async fn handle_proposal(&mut self, mutation: M::Mutation, epoch: u64) {
+    async fn handle_proposal(
+        &mut self,
+        mutation: M::Mutation,
+        epoch: u64,
+        result: Option<oneshot::Sender<(M::Outcome, u64)>>,
+    ) {
         assert_eq!(epoch, self.epoch + 1);
-        self.machine.apply_mutation(mutation);
+        if self.retained_epochs > 0 {
+            if self.history.len() == self.retained_epochs {
+                self.history.pop_front();
+            }
+            self.history.push_back((self.epoch, self.machine.clone()));
+        }
+        let outcome = self.apply_watched(mutation);
+
+        if let Some(result) = result {
+            result.send((outcome, self.epoch)).ok(); // Ignore error
+        }
+
+        self.serve_pending_queries();
+        self.serve_snapshot_requests();
+    }
+
+    // States in the middle of the batch are not retained for reads at an exact epoch. While
+    // watched, the mutations are applied one by one, so that every event gets its own epoch.
+    fn handle_recovered(&mut self, mutations: Vec<M::Mutation>, epoch: u64, threads: usize) {
+        assert_eq!(epoch, self.epoch + 1);
+        if self.update_watched() {
+            for mutation in mutations {
+                self.apply_watched(mutation);
+            }
+        } else {
+            let count = mutations.len() as u64;
+            self.machine.apply_recovered(mutations, threads);
+            self.epoch += count;
+        }
+        self.serve_pending_queries();
+        self.serve_snapshot_requests();
+    }
+
+    // Applies the mutation at the next epoch and tells the subscribers, if any, which keys
+    // it changed.
+    fn apply_watched(&mut self, mutation: M::Mutation) -> M::Outcome {
+        let watched = self.update_watched();
+        let outcome = self.machine.apply_mutation(mutation);
         self.epoch += 1;
 
+        if watched {
+            let changes = self.machine.take_key_changes();
+            if !changes.is_empty() {
+                let event = WatchEvent {
+                    epoch: self.epoch,
+                    changes: Arc::new(changes),
+                };
+                // Fails only if every subscriber has gone since.
+                self.watch_sender.send(event).ok();
+                counter!("rayd.machine_service.watch_event_count", 1);
+            }
+        }
+        outcome
+    }
+
+    // Watches the machine while there are subscribers.
+    fn update_watched(&mut self) -> bool {
+        let watched = self.watch_sender.receiver_count() > 0;
+        if watched != self.watched {
+            self.machine.watch_changes(watched);
+            self.watched = watched;
+        }
+        watched
+    }
+
+    fn serve_snapshot_requests(&mut self) {
+        if self.snapshot_requests.is_empty() {
+            return;
+        }
+        let epoch = self.epoch;
+        let (ready, waiting): (Vec<_>, Vec<_>) = self
+            .snapshot_requests
+            .drain(..)
+            .partition(|item| item.min_epoch <= epoch);
+        self.snapshot_requests = waiting;
+        for item in ready {
+            let start = Instant::now();
+            let machine = self.machine.clone();
+            timing!(
+                "rayd.machine_service.snapshot_clone_time",
+                start,
+                Instant::now()
+            );
+            // The first snapshot is a full one, so no changes need to be known before it.
+            if item.track_changes && !self.tracking_changes {
+                self.machine.track_changes();
+                self.tracking_changes = true;
+            }
+            let clone = SnapshotClone {
+                machine,
+                epoch,
+                change_count: self.machine.change_count(),
+            };
+            item.result.send(clone).ok(); // Ignore error
+        }
+    }
+
+    fn serve_pending_queries(&mut self) {
+        let now = Instant::now();
         while !self.query_queue.is_empty()
             && self.epoch >= self.query_queue.peek().unwrap().min_epoch
         {
-            let QueryPqItem { query, result, .. } = self.query_queue.pop().unwrap();
-            let status = self.machine.query_state(query);
-            result.send(status).ok();
+            let item = self.query_queue.pop().unwrap();
+            timing!("rayd.machine_service.query_wait_time", item.received, now);
+            item.record_wait(now);
+            if item.is_expired(now) {
+                // The client has given up on the result, so don't bother computing it.
+                counter!("rayd.machine_service.expired_query_count", 1);
+                continue;
+            }
+            item.result
+                .send(self.serve_query(item.query, item.at_epoch))
+                .ok();
         }
+
+        gauge!(
+            "rayd.machine_service.pending_queries",
+            self.query_queue.len() as i64
+        );
     }
 
-    fn handle_query(
-        &mut self,
-        query: M::Query,
-        min_epoch: u64,
-        result: oneshot::Sender<M::Status>,
-    ) {
-        if self.epoch >= min_epoch {
-            let status = self.machine.query_state(query);
-            result.send(status).ok();
+    fn handle_query(&mut self, item: QueryPqItem<M>) {
+        if item.is_expired(Instant::now()) {
+            counter!("rayd.machine_service.expired_query_count", 1);
+        } else if self.epoch >= item.min_epoch {
+            item.record_wait(Instant::now());
+            item.result
+                .send(self.serve_query(item.query, item.at_epoch))
+                .ok();
+        } else if self.query_queue.len() >= self.max_pending_queries {
+            // Reject the newcomer: queries already waiting are closer to being served.
+            counter!("rayd.machine_service.rejected_query_count", 1);
+            item.result
+                .send(Err(ErrorKind::QueueOverflow("pending query").into()))
+                .ok();
         } else {
-            let pq_item = QueryPqItem {
-                query,
-                min_epoch,
-                result,
-            };
-            self.query_queue.push(pq_item);
+            self.query_queue.push(item);
+            gauge!(
+                "rayd.machine_service.pending_queries",
+                self.query_queue.len() as i64
+            );
+        }
+    }
+
+    fn serve_query(&self, query: M::Query, at_epoch: Option<u64>) -> Result<(M::Status, u64)> {
+        let machine = match at_epoch {
+            Some(epoch) if epoch != self.epoch => {
+                match self
+                    .history
+                    .iter()
+                    .find(|(past_epoch, _)| *past_epoch == epoch)
+                {
+                    Some((_, machine)) => machine,
+                    None => bail!(ErrorKind::EpochUnavailable(epoch)),
+                }
+            }
+            _ => &self.machine,
+        };
+        let epoch = at_epoch.unwrap_or(self.epoch);
+        Ok((machine.query_state(query), epoch))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::{
+        kv_store::{HashStore, Value},
+        storage_machine::{storage_key, Query, Status, StorageMachine},
+    };
+
+    use crate::{
+        proto::{self, mutation::Kind},
+        util::profiled_channel,
+    };
+
+    use tokio::time;
+    use tonic::Code;
+
+    use std::time::Duration;
+
+    type TestMachine = StorageMachine<HashStore>;
+    type QueryResult = oneshot::Receiver<Result<(Status, u64)>>;
+
+    fn new_service(
+        max_pending_queries: usize,
+        retained_epochs: usize,
+    ) -> MachineService<TestMachine> {
+        let (_, receiver) = profiled_channel(1);
+        let (watch_sender, _) = broadcast::channel(1);
+        MachineService::new(
+            TestMachine::default(),
+            receiver,
+            0,
+            max_pending_queries,
+            retained_epochs,
+            watch_sender,
+        )
+    }
+
+    fn set(key: &[u8], value: &[u8]) -> proto::Mutation {
+        proto::Mutation {
+            kind: Some(Kind::Set(proto::SetRequest {
+                key: key.to_vec(),
+                value: value.to_vec(),
+                ..Default::default()
+            })),
+        }
+    }
+
+    async fn apply(service: &mut MachineService<TestMachine>, mutation: proto::Mutation) {
+        let epoch = service.epoch + 1;
+        service.handle_proposal(mutation, epoch, None).await;
+    }
+
+    fn query(
+        service: &mut MachineService<TestMachine>,
+        query: Query,
+        min_epoch: u64,
+        at_epoch: Option<u64>,
+    ) -> QueryResult {
+        query_until(service, query, min_epoch, at_epoch, None)
+    }
+
+    fn query_until(
+        service: &mut MachineService<TestMachine>,
+        query: Query,
+        min_epoch: u64,
+        at_epoch: Option<u64>,
+        deadline: Option<Instant>,
+    ) -> QueryResult {
+        submit(service, query, min_epoch, at_epoch, deadline, None)
+    }
+
+    fn submit(
+        service: &mut MachineService<TestMachine>,
+        query: Query,
+        min_epoch: u64,
+        at_epoch: Option<u64>,
+        deadline: Option<Instant>,
+        timings: Option<Arc<RequestTimings>>,
+    ) -> QueryResult {
+        let (result, receiver) = oneshot::channel();
+        service.handle_query(QueryPqItem {
+            query,
+            min_epoch,
+            at_epoch,
+            deadline,
+            received: Instant::now(),
+            timings,
+            result,
+        });
+        receiver
+    }
+
+    fn get(
+        service: &mut MachineService<TestMachine>,
+        key: &[u8],
+        min_epoch: u64,
+        at_epoch: Option<u64>,
+    ) -> QueryResult {
+        let key = storage_key(&[], key.to_vec());
+        query(service, Query::Get(key), min_epoch, at_epoch)
+    }
+
+    fn exists(
+        service: &mut MachineService<TestMachine>,
+        key: &[u8],
+        min_epoch: u64,
+        at_epoch: Option<u64>,
+    ) -> QueryResult {
+        let key = storage_key(&[], key.to_vec());
+        query(service, Query::Exists(key), min_epoch, at_epoch)
+    }
+
+    fn exists_of(result: Result<(Status, u64)>) -> (bool, u64) {
+        match result.unwrap() {
+            (Status::Exists(exists), epoch) => (exists, epoch),
+            (status, _) => panic!("unexpected status: {:?}", status),
+        }
+    }
+
+    fn value_of(result: Result<(Status, u64)>) -> (Option<Vec<u8>>, u64) {
+        match result.unwrap() {
+            (Status::Value(value), epoch) => (value.map(|value| value.to_vec()), epoch),
+            (status, _) => panic!("unexpected status: {:?}", status),
+        }
+    }
+
+    #[tokio::test]
+    async fn queries_over_the_pending_limit_are_rejected() {
+        let mut service = new_service(1, 0);
+        let waiting = get(&mut service, b"key", 1, None);
+        let mut rejected = get(&mut service, b"key", 1, None);
+        let err = rejected.try_recv().unwrap().unwrap_err();
+        assert_eq!(tonic::Status::from(err).code(), Code::ResourceExhausted);
+
+        apply(&mut service, set(b"key", b"value")).await;
+        assert_eq!(
+            value_of(waiting.await.unwrap()),
+            (Some(b"value".to_vec()), 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn queries_expired_while_queued_are_dropped() {
+        let mut service = new_service(10, 0);
+        let key = || Query::Get(storage_key(&[], b"key".to_vec()));
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let expiring = query_until(&mut service, key(), 1, None, Some(deadline));
+        let lasting = query_until(&mut service, key(), 1, None, None);
+        assert_eq!(service.query_queue.len(), 2);
+
+        time::delay_for(Duration::from_millis(50)).await;
+        apply(&mut service, set(b"key", b"value")).await;
+        // Served queries send their result, so the expired one was never run.
+        expiring.await.unwrap_err();
+        assert_eq!(
+            value_of(lasting.await.unwrap()),
+            (Some(b"value".to_vec()), 1)
+        );
+        assert!(service.query_queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn queries_record_how_long_they_waited() {
+        let mut service = new_service(10, 0);
+        let key = || Query::Get(storage_key(&[], b"key".to_vec()));
+        let queued_timings = Arc::new(RequestTimings::new(Instant::now()));
+        let timings = Some(queued_timings.clone());
+        let queued = submit(&mut service, key(), 1, None, None, timings);
+
+        time::delay_for(Duration::from_millis(20)).await;
+        apply(&mut service, set(b"key", b"value")).await;
+        queued.await.unwrap().unwrap();
+        let queued_wait = queued_timings.query_wait().unwrap();
+        assert!(
+            queued_wait >= Duration::from_millis(20),
+            "{:?}",
+            queued_wait
+        );
+
+        // A query for a reached epoch is served right away.
+        let served_timings = Arc::new(RequestTimings::new(Instant::now()));
+        let timings = Some(served_timings.clone());
+        let served = submit(&mut service, key(), 1, None, None, timings);
+        served.await.unwrap().unwrap();
+        assert!(served_timings.query_wait().unwrap() < queued_wait);
+    }
+
+    #[tokio::test]
+    async fn mutations_are_rejected_while_the_journal_queue_is_full() {
+        let (mut journal_sender, _journal_receiver) = profiled_channel(1);
+        let (machine_sender, _) = profiled_channel(1);
+        let (watch_sender, _) = broadcast::channel(1);
+        let mut handle: MachineServiceHandle<TestMachine> = MachineServiceHandle::new(
+            Some(journal_sender.clone()),
+            machine_sender,
+            Arc::new(AtomicU64::new(0)),
+            true,
+            watch_sender,
+        );
+        let (result, _) = oneshot::channel();
+        journal_sender
+            .send(JournalServiceRequest::Sync { result })
+            .await
+            .unwrap();
+
+        let err = handle
+            .apply_mutation(Traced::new(set(b"key", b"value")))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Status::from(err).code(), Code::ResourceExhausted);
+    }
+
+    #[tokio::test]
+    async fn exists_tells_whether_the_key_was_set_by_the_epoch() {
+        let mut service = new_service(10, 2);
+        let absent = exists(&mut service, b"key", 0, None);
+        assert_eq!(exists_of(absent.await.unwrap()), (false, 0));
+
+        let waiting = exists(&mut service, b"key", 2, None);
+        apply(&mut service, set(b"other", b"value")).await;
+        apply(&mut service, set(b"key", b"value")).await;
+        assert_eq!(exists_of(waiting.await.unwrap()), (true, 2));
+
+        let before = exists(&mut service, b"key", 0, Some(1));
+        assert_eq!(exists_of(before.await.unwrap()), (false, 1));
+    }
+
+    #[tokio::test]
+    async fn reads_at_a_retained_epoch_see_its_state() {
+        let mut service = new_service(10, 2);
+        for value in &[b"1", b"2", b"3"] {
+            apply(&mut service, set(b"key", *value)).await;
+        }
+
+        for &epoch in &[1, 2, 3] {
+            let value = epoch.to_string().into_bytes();
+            let past = get(&mut service, b"key", 0, Some(epoch));
+            assert_eq!(value_of(past.await.unwrap()), (Some(value), epoch));
+        }
+        let mut dropped = get(&mut service, b"key", 0, Some(0));
+        match dropped.try_recv().unwrap() {
+            Err(Error(ErrorKind::EpochUnavailable(0), _)) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    fn stored_value(machine: &TestMachine, key: &[u8]) -> Option<Value> {
+        match machine.query_state(Query::Get(storage_key(&[], key.to_vec()))) {
+            Status::Value(value) => value,
+            status => panic!("unexpected status: {:?}", status),
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshot_clones_hold_the_state_at_their_epoch() {
+        let (mut sender, receiver) = profiled_channel(100);
+        let (watch_sender, _) = broadcast::channel(1);
+        let mut service =
+            MachineService::new(TestMachine::default(), receiver, 0, 10, 0, watch_sender);
+        tokio::spawn(async move { service.serve().await });
+        let key = |epoch: u64| format!("key{}", epoch).into_bytes();
+        let proposal = |epoch: u64| MachineServiceRequest::Proposal {
+            mutation: Traced::new(set(&key(epoch), &[epoch as u8; 1000])),
+            epoch,
+            result: None,
+        };
+
+        // The request is queued behind proposals still to be applied, and served right after
+        // the one of its epoch.
+        for epoch in 1..=3 {
+            sender.send(proposal(epoch)).await.unwrap();
+        }
+        let (result, first) = oneshot::channel();
+        let request = MachineServiceRequest::Snapshot {
+            min_epoch: 5,
+            track_changes: true,
+            result,
+        };
+        sender.send(request).await.unwrap();
+        for epoch in 4..=8 {
+            sender.send(proposal(epoch)).await.unwrap();
+        }
+        let first = first.await.unwrap();
+        assert_eq!(first.epoch, 5);
+        for epoch in 1..=8 {
+            let value = stored_value(&first.machine, &key(epoch));
+            assert_eq!(value.is_some(), epoch <= 5, "epoch {}", epoch);
+        }
+
+        // Only the keys are copied: the clone shares its values with the serving machine.
+        let (result, served) = oneshot::channel();
+        let request = MachineServiceRequest::Query {
+            query: Traced::new(Query::Get(storage_key(&[], key(1)))),
+            min_epoch: 8,
+            at_epoch: None,
+            deadline: None,
+            result,
+        };
+        sender.send(request).await.unwrap();
+        let served = match served.await.unwrap().unwrap() {
+            (Status::Value(Some(value)), 8) => value,
+            result => panic!("unexpected result: {:?}", result),
+        };
+        let cloned = stored_value(&first.machine, &key(1)).unwrap();
+        assert!(Arc::ptr_eq(&served, &cloned));
+
+        // Once the snapshot of the first clone is persisted, a delta holds the later changes.
+        let change_count = first.change_count;
+        let request = MachineServiceRequest::ClearChanges { change_count };
+        sender.send(request).await.unwrap();
+        let (result, second) = oneshot::channel();
+        let request = MachineServiceRequest::Snapshot {
+            min_epoch: 8,
+            track_changes: true,
+            result,
+        };
+        sender.send(request).await.unwrap();
+        let second = second.await.unwrap();
+        assert_eq!(second.epoch, 8);
+        let mut delta = vec![];
+        second.machine.write_delta(&mut delta).unwrap();
+        let version = TestMachine::SNAPSHOT_VERSION;
+        let mut changes = TestMachine::default();
+        changes.apply_delta(&mut &delta[..], version).unwrap();
+        let mut restored = first.machine;
+        restored.apply_delta(&mut &delta[..], version).unwrap();
+        for epoch in 1..=8 {
+            let change = stored_value(&changes, &key(epoch));
+            assert_eq!(change.is_some(), epoch > 5, "epoch {}", epoch);
+            let value = stored_value(&restored, &key(epoch));
+            assert_eq!(value, stored_value(&second.machine, &key(epoch)));
         }
     }
 }
@@ -0,0 +1,82 @@
+use super::{
+    journal_service::{JournalReader, JournalWriter, ReadResult},
+    snapshot_service::{PersistentWrite, SnapshotChain, SnapshotKind, SnapshotStorage},
+};
+
+use crate::errors::*;
+
+use std::io::{self, Cursor, Write};
+
+// Stand-ins for the journal and snapshot storage when nothing is persisted: writes are
+// discarded and there is never anything to recover.
+
+pub struct NullJournalReader;
+
+impl JournalReader for NullJournalReader {
+    type Writer = NullJournalWriter;
+
+    fn read_blob(self) -> Result<ReadResult<Self, Self::Writer>> {
+        Ok(ReadResult::End(NullJournalWriter))
+    }
+
+    fn first_epoch(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+pub struct NullJournalWriter;
+
+impl JournalWriter for NullJournalWriter {
+    fn append_blob(&mut self, _blob: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    fn persist(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_blob_count(&self) -> usize {
+        0
+    }
+
+    fn dispose_oldest_blobs(&mut self, _blob_count: usize) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct NullSnapshotWriter;
+
+impl Write for NullSnapshotWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl PersistentWrite for NullSnapshotWriter {
+    fn persist(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct NullSnapshotStorage;
+
+impl SnapshotStorage for NullSnapshotStorage {
+    type Writer = NullSnapshotWriter;
+    type Reader = Cursor<Vec<u8>>;
+
+    fn create_snapshot(&mut self, _name: &str, _kind: SnapshotKind) -> Result<Self::Writer> {
+        Ok(NullSnapshotWriter)
+    }
+
+    fn open_snapshot(&self, _age: usize) -> Result<Option<SnapshotChain<Self::Reader>>> {
+        Ok(None)
+    }
+
+    fn dispose_old_snapshots(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,555 @@
+use super::{
+    config::SnapshotStorageConfig,
+    object_store::ObjectStoreClient,
+    snapshot_service::{
+        parse_snapshot_epoch, PersistentWrite, SnapshotChain, SnapshotKind, SnapshotStorage,
+    },
+};
+
+use crate::errors::*;
+
+use chrono::Utc;
+
+use std::{
+    io::{self, Cursor, Write},
+    mem,
+};
+
+// S3 rejects multipart uploads with parts smaller than this, except for the last one.
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+struct MultipartUpload {
+    id: String,
+    etags: Vec<String>,
+}
+
+// Uploads the snapshot in parts as the buffer fills up. The object only becomes visible
+// once it is persisted; an unpersisted upload is aborted when the writer is dropped.
+pub struct ObjectWriter {
+    client: ObjectStoreClient,
+    key: String,
+    part_size: usize,
+    buffer: Vec<u8>,
+    upload: Option<MultipartUpload>,
+}
+
+impl ObjectWriter {
+    fn upload_part(&mut self) -> Result<()> {
+        let upload = match self.upload {
+            Some(ref mut upload) => upload,
+            None => {
+                let id = self.client.create_multipart_upload(&self.key)?;
+                self.upload
+                    .get_or_insert(MultipartUpload { id, etags: vec![] })
+            }
+        };
+        let data = mem::replace(&mut self.buffer, Vec::with_capacity(self.part_size));
+        let etag = self
+            .client
+            .upload_part(&self.key, &upload.id, upload.etags.len() + 1, data)?;
+        upload.etags.push(etag);
+        Ok(())
+    }
+}
+
+impl Write for ObjectWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() >= self.part_size {
+            self.upload_part()
+                .map_err(|err| io::Error::other(err.to_string()))?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl PersistentWrite for ObjectWriter {
+    fn persist(&mut self) -> Result<()> {
+        if self.upload.is_none() {
+            let data = mem::take(&mut self.buffer);
+            return self.client.put_object(&self.key, data);
+        }
+
+        if !self.buffer.is_empty() {
+            self.upload_part()?;
+        }
+        let upload = self.upload.take().unwrap();
+        self.client
+            .complete_multipart_upload(&self.key, &upload.id, &upload.etags)
+    }
+}
+
+impl Drop for ObjectWriter {
+    fn drop(&mut self) {
+        if let Some(ref upload) = self.upload {
+            if let Err(err) = self.client.abort_multipart_upload(&self.key, &upload.id) {
+                warn!(
+                    "Failed to abort snapshot upload of {} (error chain below)\n{}",
+                    self.key,
+                    err.display_fancy_chain()
+                );
+            }
+        }
+    }
+}
+
+// Epoch of the snapshot and its key.
+type SnapshotObject = (u64, String);
+
+pub struct ObjectStoreSnapshotStorage {
+    client: ObjectStoreClient,
+    prefix: String,
+    part_size: usize,
+    keep_snapshots: usize,
+}
+
+impl ObjectStoreSnapshotStorage {
+    pub fn new(config: &SnapshotStorageConfig) -> Result<Self> {
+        let object_store = &config.object_store;
+        let client = ObjectStoreClient::new(object_store)?;
+
+        let mut prefix = object_store.prefix.clone();
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+
+        Ok(Self {
+            client,
+            prefix,
+            part_size: object_store.part_size.max(MIN_PART_SIZE),
+            keep_snapshots: config.keep_snapshots,
+        })
+    }
+
+    // Returns full snapshots and deltas, both sorted from oldest to newest.
+    fn list_snapshots(&self) -> Result<(Vec<SnapshotObject>, Vec<SnapshotObject>)> {
+        let keys = self
+            .client
+            .list_objects(&self.prefix)
+            .chain_err(|| format!("failed to list objects with prefix {:?}", self.prefix))?;
+        let mut full = vec![];
+        let mut deltas = vec![];
+        for key in keys {
+            // Objects under nested prefixes belong to someone else.
+            if key[self.prefix.len()..].contains('/') {
+                continue;
+            }
+            for (kind, objects) in [
+                (SnapshotKind::Full, &mut full),
+                (SnapshotKind::Delta, &mut deltas),
+            ] {
+                if !key.ends_with(extension(kind)) {
+                    continue;
+                }
+                match parse_snapshot_epoch(&key[self.prefix.len()..], extension(kind)) {
+                    Some(epoch) => objects.push((epoch, key.clone())),
+                    None => warn!("Ignoring snapshot object with no epoch in its key: {}", key),
+                }
+            }
+        }
+        full.sort();
+        deltas.sort();
+        Ok((full, deltas))
+    }
+
+    fn download(&self, key: &str) -> Result<Cursor<Vec<u8>>> {
+        let data = self
+            .client
+            .get_object(key)
+            .chain_err(|| format!("failed to download snapshot {:?}", key))?;
+        Ok(Cursor::new(data))
+    }
+}
+
+fn extension(kind: SnapshotKind) -> &'static str {
+    match kind {
+        SnapshotKind::Full => ".snap",
+        SnapshotKind::Delta => ".delta",
+    }
+}
+
+impl SnapshotStorage for ObjectStoreSnapshotStorage {
+    type Writer = ObjectWriter;
+    // Snapshots are downloaded in full, as reading them needs to seek.
+    type Reader = Cursor<Vec<u8>>;
+
+    fn create_snapshot(&mut self, name: &str, kind: SnapshotKind) -> Result<Self::Writer> {
+        let key = format!(
+            "{}{}_{}{}",
+            self.prefix,
+            Utc::now().format("%Y%m%dT%H%M%S%.6fZ"),
+            name,
+            extension(kind)
+        );
+        debug!("Creating snapshot object: {}", key);
+
+        Ok(ObjectWriter {
+            client: self.client.clone(),
+            key,
+            part_size: self.part_size,
+            buffer: Vec::with_capacity(self.part_size),
+            upload: None,
+        })
+    }
+
+    fn open_snapshot(&self, age: usize) -> Result<Option<SnapshotChain<Self::Reader>>> {
+        let (full, deltas) = self.list_snapshots()?;
+        if age >= full.len() {
+            return Ok(None);
+        }
+
+        let index = full.len() - 1 - age;
+        let (epoch, key) = &full[index];
+        let next = full.get(index + 1).map(|(epoch, _)| *epoch);
+        debug!("Snapshot found (age: {}): {}", age, key);
+        let base = self.download(key)?;
+
+        let deltas = deltas
+            .iter()
+            .filter(|(delta_epoch, _)| {
+                delta_epoch > epoch && next.iter().all(|next| delta_epoch < next)
+            })
+            .map(|(_, delta)| {
+                debug!("Snapshot delta found: {}", delta);
+                self.download(delta)
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Some(SnapshotChain { base, deltas }))
+    }
+
+    fn dispose_old_snapshots(&mut self) -> Result<()> {
+        if self.keep_snapshots == 0 {
+            return Ok(());
+        }
+
+        let (full, deltas) = self.list_snapshots()?;
+        if full.len() <= self.keep_snapshots {
+            return Ok(());
+        }
+
+        // Deltas are only useful on top of a full snapshot that is kept.
+        let (oldest_kept, _) = full[full.len() - self.keep_snapshots];
+        let disposed = full[..full.len() - self.keep_snapshots]
+            .iter()
+            .chain(deltas.iter().filter(|(epoch, _)| *epoch < oldest_kept));
+
+        for (_, key) in disposed {
+            self.client
+                .delete_object(key)
+                .chain_err(|| format!("failed to delete {:?}", key))?;
+            debug!("Removed snapshot object: {}", key);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::server::{
+        config::{ObjectStoreConfig, SnapshotBackend},
+        object_store::xml_values,
+    };
+
+    use hyper::{
+        body,
+        service::{make_service_fn, service_fn},
+        Body, Method, Request, Response, Server, StatusCode,
+    };
+    use tokio::runtime;
+
+    use std::{
+        collections::{BTreeMap, HashMap},
+        convert::Infallible,
+        fmt::Write as _,
+        net::SocketAddr,
+        sync::{mpsc, Arc, Mutex},
+        thread,
+    };
+
+    // Small enough for the listings of the tests to take several pages.
+    const LIST_PAGE_SIZE: usize = 2;
+
+    // Stand-in for an S3 bucket, keeping the objects in memory. Signatures are not checked.
+    #[derive(Clone, Default)]
+    struct MockBucket {
+        objects: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+        // Parts of the multipart uploads in progress, by upload id.
+        uploads: Arc<Mutex<HashMap<String, Vec<Vec<u8>>>>>,
+        // Part counts of the completed multipart uploads.
+        completed_uploads: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl MockBucket {
+        // Serves the bucket on a thread of its own and returns its address.
+        fn serve(&self) -> SocketAddr {
+            let bucket = self.clone();
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || {
+                let mut runtime = runtime::Builder::new()
+                    .basic_scheduler()
+                    .enable_all()
+                    .build()
+                    .unwrap();
+                runtime.block_on(async move {
+                    let make_service = make_service_fn(move |_| {
+                        let bucket = bucket.clone();
+                        async move {
+                            Ok::<_, Infallible>(service_fn(move |request| {
+                                let bucket = bucket.clone();
+                                async move { Ok::<_, Infallible>(bucket.respond(request).await) }
+                            }))
+                        }
+                    });
+                    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+                    sender.send(server.local_addr()).unwrap();
+                    server.await.unwrap();
+                });
+            });
+            receiver.recv().unwrap()
+        }
+
+        fn insert(&self, key: &str, data: &[u8]) {
+            self.objects
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), data.to_vec());
+        }
+
+        fn keys(&self) -> Vec<String> {
+            self.objects.lock().unwrap().keys().cloned().collect()
+        }
+
+        async fn respond(&self, request: Request<Body>) -> Response<Body> {
+            let method = request.method().clone();
+            // Buckets are addressed path-style: /<bucket>/<key>.
+            let path = decode(request.uri().path());
+            let key = path.splitn(3, '/').nth(2).unwrap_or_default().to_string();
+            let query: HashMap<_, _> = request
+                .uri()
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    (decode(name), decode(value))
+                })
+                .collect();
+            let data = body::to_bytes(request.into_body()).await.unwrap().to_vec();
+
+            let mut objects = self.objects.lock().unwrap();
+            let mut uploads = self.uploads.lock().unwrap();
+            match (method, query.get("uploadId")) {
+                (Method::GET, None) if query.contains_key("list-type") => {
+                    let prefix = query.get("prefix").cloned().unwrap_or_default();
+                    let start = query
+                        .get("continuation-token")
+                        .map_or(0, |token| token.parse().unwrap());
+                    let keys: Vec<_> = objects
+                        .keys()
+                        .filter(|key| key.starts_with(&prefix))
+                        .collect();
+                    let mut xml = String::from("<ListBucketResult>");
+                    for key in keys.iter().skip(start).take(LIST_PAGE_SIZE) {
+                        write!(xml, "<Contents><Key>{}</Key></Contents>", key).unwrap();
+                    }
+                    let next = start + LIST_PAGE_SIZE;
+                    if next < keys.len() {
+                        write!(xml, "<IsTruncated>true</IsTruncated>").unwrap();
+                        write!(
+                            xml,
+                            "<NextContinuationToken>{}</NextContinuationToken>",
+                            next
+                        )
+                        .unwrap();
+                    } else {
+                        write!(xml, "<IsTruncated>false</IsTruncated>").unwrap();
+                    }
+                    xml.push_str("</ListBucketResult>");
+                    reply(StatusCode::OK, xml)
+                }
+                (Method::POST, None) if query.contains_key("uploads") => {
+                    let id = format!("upload-of-{}", key);
+                    uploads.insert(id.clone(), vec![]);
+                    let xml = format!("<Result><UploadId>{}</UploadId></Result>", id);
+                    reply(StatusCode::OK, xml)
+                }
+                (Method::PUT, Some(id)) => {
+                    let parts = uploads.get_mut(id).unwrap();
+                    parts.push(data);
+                    let mut response = reply(StatusCode::OK, "");
+                    let etag = format!("\"etag{}\"", parts.len()).parse().unwrap();
+                    response.headers_mut().insert("etag", etag);
+                    response
+                }
+                (Method::POST, Some(id)) => {
+                    let parts = uploads.remove(id).unwrap();
+                    let xml = String::from_utf8(data).unwrap();
+                    assert_eq!(xml_values(&xml, "ETag").len(), parts.len());
+                    self.completed_uploads.lock().unwrap().push(parts.len());
+                    objects.insert(key, parts.concat());
+                    reply(StatusCode::OK, "<CompleteMultipartUploadResult/>")
+                }
+                (Method::DELETE, Some(id)) => {
+                    uploads.remove(id);
+                    reply(StatusCode::NO_CONTENT, "")
+                }
+                (Method::PUT, None) => {
+                    objects.insert(key, data);
+                    reply(StatusCode::OK, "")
+                }
+                (Method::GET, None) => match objects.get(&key) {
+                    Some(data) => reply(StatusCode::OK, data.clone()),
+                    None => reply(
+                        StatusCode::NOT_FOUND,
+                        "<Error><Code>NoSuchKey</Code></Error>",
+                    ),
+                },
+                (Method::DELETE, None) => {
+                    objects.remove(&key);
+                    reply(StatusCode::NO_CONTENT, "")
+                }
+                _ => reply(StatusCode::BAD_REQUEST, ""),
+            }
+        }
+    }
+
+    fn reply<T: Into<Body>>(status: StatusCode, body: T) -> Response<Body> {
+        let mut response = Response::new(body.into());
+        *response.status_mut() = status;
+        response
+    }
+
+    fn decode(value: &str) -> String {
+        let mut bytes = vec![];
+        let mut rest = value.as_bytes();
+        while let Some((&byte, tail)) = rest.split_first() {
+            if byte == b'%' {
+                let hex = std::str::from_utf8(&tail[..2]).unwrap();
+                bytes.push(u8::from_str_radix(hex, 16).unwrap());
+                rest = &tail[2..];
+            } else {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+        String::from_utf8(bytes).unwrap()
+    }
+
+    fn open_storage(bucket: &MockBucket, keep_snapshots: usize) -> ObjectStoreSnapshotStorage {
+        let config = SnapshotStorageConfig {
+            backend: SnapshotBackend::ObjectStore,
+            keep_snapshots,
+            object_store: ObjectStoreConfig {
+                endpoint: format!("http://{}", bucket.serve()),
+                bucket: "bucket".to_string(),
+                access_key_id: "key".to_string(),
+                secret_access_key: "secret".to_string(),
+                // Raised to the minimum part size.
+                part_size: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        ObjectStoreSnapshotStorage::new(&config).unwrap()
+    }
+
+    fn persist(storage: &mut ObjectStoreSnapshotStorage, epoch: u64, kind: SnapshotKind) {
+        let mut writer = storage
+            .create_snapshot(&format!("{:020}", epoch), kind)
+            .unwrap();
+        writer.write_all(epoch.to_string().as_bytes()).unwrap();
+        writer.persist().unwrap();
+    }
+
+    fn contents(reader: Cursor<Vec<u8>>) -> String {
+        String::from_utf8(reader.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn large_snapshot_is_uploaded_in_parts() {
+        let bucket = MockBucket::default();
+        let mut storage = open_storage(&bucket, 0);
+        let data: Vec<_> = (0..2 * MIN_PART_SIZE + 100).map(|i| i as u8).collect();
+        let mut writer = storage.create_snapshot("1", SnapshotKind::Full).unwrap();
+        for chunk in data.chunks(64 * 1024) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.persist().unwrap();
+        drop(writer);
+
+        assert_eq!(*bucket.completed_uploads.lock().unwrap(), vec![3]);
+        assert!(bucket.uploads.lock().unwrap().is_empty());
+        let chain = storage.open_snapshot(0).unwrap().unwrap();
+        assert_eq!(chain.base.into_inner(), data);
+    }
+
+    #[test]
+    fn unpersisted_upload_is_aborted() {
+        let bucket = MockBucket::default();
+        let mut storage = open_storage(&bucket, 0);
+        let mut writer = storage.create_snapshot("1", SnapshotKind::Full).unwrap();
+        writer.write_all(&vec![0; MIN_PART_SIZE]).unwrap();
+        assert_eq!(bucket.uploads.lock().unwrap().len(), 1);
+
+        drop(writer);
+        assert!(bucket.uploads.lock().unwrap().is_empty());
+        assert!(bucket.keys().is_empty());
+    }
+
+    #[test]
+    fn newest_snapshot_is_read_with_its_deltas() {
+        let bucket = MockBucket::default();
+        let mut storage = open_storage(&bucket, 1);
+        persist(&mut storage, 5, SnapshotKind::Full);
+        persist(&mut storage, 7, SnapshotKind::Delta);
+        // Named as if the clock went backwards: the epoch decides which one is the newest.
+        bucket.insert(
+            "snapshots/20000101T000000.000000Z_00000000000000000010.snap",
+            b"10",
+        );
+        persist(&mut storage, 12, SnapshotKind::Delta);
+        bucket.insert(
+            "snapshots/nested/20000101T000000.000000Z_00000000000000000020.snap",
+            b"",
+        );
+        bucket.insert(
+            "other/20000101T000000.000000Z_00000000000000000020.snap",
+            b"",
+        );
+
+        let chain = storage.open_snapshot(0).unwrap().unwrap();
+        assert_eq!(contents(chain.base), "10");
+        let deltas: Vec<_> = chain.deltas.into_iter().map(contents).collect();
+        assert_eq!(deltas, vec!["12"]);
+        let chain = storage.open_snapshot(1).unwrap().unwrap();
+        assert_eq!(contents(chain.base), "5");
+        let deltas: Vec<_> = chain.deltas.into_iter().map(contents).collect();
+        assert_eq!(deltas, vec!["7"]);
+        assert!(storage.open_snapshot(2).unwrap().is_none());
+
+        storage.dispose_old_snapshots().unwrap();
+        let epochs: Vec<_> = bucket
+            .keys()
+            .iter()
+            .map(|key| key.rsplit('_').next().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            epochs,
+            vec![
+                "00000000000000000020.snap",
+                "00000000000000000010.snap",
+                "00000000000000000012.delta",
+                "00000000000000000020.snap",
+            ]
+        );
+    }
+}
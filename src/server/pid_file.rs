@@ -0,0 +1,63 @@
+use crate::errors::*;
+
+use nix::{errno::Errno, sys::signal::kill, unistd::Pid};
+
+use std::{
+    fs::{self, remove_file, rename},
+    io::ErrorKind,
+    path::PathBuf,
+    process,
+};
+
+// The PID file of this rayd, removed once dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    // Fails if the file names a live process other than this one. A file left behind by a
+    // process that is gone is stale and overwritten once the PID is written.
+    pub fn acquire(path: &str) -> Result<Self> {
+        let path = PathBuf::from(path);
+        match fs::read_to_string(&path) {
+            Ok(contents) => match contents.trim().parse::<i32>() {
+                Ok(pid) if is_running(pid) => {
+                    bail!("{:?} names a running process (pid: {})", path, pid);
+                }
+                _ => warn!("Found stale PID file {:?}, it will be overwritten", path),
+            },
+            Err(err) if err.kind() == ErrorKind::NotFound => (),
+            Err(err) => {
+                return Err(err).chain_err(|| format!("failed to read PID file {:?}", path));
+            }
+        }
+        Ok(Self { path })
+    }
+
+    // Written to a temporary file first, so that the file is never seen half written.
+    pub fn write(&self) -> Result<()> {
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        fs::write(&temp_path, format!("{}\n", process::id()))
+            .chain_err(|| format!("failed to write {:?}", temp_path))?;
+        rename(&temp_path, &self.path)
+            .chain_err(|| format!("failed to rename {:?} to {:?}", temp_path, self.path))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        remove_file(&self.path).ok(); // Ignore error
+    }
+}
+
+// Signal 0 only checks that the process exists; EPERM means it does but belongs to another user.
+fn is_running(pid: i32) -> bool {
+    if pid <= 0 || pid as u32 == process::id() {
+        return false;
+    }
+    match kill(Pid::from_raw(pid), None) {
+        Ok(()) => true,
+        Err(err) => err.as_errno() == Some(Errno::EPERM),
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+// Token bucket shared by all RPC worker threads. Instead of a token count, it keeps the time at
+// which the bucket will be full again (the GCRA formulation), so that a single atomic suffices.
+pub struct RateLimiter {
+    start: Instant,
+    // Nanoseconds it takes to earn one token, 0 for no limit.
+    interval: u64,
+    // How far ahead of now the full time may move before requests are rejected.
+    tolerance: u64,
+    full_at: AtomicU64,
+}
+
+impl RateLimiter {
+    // Zero rate means no limit. Burst is the bucket capacity, at least one request.
+    pub fn new(rate: u32, burst: u32) -> Self {
+        let interval = if rate > 0 {
+            1_000_000_000 / rate as u64
+        } else {
+            0
+        };
+        Self {
+            start: Instant::now(),
+            interval,
+            tolerance: interval * burst.saturating_sub(1) as u64,
+            full_at: AtomicU64::new(0),
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        if self.interval == 0 {
+            return true;
+        }
+
+        let now = self.start.elapsed().as_nanos() as u64;
+        let mut full_at = self.full_at.load(Ordering::Relaxed);
+        loop {
+            let base = full_at.max(now);
+            if base - now > self.tolerance {
+                return false;
+            }
+            match self.full_at.compare_exchange_weak(
+                full_at,
+                base + self.interval,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(current) => full_at = current,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn burst_is_rejected_until_the_bucket_refills() {
+        // One token every 100ms.
+        let limiter = RateLimiter::new(10, 5);
+        for _ in 0..5 {
+            assert!(limiter.try_acquire());
+        }
+        assert!(!limiter.try_acquire());
+
+        thread::sleep(Duration::from_millis(150));
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+    }
+
+    #[test]
+    fn zero_rate_is_unlimited() {
+        let limiter = RateLimiter::new(0, 1);
+        for _ in 0..1000 {
+            assert!(limiter.try_acquire());
+        }
+    }
+}
@@ -1,78 +1,1206 @@
-use super::{machine_service::MachineServiceHandle, storage_machine::StorageMachine};
-use crate::util::Traced;
+use super::{
+    config::RpcConfig,
+    health_service::HealthReporter,
+    kv_store::KvStore,
+    machine_service::{MachineServiceHandle, WatchEvent},
+    rate_limiter::RateLimiter,
+    snapshot_service::SnapshotServiceHandle,
+    storage_machine::{
+        storage_key, MutationOutcome, Query, Status as MachineStatus, StorageMachine,
+        MAX_NAMESPACE_LEN,
+    },
+};
+use crate::util::{RequestTimings, Traced};
+
+use futures::{FutureExt, Stream};
+
+use metrics::{counter, timing, value};
+
+use crate::proto::{
+    mutation::Kind, storage_server::Storage, AppendMutation, AppendReply, AppendRequest,
+    BatchSetReply, BatchSetRequest, BulkSetReply, ChangeEvent, ClearReply, ClearRequest,
+    DeletePrefixReply, DeletePrefixRequest, DeleteReply, DeleteRequest, DumpKeysRequest,
+    ExistsReply, ExistsRequest, FlushNamespaceReply, FlushNamespaceRequest, GetReply, GetRequest,
+    GetSetReply, GetSetRequest, GetWithMetaReply, IncrementReply, IncrementRequest, InfoReply,
+    InfoRequest, KeyValue, Mutation, PingRequest, PongReply, SetReply, SetRequest, SyncReply,
+    SyncRequest, TransactionReply, TransactionRequest, TriggerSnapshotReply,
+    TriggerSnapshotRequest, WatchRequest, NOT_READY_METADATA,
+};
+
+use tokio::{
+    sync::{broadcast::RecvError, mpsc},
+    task,
+};
+
+use tonic::{
+    metadata::{MetadataMap, MetadataValue},
+    Code, Request, Response, Status, Streaming,
+};
+
+use tracing::debug_span;
+use tracing_futures::Instrument;
+
+use uuid::Uuid;
+
+use std::{
+    collections::VecDeque,
+    fmt::{self, Debug, Display},
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+// Entries a dump may have in flight before waiting for the client to catch up.
+const DUMP_BUFFER_SIZE: usize = 1000;
+// Limits on the sets of a bulk set that are journaled as a single mutation.
+const BULK_SET_BATCH_ENTRIES: usize = 1000;
+const BULK_SET_BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+pub struct RayStorageService<K: KvStore> {
+    handle: MachineServiceHandle<StorageMachine<K>>,
+    snapshot_handle: SnapshotServiceHandle,
+    health: HealthReporter,
+    max_key_size: usize,
+    max_value_size: u64,
+    max_recv_message_size: usize,
+    max_send_message_size: usize,
+    per_ip_metrics: bool,
+    slow_request_threshold: Option<Duration>,
+    allow_clear: bool,
+    read_limiter: RateLimiter,
+    write_limiter: RateLimiter,
+    started: Instant,
+}
+
+#[tonic::async_trait]
+trait RequestHandler {
+    type Request: Debug + Display;
+    type Response: Debug + Display;
+    const METHOD_NAME: &'static str;
+    // Decides which rate limit applies.
+    const IS_WRITE: bool;
+    // Requests that don't touch the PSM may be served during recovery.
+    const REQUIRES_READY: bool = true;
+    // Requests that last as long as the client keeps streaming are never logged as slow.
+    const LOGGED_WHEN_SLOW: bool = true;
+
+    // Payload sizes in bytes, taken from the fields rather than the encoded message.
+    fn request_size(request: &Self::Request) -> usize;
+    fn response_size(response: &Self::Response) -> usize;
+
+    // Only mutations carry request ids.
+    fn request_id(_request: &Self::Request) -> &[u8] {
+        &[]
+    }
+
+    async fn handle_request<K: KvStore>(
+        request: Traced<Self::Request>,
+        service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status>;
+}
+
+struct SetRequestHandler {}
+
+#[tonic::async_trait]
+impl RequestHandler for SetRequestHandler {
+    type Request = SetRequest;
+    type Response = SetReply;
+    const METHOD_NAME: &'static str = "set";
+    const IS_WRITE: bool = true;
+
+    fn request_size(request: &Self::Request) -> usize {
+        request.key.len() + request.value.len()
+    }
+
+    fn response_size(_response: &Self::Response) -> usize {
+        8
+    }
+
+    fn request_id(request: &Self::Request) -> &[u8] {
+        &request.request_id
+    }
+
+    async fn handle_request<K: KvStore>(
+        request: Traced<Self::Request>,
+        service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status> {
+        let set = &request.payload;
+        if let Some(err) = namespace_error(&set.namespace) {
+            return Err(err);
+        }
+        if let Some(err) = service.entry_error(&set.key, &set.value) {
+            return Err(err);
+        }
+        let mutation = request.map(|set| Mutation {
+            kind: Some(Kind::Set(set)),
+        });
+        let (outcome, epoch) = service
+            .handle
+            .clone()
+            .apply_mutation_with_epoch(mutation)
+            .await?;
+        outcome?;
+        Ok(SetReply { epoch })
+    }
+}
+
+struct GetSetRequestHandler {}
+
+#[tonic::async_trait]
+impl RequestHandler for GetSetRequestHandler {
+    type Request = GetSetRequest;
+    type Response = GetSetReply;
+    const METHOD_NAME: &'static str = "get_set";
+    const IS_WRITE: bool = true;
+
+    fn request_size(request: &Self::Request) -> usize {
+        request.key.len() + request.value.len()
+    }
+
+    fn response_size(response: &Self::Response) -> usize {
+        response.value.len()
+    }
+
+    fn request_id(request: &Self::Request) -> &[u8] {
+        &request.request_id
+    }
+
+    async fn handle_request<K: KvStore>(
+        request: Traced<Self::Request>,
+        service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status> {
+        let get_set = &request.payload;
+        if let Some(err) = service.entry_error(&get_set.key, &get_set.value) {
+            return Err(err);
+        }
+        let max_previous_size = get_set.max_previous_size;
+        let mutation = request.map(|get_set| Mutation {
+            kind: Some(Kind::GetSet(get_set)),
+        });
+        let (outcome, epoch) = service
+            .handle
+            .clone()
+            .apply_mutation_with_epoch(mutation)
+            .await?;
+        match outcome? {
+            MutationOutcome::GetSet(value) => {
+                let omitted = max_previous_size > 0 && value.len() as u64 > max_previous_size;
+                Ok(GetSetReply {
+                    value: if omitted { vec![] } else { value.to_vec() },
+                    epoch,
+                    previous_omitted: omitted,
+                })
+            }
+            outcome => unreachable!("unexpected get-set outcome: {:?}", outcome),
+        }
+    }
+}
+
+struct IncrementRequestHandler {}
+
+#[tonic::async_trait]
+impl RequestHandler for IncrementRequestHandler {
+    type Request = IncrementRequest;
+    type Response = IncrementReply;
+    const METHOD_NAME: &'static str = "increment";
+    const IS_WRITE: bool = true;
+
+    fn request_size(request: &Self::Request) -> usize {
+        request.key.len() + 8
+    }
+
+    fn response_size(_response: &Self::Response) -> usize {
+        8
+    }
+
+    fn request_id(request: &Self::Request) -> &[u8] {
+        &request.request_id
+    }
+
+    async fn handle_request<K: KvStore>(
+        request: Traced<Self::Request>,
+        service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status> {
+        if let Some(err) = service.entry_error(&request.payload.key, &[]) {
+            return Err(err);
+        }
+        let mutation = request.map(|increment| Mutation {
+            kind: Some(Kind::Increment(increment)),
+        });
+        match service.handle.clone().apply_mutation(mutation).await?? {
+            MutationOutcome::Increment(value) => Ok(IncrementReply { value }),
+            outcome => unreachable!("unexpected increment outcome: {:?}", outcome),
+        }
+    }
+}
+
+struct AppendRequestHandler {}
+
+#[tonic::async_trait]
+impl RequestHandler for AppendRequestHandler {
+    type Request = AppendRequest;
+    type Response = AppendReply;
+    const METHOD_NAME: &'static str = "append";
+    const IS_WRITE: bool = true;
+
+    fn request_size(request: &Self::Request) -> usize {
+        request.key.len() + request.suffix.len()
+    }
+
+    fn response_size(_response: &Self::Response) -> usize {
+        8
+    }
+
+    fn request_id(request: &Self::Request) -> &[u8] {
+        &request.request_id
+    }
+
+    async fn handle_request<K: KvStore>(
+        request: Traced<Self::Request>,
+        service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status> {
+        // Appends over the value limit fail when applied, as it depends on the current value.
+        if let Some(err) = service.entry_error(&request.payload.key, &[]) {
+            return Err(err);
+        }
+        let max_value_size = service.max_value_size;
+        let mutation = request.map(|append| Mutation {
+            kind: Some(Kind::Append(AppendMutation {
+                key: append.key,
+                suffix: append.suffix,
+                max_value_size,
+                request_id: append.request_id,
+            })),
+        });
+        match service.handle.clone().apply_mutation(mutation).await?? {
+            MutationOutcome::Append(length) => Ok(AppendReply { length }),
+            outcome => unreachable!("unexpected append outcome: {:?}", outcome),
+        }
+    }
+}
+
+struct BatchSetRequestHandler {}
+
+#[tonic::async_trait]
+impl RequestHandler for BatchSetRequestHandler {
+    type Request = BatchSetRequest;
+    type Response = BatchSetReply;
+    const METHOD_NAME: &'static str = "batch_set";
+    const IS_WRITE: bool = true;
+
+    fn request_size(request: &Self::Request) -> usize {
+        request
+            .entries
+            .iter()
+            .map(|entry| entry.key.len() + entry.value.len())
+            .sum()
+    }
+
+    fn response_size(_response: &Self::Response) -> usize {
+        0
+    }
+
+    fn request_id(request: &Self::Request) -> &[u8] {
+        &request.request_id
+    }
+
+    async fn handle_request<K: KvStore>(
+        request: Traced<Self::Request>,
+        service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status> {
+        for entry in &request.payload.entries {
+            if let Some(err) = service.entry_error(&entry.key, &entry.value) {
+                return Err(err);
+            }
+        }
+        let mutation = request.map(|batch_set| Mutation {
+            kind: Some(Kind::BatchSet(batch_set)),
+        });
+        service.handle.clone().apply_mutation(mutation).await??;
+        Ok(BatchSetReply {})
+    }
+}
+
+struct TransactionRequestHandler {}
+
+#[tonic::async_trait]
+impl RequestHandler for TransactionRequestHandler {
+    type Request = TransactionRequest;
+    type Response = TransactionReply;
+    const METHOD_NAME: &'static str = "transaction";
+    const IS_WRITE: bool = true;
+
+    fn request_size(request: &Self::Request) -> usize {
+        let conditions: usize = request
+            .conditions
+            .iter()
+            .map(|condition| condition.key.len() + condition.value.len())
+            .sum();
+        let mutations: usize = request.mutations.iter().map(mutation_size).sum();
+        conditions + mutations
+    }
+
+    fn response_size(_response: &Self::Response) -> usize {
+        1
+    }
+
+    fn request_id(request: &Self::Request) -> &[u8] {
+        &request.request_id
+    }
+
+    async fn handle_request<K: KvStore>(
+        mut request: Traced<Self::Request>,
+        service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status> {
+        let mut conditions = request.payload.conditions.iter();
+        if let Some(err) = conditions.find_map(|condition| empty_key_error(&condition.key)) {
+            return Err(err);
+        }
+        for mutation in request.payload.mutations.iter_mut() {
+            let error = match mutation.kind {
+                Some(Kind::Set(ref set)) => namespace_error(&set.namespace)
+                    .or_else(|| service.entry_error(&set.key, &set.value)),
+                Some(Kind::GetSet(ref get_set)) => {
+                    service.entry_error(&get_set.key, &get_set.value)
+                }
+                Some(Kind::Increment(ref increment)) => service.entry_error(&increment.key, &[]),
+                // Held to the limit of this server, like a standalone append.
+                Some(Kind::Append(ref mut append)) => {
+                    append.max_value_size = service.max_value_size;
+                    service.entry_error(&append.key, &[])
+                }
+                Some(Kind::BatchSet(ref batch_set)) => batch_set
+                    .entries
+                    .iter()
+                    .find_map(|entry| service.entry_error(&entry.key, &entry.value)),
+                Some(Kind::Delete(ref delete)) => {
+                    namespace_error(&delete.namespace).or_else(|| empty_key_error(&delete.key))
+                }
+                Some(Kind::Transaction(_)) => Some(Status::new(
+                    Code::InvalidArgument,
+                    "nested transactions are not supported",
+                )),
+                Some(Kind::FlushNamespace(_)) => Some(Status::new(
+                    Code::InvalidArgument,
+                    "flushes are not supported in transactions",
+                )),
+                Some(Kind::Clear(_)) => Some(Status::new(
+                    Code::InvalidArgument,
+                    "clears are not supported in transactions",
+                )),
+                Some(Kind::DeletePrefix(_)) => Some(Status::new(
+                    Code::InvalidArgument,
+                    "prefix deletions are not supported in transactions",
+                )),
+                None => Some(Status::new(
+                    Code::InvalidArgument,
+                    "empty mutation in transaction",
+                )),
+            };
+            if let Some(err) = error {
+                return Err(err);
+            }
+        }
+        let mutation = request.map(|transaction| Mutation {
+            kind: Some(Kind::Transaction(transaction)),
+        });
+        match service.handle.clone().apply_mutation(mutation).await?? {
+            MutationOutcome::Transaction(committed) => Ok(TransactionReply { committed }),
+            outcome => unreachable!("unexpected transaction outcome: {:?}", outcome),
+        }
+    }
+}
+
+fn mutation_size(mutation: &Mutation) -> usize {
+    match mutation.kind {
+        Some(Kind::Set(ref set)) => set.key.len() + set.value.len(),
+        Some(Kind::GetSet(ref get_set)) => get_set.key.len() + get_set.value.len(),
+        Some(Kind::Increment(ref increment)) => increment.key.len() + 8,
+        Some(Kind::Append(ref append)) => append.key.len() + append.suffix.len(),
+        Some(Kind::BatchSet(ref batch_set)) => batch_set
+            .entries
+            .iter()
+            .map(|entry| entry.key.len() + entry.value.len())
+            .sum(),
+        Some(Kind::FlushNamespace(ref flush)) => flush.namespace.len(),
+        Some(Kind::DeletePrefix(ref delete)) => delete.namespace.len() + delete.prefix.len(),
+        Some(Kind::Delete(ref delete)) => delete.namespace.len() + delete.key.len(),
+        Some(Kind::Transaction(_)) | Some(Kind::Clear(_)) | None => 0,
+    }
+}
+
+#[derive(Debug)]
+pub struct SetStream(Streaming<SetRequest>);
+
+impl Display for SetStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SetStream")
+    }
+}
+
+struct BulkSetRequestHandler {}
+
+#[tonic::async_trait]
+impl RequestHandler for BulkSetRequestHandler {
+    type Request = SetStream;
+    type Response = BulkSetReply;
+    const METHOD_NAME: &'static str = "bulk_set";
+    const IS_WRITE: bool = true;
+    const LOGGED_WHEN_SLOW: bool = false;
+
+    // Sets are checked one by one as they arrive.
+    fn request_size(_request: &Self::Request) -> usize {
+        0
+    }
+
+    fn response_size(_response: &Self::Response) -> usize {
+        8
+    }
+
+    // Each batch takes the sets that have arrived while the previous one was journaled, so
+    // a fast client gets large batches and a slow one is not kept waiting.
+    async fn handle_request<K: KvStore>(
+        request: Traced<Self::Request>,
+        service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status> {
+        let id = request.id;
+        let mut stream = request.payload.0;
+        let mut count = 0;
+        let partial_error = |err: Status, count: u64| {
+            let message = format!("{} (sets applied: {})", err.message(), count);
+            Status::new(err.code(), message)
+        };
+
+        loop {
+            let mut entries = vec![];
+            let mut batch_bytes = 0;
+            let mut finished = false;
+            let mut next = Some(stream.message().await);
+            while let Some(message) = next {
+                let set = match message {
+                    Ok(Some(set)) => set,
+                    Ok(None) => {
+                        finished = true;
+                        break;
+                    }
+                    Err(err) => return Err(partial_error(err, count)),
+                };
+                if !set.namespace.is_empty() {
+                    let err = Status::new(
+                        Code::InvalidArgument,
+                        "namespaces are not supported in bulk sets",
+                    );
+                    return Err(partial_error(err, count));
+                }
+                if let Some(err) = service.entry_error(&set.key, &set.value) {
+                    return Err(partial_error(err, count));
+                }
+                batch_bytes += set.key.len() + set.value.len();
+                entries.push(KeyValue {
+                    key: set.key,
+                    value: set.value,
+                });
+                if entries.len() >= BULK_SET_BATCH_ENTRIES || batch_bytes >= BULK_SET_BATCH_BYTES {
+                    break;
+                }
+                next = stream.message().now_or_never();
+            }
+
+            if !entries.is_empty() {
+                let batch_count = entries.len() as u64;
+                let mutation = Traced::with_id(
+                    id,
+                    Mutation {
+                        kind: Some(Kind::BatchSet(BatchSetRequest {
+                            entries,
+                            request_id: vec![],
+                        })),
+                    },
+                );
+                let outcome = service.handle.clone().apply_mutation(mutation).await;
+                if let Err(err) = outcome.and_then(|outcome| outcome) {
+                    return Err(partial_error(err.into(), count));
+                }
+                count += batch_count;
+            }
+            if finished {
+                return Ok(BulkSetReply { count });
+            }
+        }
+    }
+}
+
+struct GetRequestHandler {}
+
+#[tonic::async_trait]
+impl RequestHandler for GetRequestHandler {
+    type Request = GetRequest;
+    type Response = GetReply;
+    const METHOD_NAME: &'static str = "get";
+    const IS_WRITE: bool = false;
+
+    fn request_size(request: &Self::Request) -> usize {
+        request.key.len()
+    }
+
+    fn response_size(response: &Self::Response) -> usize {
+        response.value.len() + 8
+    }
+
+    async fn handle_request<K: KvStore>(
+        request: Traced<Self::Request>,
+        service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status> {
+        if let Some(err) = namespace_error(&request.payload.namespace) {
+            return Err(err);
+        }
+        if let Some(err) = empty_key_error(&request.payload.key) {
+            return Err(err);
+        }
+        let (at_epoch, min_epoch) = (request.payload.at_epoch, request.payload.min_epoch);
+        let query = request.map(|req| Query::Get(storage_key(&req.namespace, req.key)));
+        let mut handle = service.handle.clone();
+        let (status, epoch) = if at_epoch > 0 {
+            (handle.query_state_at(query, at_epoch).await?, at_epoch)
+        } else {
+            handle.query_state_after(query, min_epoch).await?
+        };
+        match status {
+            // The only copy of the value on the read path, made off the machine thread.
+            MachineStatus::Value(value) => Ok(GetReply {
+                found: value.is_some(),
+                value: value.map(|value| value.to_vec()).unwrap_or_default(),
+                epoch,
+            }),
+            status => unreachable!("unexpected get status: {:?}", status),
+        }
+    }
+}
+
+struct GetWithMetaRequestHandler {}
+
+#[tonic::async_trait]
+impl RequestHandler for GetWithMetaRequestHandler {
+    type Request = GetRequest;
+    type Response = GetWithMetaReply;
+    const METHOD_NAME: &'static str = "get_with_meta";
+    const IS_WRITE: bool = false;
+
+    fn request_size(request: &Self::Request) -> usize {
+        request.key.len()
+    }
+
+    fn response_size(response: &Self::Response) -> usize {
+        response.value.len() + 16
+    }
+
+    async fn handle_request<K: KvStore>(
+        request: Traced<Self::Request>,
+        service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status> {
+        if let Some(err) = namespace_error(&request.payload.namespace) {
+            return Err(err);
+        }
+        if let Some(err) = empty_key_error(&request.payload.key) {
+            return Err(err);
+        }
+        let (at_epoch, min_epoch) = (request.payload.at_epoch, request.payload.min_epoch);
+        let query = request.map(|req| Query::Get(storage_key(&req.namespace, req.key)));
+        let mut handle = service.handle.clone();
+        let (status, epoch) = if at_epoch > 0 {
+            (handle.query_state_at(query, at_epoch).await?, at_epoch)
+        } else {
+            handle.query_state_after(query, min_epoch).await?
+        };
+        match status {
+            MachineStatus::Value(value) => {
+                let value = value.map(|value| value.to_vec());
+                Ok(GetWithMetaReply {
+                    found: value.is_some(),
+                    size: value.as_ref().map_or(0, |value| value.len() as u64),
+                    value: value.unwrap_or_default(),
+                    epoch,
+                })
+            }
+            status => unreachable!("unexpected get status: {:?}", status),
+        }
+    }
+}
+
+struct ExistsRequestHandler {}
+
+#[tonic::async_trait]
+impl RequestHandler for ExistsRequestHandler {
+    type Request = ExistsRequest;
+    type Response = ExistsReply;
+    const METHOD_NAME: &'static str = "exists";
+    const IS_WRITE: bool = false;
+
+    fn request_size(request: &Self::Request) -> usize {
+        request.key.len()
+    }
+
+    fn response_size(_response: &Self::Response) -> usize {
+        1
+    }
+
+    async fn handle_request<K: KvStore>(
+        request: Traced<Self::Request>,
+        service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status> {
+        if let Some(err) = namespace_error(&request.payload.namespace) {
+            return Err(err);
+        }
+        if let Some(err) = empty_key_error(&request.payload.key) {
+            return Err(err);
+        }
+        let query = request.map(|req| Query::Exists(storage_key(&req.namespace, req.key)));
+        match service.handle.clone().query_state(query).await? {
+            MachineStatus::Exists(exists) => Ok(ExistsReply { exists }),
+            status => unreachable!("unexpected exists status: {:?}", status),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct KeyValueStream(mpsc::Receiver<Result<KeyValue, Status>>);
+
+impl Stream for KeyValueStream {
+    type Item = Result<KeyValue, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.0).poll_next(cx)
+    }
+}
+
+impl Display for KeyValueStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KeyValueStream")
+    }
+}
+
+struct DumpKeysRequestHandler {}
+
+#[tonic::async_trait]
+impl RequestHandler for DumpKeysRequestHandler {
+    type Request = DumpKeysRequest;
+    type Response = KeyValueStream;
+    const METHOD_NAME: &'static str = "dump_keys";
+    const IS_WRITE: bool = false;
+
+    fn request_size(request: &Self::Request) -> usize {
+        request.start_after.len()
+    }
+
+    fn response_size(_response: &Self::Response) -> usize {
+        0
+    }
+
+    // The machine copies the keys out at the current epoch and shares the values, so the
+    // dump is consistent and the machine is not held up while it is streamed.
+    async fn handle_request<K: KvStore>(
+        request: Traced<Self::Request>,
+        service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status> {
+        if let Some(err) = namespace_error(&request.payload.namespace) {
+            return Err(err);
+        }
+        let query = request.map(|req| {
+            Query::Dump(
+                req.namespace.into_boxed_slice(),
+                req.start_after.into_boxed_slice(),
+            )
+        });
+        let mut entries = match service.handle.clone().query_state(query).await? {
+            MachineStatus::Entries(entries) => entries,
+            status => unreachable!("unexpected dump status: {:?}", status),
+        };
+
+        let (mut sender, receiver) = mpsc::channel(DUMP_BUFFER_SIZE);
+        tokio::spawn(async move {
+            // Resuming relies on key order, which hash stores don't keep.
+            let sorted = task::spawn_blocking(move || {
+                entries.sort_unstable_by(|left, right| left.0.cmp(&right.0));
+                entries
+            });
+            let entries = match sorted.await {
+                Ok(entries) => entries,
+                Err(err) => {
+                    sender
+                        .send(Err(Status::internal(err.to_string())))
+                        .await
+                        .ok();
+                    return;
+                }
+            };
+            for (key, value) in entries {
+                let entry = KeyValue {
+                    key: key.into_vec(),
+                    value: value.to_vec(),
+                };
+                if sender.send(Ok(entry)).await.is_err() {
+                    break; // Client went away
+                }
+            }
+        });
+
+        Ok(KeyValueStream(receiver))
+    }
+}
+
+type WatchEvents = Pin<Box<dyn Stream<Item = Result<WatchEvent, RecvError>> + Send + Sync>>;
+
+// Subscribed to the machine service for as long as the client keeps the stream, which is
+// dropped along with the subscription once the client goes away.
+pub struct ChangeEventStream {
+    events: WatchEvents,
+    namespace: Vec<u8>,
+    prefix: Vec<u8>,
+    // Changes of the last event that are yet to be streamed.
+    pending: VecDeque<ChangeEvent>,
+}
+
+impl ChangeEventStream {
+    fn push_changes(&mut self, event: WatchEvent) {
+        for change in event.changes.iter() {
+            if change.namespace != self.namespace || !change.key.starts_with(&self.prefix) {
+                continue;
+            }
+            self.pending.push_back(ChangeEvent {
+                key: change.key.clone(),
+                value: change.value.as_deref().unwrap_or_default().to_vec(),
+                deleted: change.value.is_none(),
+                epoch: event.epoch,
+                namespace: change.namespace.clone(),
+                missed: 0,
+            });
+        }
+    }
+}
+
+impl Stream for ChangeEventStream {
+    type Item = Result<ChangeEvent, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Poll::Ready(Some(Ok(event)));
+            }
+            match self.events.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(event))) => self.push_changes(event),
+                // The oldest events were overwritten before this watcher got to them.
+                Poll::Ready(Some(Err(RecvError::Lagged(missed)))) => {
+                    counter!("rayd.rpc.watch_lagged_count", 1);
+                    let event = ChangeEvent {
+                        missed,
+                        ..ChangeEvent::default()
+                    };
+                    return Poll::Ready(Some(Ok(event)));
+                }
+                Poll::Ready(Some(Err(RecvError::Closed))) | Poll::Ready(None) => {
+                    return Poll::Ready(None)
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Debug for ChangeEventStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ChangeEventStream")
+    }
+}
+
+impl Display for ChangeEventStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ChangeEventStream")
+    }
+}
+
+struct WatchRequestHandler {}
+
+#[tonic::async_trait]
+impl RequestHandler for WatchRequestHandler {
+    type Request = WatchRequest;
+    type Response = ChangeEventStream;
+    const METHOD_NAME: &'static str = "watch";
+    const IS_WRITE: bool = false;
+
+    fn request_size(request: &Self::Request) -> usize {
+        request.prefix.len()
+    }
+
+    fn response_size(_response: &Self::Response) -> usize {
+        0
+    }
+
+    // Events are filtered by every watcher on its own, as the machine service only sends
+    // each of them once for all.
+    async fn handle_request<K: KvStore>(
+        request: Traced<Self::Request>,
+        service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status> {
+        if let Some(err) = namespace_error(&request.payload.namespace) {
+            return Err(err);
+        }
+        let watch = request.into_payload();
+        Ok(ChangeEventStream {
+            events: Box::pin(service.handle.watch().into_stream()),
+            namespace: watch.namespace,
+            prefix: watch.prefix,
+            pending: VecDeque::new(),
+        })
+    }
+}
+
+struct FlushNamespaceRequestHandler {}
+
+#[tonic::async_trait]
+impl RequestHandler for FlushNamespaceRequestHandler {
+    type Request = FlushNamespaceRequest;
+    type Response = FlushNamespaceReply;
+    const METHOD_NAME: &'static str = "flush_namespace";
+    const IS_WRITE: bool = true;
+
+    fn request_size(request: &Self::Request) -> usize {
+        request.namespace.len()
+    }
+
+    fn response_size(_response: &Self::Response) -> usize {
+        8
+    }
+
+    fn request_id(request: &Self::Request) -> &[u8] {
+        &request.request_id
+    }
+
+    async fn handle_request<K: KvStore>(
+        request: Traced<Self::Request>,
+        service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status> {
+        let namespace = &request.payload.namespace;
+        if namespace.is_empty() {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "the default namespace cannot be flushed",
+            ));
+        }
+        if let Some(err) = namespace_error(namespace) {
+            return Err(err);
+        }
+        let mutation = request.map(|flush| Mutation {
+            kind: Some(Kind::FlushNamespace(flush)),
+        });
+        match service.handle.clone().apply_mutation(mutation).await?? {
+            MutationOutcome::FlushNamespace(count) => Ok(FlushNamespaceReply { count }),
+            outcome => unreachable!("unexpected flush outcome: {:?}", outcome),
+        }
+    }
+}
+
+struct DeleteRequestHandler {}
+
+#[tonic::async_trait]
+impl RequestHandler for DeleteRequestHandler {
+    type Request = DeleteRequest;
+    type Response = DeleteReply;
+    const METHOD_NAME: &'static str = "delete";
+    const IS_WRITE: bool = true;
+
+    fn request_size(request: &Self::Request) -> usize {
+        request.namespace.len() + request.key.len()
+    }
+
+    fn response_size(_response: &Self::Response) -> usize {
+        1
+    }
+
+    fn request_id(request: &Self::Request) -> &[u8] {
+        &request.request_id
+    }
+
+    async fn handle_request<K: KvStore>(
+        request: Traced<Self::Request>,
+        service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status> {
+        if let Some(err) = namespace_error(&request.payload.namespace) {
+            return Err(err);
+        }
+        if let Some(err) = empty_key_error(&request.payload.key) {
+            return Err(err);
+        }
+        let mutation = request.map(|delete| Mutation {
+            kind: Some(Kind::Delete(delete)),
+        });
+        match service.handle.clone().apply_mutation(mutation).await?? {
+            MutationOutcome::Delete(found) => Ok(DeleteReply { found }),
+            outcome => unreachable!("unexpected deletion outcome: {:?}", outcome),
+        }
+    }
+}
+
+struct DeletePrefixRequestHandler {}
+
+#[tonic::async_trait]
+impl RequestHandler for DeletePrefixRequestHandler {
+    type Request = DeletePrefixRequest;
+    type Response = DeletePrefixReply;
+    const METHOD_NAME: &'static str = "delete_prefix";
+    const IS_WRITE: bool = true;
+
+    fn request_size(request: &Self::Request) -> usize {
+        request.namespace.len() + request.prefix.len()
+    }
+
+    fn response_size(_response: &Self::Response) -> usize {
+        8
+    }
+
+    fn request_id(request: &Self::Request) -> &[u8] {
+        &request.request_id
+    }
+
+    async fn handle_request<K: KvStore>(
+        request: Traced<Self::Request>,
+        service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status> {
+        if request.payload.prefix.is_empty() {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "the prefix to delete is empty, use FlushNamespace or Clear instead",
+            ));
+        }
+        if let Some(err) = namespace_error(&request.payload.namespace) {
+            return Err(err);
+        }
+        let mutation = request.map(|delete| Mutation {
+            kind: Some(Kind::DeletePrefix(delete)),
+        });
+        match service.handle.clone().apply_mutation(mutation).await?? {
+            MutationOutcome::DeletePrefix(count) => Ok(DeletePrefixReply { count }),
+            outcome => unreachable!("unexpected prefix deletion outcome: {:?}", outcome),
+        }
+    }
+}
+
+struct ClearRequestHandler {}
+
+#[tonic::async_trait]
+impl RequestHandler for ClearRequestHandler {
+    type Request = ClearRequest;
+    type Response = ClearReply;
+    const METHOD_NAME: &'static str = "clear";
+    const IS_WRITE: bool = true;
+
+    fn request_size(_request: &Self::Request) -> usize {
+        0
+    }
+
+    fn response_size(_response: &Self::Response) -> usize {
+        8
+    }
+
+    fn request_id(request: &Self::Request) -> &[u8] {
+        &request.request_id
+    }
 
-use metrics::{counter, timing};
+    async fn handle_request<K: KvStore>(
+        request: Traced<Self::Request>,
+        service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status> {
+        if !service.allow_clear {
+            return Err(Status::new(
+                Code::FailedPrecondition,
+                "clear is disabled, set rpc.allow_clear to enable it",
+            ));
+        }
+        let mutation = request.map(|clear| Mutation {
+            kind: Some(Kind::Clear(clear)),
+        });
+        let mut handle = service.handle.clone();
+        let count = match handle.apply_mutation(mutation).await?? {
+            MutationOutcome::Clear(count) => count,
+            outcome => unreachable!("unexpected clear outcome: {:?}", outcome),
+        };
 
-use crate::proto::{storage_server::Storage, GetReply, GetRequest, SetReply, SetRequest};
+        // The clear is durable once journaled, the snapshot only lets the journal before it
+        // be disposed sooner. So the reply does not wait for it.
+        let epoch = handle.persisted_epoch();
+        let snapshot_handle = service.snapshot_handle.clone();
+        task::spawn(async move {
+            if let Err(err) = snapshot_handle.make_snapshot(epoch).await {
+                tracing::warn!("Failed to snapshot after clear: {}", err);
+            }
+        });
+        Ok(ClearReply { count })
+    }
+}
 
-use tonic::{Code, Request, Response, Status};
+struct PingRequestHandler {}
 
-use uuid::Uuid;
+#[tonic::async_trait]
+impl RequestHandler for PingRequestHandler {
+    type Request = PingRequest;
+    type Response = PongReply;
+    const METHOD_NAME: &'static str = "ping";
+    const IS_WRITE: bool = false;
+    const REQUIRES_READY: bool = false;
 
-use std::{
-    fmt::{Debug, Display},
-    future::Future,
-    pin::Pin,
-    time::Instant,
-};
+    fn request_size(_request: &Self::Request) -> usize {
+        0
+    }
+
+    fn response_size(_response: &Self::Response) -> usize {
+        0
+    }
 
-pub struct RayStorageService {
-    handle: MachineServiceHandle<StorageMachine>,
+    async fn handle_request<K: KvStore>(
+        _request: Traced<Self::Request>,
+        _service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status> {
+        Ok(PongReply {})
+    }
 }
 
+struct InfoRequestHandler {}
+
 #[tonic::async_trait]
-trait RequestHandler {
-    type Request: Debug + Display;
-    type Response: Debug + Display;
-    const METHOD_NAME: &'static str;
+impl RequestHandler for InfoRequestHandler {
+    type Request = InfoRequest;
+    type Response = InfoReply;
+    const METHOD_NAME: &'static str = "info";
+    const IS_WRITE: bool = false;
+
+    fn request_size(_request: &Self::Request) -> usize {
+        0
+    }
+
+    fn response_size(response: &Self::Response) -> usize {
+        response.version.len() + 24
+    }
 
-    async fn handle_request(
+    async fn handle_request<K: KvStore>(
         request: Traced<Self::Request>,
-        handle: MachineServiceHandle<StorageMachine>,
-    ) -> Result<Self::Response, Status>;
+        service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status> {
+        let mut handle = service.handle.clone();
+        let epoch = handle.persisted_epoch();
+        let query = request.map(|_| Query::KeyCount);
+        let key_count = match handle.query_state(query).await? {
+            MachineStatus::KeyCount(count) => count,
+            status => unreachable!("unexpected key count status: {:?}", status),
+        };
+        Ok(InfoReply {
+            version: crate::VERSION.to_string(),
+            epoch,
+            uptime_seconds: service.started.elapsed().as_secs(),
+            key_count,
+        })
+    }
 }
 
-struct SetRequestHandler {}
+struct TriggerSnapshotRequestHandler {}
 
 #[tonic::async_trait]
-impl RequestHandler for SetRequestHandler {
-    type Request = SetRequest;
-    type Response = SetReply;
-    const METHOD_NAME: &'static str = "set";
+impl RequestHandler for TriggerSnapshotRequestHandler {
+    type Request = TriggerSnapshotRequest;
+    type Response = TriggerSnapshotReply;
+    const METHOD_NAME: &'static str = "trigger_snapshot";
+    const IS_WRITE: bool = true;
 
-    async fn handle_request(
-        request: Traced<Self::Request>,
-        mut handle: MachineServiceHandle<StorageMachine>,
+    fn request_size(_request: &Self::Request) -> usize {
+        0
+    }
+
+    fn response_size(_response: &Self::Response) -> usize {
+        0
+    }
+
+    async fn handle_request<K: KvStore>(
+        _request: Traced<Self::Request>,
+        service: &RayStorageService<K>,
     ) -> Result<Self::Response, Status> {
-        handle.apply_mutation(request).await?;
-        Ok(SetReply {})
+        // Snapshot whatever the snapshot replica has applied so far.
+        let epoch = service.snapshot_handle.make_snapshot(0).await?;
+        Ok(TriggerSnapshotReply { epoch })
     }
 }
 
-struct GetRequestHandler {}
+struct SyncRequestHandler {}
 
 #[tonic::async_trait]
-impl RequestHandler for GetRequestHandler {
-    type Request = GetRequest;
-    type Response = GetReply;
-    const METHOD_NAME: &'static str = "get";
+impl RequestHandler for SyncRequestHandler {
+    type Request = SyncRequest;
+    type Response = SyncReply;
+    const METHOD_NAME: &'static str = "sync";
+    const IS_WRITE: bool = true;
 
-    async fn handle_request(
-        request: Traced<Self::Request>,
-        mut handle: MachineServiceHandle<StorageMachine>,
-    ) -> Result<Self::Response, Status> {
-        let key = request.map(|req| req.key.into_boxed_slice());
-        let value = handle.query_state(key).await?;
+    fn request_size(_request: &Self::Request) -> usize {
+        0
+    }
 
-        Ok(GetReply {
-            value: value.to_vec(),
-        })
+    fn response_size(_response: &Self::Response) -> usize {
+        0
+    }
+
+    async fn handle_request<K: KvStore>(
+        _request: Traced<Self::Request>,
+        service: &RayStorageService<K>,
+    ) -> Result<Self::Response, Status> {
+        let persisted_epoch = service.handle.clone().sync().await?;
+        Ok(SyncReply { persisted_epoch })
     }
 }
 
-impl RayStorageService {
-    pub fn new(handle: MachineServiceHandle<StorageMachine>) -> Self {
-        Self { handle }
+impl<K: KvStore> RayStorageService<K> {
+    pub fn new(
+        handle: MachineServiceHandle<StorageMachine<K>>,
+        snapshot_handle: SnapshotServiceHandle,
+        health: HealthReporter,
+        config: &RpcConfig,
+    ) -> Self {
+        let limits = &config.rate_limit;
+        Self {
+            handle,
+            snapshot_handle,
+            health,
+            max_key_size: config.max_key_size,
+            max_value_size: config.max_value_size,
+            max_recv_message_size: config.max_recv_message_size,
+            max_send_message_size: config.max_send_message_size,
+            per_ip_metrics: config.per_ip_metrics,
+            slow_request_threshold: match config.slow_request_threshold_ms {
+                0 => None,
+                millis => Some(Duration::from_millis(millis)),
+            },
+            allow_clear: config.allow_clear,
+            read_limiter: RateLimiter::new(limits.read_rate, limits.read_burst),
+            write_limiter: RateLimiter::new(limits.write_rate, limits.write_burst),
+            started: Instant::now(),
+        }
+    }
+
+    // Empty keys, and keys and values over the limits, are rejected before they reach the
+    // journal.
+    fn entry_error(&self, key: &[u8], value: &[u8]) -> Option<Status> {
+        if let Some(err) = empty_key_error(key) {
+            return Some(err);
+        }
+        let (kind, size, limit) = if self.max_key_size > 0 && key.len() > self.max_key_size {
+            ("key", key.len() as u64, self.max_key_size as u64)
+        } else if self.max_value_size > 0 && value.len() as u64 > self.max_value_size {
+            ("value", value.len() as u64, self.max_value_size)
+        } else {
+            return None;
+        };
+        let message = format!(
+            "{} of {} bytes exceeds the limit of {} bytes",
+            kind, size, limit
+        );
+        Some(Status::new(Code::InvalidArgument, message))
     }
 
     async fn handle_request<T: RequestHandler>(
@@ -81,45 +1209,209 @@ impl RayStorageService {
     ) -> Result<Response<T::Response>, Status> {
         let start = Instant::now();
         counter!("rayd.rpc.request_count", 1, "method" => T::METHOD_NAME);
+        if self.per_ip_metrics {
+            let ip = request
+                .remote_addr()
+                .map_or_else(|| String::from("local"), |addr| addr.ip().to_string());
+            counter!("rayd.rpc.request_count_by_ip", 1, "ip" => ip);
+        }
+        value!(
+            "rayd.rpc.request_size",
+            T::request_size(request.get_ref()) as u64,
+            "method" => T::METHOD_NAME
+        );
 
+        // Clients connected over a Unix socket have no address.
+        let remote_addr = request
+            .remote_addr()
+            .map_or_else(|| String::from("local"), |addr| addr.to_string());
         let uuid = Uuid::new_v4();
+        let span = debug_span!(
+            "request",
+            id = %uuid,
+            method = T::METHOD_NAME,
+            remote = %remote_addr
+        );
+
+        let timings = match self.slow_request_threshold {
+            Some(_) if T::LOGGED_WHEN_SLOW => Some(Arc::new(RequestTimings::new(start))),
+            _ => None,
+        };
 
         let inner = async {
-            let remote_addr = request
-                .remote_addr()
-                .ok_or_else(|| Status::new(Code::Aborted, "unknown IP"))?;
-            debug!(
-                "New request: {} (remote: {}, id: {})",
-                request.get_ref(),
-                remote_addr,
-                uuid,
-            );
-
-            let traced = Traced::with_id(uuid, request.into_inner());
-            T::handle_request(traced, self.handle.clone())
-                .await
-                .map(Response::new)
+            if T::REQUIRES_READY && !self.health.is_serving() {
+                return Err(not_ready_error(&self.health));
+            }
+
+            let limiter = if T::IS_WRITE {
+                &self.write_limiter
+            } else {
+                &self.read_limiter
+            };
+            if !limiter.try_acquire() {
+                counter!("rayd.rpc.rate_limited_count", 1, "method" => T::METHOD_NAME);
+                return Err(Status::new(Code::ResourceExhausted, "rate limit exceeded"));
+            }
+
+            if let Some(err) = request_id_error(T::request_id(request.get_ref())) {
+                return Err(err);
+            }
+
+            let request_size = T::request_size(request.get_ref());
+            if let Some(err) = size_error("request", request_size, self.max_recv_message_size) {
+                return Err(err);
+            }
+
+            tracing::debug!("New request: {}", request.get_ref());
+
+            let deadline = request_deadline(request.metadata(), start);
+            let traced = Traced::with_id(uuid, request.into_inner())
+                .with_deadline(deadline)
+                .with_timings(timings.clone());
+            let response = T::handle_request(traced, self).await?;
+            let response_size = T::response_size(&response);
+            if let Some(err) = size_error("reply", response_size, self.max_send_message_size) {
+                return Err(err);
+            }
+            Ok(Response::new(response))
         };
 
-        let response = inner.await;
+        let response = inner.instrument(span.clone()).await;
+        let _enter = span.enter();
         match response {
-            Ok(ref inner) => debug!("Replying OK: {} (id: {})", inner.get_ref(), uuid),
+            Ok(ref inner) => {
+                tracing::debug!("Replying OK: {}", inner.get_ref());
+                value!(
+                    "rayd.rpc.response_size",
+                    T::response_size(inner.get_ref()) as u64,
+                    "method" => T::METHOD_NAME
+                );
+            }
             Err(ref err) => {
-                debug!("Replying ERROR: {} (id: {})", err, uuid);
+                tracing::debug!("Replying ERROR: {}", err);
                 counter!("rayd.rpc.error_count", 1, "method" => T::METHOD_NAME);
             }
         }
 
         timing!("rayd.rpc.request_duration", start, Instant::now(), "method" => T::METHOD_NAME);
 
+        if let (Some(threshold), Some(timings)) = (self.slow_request_threshold, timings) {
+            let duration = start.elapsed();
+            if duration > threshold {
+                tracing::warn!(
+                    "Slow request: method {}, id {}, took {:?} ({})",
+                    T::METHOD_NAME,
+                    uuid,
+                    duration,
+                    slow_request_breakdown(duration, &timings)
+                );
+            }
+        }
+
         response
     }
 }
 
+// Writes are split at the time they were persisted, reads tell how long they waited for their
+// epoch, as in rayd.machine_service.query_wait_time.
+fn slow_request_breakdown(duration: Duration, timings: &RequestTimings) -> String {
+    match (timings.persisted(), timings.query_wait()) {
+        (Some(persisted), _) => {
+            let applied = duration.checked_sub(persisted).unwrap_or_default();
+            format!("journal: {:?}, machine service: {:?}", persisted, applied)
+        }
+        (None, Some(wait)) => format!("waited for epoch: {:?}", wait),
+        (None, None) => String::from("neither journaled nor queried"),
+    }
+}
+
+fn size_error(kind: &str, size: usize, limit: usize) -> Option<Status> {
+    if limit > 0 && size > limit {
+        let message = format!(
+            "{} of {} bytes exceeds the limit of {} bytes",
+            kind, size, limit
+        );
+        Some(Status::new(Code::ResourceExhausted, message))
+    } else {
+        None
+    }
+}
+
+// Only requests made during recovery are marked for retrying: once shutdown has begun, this
+// rayd is not coming back.
+fn not_ready_error(health: &HealthReporter) -> Status {
+    if health.is_recovered() {
+        return Status::new(Code::Unavailable, "rayd is shutting down");
+    }
+    let mut metadata = MetadataMap::new();
+    metadata.insert(NOT_READY_METADATA, MetadataValue::from_static("recovering"));
+    Status::with_metadata(Code::Unavailable, "recovering, retry", metadata)
+}
+
+// Parses the grpc-timeout header: up to 8 digits followed by a unit. A malformed timeout is
+// treated as no timeout at all.
+fn request_deadline(metadata: &MetadataMap, start: Instant) -> Option<Instant> {
+    let timeout = metadata.get("grpc-timeout")?.to_str().ok()?;
+    if timeout.len() < 2 || timeout.len() > 9 {
+        return None;
+    }
+    let (amount, unit) = timeout.split_at(timeout.len() - 1);
+    let amount: u64 = amount.parse().ok()?;
+    let timeout = match unit {
+        "H" => Duration::from_secs(amount * 60 * 60),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    };
+    start.checked_add(timeout)
+}
+
+fn request_id_error(id: &[u8]) -> Option<Status> {
+    match id.len() {
+        0 | 16 => None,
+        len => Some(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "request_id must be empty or 16 bytes long, got {} bytes",
+                len
+            ),
+        )),
+    }
+}
+
+// Requests without a key are more likely a field left unset than a key meant to be empty, so
+// they are rejected rather than served from a key shared by all of them.
+fn empty_key_error(key: &[u8]) -> Option<Status> {
+    if key.is_empty() {
+        Some(Status::new(Code::InvalidArgument, "the key is empty"))
+    } else {
+        None
+    }
+}
+
+fn namespace_error(namespace: &[u8]) -> Option<Status> {
+    if namespace.len() > MAX_NAMESPACE_LEN {
+        let message = format!(
+            "namespace of {} bytes exceeds the limit of {} bytes",
+            namespace.len(),
+            MAX_NAMESPACE_LEN
+        );
+        Some(Status::new(Code::InvalidArgument, message))
+    } else {
+        None
+    }
+}
+
 type BoxedFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
 
 // Don't use async_trait macro to avoid one excessive heap allocation.
-impl Storage for RayStorageService {
+impl<K: KvStore> Storage for RayStorageService<K> {
+    type DumpKeysStream = KeyValueStream;
+    type WatchStream = ChangeEventStream;
+
     fn set<'a, 'b>(
         &'a self,
         request: Request<SetRequest>,
@@ -131,6 +1423,39 @@ impl Storage for RayStorageService {
         Box::pin(self.handle_request::<SetRequestHandler>(request))
     }
 
+    fn batch_set<'a, 'b>(
+        &'a self,
+        request: Request<BatchSetRequest>,
+    ) -> BoxedFuture<'b, Result<Response<BatchSetReply>, Status>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(self.handle_request::<BatchSetRequestHandler>(request))
+    }
+
+    fn transaction<'a, 'b>(
+        &'a self,
+        request: Request<TransactionRequest>,
+    ) -> BoxedFuture<'b, Result<Response<TransactionReply>, Status>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(self.handle_request::<TransactionRequestHandler>(request))
+    }
+
+    fn bulk_set<'a, 'b>(
+        &'a self,
+        request: Request<Streaming<SetRequest>>,
+    ) -> BoxedFuture<'b, Result<Response<BulkSetReply>, Status>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(self.handle_request::<BulkSetRequestHandler>(request.map(SetStream)))
+    }
+
     fn get<'a, 'b>(
         &'a self,
         request: Request<GetRequest>,
@@ -141,4 +1466,312 @@ impl Storage for RayStorageService {
     {
         Box::pin(self.handle_request::<GetRequestHandler>(request))
     }
+
+    fn get_with_meta<'a, 'b>(
+        &'a self,
+        request: Request<GetRequest>,
+    ) -> BoxedFuture<'b, Result<Response<GetWithMetaReply>, Status>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(self.handle_request::<GetWithMetaRequestHandler>(request))
+    }
+
+    fn exists<'a, 'b>(
+        &'a self,
+        request: Request<ExistsRequest>,
+    ) -> BoxedFuture<'b, Result<Response<ExistsReply>, Status>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(self.handle_request::<ExistsRequestHandler>(request))
+    }
+
+    fn dump_keys<'a, 'b>(
+        &'a self,
+        request: Request<DumpKeysRequest>,
+    ) -> BoxedFuture<'b, Result<Response<Self::DumpKeysStream>, Status>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(self.handle_request::<DumpKeysRequestHandler>(request))
+    }
+
+    fn watch<'a, 'b>(
+        &'a self,
+        request: Request<WatchRequest>,
+    ) -> BoxedFuture<'b, Result<Response<Self::WatchStream>, Status>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(self.handle_request::<WatchRequestHandler>(request))
+    }
+
+    fn flush_namespace<'a, 'b>(
+        &'a self,
+        request: Request<FlushNamespaceRequest>,
+    ) -> BoxedFuture<'b, Result<Response<FlushNamespaceReply>, Status>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(self.handle_request::<FlushNamespaceRequestHandler>(request))
+    }
+
+    fn delete<'a, 'b>(
+        &'a self,
+        request: Request<DeleteRequest>,
+    ) -> BoxedFuture<'b, Result<Response<DeleteReply>, Status>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(self.handle_request::<DeleteRequestHandler>(request))
+    }
+
+    fn delete_prefix<'a, 'b>(
+        &'a self,
+        request: Request<DeletePrefixRequest>,
+    ) -> BoxedFuture<'b, Result<Response<DeletePrefixReply>, Status>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(self.handle_request::<DeletePrefixRequestHandler>(request))
+    }
+
+    fn ping<'a, 'b>(
+        &'a self,
+        request: Request<PingRequest>,
+    ) -> BoxedFuture<'b, Result<Response<PongReply>, Status>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(self.handle_request::<PingRequestHandler>(request))
+    }
+
+    fn info<'a, 'b>(
+        &'a self,
+        request: Request<InfoRequest>,
+    ) -> BoxedFuture<'b, Result<Response<InfoReply>, Status>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(self.handle_request::<InfoRequestHandler>(request))
+    }
+
+    fn trigger_snapshot<'a, 'b>(
+        &'a self,
+        request: Request<TriggerSnapshotRequest>,
+    ) -> BoxedFuture<'b, Result<Response<TriggerSnapshotReply>, Status>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(self.handle_request::<TriggerSnapshotRequestHandler>(request))
+    }
+
+    fn sync<'a, 'b>(
+        &'a self,
+        request: Request<SyncRequest>,
+    ) -> BoxedFuture<'b, Result<Response<SyncReply>, Status>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(self.handle_request::<SyncRequestHandler>(request))
+    }
+
+    fn clear<'a, 'b>(
+        &'a self,
+        request: Request<ClearRequest>,
+    ) -> BoxedFuture<'b, Result<Response<ClearReply>, Status>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(self.handle_request::<ClearRequestHandler>(request))
+    }
+
+    fn get_set<'a, 'b>(
+        &'a self,
+        request: Request<GetSetRequest>,
+    ) -> BoxedFuture<'b, Result<Response<GetSetReply>, Status>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(self.handle_request::<GetSetRequestHandler>(request))
+    }
+
+    fn increment<'a, 'b>(
+        &'a self,
+        request: Request<IncrementRequest>,
+    ) -> BoxedFuture<'b, Result<Response<IncrementReply>, Status>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(self.handle_request::<IncrementRequestHandler>(request))
+    }
+
+    fn append<'a, 'b>(
+        &'a self,
+        request: Request<AppendRequest>,
+    ) -> BoxedFuture<'b, Result<Response<AppendReply>, Status>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(self.handle_request::<AppendRequestHandler>(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        server::{kv_store::HashStore, machine_service::MachineServiceRequest},
+        util::{profiled_channel, ProfiledReceiver},
+    };
+
+    use tokio::sync::broadcast;
+
+    use std::sync::atomic::AtomicU64;
+
+    type TestMachine = StorageMachine<HashStore>;
+
+    // The service along with the queue of the machine service it sends its requests to,
+    // which nothing serves. The service is not ready until its health reporter is set.
+    fn new_service(
+        config: &RpcConfig,
+    ) -> (
+        RayStorageService<HashStore>,
+        ProfiledReceiver<MachineServiceRequest<TestMachine>>,
+    ) {
+        let (machine_sender, machine_receiver) = profiled_channel(10);
+        let (watch_sender, _) = broadcast::channel(1);
+        let handle = MachineServiceHandle::new(
+            None,
+            machine_sender,
+            Arc::new(AtomicU64::new(0)),
+            false,
+            watch_sender,
+        );
+        let service = RayStorageService::new(
+            handle,
+            SnapshotServiceHandle::disabled(),
+            HealthReporter::new(),
+            config,
+        );
+        (service, machine_receiver)
+    }
+
+    #[test]
+    fn payload_sizes_count_the_fields() {
+        let request = SetRequest {
+            key: b"key".to_vec(),
+            value: b"value".to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(SetRequestHandler::request_size(&request), 8);
+
+        let request = GetRequest {
+            key: b"key".to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(GetRequestHandler::request_size(&request), 3);
+        let reply = GetReply {
+            value: vec![0; 100],
+            found: true,
+            epoch: 1,
+        };
+        assert_eq!(GetRequestHandler::response_size(&reply), 108);
+    }
+
+    #[tokio::test]
+    async fn ping_is_served_during_recovery() {
+        let (service, _machine_receiver) = new_service(&RpcConfig::default());
+        assert!(!service.health.is_recovered());
+        service.ping(Request::new(PingRequest {})).await.unwrap();
+
+        let request = Request::new(GetRequest {
+            key: b"key".to_vec(),
+            ..Default::default()
+        });
+        let status = service.get(request).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+    }
+
+    #[tokio::test]
+    async fn requests_are_marked_retryable_until_recovered() {
+        let (service, mut machine_receiver) = new_service(&RpcConfig::default());
+        let exists = || {
+            Request::new(ExistsRequest {
+                key: b"key".to_vec(),
+                ..Default::default()
+            })
+        };
+        let status = service.exists(exists()).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+        assert_eq!(status.message(), "recovering, retry");
+        assert_eq!(
+            status.metadata().get(NOT_READY_METADATA).unwrap(),
+            "recovering"
+        );
+        assert!(crate::client::is_not_ready(&status));
+
+        // Once recovered, the same request reaches the machine service.
+        service.health.set_serving(true);
+        let machine = tokio::spawn(async move {
+            match machine_receiver.recv().await {
+                Some(MachineServiceRequest::Query { result, .. }) => {
+                    result.send(Ok((MachineStatus::Exists(true), 1))).ok();
+                }
+                _ => panic!("the request did not reach the machine service"),
+            }
+        });
+        assert!(service.exists(exists()).await.unwrap().into_inner().exists);
+        machine.await.unwrap();
+
+        // Rejections during shutdown are not marked, as retrying them is pointless.
+        service.health.set_serving(false);
+        let status = service.exists(exists()).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+        assert_eq!(status.message(), "rayd is shutting down");
+        assert!(!crate::client::is_not_ready(&status));
+    }
+
+    #[test]
+    fn slow_requests_tell_where_the_time_went() {
+        let start = Instant::now();
+        let duration = Duration::from_millis(30);
+        let timings = RequestTimings::new(start);
+        assert_eq!(
+            slow_request_breakdown(duration, &timings),
+            "neither journaled nor queried"
+        );
+
+        timings.record_query_wait(Duration::from_millis(25));
+        assert_eq!(
+            slow_request_breakdown(duration, &timings),
+            "waited for epoch: 25ms"
+        );
+
+        // Writes are split at the time they were persisted, whether or not they also waited.
+        timings.mark_persisted(start + Duration::from_millis(20));
+        assert_eq!(
+            slow_request_breakdown(duration, &timings),
+            "journal: 20ms, machine service: 10ms"
+        );
+        let breakdown = slow_request_breakdown(Duration::from_millis(10), &timings);
+        assert_eq!(breakdown, "journal: 20ms, machine service: 0ns");
+    }
 }
@@ -1,76 +1,437 @@
-use super::{logging_service::FastlogMessage, machine_service::Machine};
+use super::machine_service::{Machine, MachineServiceRequest, SnapshotClone};
 
 use crate::{
     errors::*,
-    fastlog,
-    util::{ProfiledUnboundedReceiver, ProfiledUnboundedSender, Traced},
+    util::{
+        try_read_u32, Crc64Reader, Crc64Writer, ProfiledReceiver, ProfiledSender,
+        ProfiledUnboundedReceiver, ProfiledUnboundedSender,
+    },
 };
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
-use metrics::{gauge, value};
+use tokio::{
+    sync::oneshot,
+    task::{self, JoinHandle},
+    time::{self, Interval},
+};
+
+use futures::{future, select, FutureExt};
+
+use metrics::{counter, gauge, value};
+
+use std::{
+    cmp,
+    fmt::{self, Debug},
+    io::{self, Cursor, Read, Seek, SeekFrom, Take, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+// On-disk layout:
+//   full snapshot: [epoch: u64][format][machine snapshot][trailer]
+//   delta:         [epoch: u64][previous epoch: u64][format][machine delta][trailer]
+//   format:        [VERSIONED][Machine::SNAPSHOT_VERSION: u16]
+//   trailer:       [payload length: u64][payload CRC-64/ECMA: u64]
+// All integers are little-endian. The payload is everything before the trailer. Snapshots
+// written before format versions lack the format and are read as version 1.
+const TRAILER_SIZE: u64 = 16;
+// Tells the format apart from the machine state of older snapshots, which the storage machine
+// starts with u32::MAX or the length of a record.
+const VERSIONED: u32 = u32::MAX - 1;
 
-use std::io::{Read, Write};
+// How often the age of the last snapshot is reported, and the time trigger checked, when no
+// mutations come in.
+const AGE_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+const RETRY_MIN_BACKOFF: Duration = Duration::from_secs(1);
+const RETRY_MAX_BACKOFF: Duration = Duration::from_secs(300);
 
 pub trait PersistentWrite: Write {
     fn persist(&mut self) -> Result<()>;
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SnapshotKind {
+    // Whole machine state.
+    Full,
+    // Only the changes since the previous snapshot, full or delta.
+    Delta,
+}
+
+// A full snapshot along with all deltas taken after it, oldest first.
+pub struct SnapshotChain<R> {
+    pub base: R,
+    pub deltas: Vec<R>,
+}
+
 pub trait SnapshotStorage: Send + 'static {
-    type Writer: PersistentWrite;
-    type Reader: Read;
+    type Writer: PersistentWrite + Send + 'static;
+    type Reader: Read + Seek;
+
+    fn create_snapshot(&mut self, name: &str, kind: SnapshotKind) -> Result<Self::Writer>;
+    // Age 0 is the newest full snapshot, 1 is the one before it and so on.
+    fn open_snapshot(&self, age: usize) -> Result<Option<SnapshotChain<Self::Reader>>>;
+    fn dispose_old_snapshots(&mut self) -> Result<()>;
+}
+
+// Storages name snapshots <timestamp>_<epoch><extension>. Wall clocks may go backwards, so
+// snapshots are ordered by the epoch rather than by name.
+pub fn parse_snapshot_epoch(name: &str, extension: &str) -> Option<u64> {
+    let stem = name.strip_suffix(extension)?;
+    let (_, epoch) = stem.rsplit_once('_')?;
+    epoch.parse().ok()
+}
 
-    fn create_snapshot(&mut self, name: &str) -> Result<Self::Writer>;
-    fn open_last_snapshot(&self) -> Result<Option<Self::Reader>>;
+pub struct SnapshotRequest {
+    // Snapshot will be taken once the replica reaches at least this epoch.
+    pub min_epoch: u64,
+    pub notify: oneshot::Sender<u64>,
 }
 
-#[derive(Debug)]
-pub struct MutationProposal<U> {
-    pub mutation: Traced<U>,
-    pub epoch: u64,
+// Only need Debug to make tokio::sync::mpsc::errors::SendError<_> implement Error.
+impl Debug for SnapshotRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SnapshotRequest")
+    }
 }
 
-pub fn read_snapshot<R: Read, M: Machine>(reader: &mut R) -> Result<(M, u64)> {
-    let epoch = reader.read_u64::<LittleEndian>()?;
-    let machine = M::from_snapshot(reader)?;
+#[derive(Clone)]
+pub struct SnapshotServiceHandle {
+    // None on read-only replicas, which take no snapshots.
+    request_sender: Option<ProfiledUnboundedSender<SnapshotRequest>>,
+}
+
+impl SnapshotServiceHandle {
+    pub fn new(request_sender: ProfiledUnboundedSender<SnapshotRequest>) -> Self {
+        Self {
+            request_sender: Some(request_sender),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            request_sender: None,
+        }
+    }
+
+    // Returns the epoch of the snapshot, which is at least min_epoch.
+    pub async fn make_snapshot(&self, min_epoch: u64) -> Result<u64> {
+        let request_sender = match self.request_sender {
+            Some(ref sender) => sender,
+            None => bail!(ErrorKind::ReadOnlyReplica),
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        let request = SnapshotRequest {
+            min_epoch,
+            notify: sender,
+        };
+        request_sender
+            .send(request)
+            .chain_err(|| "snapshot request_sender failed")?;
+        receiver.await.chain_err(|| "sender dropped")
+    }
+}
+
+pub fn read_snapshot<R: Read + Seek, M: Machine>(reader: &mut R) -> Result<(M, u64)> {
+    read_checksummed(reader, |reader| {
+        let epoch = reader.read_u64::<LittleEndian>()?;
+        let (version, mut reader) = read_format_version::<_, M>(reader)?;
+        let machine = M::from_snapshot(&mut reader, version)?;
+        Ok((machine, epoch))
+    })
+}
+
+// Applies a delta on top of a machine at the given epoch, returns the new epoch.
+pub fn read_delta<R: Read + Seek, M: Machine>(
+    reader: &mut R,
+    machine: &mut M,
+    epoch: u64,
+) -> Result<u64> {
+    read_checksummed(reader, |reader| {
+        let delta_epoch = reader.read_u64::<LittleEndian>()?;
+        let previous_epoch = reader.read_u64::<LittleEndian>()?;
+        if previous_epoch != epoch {
+            bail!(
+                "snapshot chain is broken: delta for epoch {} follows epoch {}, expected {}",
+                delta_epoch,
+                epoch,
+                previous_epoch
+            );
+        }
+        let (version, mut reader) = read_format_version::<_, M>(reader)?;
+        machine.apply_delta(&mut reader, version)?;
+        Ok(delta_epoch)
+    })
+}
+
+// Returns the format version along with the reader of the machine state. Telling whether the
+// format is there takes reading the first bytes of the state of older snapshots, so these are
+// put back in front of it.
+pub fn read_format_version<R: Read, M: Machine>(mut reader: R) -> Result<(u16, impl Read)> {
+    let (version, consumed) = match try_read_u32(&mut reader)? {
+        Some(VERSIONED) => (reader.read_u16::<LittleEndian>()?, vec![]),
+        Some(first) => (1, first.to_le_bytes().to_vec()),
+        None => (1, vec![]),
+    };
+    if version > M::SNAPSHOT_VERSION {
+        bail!(
+            "snapshot format version {} is newer than this rayd supports (up to {})",
+            version,
+            M::SNAPSHOT_VERSION
+        );
+    }
+    Ok((version, Cursor::new(consumed).chain(reader)))
+}
+
+fn write_format_version<W: Write, M: Machine>(writer: &mut W) -> Result<()> {
+    writer.write_u32::<LittleEndian>(VERSIONED)?;
+    writer.write_u16::<LittleEndian>(M::SNAPSHOT_VERSION)?;
+    Ok(())
+}
+
+// Reads a full snapshot and as many of its deltas as are intact.
+pub fn read_snapshot_chain<R: Read + Seek, M: Machine>(
+    chain: SnapshotChain<R>,
+) -> Result<(M, u64)> {
+    let SnapshotChain { mut base, deltas } = chain;
+    let (mut machine, mut epoch) =
+        read_snapshot(&mut base).chain_err(|| "failed to read full snapshot")?;
+    for mut delta in deltas {
+        match read_delta(&mut delta, &mut machine, epoch) {
+            Ok(delta_epoch) => epoch = delta_epoch,
+            Err(err) => {
+                warn!(
+                    "Failed to read delta following epoch {}, ignoring the rest of the chain (error chain below)\n{}",
+                    epoch,
+                    err.display_fancy_chain()
+                );
+                break;
+            }
+        }
+    }
     Ok((machine, epoch))
 }
 
+// Recovers from the newest intact snapshot, falling back to older ones if it is corrupted.
+// The journal is disposed of up to the newest snapshot, so an older one is only used if the
+// journal, starting at journal_start, still continues it: otherwise the mutations in between
+// would be silently dropped. Returns None only if there are no snapshots at all.
+pub fn read_last_snapshot<S: SnapshotStorage, M: Machine>(
+    storage: &S,
+    journal_start: Option<u64>,
+) -> Result<Option<(M, u64)>> {
+    for age in 0.. {
+        let chain = match storage
+            .open_snapshot(age)
+            .chain_err(|| "failed to open snapshot")?
+        {
+            Some(chain) => chain,
+            None if age == 0 => break,
+            // Starting fresh would silently drop the state, as older journal files are gone.
+            None => bail!("all {} snapshots are corrupted", age),
+        };
+        match read_snapshot_chain(chain) {
+            Ok((machine, epoch)) => {
+                let covered = matches!(journal_start, Some(start) if start <= epoch + 1);
+                if age > 0 && !covered {
+                    let journal = match journal_start {
+                        Some(start) => format!("the journal starts at epoch {}", start),
+                        None => "the journal is empty".to_string(),
+                    };
+                    bail!(
+                        "the newest intact snapshot (age: {}, epoch: {}) is not continued by the journal, as {}",
+                        age,
+                        epoch,
+                        journal
+                    );
+                }
+                return Ok(Some((machine, epoch)));
+            }
+            Err(err) => warn!(
+                "Snapshot is corrupted, falling back to an older one (age: {}, error chain below)\n{}",
+                age,
+                err.display_fancy_chain()
+            ),
+        }
+    }
+    Ok(None)
+}
+
+// Verifies the trailer and the checksum of the whole payload before handing it to
+// `parse`, so that a corrupted file is never partially applied.
+fn read_checksummed<R, T, F>(reader: &mut R, parse: F) -> Result<T>
+where
+    R: Read + Seek,
+    F: FnOnce(&mut Take<&mut R>) -> Result<T>,
+{
+    let size = reader.seek(SeekFrom::End(0))?;
+    if size < TRAILER_SIZE {
+        bail!("snapshot is truncated (size: {})", size);
+    }
+
+    reader.seek(SeekFrom::End(-(TRAILER_SIZE as i64)))?;
+    let len = reader.read_u64::<LittleEndian>()?;
+    let checksum = reader.read_u64::<LittleEndian>()?;
+    if len != size - TRAILER_SIZE {
+        bail!(
+            "snapshot is truncated (payload size: {}, expected: {})",
+            size - TRAILER_SIZE,
+            len
+        );
+    }
+
+    reader.seek(SeekFrom::Start(0))?;
+    let mut payload = Crc64Reader::new(reader.by_ref().take(len));
+    io::copy(&mut payload, &mut io::sink())?;
+    if payload.sum64() != checksum {
+        bail!(
+            "snapshot checksum mismatch (actual: {:016x}, expected: {:016x})",
+            payload.sum64(),
+            checksum
+        );
+    }
+
+    reader.seek(SeekFrom::Start(0))?;
+    parse(&mut reader.take(len))
+}
+
+// Length of the payload of a snapshot that may be corrupted: as the trailer says if that fits
+// the size of the file, the whole file otherwise, e.g. when it is truncated.
+pub fn unchecked_payload_len<R: Read + Seek>(reader: &mut R) -> Result<u64> {
+    let size = reader.seek(SeekFrom::End(0))?;
+    if size >= TRAILER_SIZE {
+        reader.seek(SeekFrom::End(-(TRAILER_SIZE as i64)))?;
+        if reader.read_u64::<LittleEndian>()? == size - TRAILER_SIZE {
+            return Ok(size - TRAILER_SIZE);
+        }
+    }
+    Ok(size)
+}
+
+fn write_checksummed<W, F>(writer: &mut W, write: F) -> Result<()>
+where
+    W: Write,
+    F: FnOnce(&mut Crc64Writer<W>) -> Result<()>,
+{
+    let mut payload = Crc64Writer::new(writer);
+    write(&mut payload)?;
+    let (len, checksum) = (payload.len(), payload.sum64());
+    let writer = payload.into_inner();
+    writer.write_u64::<LittleEndian>(len)?;
+    writer.write_u64::<LittleEndian>(checksum)?;
+    Ok(())
+}
+
 fn write_snapshot<W: Write, M: Machine>(writer: &mut W, machine: &M, epoch: u64) -> Result<()> {
-    writer.write_u64::<LittleEndian>(epoch)?;
-    machine.write_snapshot(writer)
+    write_checksummed(writer, |writer| {
+        writer.write_u64::<LittleEndian>(epoch)?;
+        write_format_version::<_, M>(writer)?;
+        machine.write_snapshot(writer)
+    })
 }
 
+fn write_delta<W: Write, M: Machine>(
+    writer: &mut W,
+    machine: &M,
+    epoch: u64,
+    previous_epoch: u64,
+) -> Result<()> {
+    write_checksummed(writer, |writer| {
+        writer.write_u64::<LittleEndian>(epoch)?;
+        writer.write_u64::<LittleEndian>(previous_epoch)?;
+        write_format_version::<_, M>(writer)?;
+        machine.write_delta(writer)
+    })
+}
+
+struct SnapshotTask {
+    handle: JoinHandle<Result<()>>,
+    epoch: u64,
+    kind: SnapshotKind,
+    // Changes of the machine written, cleared once it is persisted.
+    change_count: u64,
+}
+
+// Keeps no machine of its own: snapshots are written from clones of the serving machine, so
+// that the state is held once rather than twice. Only the epochs journaled are followed, to
+// tell when a snapshot is due.
 pub struct SnapshotService<S: SnapshotStorage, M: Machine> {
     storage: S,
-    machine: M,
-    proposal_receiver: ProfiledUnboundedReceiver<MutationProposal<M::Mutation>>,
+    machine_sender: ProfiledSender<MachineServiceRequest<M>>,
+    epoch_receiver: ProfiledReceiver<u64>,
+    request_receiver: ProfiledUnboundedReceiver<SnapshotRequest>,
     min_epoch_sender: ProfiledUnboundedSender<u64>,
     epoch: u64,
     snapshot_interval: u64,
+    // Zero disables the trigger.
+    snapshot_interval_bytes: u64,
+    snapshot_interval_time: Option<Duration>,
     batch_size: usize,
     last_snapshot_epoch: u64,
+    // Written by the journal service, which runs ahead of this one, so the bytes journaled
+    // since the last snapshot are somewhat overestimated.
+    journal_bytes: Arc<AtomicU64>,
+    last_snapshot_bytes: u64,
+    pending_requests: Vec<SnapshotRequest>,
+    deltas_per_full: u32,
+    deltas_since_full: u32,
+    snapshot_task: Option<SnapshotTask>,
+    // Startup counts as a snapshot, as the state was just recovered.
+    last_snapshot_at: Instant,
+    age_report: Interval,
+    // Set after a failure until the next snapshot may be attempted. Epochs and requests keep
+    // being received meanwhile.
+    retry_at: Option<Instant>,
+    retry_backoff: Duration,
+    // Counted in rayd.snapshot_service.failures as well.
+    failure_count: u64,
 }
 
 impl<S: SnapshotStorage, M: Machine> SnapshotService<S, M> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         storage: S,
-        machine: M,
-        proposal_receiver: ProfiledUnboundedReceiver<MutationProposal<M::Mutation>>,
+        machine_sender: ProfiledSender<MachineServiceRequest<M>>,
+        epoch_receiver: ProfiledReceiver<u64>,
+        request_receiver: ProfiledUnboundedReceiver<SnapshotRequest>,
         min_epoch_sender: ProfiledUnboundedSender<u64>,
         epoch: u64,
         snapshot_interval: u64,
+        snapshot_interval_bytes: u64,
+        snapshot_interval_time: Option<Duration>,
+        journal_bytes: Arc<AtomicU64>,
         batch_size: usize,
+        deltas_per_full: u32,
     ) -> Self {
         Self {
             storage,
-            machine,
-            proposal_receiver,
+            machine_sender,
+            epoch_receiver,
+            request_receiver,
             min_epoch_sender,
             epoch,
             snapshot_interval,
+            snapshot_interval_bytes,
+            snapshot_interval_time,
             batch_size,
             last_snapshot_epoch: epoch,
+            journal_bytes,
+            last_snapshot_bytes: 0,
+            pending_requests: Vec::new(),
+            deltas_per_full,
+            // Always start with a full snapshot, so that every delta has a base to follow.
+            deltas_since_full: deltas_per_full,
+            snapshot_task: None,
+            last_snapshot_at: Instant::now(),
+            age_report: time::interval(AGE_REPORT_INTERVAL),
+            retry_at: None,
+            retry_backoff: RETRY_MIN_BACKOFF,
+            failure_count: 0,
         }
     }
 
@@ -79,71 +440,774 @@ impl<S: SnapshotStorage, M: Machine> SnapshotService<S, M> {
             gauge!("rayd.snapshot_service.epoch", self.epoch as i64);
             gauge!(
                 "rayd.snapshot_service.queue_size",
-                self.proposal_receiver.approx_len()
+                self.epoch_receiver.approx_len()
+            );
+            gauge!(
+                "rayd.snapshot_service.last_snapshot_age_seconds",
+                self.last_snapshot_at.elapsed().as_secs() as i64
             );
 
-            self.apply_mutation_batch()
-                .await
-                .chain_err(|| "failed to apply mutation batch")?;
+            let snapshot_task = &mut self.snapshot_task;
+            let snapshot_written = async move {
+                match snapshot_task {
+                    Some(task) => (&mut task.handle).await,
+                    None => future::pending().await,
+                }
+            };
+            let retry_at = self.retry_at;
+            let retry_due = async move {
+                match retry_at {
+                    Some(at) => time::delay_until(at.into()).await,
+                    None => future::pending().await,
+                }
+            };
+
+            select! {
+                maybe_request = self.request_receiver.recv().fuse() => {
+                    let request = maybe_request.chain_err(|| "request_receiver failed")?;
+                    self.pending_requests.push(request);
+                },
+                maybe_epoch = self.epoch_receiver.recv().fuse() => {
+                    let epoch = maybe_epoch.chain_err(|| "epoch_receiver failed")?;
+                    self.receive_epoch_batch(epoch);
+                },
+                _ = self.age_report.tick().fuse() => (),
+                _ = retry_due.fuse() => self.retry_at = None,
+                result = snapshot_written.fuse() => {
+                    let task = self.snapshot_task.take().unwrap();
+                    gauge!("rayd.snapshot_service.in_progress", 0);
+                    let epoch = task.epoch;
+                    let result = result
+                        .chain_err(|| "snapshot writer panicked")
+                        .and_then(|result| result);
+                    let result = self
+                        .finish_snapshot(task, result)
+                        .await
+                        .chain_err(|| ErrorKind::SnapshotFailed(epoch));
+                    if let Err(err) = result {
+                        self.snapshot_failed(err);
+                    }
+                },
+            }
 
-            if self.epoch - self.last_snapshot_epoch >= self.snapshot_interval {
-                self.make_snapshot()
-                    .chain_err(|| format!("failed to make snapshot for epoch {}", self.epoch))?;
+            // Only one snapshot is written at a time.
+            if self.snapshot_task.is_some() {
+                continue;
             }
-        }
-    }
 
-    pub async fn apply_mutation_batch(&mut self) -> Result<()> {
-        for i in 0..self.batch_size {
-            let proposal = if i == 0 {
-                self.proposal_receiver
-                    .recv()
-                    .await
-                    .chain_err(|| "proposal_receiver failed")?
-            } else {
-                match self.proposal_receiver.try_recv() {
-                    Ok(proposal) => proposal,
-                    Err(_) => {
-                        value!("rayd.snapshot_service.batch_size", i as u64);
-                        break;
+            let requested = self
+                .pending_requests
+                .iter()
+                .any(|request| request.min_epoch <= self.epoch);
+
+            if requested || self.snapshot_due() {
+                if self.last_snapshot_epoch >= self.epoch {
+                    self.notify_requests();
+                } else if self.retry_at.is_none() {
+                    let epoch = self.epoch;
+                    let result = self
+                        .start_snapshot()
+                        .await
+                        .chain_err(|| ErrorKind::SnapshotFailed(epoch));
+                    if let Err(err) = result {
+                        self.snapshot_failed(err);
                     }
                 }
-            };
+            }
+        }
+    }
+
+    // Whichever of the mutation count, journal size and time triggers fires first.
+    fn snapshot_due(&self) -> bool {
+        let journaled = self.journal_bytes.load(Ordering::Relaxed) - self.last_snapshot_bytes;
+        self.epoch - self.last_snapshot_epoch >= self.snapshot_interval
+            || (self.snapshot_interval_bytes > 0 && journaled >= self.snapshot_interval_bytes)
+            || self
+                .snapshot_interval_time
+                .is_some_and(|interval| self.last_snapshot_at.elapsed() >= interval)
+    }
 
-            let MutationProposal { mutation, epoch } = proposal;
+    fn snapshot_failed(&mut self, err: Error) {
+        error!(
+            "Snapshot failed, retrying in {:?} (error chain below)\n{}",
+            self.retry_backoff,
+            err.display_fancy_chain()
+        );
+        self.recover_from_failure();
+    }
 
-            assert_eq!(epoch, self.epoch + 1);
+    // Prepares the service to serve again after a failed snapshot, or after it panicked, and
+    // puts off the next snapshot for a backoff.
+    pub fn recover_from_failure(&mut self) {
+        self.failure_count += 1;
+        counter!("rayd.snapshot_service.failures", 1);
+        // The failed snapshot may have left a delta behind that reads as intact, which the
+        // next delta would not follow, so a new chain is started.
+        self.deltas_since_full = self.deltas_per_full;
+        // Requesters get an error rather than waiting for the retry.
+        self.pending_requests.clear();
+        self.retry_at = Some(Instant::now() + self.retry_backoff);
+        self.retry_backoff = cmp::min(self.retry_backoff * 2, RETRY_MAX_BACKOFF);
+    }
 
-            fastlog!(FastlogMessage::ApplyingMutation {
-                epoch: self.epoch + 1,
-                id: mutation.id
-            });
+    fn receive_epoch_batch(&mut self, first: u64) {
+        self.epoch = self.epoch.max(first);
+        for i in 1..self.batch_size {
+            match self.epoch_receiver.try_recv() {
+                Ok(epoch) => self.epoch = self.epoch.max(epoch),
+                Err(_) => {
+                    value!("rayd.snapshot_service.batch_size", i as u64);
+                    break;
+                }
+            }
+        }
+    }
 
-            self.machine.apply_mutation(mutation.into_payload());
-            self.epoch += 1;
+    // Reply to every request satisfied by the last snapshot.
+    fn notify_requests(&mut self) {
+        let epoch = self.last_snapshot_epoch;
+        let (satisfied, pending): (Vec<_>, Vec<_>) = self
+            .pending_requests
+            .drain(..)
+            .partition(|request| request.min_epoch <= epoch);
+        self.pending_requests = pending;
+        for request in satisfied {
+            request.notify.send(epoch).ok(); // Ignore error
         }
-        Ok(())
     }
 
-    pub fn make_snapshot(&mut self) -> Result<()> {
-        info!("Snapshot initiated (epoch: {})", self.epoch);
+    // Snapshot is written from a clone of the serving machine in a blocking thread, so that
+    // mutations keep being applied meanwhile.
+    async fn start_snapshot(&mut self) -> Result<()> {
+        let kind = if self.deltas_since_full < self.deltas_per_full {
+            SnapshotKind::Delta
+        } else {
+            SnapshotKind::Full
+        };
+
+        // The machine may be a little ahead of the epochs received so far, never behind them.
+        let (sender, receiver) = oneshot::channel();
+        self.machine_sender
+            .send(MachineServiceRequest::Snapshot {
+                min_epoch: self.epoch,
+                track_changes: self.deltas_per_full > 0,
+                result: sender,
+            })
+            .await
+            .chain_err(|| "machine_sender failed")?;
+        let SnapshotClone {
+            machine,
+            epoch,
+            change_count,
+        } = receiver
+            .await
+            .chain_err(|| "machine service dropped request")?;
+        self.epoch = epoch;
+        self.last_snapshot_bytes = self.journal_bytes.load(Ordering::Relaxed);
+
+        info!("Snapshot initiated (epoch: {}, kind: {:?})", epoch, kind);
 
         let mut writer = self
             .storage
-            .create_snapshot(&self.epoch.to_string())
+            .create_snapshot(&format!("{:020}", epoch), kind)
             .chain_err(|| "failed to create snapshot writer")?;
 
-        write_snapshot(&mut writer, &self.machine, self.epoch)
+        let previous_epoch = self.last_snapshot_epoch;
+        let handle = task::spawn_blocking(move || {
+            match kind {
+                SnapshotKind::Full => write_snapshot(&mut writer, &machine, epoch),
+                SnapshotKind::Delta => write_delta(&mut writer, &machine, epoch, previous_epoch),
+            }
             .and_then(|_| writer.persist())
-            .chain_err(|| "snapshot write failed")?;
+        });
+
+        self.snapshot_task = Some(SnapshotTask {
+            handle,
+            epoch,
+            kind,
+            change_count,
+        });
+        gauge!("rayd.snapshot_service.in_progress", 1);
+
+        Ok(())
+    }
 
+    async fn finish_snapshot(&mut self, task: SnapshotTask, result: Result<()>) -> Result<()> {
+        result.chain_err(|| "snapshot write failed")?;
+
+        self.machine_sender
+            .send(MachineServiceRequest::ClearChanges {
+                change_count: task.change_count,
+            })
+            .await
+            .chain_err(|| "machine_sender failed")?;
+
+        self.deltas_since_full = match task.kind {
+            SnapshotKind::Full => 0,
+            SnapshotKind::Delta => self.deltas_since_full + 1,
+        };
+
+        // The last full snapshot and its deltas now cover everything up to this epoch.
         self.min_epoch_sender
-            .send(self.epoch + 1)
+            .send(task.epoch + 1)
             .chain_err(|| "min_epoch_sender failed")?;
-        self.last_snapshot_epoch = self.epoch;
+        self.last_snapshot_epoch = task.epoch;
+        self.last_snapshot_at = Instant::now();
+        self.retry_backoff = RETRY_MIN_BACKOFF;
 
-        info!("Snapshot finished (epoch: {})", self.epoch);
+        info!("Snapshot finished (epoch: {})", task.epoch);
+
+        if let Err(err) = self.storage.dispose_old_snapshots() {
+            warn!(
+                "Failed to dispose old snapshots (error chain below)\n{}",
+                err.display_fancy_chain()
+            );
+        }
+
+        self.notify_requests();
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::{
+        config::SnapshotServiceConfig,
+        kv_store::HashStore,
+        machine_service::MachineService,
+        serve_snapshots,
+        storage_machine::{storage_key, Query, Status, StorageMachine},
+    };
+
+    use crate::{
+        proto::{self, mutation::Kind},
+        util::{profiled_channel, profiled_unbounded_channel, Traced},
+    };
+
+    use tokio::sync::broadcast;
+
+    use std::{
+        future::Future,
+        sync::{mpsc, Mutex},
+    };
+
+    type TestMachine = StorageMachine<HashStore>;
+
+    type Persisted = Arc<Mutex<Vec<(SnapshotKind, Vec<u8>)>>>;
+
+    enum Failure {
+        Error,
+        Panic,
+    }
+
+    // Keeps the snapshots persisted in memory, in the order they were.
+    #[derive(Clone, Default)]
+    struct MemoryStorage {
+        persisted: Persisted,
+        // Taken by the next writer, which waits for a message on it before persisting.
+        gate: Arc<Mutex<Option<mpsc::Receiver<()>>>>,
+        // Taken by the next snapshot, which fails to be created.
+        failure: Arc<Mutex<Option<Failure>>>,
+    }
+
+    struct MemoryWriter {
+        kind: SnapshotKind,
+        buffer: Vec<u8>,
+        gate: Option<mpsc::Receiver<()>>,
+        persisted: Persisted,
+    }
+
+    impl Write for MemoryWriter {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.buffer.write(data)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl PersistentWrite for MemoryWriter {
+        fn persist(&mut self) -> Result<()> {
+            if let Some(ref gate) = self.gate {
+                gate.recv().chain_err(|| "gate dropped")?;
+            }
+            let snapshot = (self.kind, self.buffer.clone());
+            self.persisted.lock().unwrap().push(snapshot);
+            Ok(())
+        }
+    }
+
+    impl SnapshotStorage for MemoryStorage {
+        type Writer = MemoryWriter;
+        type Reader = Cursor<Vec<u8>>;
+
+        fn create_snapshot(&mut self, _name: &str, kind: SnapshotKind) -> Result<MemoryWriter> {
+            let failure = self.failure.lock().unwrap().take();
+            match failure {
+                Some(Failure::Error) => bail!("injected failure"),
+                Some(Failure::Panic) => panic!("injected panic"),
+                None => (),
+            }
+            Ok(MemoryWriter {
+                kind,
+                buffer: vec![],
+                gate: self.gate.lock().unwrap().take(),
+                persisted: self.persisted.clone(),
+            })
+        }
+
+        // A full snapshot followed by the deltas persisted after it.
+        fn open_snapshot(&self, age: usize) -> Result<Option<SnapshotChain<Self::Reader>>> {
+            let persisted = self.persisted.lock().unwrap();
+            let base = persisted
+                .iter()
+                .rposition(|(kind, _)| *kind == SnapshotKind::Full);
+            let mut base = match base {
+                Some(base) => base,
+                None => return Ok(None),
+            };
+            for _ in 0..age {
+                base = match persisted[..base]
+                    .iter()
+                    .rposition(|(kind, _)| *kind == SnapshotKind::Full)
+                {
+                    Some(older) => older,
+                    None => return Ok(None),
+                };
+            }
+            let deltas = persisted[base + 1..]
+                .iter()
+                .take_while(|(kind, _)| *kind == SnapshotKind::Delta)
+                .map(|(_, snapshot)| Cursor::new(snapshot.clone()))
+                .collect();
+            Ok(Some(SnapshotChain {
+                base: Cursor::new(persisted[base].1.clone()),
+                deltas,
+            }))
+        }
+
+        fn dispose_old_snapshots(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    type TestService = SnapshotService<MemoryStorage, TestMachine>;
+
+    // Snapshot triggers, all off by default, so that snapshots are only taken on request.
+    struct Triggers {
+        interval: u64,
+        interval_bytes: u64,
+        interval_time: Option<Duration>,
+    }
+
+    impl Default for Triggers {
+        fn default() -> Self {
+            Self {
+                interval: u64::MAX,
+                interval_bytes: 0,
+                interval_time: None,
+            }
+        }
+    }
+
+    // A machine service and a snapshot service taking a delta on request after the first
+    // full snapshot, fed with mutations by hand instead of the journal service.
+    struct Services {
+        storage: MemoryStorage,
+        // Bytes journaled so far, as reported by the journal service.
+        journal_bytes: Arc<AtomicU64>,
+        machine_sender: ProfiledSender<MachineServiceRequest<TestMachine>>,
+        epoch_sender: ProfiledSender<u64>,
+        snapshots: SnapshotServiceHandle,
+        // Disposal is up to the journal service, which is left out.
+        _min_epoch_receiver: ProfiledUnboundedReceiver<u64>,
+        epoch: u64,
+    }
+
+    impl Services {
+        fn start() -> Self {
+            Self::start_with(Triggers::default())
+        }
+
+        fn start_with(triggers: Triggers) -> Self {
+            let (services, snapshot_service) = Self::new_with(triggers);
+            tokio::spawn(serve_snapshots(snapshot_service));
+            services
+        }
+
+        // The snapshot service is left for the caller to serve, see serve_until.
+        fn new() -> (Self, TestService) {
+            Self::new_with(Triggers::default())
+        }
+
+        fn new_with(triggers: Triggers) -> (Self, TestService) {
+            let storage = MemoryStorage::default();
+            let journal_bytes = Arc::new(AtomicU64::new(0));
+            let (machine_sender, machine_receiver) = profiled_channel(100);
+            let (epoch_sender, epoch_receiver) = profiled_channel(100);
+            let (request_sender, request_receiver) = profiled_unbounded_channel();
+            let (min_epoch_sender, min_epoch_receiver) = profiled_unbounded_channel();
+            let (watch_sender, _) = broadcast::channel(1);
+
+            let mut machine_service = MachineService::new(
+                TestMachine::default(),
+                machine_receiver,
+                0,
+                100,
+                0,
+                watch_sender,
+            );
+            tokio::spawn(async move { machine_service.serve().await });
+            let snapshot_service = SnapshotService::new(
+                storage.clone(),
+                machine_sender.clone(),
+                epoch_receiver,
+                request_receiver,
+                min_epoch_sender,
+                0,
+                triggers.interval,
+                triggers.interval_bytes,
+                triggers.interval_time,
+                journal_bytes.clone(),
+                100,
+                10,
+            );
+
+            let services = Self {
+                storage,
+                journal_bytes,
+                machine_sender,
+                epoch_sender,
+                snapshots: SnapshotServiceHandle::new(request_sender),
+                _min_epoch_receiver: min_epoch_receiver,
+                epoch: 0,
+            };
+            (services, snapshot_service)
+        }
+
+        async fn set(&mut self, key: &[u8]) {
+            self.epoch += 1;
+            let (result, applied) = oneshot::channel();
+            self.machine_sender
+                .send(MachineServiceRequest::Proposal {
+                    mutation: Traced::new(set(key)),
+                    epoch: self.epoch,
+                    result: Some(result),
+                })
+                .await
+                .unwrap();
+            applied.await.unwrap().0.unwrap();
+            self.epoch_sender.send(self.epoch).await.unwrap();
+        }
+
+        fn persisted(&self) -> Vec<(SnapshotKind, Vec<u8>)> {
+            self.storage.persisted.lock().unwrap().clone()
+        }
+
+        // Waits for the count of snapshots persisted to reach the given one.
+        async fn await_persisted(&self, count: usize) {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while self.persisted().len() < count {
+                assert!(Instant::now() < deadline, "no snapshot was taken");
+                time::delay_for(Duration::from_millis(10)).await;
+            }
+        }
+    }
+
+    async fn serve_until<F: Future>(snapshot_service: &mut TestService, until: F) -> F::Output {
+        select! {
+            result = snapshot_service.serve().fuse() => panic!("service stopped: {:?}", result),
+            output = until.fuse() => output,
+        }
+    }
+
+    fn set(key: &[u8]) -> proto::Mutation {
+        proto::Mutation {
+            kind: Some(Kind::Set(proto::SetRequest {
+                key: key.to_vec(),
+                value: key.to_vec(),
+                ..Default::default()
+            })),
+        }
+    }
+
+    fn persist_full(storage: &MemoryStorage, keys: &[&[u8]], epoch: u64) {
+        let mut machine = TestMachine::default();
+        for key in keys {
+            machine.apply_mutation(set(key)).unwrap();
+        }
+        let mut snapshot = vec![];
+        write_snapshot(&mut snapshot, &machine, epoch).unwrap();
+        let snapshot = (SnapshotKind::Full, snapshot);
+        storage.persisted.lock().unwrap().push(snapshot);
+    }
+
+    fn truncate_last(storage: &MemoryStorage) {
+        let mut persisted = storage.persisted.lock().unwrap();
+        let snapshot = &mut persisted.last_mut().unwrap().1;
+        snapshot.truncate(snapshot.len() - 1);
+    }
+
+    fn has_key(machine: &TestMachine, key: &[u8]) -> bool {
+        match machine.query_state(Query::Get(storage_key(&[], key.to_vec()))) {
+            Status::Value(value) => value.is_some(),
+            status => panic!("unexpected status: {:?}", status),
+        }
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn mutations_apply_while_a_snapshot_is_written() {
+        let mut services = Services::start();
+        services.set(b"a").await;
+        assert_eq!(services.snapshots.make_snapshot(1).await.unwrap(), 1);
+
+        services.set(b"b").await;
+        let (release, gate) = mpsc::channel();
+        *services.storage.gate.lock().unwrap() = Some(gate);
+        let snapshots = services.snapshots.clone();
+        let snapshot = tokio::spawn(async move { snapshots.make_snapshot(2).await });
+        while services.storage.gate.lock().unwrap().is_some() {
+            time::delay_for(Duration::from_millis(1)).await;
+        }
+
+        // The writer of the delta at epoch 2 is stuck meanwhile.
+        services.set(b"c").await;
+        assert_eq!(services.persisted().len(), 1);
+        release.send(()).unwrap();
+        assert_eq!(snapshot.await.unwrap().unwrap(), 2);
+
+        // The keys changed while the delta was written are in the next one, and only them.
+        assert_eq!(services.snapshots.make_snapshot(3).await.unwrap(), 3);
+        let persisted = services.persisted();
+        let kinds: Vec<_> = persisted.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(
+            kinds,
+            vec![SnapshotKind::Full, SnapshotKind::Delta, SnapshotKind::Delta]
+        );
+        let mut machine = TestMachine::default();
+        let mut delta = Cursor::new(persisted[2].1.clone());
+        assert_eq!(read_delta(&mut delta, &mut machine, 2).unwrap(), 3);
+        assert!(has_key(&machine, b"c"));
+        assert!(!has_key(&machine, b"b"));
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn mutations_apply_while_snapshots_are_retried() {
+        let mut services = Services::start();
+        services.set(b"a").await;
+        *services.storage.failure.lock().unwrap() = Some(Failure::Error);
+        services.snapshots.make_snapshot(1).await.unwrap_err();
+
+        // Epochs keep being received during the backoff, many more than their queue holds.
+        let sets = async {
+            for index in 0..500u32 {
+                services.set(&index.to_be_bytes()).await;
+            }
+        };
+        time::timeout(RETRY_MIN_BACKOFF, sets).await.unwrap();
+        assert_eq!(services.snapshots.make_snapshot(501).await.unwrap(), 501);
+
+        // A panicking service is restarted.
+        services.set(b"b").await;
+        *services.storage.failure.lock().unwrap() = Some(Failure::Panic);
+        services.snapshots.make_snapshot(502).await.unwrap_err();
+        services.set(b"c").await;
+        assert_eq!(services.snapshots.make_snapshot(503).await.unwrap(), 503);
+
+        // No delta follows a failure.
+        let persisted = services.persisted();
+        let kinds: Vec<_> = persisted.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, vec![SnapshotKind::Full, SnapshotKind::Full]);
+        let (machine, epoch) = read_last_snapshot::<_, TestMachine>(&services.storage, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(epoch, 503);
+        assert!(has_key(&machine, b"c"));
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn snapshots_reset_the_age_and_failures_are_counted() {
+        let (mut services, mut snapshot_service) = Services::new();
+        let snapshots = services.snapshots.clone();
+        services.set(b"a").await;
+        let started = Instant::now();
+        time::delay_for(Duration::from_millis(50)).await;
+        let epoch = serve_until(&mut snapshot_service, snapshots.make_snapshot(1)).await;
+        assert_eq!(epoch.unwrap(), 1);
+        assert!(snapshot_service.last_snapshot_at >= started + Duration::from_millis(50));
+        assert_eq!(snapshot_service.failure_count, 0);
+
+        services.set(b"b").await;
+        let last_snapshot_at = snapshot_service.last_snapshot_at;
+        *services.storage.failure.lock().unwrap() = Some(Failure::Error);
+        let epoch = serve_until(&mut snapshot_service, snapshots.make_snapshot(2)).await;
+        epoch.unwrap_err();
+        assert_eq!(snapshot_service.last_snapshot_at, last_snapshot_at);
+        assert_eq!(snapshot_service.failure_count, 1);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn mutation_count_triggers_a_snapshot() {
+        let mut services = Services::start_with(Triggers {
+            interval: 3,
+            ..Default::default()
+        });
+        services.set(b"a").await;
+        services.set(b"b").await;
+        time::delay_for(Duration::from_millis(100)).await;
+        assert!(services.persisted().is_empty());
+
+        services.set(b"c").await;
+        services.await_persisted(1).await;
+        assert_eq!(services.snapshots.make_snapshot(0).await.unwrap(), 3);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn journal_size_triggers_a_snapshot() {
+        let mut services = Services::start_with(Triggers {
+            interval_bytes: 1000,
+            ..Default::default()
+        });
+        services.journal_bytes.store(999, Ordering::Relaxed);
+        services.set(b"a").await;
+        time::delay_for(Duration::from_millis(100)).await;
+        assert!(services.persisted().is_empty());
+
+        services.journal_bytes.store(1000, Ordering::Relaxed);
+        services.set(b"b").await;
+        services.await_persisted(1).await;
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn elapsed_time_triggers_a_snapshot() {
+        let mut services = Services::start_with(Triggers {
+            interval_time: Some(Duration::from_millis(200)),
+            ..Default::default()
+        });
+        let started = Instant::now();
+        services.set(b"a").await;
+        // Checked along with the age report, even with no more mutations coming in.
+        services.await_persisted(1).await;
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn intact_snapshot_is_read() {
+        let storage = MemoryStorage::default();
+        persist_full(&storage, &[b"a"], 3);
+        persist_full(&storage, &[b"a", b"b"], 7);
+
+        let (machine, epoch) = read_last_snapshot::<_, TestMachine>(&storage, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(epoch, 7);
+        assert!(has_key(&machine, b"b"));
+    }
+
+    #[test]
+    fn truncated_snapshot_falls_back_to_one_the_journal_continues() {
+        let storage = MemoryStorage::default();
+        persist_full(&storage, &[b"a"], 3);
+        persist_full(&storage, &[b"a", b"b"], 7);
+        truncate_last(&storage);
+
+        let (machine, epoch) = read_last_snapshot::<_, TestMachine>(&storage, Some(4))
+            .unwrap()
+            .unwrap();
+        assert_eq!(epoch, 3);
+        assert!(has_key(&machine, b"a"));
+        assert!(!has_key(&machine, b"b"));
+    }
+
+    #[test]
+    fn truncated_snapshot_is_not_replaced_by_one_the_journal_skips() {
+        let storage = MemoryStorage::default();
+        persist_full(&storage, &[b"a"], 3);
+        persist_full(&storage, &[b"a", b"b"], 7);
+        truncate_last(&storage);
+
+        for &(journal_start, reason) in &[
+            (Some(5), "the journal starts at epoch 5"),
+            (None, "the journal is empty"),
+        ] {
+            let err = read_last_snapshot::<_, TestMachine>(&storage, journal_start)
+                .err()
+                .unwrap();
+            assert!(err.to_string().contains(reason), "{}", err);
+        }
+    }
+
+    #[test]
+    fn all_snapshots_truncated_is_an_error() {
+        let storage = MemoryStorage::default();
+        persist_full(&storage, &[b"a"], 3);
+        truncate_last(&storage);
+
+        let err = read_last_snapshot::<_, TestMachine>(&storage, Some(1))
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "all 1 snapshots are corrupted");
+    }
+
+    #[test]
+    fn snapshots_without_a_format_version_load_as_version_1() {
+        // Either kind of machine state, a record or the marker of sections, comes right after
+        // the header in snapshots written before format versions.
+        for &segments in &[1, 2] {
+            let mut machine = TestMachine::default();
+            machine.configure_snapshots(&SnapshotServiceConfig {
+                snapshot_segments: segments,
+                ..Default::default()
+            });
+            machine.apply_mutation(set(b"a")).unwrap();
+            let mut snapshot = vec![];
+            write_checksummed(&mut snapshot, |writer| {
+                writer.write_u64::<LittleEndian>(7)?;
+                machine.write_snapshot(writer)
+            })
+            .unwrap();
+            let (mut loaded, epoch) =
+                read_snapshot::<_, TestMachine>(&mut Cursor::new(snapshot)).unwrap();
+            assert_eq!(epoch, 7);
+            assert!(has_key(&loaded, b"a"));
+
+            machine.track_changes();
+            machine.apply_mutation(set(b"b")).unwrap();
+            let mut delta = vec![];
+            write_checksummed(&mut delta, |writer| {
+                writer.write_u64::<LittleEndian>(8)?;
+                writer.write_u64::<LittleEndian>(7)?;
+                machine.write_delta(writer)
+            })
+            .unwrap();
+            assert_eq!(
+                read_delta(&mut Cursor::new(delta), &mut loaded, 7).unwrap(),
+                8
+            );
+            assert!(has_key(&loaded, b"b"));
+        }
+    }
+
+    #[test]
+    fn snapshots_of_a_newer_format_version_are_refused() {
+        let mut snapshot = vec![];
+        write_checksummed(&mut snapshot, |writer| {
+            writer.write_u64::<LittleEndian>(7)?;
+            writer.write_u32::<LittleEndian>(VERSIONED)?;
+            writer.write_u16::<LittleEndian>(TestMachine::SNAPSHOT_VERSION + 1)?;
+            TestMachine::default().write_snapshot(writer)
+        })
+        .unwrap();
+        let err = read_snapshot::<_, TestMachine>(&mut Cursor::new(snapshot))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "snapshot format version 2 is newer than this rayd supports (up to 1)"
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use crate::errors::*;
+
+use log::{Level, Log, Record};
+
+use tracing::{
+    field::{Field, Visit},
+    span::{self, Attributes, Id},
+    subscriber::{self, Interest},
+    Event, Metadata, Subscriber,
+};
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+thread_local! {
+    // Spans entered on this thread, innermost last.
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+// Passes tracing events on to the log facade, prefixed with the spans they happen in, e.g.
+// "request{id=... method=Get}: New request: ...". Whatever the log facade would drop is
+// disabled here as well, so spans cost next to nothing unless their level is logged.
+pub struct SpanLogger {
+    // The log facade once it is set, see init.
+    logger: &'static dyn Log,
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, SpanData>>,
+}
+
+struct SpanData {
+    text: String,
+    refs: usize,
+}
+
+impl SpanLogger {
+    pub fn init() -> Result<()> {
+        subscriber::set_global_default(Self::new(log::logger()))
+            .chain_err(|| "failed to set tracing subscriber")
+    }
+
+    fn new(logger: &'static dyn Log) -> Self {
+        Self {
+            logger,
+            next_id: AtomicU64::new(1),
+            spans: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Subscriber for SpanLogger {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if self.enabled(metadata) {
+            Interest::always()
+        } else {
+            Interest::never()
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.logger.enabled(
+            &log::Metadata::builder()
+                .level(log_level(metadata.level()))
+                .target(metadata.target())
+                .build(),
+        )
+    }
+
+    fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+        let mut fields = FieldFormatter::default();
+        attributes.record(&mut fields);
+        let text = format!("{}{{{}}}", attributes.metadata().name(), fields.fields);
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let span = SpanData { text, refs: 1 };
+        self.spans.lock().unwrap().insert(id, span);
+        Id::from_u64(id)
+    }
+
+    // Fields recorded after a span is created are not shown.
+    fn record(&self, _span: &Id, _values: &span::Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut text = String::new();
+        {
+            let spans = self.spans.lock().unwrap();
+            ENTERED.with(|entered| {
+                for id in entered.borrow().iter() {
+                    if let Some(span) = spans.get(id) {
+                        text.push_str(&span.text);
+                        text.push_str(": ");
+                    }
+                }
+            });
+        }
+
+        let mut fields = FieldFormatter::default();
+        event.record(&mut fields);
+        text.push_str(&fields.message);
+        if !fields.fields.is_empty() {
+            text.push_str(" (");
+            text.push_str(&fields.fields);
+            text.push(')');
+        }
+
+        let metadata = event.metadata();
+        self.logger.log(
+            &Record::builder()
+                .level(log_level(metadata.level()))
+                .target(metadata.target())
+                .module_path(metadata.module_path())
+                .file(metadata.file())
+                .line(metadata.line())
+                .args(format_args!("{}", text))
+                .build(),
+        );
+    }
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(index) = entered.iter().rposition(|id| *id == span.into_u64()) {
+                entered.remove(index);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(data) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            data.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let id = span.into_u64();
+        match spans.get_mut(&id) {
+            Some(data) if data.refs > 1 => {
+                data.refs -= 1;
+                false
+            }
+            Some(_) => {
+                spans.remove(&id);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct FieldFormatter {
+    message: String,
+    fields: String,
+}
+
+impl Visit for FieldFormatter {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{}", value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            write!(self.message, "{:?}", value).unwrap();
+            return;
+        }
+        if !self.fields.is_empty() {
+            self.fields.push(' ');
+        }
+        write!(self.fields, "{}={:?}", field.name(), value).unwrap();
+    }
+}
+
+fn log_level(level: &tracing::Level) -> Level {
+    match *level {
+        tracing::Level::ERROR => Level::Error,
+        tracing::Level::WARN => Level::Warn,
+        tracing::Level::INFO => Level::Info,
+        tracing::Level::DEBUG => Level::Debug,
+        tracing::Level::TRACE => Level::Trace,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::future;
+
+    use tokio::time;
+
+    use tracing::debug_span;
+    use tracing_futures::Instrument;
+
+    use uuid::Uuid;
+
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct CapturingLogger {
+        lines: Mutex<Vec<String>>,
+    }
+
+    impl Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record<'_>) {
+            let line = record.args().to_string();
+            self.lines.lock().unwrap().push(line);
+        }
+
+        fn flush(&self) {}
+    }
+
+    // The way the RPC and machine services handle a request, with each one's span entered
+    // and left at every await.
+    async fn handle_request(id: Uuid) {
+        let span = debug_span!("request", id = %id, method = "get", remote = "local");
+        async {
+            tracing::debug!("New request");
+            time::delay_for(Duration::from_millis(1)).await;
+            let machine_span = debug_span!("request", id = %id);
+            machine_span.in_scope(|| tracing::debug!("Serving query"));
+            time::delay_for(Duration::from_millis(1)).await;
+            tracing::debug!("Replying OK");
+        }
+        .instrument(span)
+        .await
+    }
+
+    #[tokio::test]
+    async fn events_carry_the_id_of_their_request() {
+        let captured: &'static CapturingLogger = Box::leak(Box::default());
+        let dispatch = tracing::Dispatch::new(SpanLogger::new(captured));
+        let _default = tracing::dispatcher::set_default(&dispatch);
+        let ids = [Uuid::new_v4(), Uuid::new_v4()];
+        future::join(handle_request(ids[0]), handle_request(ids[1])).await;
+
+        let lines = captured.lines.lock().unwrap();
+        assert_eq!(lines.len(), 6, "{:?}", *lines);
+        for id in ids.iter() {
+            let tagged = format!("id={}", id);
+            let own: Vec<_> = lines.iter().filter(|line| line.contains(&tagged)).collect();
+            assert_eq!(own.len(), 3, "{:?}", *lines);
+            let prefix = format!("request{{{} method=get remote=local}}: ", tagged);
+            assert!(
+                own.iter().all(|line| line.starts_with(&prefix)),
+                "{:?}",
+                own
+            );
+            // The machine span is nested in the RPC one, with the same id.
+            assert!(own[1].ends_with(&format!("request{{{}}}: Serving query", tagged)));
+        }
+    }
+}
@@ -1,83 +1,2132 @@
-use crate::{errors::*, proto, server::machine_service::Machine, util::try_read_u32};
+use crate::{
+    errors::*,
+    proto::{self, applied_request::Outcome, mutation::Kind},
+    server::{
+        bloom_filter::CountingBloomFilter,
+        config::{MachineServiceConfig, SnapshotServiceConfig},
+        kv_store::{Compression, KvStore, StoredValue, Value},
+        machine_service::{AuditedWrite, KeyChange, Machine},
+        snapshot_service::{read_format_version, read_snapshot, unchecked_payload_len},
+    },
+    util::{try_read_u32, ByteCounter, Crc64Reader},
+};
 
 use prost::Message;
 
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use byte_string::ByteStr;
 
 use std::{
-    collections::HashMap,
-    io::{Read, Write},
+    borrow::Cow,
+    cmp,
+    collections::{hash_map::DefaultHasher, BTreeSet, HashMap, VecDeque},
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    mem,
+    path::Path,
 };
 
+// Layout of snapshots and deltas:
+//   [SECTIONED][section]...
+//   section: [tag: u32][payload length: u64][record]...
+//   record:  [length: u32][SnapshotRecord]
+// All integers are little-endian. Readers skip sections with unknown tags, so that new parts
+// of the machine state do not break older readers. Snapshots written before sections lack the
+// marker and hold the records alone.
+//
+// A full snapshot may split the map into several map sections, announced by a segments
+// section before them: [SEGMENTS_SECTION][4][segment count: u32]. Readers that know it decode
+// the segments in parallel, others read them one after another. The count comes first rather
+// than in a footer as snapshots are read as a stream.
+const SECTIONED: u32 = u32::MAX;
+const MAP_SECTION: u32 = 1;
+const REQUESTS_SECTION: u32 = 2;
+const SEGMENTS_SECTION: u32 = 3;
+
+// Keys of a namespace are stored as [0][namespace length][namespace][key]. Keys of the default
+// namespace are stored as they are, except for those starting with a zero byte, which are stored
+// as [0][0][key], so that keys of different namespaces never collide. Snapshots and the journal
+// hold namespaces apart from keys, so this layout only exists in memory.
+const NAMESPACE_MARKER: u8 = 0;
+pub const MAX_NAMESPACE_LEN: usize = u8::MAX as usize;
+
+// How many request ids are remembered. Not configurable, as every replica and every replay of
+// the journal must forget the same ones.
+const REQUEST_WINDOW: usize = 100_000;
+
+#[derive(Default, Clone)]
+pub struct StorageMachine<K: KvStore> {
+    map: K,
+    requests: RequestLog,
+    // Keys mutated since the last persisted snapshot, tracked only when incremental snapshots
+    // are on, each with the number of its last change.
+    changes: Option<HashMap<Box<[u8]>, u64>>,
+    // Changes counted so far: keys mutated and requests recorded while tracking.
+    change_count: u64,
+    // Numbers of the changes that recorded the requests since the last persisted snapshot,
+    // oldest first, tracked along with the keys.
+    new_requests: VecDeque<u64>,
+    // Holds every key in the map; only set up on the serving replica.
+    filter: Option<CountingBloomFilter>,
+    // Map sections of full snapshots, see SEGMENTS_SECTION; set on machines taking snapshots.
+    snapshot_segments: usize,
+    // Values written from now on are compressed if set. Values already stored, including
+    // those read from snapshots, are kept as they are.
+    compression: Option<Compression>,
+    // Set if eviction is configured, or while loading a snapshot that was taken with it.
+    eviction: Option<Eviction>,
+    // Keys changed by the mutation being applied, recorded only while the machine is watched.
+    key_changes: Option<Vec<KeyChange>>,
+}
+
+// Outcomes of the last REQUEST_WINDOW mutations that carried a request id, oldest first.
+// None stands for a mutation that failed.
+#[derive(Default, Clone)]
+struct RequestLog {
+    outcomes: HashMap<Box<[u8]>, Option<MutationOutcome>>,
+    order: VecDeque<Box<[u8]>>,
+}
+
+impl RequestLog {
+    fn get(&self, id: &[u8]) -> Option<Option<MutationOutcome>> {
+        self.outcomes.get(id).cloned()
+    }
+
+    fn record(&mut self, id: Box<[u8]>, outcome: Option<MutationOutcome>) {
+        if self.order.len() == REQUEST_WINDOW {
+            let oldest = self.order.pop_front().unwrap();
+            self.outcomes.remove(&oldest);
+        }
+        self.order.push_back(id.clone());
+        self.outcomes.insert(id, outcome);
+    }
+
+    // Fills in the outcome of a request recorded before it was known, unless it has been
+    // forgotten since.
+    fn update(&mut self, id: &[u8], outcome: Option<MutationOutcome>) {
+        if let Some(recorded) = self.outcomes.get_mut(id) {
+            *recorded = outcome;
+        }
+    }
+
+    // The last count requests, oldest first.
+    fn last(&self, count: usize) -> impl Iterator<Item = (&[u8], Option<MutationOutcome>)> {
+        self.order
+            .iter()
+            .skip(self.order.len().saturating_sub(count))
+            .map(move |id| (&**id, self.outcomes[id].clone()))
+    }
+}
+
+// Least recently written keys are evicted once the map is over either limit (0 for none).
+// Recency only follows mutations, in journal order, so every replica and every replay of the
+// journal evicts the same keys; reads would only touch the serving replica. Evictions are not
+// journaled: they follow from the mutations and the limits, and are removals like any other in
+// deltas. The ticks are kept in snapshots for the same reason.
 #[derive(Default, Clone)]
-pub struct StorageMachine {
-    map: HashMap<Box<[u8]>, Box<[u8]>>,
+struct Eviction {
+    max_keys: usize,
+    max_bytes: u64,
+    // Keys and stored values, without the overhead of the store.
+    bytes: u64,
+    // Keys by the tick of their last write, least recent first. Keys written before eviction
+    // was set up have tick 0 and go first, in key order.
+    keys: BTreeSet<(u64, Box<[u8]>)>,
+    ticks: HashMap<Box<[u8]>, u64>,
+    tick: u64,
 }
 
-impl Machine for StorageMachine {
-    type Mutation = proto::SetRequest;
-    type Query = Box<[u8]>;
-    type Status = Box<[u8]>;
+impl Eviction {
+    // Ticks read from snapshots are given, new writes take the next one.
+    fn touch(&mut self, key: &[u8], tick: Option<u64>) {
+        let tick = match tick {
+            Some(tick) => tick,
+            None => self.tick + 1,
+        };
+        self.tick = self.tick.max(tick);
+        if let Some(previous) = self.ticks.insert(key.into(), tick) {
+            self.keys.remove(&(previous, key.into()));
+        }
+        self.keys.insert((tick, key.into()));
+    }
 
-    fn apply_mutation(&mut self, mutation: Self::Mutation) {
-        let key = mutation.key.into_boxed_slice();
-        let value = mutation.value.into_boxed_slice();
+    fn forget(&mut self, key: &[u8]) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.keys.remove(&(tick, key.into()));
+        }
+    }
+
+    fn is_over(&self, key_count: usize) -> bool {
+        (self.max_keys > 0 && key_count > self.max_keys)
+            || (self.max_bytes > 0 && self.bytes > self.max_bytes)
+    }
+
+    fn tick_of(&self, key: &[u8]) -> u64 {
+        self.ticks.get(key).copied().unwrap_or(0)
+    }
+
+    // The keys of other must not be tracked yet.
+    fn append(&mut self, other: Self) {
+        self.bytes += other.bytes;
+        self.tick = self.tick.max(other.tick);
+        self.keys.extend(other.keys);
+        self.ticks.extend(other.ticks);
+    }
+}
+
+fn entry_bytes(key: &[u8], value: &StoredValue) -> u64 {
+    (key.len() + value.stored().len()) as u64
+}
+
+#[derive(Clone, Debug)]
+pub enum MutationOutcome {
+    Set,
+    Increment(i64),
+    Append(u64),
+    Transaction(bool),
+    FlushNamespace(u64),
+    Clear(u64),
+    DeletePrefix(u64),
+    // Whether the key was set.
+    Delete(bool),
+    // The value replaced, empty if the key was missing.
+    GetSet(Value),
+}
+
+// Keys of Get and Exists are stored keys, see storage_key.
+pub enum Query {
+    Get(Box<[u8]>),
+    Exists(Box<[u8]>),
+    // Entries of the namespace with keys greater than the given one (all entries if it is
+    // empty), unordered. The keys are returned without the namespace.
+    Dump(Box<[u8]>, Box<[u8]>),
+    // Keys of all namespaces.
+    KeyCount,
+}
+
+pub type Entry = (Box<[u8]>, Value);
+
+#[derive(Debug)]
+pub enum Status {
+    // None for a missing key.
+    Value(Option<Value>),
+    Exists(bool),
+    Entries(Vec<Entry>),
+    KeyCount(u64),
+}
+
+impl<K: KvStore> StorageMachine<K> {
+    // Skips the store for keys the filter rules out.
+    fn lookup(&self, key: &[u8]) -> Option<&StoredValue> {
+        match self.filter {
+            Some(ref filter) if !filter.may_contain(key) => None,
+            _ => self.map.get(key),
+        }
+    }
+
+    fn insert(&mut self, key: Box<[u8]>, value: Value) {
+        let value = StoredValue::new(value, self.compression);
+        self.insert_stored(key, value);
+    }
+
+    fn insert_stored(&mut self, key: Box<[u8]>, value: StoredValue) {
+        self.insert_ticked(key, value, None);
+    }
+
+    fn insert_ticked(&mut self, key: Box<[u8]>, value: StoredValue, tick: Option<u64>) {
+        self.record_change(&key);
+        if let Some(ref mut key_changes) = self.key_changes {
+            key_changes.push(key_change(&key, Some(value.to_plain())));
+        }
+        if let Some(ref mut filter) = self.filter {
+            if !self.map.contains_key(&key) {
+                filter.insert(&key);
+            }
+        }
+        if let Some(ref mut eviction) = self.eviction {
+            if let Some(previous) = self.map.get(&key) {
+                eviction.bytes -= entry_bytes(&key, previous);
+            }
+            eviction.bytes += entry_bytes(&key, &value);
+            eviction.touch(&key, tick);
+        }
         self.map.insert(key, value);
     }
 
-    fn query_state(&self, query: Self::Query) -> Self::Status {
-        self.map
-            .get(&query)
-            .cloned()
-            .unwrap_or_else(|| Vec::new().into_boxed_slice())
+    // The key leaves no tombstone in the map: the mutation removing it stays in the journal
+    // until a snapshot past it is taken, deltas record it as removed, and full snapshots just
+    // omit it. Journal blobs are only disposed of once such a snapshot is written, and deltas
+    // only along with the full snapshot under them, so a state from before the removal is
+    // never loaded without it.
+    fn remove(&mut self, key: &[u8]) -> bool {
+        let value = match self.map.remove(key) {
+            Some(value) => value,
+            None => return false,
+        };
+        if let Some(ref mut filter) = self.filter {
+            filter.remove(key);
+        }
+        if let Some(ref mut eviction) = self.eviction {
+            eviction.bytes -= entry_bytes(key, &value);
+            eviction.forget(key);
+        }
+        self.record_change(key);
+        if let Some(ref mut key_changes) = self.key_changes {
+            key_changes.push(key_change(key, None));
+        }
+        true
     }
 
-    fn write_snapshot<T: Write>(&self, writer: &mut T) -> Result<()> {
+    // The store only has to visit the keys of the namespace if it keeps them in order.
+    fn flush_namespace(&mut self, namespace: &[u8]) -> Result<u64> {
+        if namespace.is_empty() {
+            bail!(ErrorKind::InvalidArgument(
+                "the default namespace cannot be flushed".into()
+            ));
+        }
+        Ok(self.remove_prefix(&namespace_prefix(namespace)))
+    }
+
+    // Keys of the namespace starting with the prefix start with its stored form, see
+    // storage_key, and so do no other keys as long as the prefix is not empty.
+    fn delete_prefix(&mut self, namespace: &[u8], prefix: Vec<u8>) -> Result<u64> {
+        if prefix.is_empty() {
+            bail!(ErrorKind::InvalidArgument(
+                "the prefix to delete is empty".into()
+            ));
+        }
+        Ok(self.remove_prefix(&storage_key(namespace, prefix)))
+    }
+
+    // Returns the number of keys removed.
+    fn remove_prefix(&mut self, prefix: &[u8]) -> u64 {
+        let removed = self.map.remove_prefix(prefix);
+        let count = removed.len() as u64;
+        for (key, value) in removed {
+            if let Some(ref mut filter) = self.filter {
+                filter.remove(&key);
+            }
+            if let Some(ref mut eviction) = self.eviction {
+                eviction.bytes -= entry_bytes(&key, &value);
+                eviction.forget(&key);
+            }
+            if let Some(ref mut key_changes) = self.key_changes {
+                key_changes.push(key_change(&key, None));
+            }
+            self.record_change(&key);
+        }
+        count
+    }
+
+    // Runs after every mutation, so that which keys go does not depend on how mutations are
+    // batched. The key just written goes last, and only if it is over the limits on its own.
+    fn evict(&mut self) {
+        loop {
+            let key = match self.eviction {
+                Some(ref eviction) if eviction.is_over(self.map.len()) => {
+                    match eviction.keys.iter().next() {
+                        Some((_, key)) => key.clone(),
+                        None => return,
+                    }
+                }
+                _ => return,
+            };
+            self.remove(&key);
+        }
+    }
+
+    fn tick_of(&self, key: &[u8]) -> u64 {
+        self.eviction
+            .as_ref()
+            .map_or(0, |eviction| eviction.tick_of(key))
+    }
+
+    // Starts tracking the keys in the map, at tick 0, unless they already are.
+    fn track_recency(&mut self) {
+        if self.eviction.is_some() {
+            return;
+        }
+        let mut eviction = Eviction::default();
         for (key, value) in self.map.iter() {
-            let set = proto::SetRequest {
-                key: key.to_vec(),
-                value: value.to_vec(),
+            eviction.bytes += entry_bytes(key, value);
+            eviction.touch(key, Some(0));
+        }
+        self.eviction = Some(eviction);
+    }
+
+    fn record_change(&mut self, key: &[u8]) {
+        if let Some(ref mut changes) = self.changes {
+            self.change_count += 1;
+            changes.insert(key.into(), self.change_count);
+        }
+    }
+
+    // Only the last REQUEST_WINDOW requests are remembered, so no more are tracked.
+    fn record_request(&mut self, id: Box<[u8]>, outcome: Option<MutationOutcome>) {
+        if self.changes.is_some() {
+            self.change_count += 1;
+            if self.new_requests.len() == REQUEST_WINDOW {
+                self.new_requests.pop_front();
+            }
+            self.new_requests.push_back(self.change_count);
+        }
+        self.requests.record(id, outcome);
+    }
+
+    fn apply(&mut self, mutation: proto::Mutation) -> Result<MutationOutcome> {
+        match mutation.kind {
+            Some(Kind::Set(set)) => {
+                self.insert(storage_key(&set.namespace, set.key), set.value.into());
+                Ok(MutationOutcome::Set)
+            }
+            Some(Kind::Increment(increment)) => {
+                let key = storage_key(&[], increment.key);
+                let value = self.increment(key, increment.delta)?;
+                Ok(MutationOutcome::Increment(value))
+            }
+            Some(Kind::Append(append)) => {
+                let key = storage_key(&[], append.key);
+                let length = self.append(key, &append.suffix, append.max_value_size)?;
+                Ok(MutationOutcome::Append(length))
+            }
+            Some(Kind::BatchSet(batch_set)) => {
+                for entry in batch_set.entries {
+                    self.insert(storage_key(&[], entry.key), entry.value.into());
+                }
+                Ok(MutationOutcome::Set)
+            }
+            Some(Kind::Transaction(transaction)) => {
+                let committed = self.transact(transaction)?;
+                Ok(MutationOutcome::Transaction(committed))
+            }
+            Some(Kind::FlushNamespace(flush)) => {
+                let count = self.flush_namespace(&flush.namespace)?;
+                Ok(MutationOutcome::FlushNamespace(count))
+            }
+            // Every stored key starts with the empty prefix, whatever its namespace.
+            Some(Kind::Clear(_)) => Ok(MutationOutcome::Clear(self.remove_prefix(&[]))),
+            Some(Kind::DeletePrefix(delete)) => {
+                let count = self.delete_prefix(&delete.namespace, delete.prefix)?;
+                Ok(MutationOutcome::DeletePrefix(count))
+            }
+            Some(Kind::Delete(delete)) => {
+                let found = self.remove(&storage_key(&delete.namespace, delete.key));
+                Ok(MutationOutcome::Delete(found))
+            }
+            Some(Kind::GetSet(get_set)) => {
+                let key = storage_key(&[], get_set.key);
+                let previous = self.map.get(&key).map(StoredValue::to_plain);
+                self.insert(key, get_set.value.into());
+                Ok(MutationOutcome::GetSet(
+                    previous.unwrap_or_else(empty_value),
+                ))
+            }
+            None => bail!(ErrorKind::InvalidArgument("empty mutation".into())),
+        }
+    }
+
+    // The mutations are staged on top of the map and only applied once all of them succeed.
+    fn transact(&mut self, transaction: proto::TransactionRequest) -> Result<bool> {
+        let holds = |condition: &proto::Condition| match self
+            .map
+            .get(&storage_key(&[], condition.key.clone()))
+        {
+            Some(value) => !condition.missing && *value.plain() == *condition.value,
+            None => condition.missing,
+        };
+        if !transaction.conditions.iter().all(holds) {
+            return Ok(false);
+        }
+
+        let mut staged: HashMap<Box<[u8]>, Option<Value>> = HashMap::new();
+        for mutation in transaction.mutations {
+            let current = |key| staged_value(&staged, &self.map, key);
+            match mutation.kind {
+                Some(Kind::Set(set)) => {
+                    staged.insert(storage_key(&set.namespace, set.key), Some(set.value.into()));
+                }
+                Some(Kind::GetSet(get_set)) => {
+                    staged.insert(storage_key(&[], get_set.key), Some(get_set.value.into()));
+                }
+                Some(Kind::Increment(increment)) => {
+                    let key = storage_key(&[], increment.key);
+                    let (_, value) = incremented(current(&key).as_deref(), increment.delta)?;
+                    staged.insert(key, Some(value));
+                }
+                Some(Kind::Append(append)) => {
+                    let key = storage_key(&[], append.key);
+                    let current = current(&key);
+                    let value =
+                        appended(current.as_deref(), &append.suffix, append.max_value_size)?;
+                    staged.insert(key, Some(value));
+                }
+                Some(Kind::BatchSet(batch_set)) => {
+                    for entry in batch_set.entries {
+                        staged.insert(storage_key(&[], entry.key), Some(entry.value.into()));
+                    }
+                }
+                Some(Kind::Delete(delete)) => {
+                    staged.insert(storage_key(&delete.namespace, delete.key), None);
+                }
+                Some(Kind::Transaction(_)) => {
+                    bail!(ErrorKind::InvalidArgument("nested transaction".into()))
+                }
+                Some(Kind::FlushNamespace(_)) => {
+                    bail!(ErrorKind::InvalidArgument("flush in a transaction".into()))
+                }
+                Some(Kind::Clear(_)) => {
+                    bail!(ErrorKind::InvalidArgument("clear in a transaction".into()))
+                }
+                Some(Kind::DeletePrefix(_)) => bail!(ErrorKind::InvalidArgument(
+                    "prefix deletion in a transaction".into()
+                )),
+                None => bail!(ErrorKind::InvalidArgument("empty mutation".into())),
+            }
+        }
+
+        // Sorted, so that the keys written get the same ticks on every replica.
+        let mut staged: Vec<_> = staged.into_iter().collect();
+        staged.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (key, value) in staged {
+            match value {
+                Some(value) => self.insert(key, value),
+                None => {
+                    self.remove(&key);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    // A mutation with a known request id is not applied again; its original outcome is
+    // returned instead.
+    fn apply_once(&mut self, mutation: proto::Mutation) -> Result<MutationOutcome> {
+        let id = request_id(&mutation);
+        if id.is_empty() {
+            return self.apply(mutation);
+        }
+
+        match self.requests.get(id) {
+            Some(Some(outcome)) => return Ok(outcome),
+            Some(None) => bail!(ErrorKind::FailedRequest),
+            None => (),
+        }
+
+        let id = id.into();
+        let outcome = self.apply(mutation);
+        self.record_request(id, outcome.as_ref().ok().cloned());
+        outcome
+    }
+
+    fn increment(&mut self, key: Box<[u8]>, delta: i64) -> Result<i64> {
+        let current = self.map.get(&key).map(StoredValue::plain);
+        let (updated, value) = incremented(current.as_deref(), delta)?;
+        self.insert(key, value);
+        Ok(updated)
+    }
+
+    fn append(&mut self, key: Box<[u8]>, suffix: &[u8], max_value_size: u64) -> Result<u64> {
+        let current = self.map.get(&key).map(StoredValue::plain);
+        let value = appended(current.as_deref(), suffix, max_value_size)?;
+        let length = value.len() as u64;
+        self.insert(key, value);
+        Ok(length)
+    }
+}
+
+fn request_id(mutation: &proto::Mutation) -> &[u8] {
+    match mutation.kind {
+        Some(Kind::Set(ref set)) => &set.request_id,
+        Some(Kind::Increment(ref increment)) => &increment.request_id,
+        Some(Kind::Append(ref append)) => &append.request_id,
+        Some(Kind::BatchSet(ref batch_set)) => &batch_set.request_id,
+        Some(Kind::Transaction(ref transaction)) => &transaction.request_id,
+        Some(Kind::FlushNamespace(ref flush)) => &flush.request_id,
+        Some(Kind::GetSet(ref get_set)) => &get_set.request_id,
+        Some(Kind::Clear(ref clear)) => &clear.request_id,
+        Some(Kind::DeletePrefix(ref delete)) => &delete.request_id,
+        Some(Kind::Delete(ref delete)) => &delete.request_id,
+        None => &[],
+    }
+}
+
+// Maps a key of a namespace to the key it is stored under.
+pub fn storage_key(namespace: &[u8], key: Vec<u8>) -> Box<[u8]> {
+    if namespace.is_empty() && key.first() != Some(&NAMESPACE_MARKER) {
+        return key.into_boxed_slice();
+    }
+    let mut stored = namespace_prefix(namespace);
+    stored.extend_from_slice(&key);
+    stored.into_boxed_slice()
+}
+
+// Every stored key of the namespace, and no other, starts with it. For the default namespace
+// it only covers the keys that start with a zero byte.
+fn namespace_prefix(namespace: &[u8]) -> Vec<u8> {
+    assert!(
+        namespace.len() <= MAX_NAMESPACE_LEN,
+        "namespace is too long"
+    );
+    let mut prefix = Vec::with_capacity(namespace.len() + 2);
+    prefix.push(NAMESPACE_MARKER);
+    prefix.push(namespace.len() as u8);
+    prefix.extend_from_slice(namespace);
+    prefix
+}
+
+// Splits a stored key into the namespace and the key.
+fn split_key(stored: &[u8]) -> (&[u8], &[u8]) {
+    match stored {
+        [NAMESPACE_MARKER, len, rest @ ..] => rest.split_at(*len as usize),
+        key => (&[], key),
+    }
+}
+
+fn key_change(stored: &[u8], value: Option<Value>) -> KeyChange {
+    let (namespace, key) = split_key(stored);
+    KeyChange {
+        namespace: namespace.to_vec(),
+        key: key.to_vec(),
+        value,
+    }
+}
+
+fn empty_value() -> Value {
+    Value::from(&[][..])
+}
+
+// Staged None stands for a key removed by an earlier mutation.
+fn staged_value<'a, K: KvStore>(
+    staged: &'a HashMap<Box<[u8]>, Option<Value>>,
+    map: &'a K,
+    key: &[u8],
+) -> Option<Cow<'a, [u8]>> {
+    match staged.get(key) {
+        Some(value) => value.as_deref().map(Cow::Borrowed),
+        None => map.get(key).map(StoredValue::plain),
+    }
+}
+
+// Transactions, flushes, clears and prefix deletions may touch keys of every shard.
+fn spans_shards(mutation: &proto::Mutation) -> bool {
+    matches!(
+        mutation.kind,
+        Some(Kind::Transaction(_))
+            | Some(Kind::FlushNamespace(_))
+            | Some(Kind::Clear(_))
+            | Some(Kind::DeletePrefix(_))
+    )
+}
+
+// Values that are not exactly 8 bytes long are left intact.
+fn incremented(current: Option<&[u8]>, delta: i64) -> Result<(i64, Value)> {
+    let current = match current {
+        Some(mut value) if value.len() == 8 => value.read_i64::<LittleEndian>().unwrap(),
+        Some(value) => bail!(ErrorKind::NotAnInteger(value.len())),
+        None => 0,
+    };
+
+    let updated = current.wrapping_add(delta);
+    let mut value = vec![0; 8];
+    (&mut value[..]).write_i64::<LittleEndian>(updated).unwrap();
+    Ok((updated, value.into()))
+}
+
+fn appended(current: Option<&[u8]>, suffix: &[u8], max_value_size: u64) -> Result<Value> {
+    let current = current.unwrap_or(&[]);
+    let length = (current.len() + suffix.len()) as u64;
+    if max_value_size > 0 && length > max_value_size {
+        bail!(ErrorKind::ValueTooLarge(length, max_value_size));
+    }
+
+    let mut value = Vec::with_capacity(length as usize);
+    value.extend_from_slice(current);
+    value.extend_from_slice(suffix);
+    Ok(value.into())
+}
+
+// A recovered mutation of a single key.
+enum KeyMutation {
+    Set(Value),
+    GetSet(Value),
+    Increment(i64),
+    Append(Vec<u8>, u64),
+    Delete,
+}
+
+// The tag indexes the request whose outcome is awaited, if any.
+type ShardMutation = (Box<[u8]>, KeyMutation, Option<usize>);
+// Final values of the keys that changed, None for removed ones, and the outcomes of tagged
+// mutations.
+type ShardResult = (
+    Vec<(Box<[u8]>, Option<StoredValue>)>,
+    Vec<(usize, MutationOutcome)>,
+);
+
+fn shard_of(key: &[u8], shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+// Applies mutations of one shard in journal order, so the last write to each key wins. The
+// final values are compressed on the shard's thread too.
+fn apply_shard<K: KvStore>(
+    map: &K,
+    mutations: Vec<ShardMutation>,
+    compression: Option<Compression>,
+) -> ShardResult {
+    let mut updated: HashMap<Box<[u8]>, Option<Value>> = HashMap::new();
+    let mut outcomes = vec![];
+    for (key, mutation, tag) in mutations {
+        let current = staged_value(&updated, map, &key);
+        let current = current.as_deref();
+        // Failed mutations leave the value intact, just like when applied one by one.
+        let result = match mutation {
+            KeyMutation::Set(value) => Ok((MutationOutcome::Set, Some(value))),
+            KeyMutation::GetSet(value) => {
+                let previous = current.map_or_else(empty_value, Value::from);
+                Ok((MutationOutcome::GetSet(previous), Some(value)))
+            }
+            KeyMutation::Increment(delta) => incremented(current, delta)
+                .map(|(updated, value)| (MutationOutcome::Increment(updated), Some(value))),
+            KeyMutation::Append(suffix, max_value_size) => {
+                appended(current, &suffix, max_value_size)
+                    .map(|value| (MutationOutcome::Append(value.len() as u64), Some(value)))
+            }
+            KeyMutation::Delete => Ok((MutationOutcome::Delete(current.is_some()), None)),
+        };
+        if let Ok((outcome, value)) = result {
+            if let Some(tag) = tag {
+                outcomes.push((tag, outcome));
+            }
+            updated.insert(key, value);
+        }
+    }
+    let updated = updated
+        .into_iter()
+        .map(|(key, value)| (key, value.map(|value| StoredValue::new(value, compression))))
+        .collect();
+    (updated, outcomes)
+}
+
+impl<K: KvStore> Machine for StorageMachine<K> {
+    type Mutation = proto::Mutation;
+    type Outcome = Result<MutationOutcome>;
+    type Query = Query;
+    type Status = Status;
+
+    fn apply_mutation(&mut self, mutation: Self::Mutation) -> Self::Outcome {
+        let outcome = self.apply_once(mutation);
+        self.evict();
+        outcome
+    }
+
+    // Every mutation touches keys independently, so keys are split into shards, each applied
+    // by its own thread against the current map, and the results are merged. Request ids are
+    // recorded up front in journal order, and their outcomes are filled in after the merge.
+    // Evictions depend on the order of all writes, so they rule this out.
+    fn apply_recovered(&mut self, mutations: Vec<Self::Mutation>, threads: usize) {
+        if threads <= 1 || self.eviction.is_some() {
+            for mutation in mutations {
+                let _ = self.apply_mutation(mutation);
+            }
+            return;
+        }
+
+        // Mutations spanning shards are applied on their own in between the mutations around
+        // them.
+        if mutations.iter().any(spans_shards) {
+            let mut chunk = vec![];
+            for mutation in mutations {
+                if spans_shards(&mutation) {
+                    self.apply_recovered(mem::take(&mut chunk), threads);
+                    let _ = self.apply_mutation(mutation);
+                } else {
+                    chunk.push(mutation);
+                }
+            }
+            self.apply_recovered(chunk, threads);
+            return;
+        }
+
+        let mut shards: Vec<Vec<_>> = (0..threads).map(|_| vec![]).collect();
+        let mut push = |key: Box<[u8]>, mutation, tag| {
+            shards[shard_of(&key, threads)].push((key, mutation, tag));
+        };
+        let mut requests: Vec<Box<[u8]>> = vec![];
+        let mut outcomes = vec![];
+        for mutation in mutations {
+            let id = request_id(&mutation);
+            let tag = if id.is_empty() {
+                None
+            } else if self.requests.get(id).is_some() {
+                continue;
+            } else {
+                let id: Box<[u8]> = id.into();
+                self.record_request(id.clone(), None);
+                requests.push(id);
+                outcomes.push(None);
+                Some(requests.len() - 1)
             };
 
-            let len = set.encoded_len();
-            let mut buf = vec![0; len + 4];
+            match mutation.kind {
+                Some(Kind::Set(set)) => {
+                    if let Some(tag) = tag {
+                        outcomes[tag] = Some(MutationOutcome::Set);
+                    }
+                    let key = storage_key(&set.namespace, set.key);
+                    push(key, KeyMutation::Set(set.value.into()), None)
+                }
+                Some(Kind::GetSet(get_set)) => push(
+                    storage_key(&[], get_set.key),
+                    KeyMutation::GetSet(get_set.value.into()),
+                    tag,
+                ),
+                Some(Kind::Increment(increment)) => push(
+                    storage_key(&[], increment.key),
+                    KeyMutation::Increment(increment.delta),
+                    tag,
+                ),
+                Some(Kind::Append(append)) => push(
+                    storage_key(&[], append.key),
+                    KeyMutation::Append(append.suffix, append.max_value_size),
+                    tag,
+                ),
+                Some(Kind::BatchSet(batch_set)) => {
+                    if let Some(tag) = tag {
+                        outcomes[tag] = Some(MutationOutcome::Set);
+                    }
+                    for entry in batch_set.entries {
+                        let key = storage_key(&[], entry.key);
+                        push(key, KeyMutation::Set(entry.value.into()), None);
+                    }
+                }
+                Some(Kind::Delete(delete)) => push(
+                    storage_key(&delete.namespace, delete.key),
+                    KeyMutation::Delete,
+                    tag,
+                ),
+                Some(Kind::Transaction(_))
+                | Some(Kind::FlushNamespace(_))
+                | Some(Kind::Clear(_))
+                | Some(Kind::DeletePrefix(_)) => {
+                    unreachable!("mutation spanning shards among sharded mutations")
+                }
+                None => (),
+            }
+        }
+
+        let map = &self.map;
+        let compression = self.compression;
+        let results = crossbeam::scope(|scope| {
+            let handles: Vec<_> = shards
+                .into_iter()
+                .map(|shard| scope.spawn(move |_| apply_shard(map, shard, compression)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        })
+        .unwrap();
 
-            assert!(len >> 32 == 0);
-            (&mut buf[..4])
-                .write_u32::<LittleEndian>(len as u32)
-                .unwrap();
-            set.encode(&mut &mut buf[4..])?;
+        for (updates, shard_outcomes) in results {
+            for (key, value) in updates {
+                match value {
+                    Some(value) => self.insert_stored(key, value),
+                    None => {
+                        self.remove(&key);
+                    }
+                }
+            }
+            for (tag, outcome) in shard_outcomes {
+                outcomes[tag] = Some(outcome);
+            }
+        }
 
-            writer.write_all(&buf)?;
+        // In journal order, so that a request id reused after being forgotten ends up with
+        // the outcome of its last use.
+        for (id, outcome) in requests.iter().zip(outcomes) {
+            self.requests.update(id, outcome);
         }
+    }
 
-        Ok(())
+    fn query_state(&self, query: Self::Query) -> Self::Status {
+        match query {
+            Query::Get(key) => Status::Value(self.lookup(&key).map(StoredValue::to_plain)),
+            Query::Exists(key) => Status::Exists(self.lookup(&key).is_some()),
+            Query::Dump(namespace, start_after) => Status::Entries(
+                self.map
+                    .iter()
+                    .filter_map(|(stored, value)| {
+                        let (key_namespace, key) = split_key(stored);
+                        if key_namespace != &*namespace
+                            || (!start_after.is_empty() && key <= &*start_after)
+                        {
+                            return None;
+                        }
+                        Some((key.into(), value.to_plain()))
+                    })
+                    .collect(),
+            ),
+            Query::KeyCount => Status::KeyCount(self.map.len() as u64),
+        }
     }
 
-    fn from_snapshot<T: Read>(reader: &mut T) -> Result<Self> {
+    fn write_snapshot<T: Write>(&self, writer: &mut T) -> Result<()> {
+        writer.write_u32::<LittleEndian>(SECTIONED)?;
+        if self.snapshot_segments > 1 {
+            self.write_segments(writer)?;
+        } else {
+            write_section(writer, MAP_SECTION, |writer| {
+                for (key, value) in self.map.iter() {
+                    let tick = self.tick_of(key);
+                    let (namespace, key) = split_key(key);
+                    write_entry(writer, namespace, key, value, tick)?;
+                }
+                Ok(())
+            })?;
+        }
+        write_section(writer, REQUESTS_SECTION, |writer| {
+            for (id, outcome) in self.requests.last(REQUEST_WINDOW) {
+                write_request(writer, id, outcome)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn from_snapshot<T: Read>(reader: &mut T, version: u16) -> Result<Self> {
         let mut machine = Self::default();
+        machine.read_sections(reader, version)?;
+        Ok(machine)
+    }
+
+    fn configure(&mut self, config: &MachineServiceConfig) {
+        let eviction_config = &config.eviction;
+        if eviction_config.max_keys > 0 || eviction_config.max_bytes > 0 {
+            self.track_recency();
+            let eviction = self.eviction.as_mut().unwrap();
+            eviction.max_keys = eviction_config.max_keys;
+            eviction.max_bytes = eviction_config.max_bytes;
+            // The limits may have been lowered since the snapshot.
+            self.evict();
+        } else {
+            self.eviction = None;
+        }
+
+        let compression_config = &config.compression;
+        if compression_config.enable {
+            self.compression = Some(Compression {
+                min_value_size: compression_config.min_value_size,
+                level: compression_config.level,
+            });
+        }
+
+        let filter_config = &config.bloom_filter;
+        if !filter_config.enable {
+            return;
+        }
+        let mut filter = CountingBloomFilter::new(
+            filter_config.expected_keys,
+            filter_config.false_positive_rate,
+        );
+        for (key, _) in self.map.iter() {
+            filter.insert(key);
+        }
+        self.filter = Some(filter);
+    }
+
+    fn configure_snapshots(&mut self, config: &SnapshotServiceConfig) {
+        self.snapshot_segments = config.snapshot_segments as usize;
+    }
+
+    fn track_changes(&mut self) {
+        self.changes = Some(HashMap::new());
+        self.new_requests.clear();
+    }
+
+    fn change_count(&self) -> u64 {
+        self.change_count
+    }
+
+    fn clear_changes(&mut self, count: u64) {
+        if let Some(ref mut changes) = self.changes {
+            changes.retain(|_, change| *change > count);
+        }
+        while self
+            .new_requests
+            .front()
+            .is_some_and(|change| *change <= count)
+        {
+            self.new_requests.pop_front();
+        }
+    }
+
+    fn write_delta<T: Write>(&self, writer: &mut T) -> Result<()> {
+        let changes = match self.changes {
+            Some(ref changes) => changes,
+            None => bail!("changes are not tracked"),
+        };
+
+        // Sorted, so that deltas are as deterministic as ordered snapshots.
+        let mut keys: Vec<_> = changes.keys().collect();
+        keys.sort();
+
+        writer.write_u32::<LittleEndian>(SECTIONED)?;
+        write_section(writer, MAP_SECTION, |writer| {
+            for key in &keys {
+                let (namespace, plain_key) = split_key(key);
+                match self.map.get(key) {
+                    Some(value) => {
+                        write_entry(writer, namespace, plain_key, value, self.tick_of(key))?
+                    }
+                    None => write_removal(writer, namespace, plain_key)?,
+                }
+            }
+            Ok(())
+        })?;
+        // Recording the new requests on top of the old ones forgets the same old ones.
+        write_section(writer, REQUESTS_SECTION, |writer| {
+            for (id, outcome) in self.requests.last(self.new_requests.len()) {
+                write_request(writer, id, outcome)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn apply_delta<T: Read>(&mut self, reader: &mut T, version: u16) -> Result<()> {
+        self.read_sections(reader, version)
+    }
+
+    fn watch_changes(&mut self, watched: bool) {
+        self.key_changes = if watched { Some(Vec::new()) } else { None };
+    }
+
+    fn take_key_changes(&mut self) -> Vec<KeyChange> {
+        match self.key_changes {
+            Some(ref mut key_changes) => mem::take(key_changes),
+            None => vec![],
+        }
+    }
+
+    fn audited_writes(mutation: &Self::Mutation) -> Vec<AuditedWrite> {
+        let mut writes = vec![];
+        add_audited_writes(mutation, &mut writes);
+        writes
+    }
+
+    // Journals of rayd versions without mutation kinds hold bare sets.
+    fn decode_legacy_mutation(data: &[u8]) -> Result<Self::Mutation> {
+        let set = proto::SetRequest::decode(data)?;
+        Ok(proto::Mutation {
+            kind: Some(Kind::Set(set)),
+        })
+    }
+}
+
+// Transactions are audited as the writes they would make, whether or not they commit.
+fn add_audited_writes(mutation: &proto::Mutation, writes: &mut Vec<AuditedWrite>) {
+    let mut add = |operation, namespace: &[u8], key: &[u8], value_len| {
+        writes.push(AuditedWrite {
+            operation,
+            namespace: namespace.to_vec(),
+            key: key.to_vec(),
+            value_len,
+        })
+    };
+    match mutation.kind {
+        Some(Kind::Set(ref set)) => add("set", &set.namespace, &set.key, set.value.len()),
+        Some(Kind::Increment(ref increment)) => add("increment", &[], &increment.key, 8),
+        Some(Kind::Append(ref append)) => add("append", &[], &append.key, append.suffix.len()),
+        Some(Kind::BatchSet(ref batch_set)) => {
+            for entry in &batch_set.entries {
+                add("set", &[], &entry.key, entry.value.len());
+            }
+        }
+        Some(Kind::Transaction(ref transaction)) => {
+            for nested in &transaction.mutations {
+                add_audited_writes(nested, writes);
+            }
+        }
+        Some(Kind::FlushNamespace(ref flush)) => add("flush_namespace", &flush.namespace, &[], 0),
+        Some(Kind::GetSet(ref get_set)) => add("get_set", &[], &get_set.key, get_set.value.len()),
+        Some(Kind::Clear(_)) => add("clear", &[], &[], 0),
+        Some(Kind::DeletePrefix(ref delete)) => {
+            add("delete_prefix", &delete.namespace, &delete.prefix, 0)
+        }
+        Some(Kind::Delete(ref delete)) => add("delete", &delete.namespace, &delete.key, 0),
+        None => (),
+    }
+}
+
+impl<K: KvStore> StorageMachine<K> {
+    // Each segment is a run of consecutive keys in the order the map iterates them: a key range
+    // with the ordered store. The map is iterated once to split it, as skipping to a segment
+    // would walk every key before it. Segments are serialized into memory on threads of their
+    // own, so the whole map is held encoded in memory while it is written.
+    fn write_segments<T: Write>(&self, writer: &mut T) -> Result<()> {
+        let segments = self.snapshot_segments;
+        let entries: Vec<_> = self.map.iter().collect();
+        let segment_len = entries.len().div_ceil(segments);
+        let eviction = &self.eviction;
+        let payloads = crossbeam::scope(|scope| {
+            let handles: Vec<_> = (0..segments)
+                .map(|index| {
+                    let start = cmp::min(index * segment_len, entries.len());
+                    let end = cmp::min(start + segment_len, entries.len());
+                    let segment = &entries[start..end];
+                    scope.spawn(move |_| -> Result<Vec<u8>> {
+                        let mut payload = vec![];
+                        for &(key, value) in segment {
+                            let tick = eviction
+                                .as_ref()
+                                .map_or(0, |eviction| eviction.tick_of(key));
+                            let (namespace, key) = split_key(key);
+                            write_entry(&mut payload, namespace, key, value, tick)?;
+                        }
+                        Ok(payload)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Result<Vec<_>>>()
+        })
+        .unwrap()?;
+
+        writer.write_u32::<LittleEndian>(SEGMENTS_SECTION)?;
+        writer.write_u64::<LittleEndian>(4)?;
+        writer.write_u32::<LittleEndian>(segments as u32)?;
+        for payload in payloads {
+            writer.write_u32::<LittleEndian>(MAP_SECTION)?;
+            writer.write_u64::<LittleEndian>(payload.len() as u64)?;
+            writer.write_all(&payload)?;
+        }
+        Ok(())
+    }
+
+    fn read_sections<T: Read>(&mut self, reader: &mut T, version: u16) -> Result<()> {
+        if version != 1 {
+            bail!("unsupported snapshot format version {}", version);
+        }
+        match try_read_u32(reader)? {
+            Some(SECTIONED) => (),
+            first_len => return self.read_records(reader, first_len),
+        }
+
+        // Segments are decoded while the ones after them are read, then merged in order. Only
+        // full snapshots are segmented and they are read into a fresh machine, so the filter
+        // and the changes need no updating.
+        crossbeam::scope(|scope| {
+            let mut segmented = false;
+            let mut segments = vec![];
+            while let Some(tag) = try_read_u32(reader)? {
+                let len = reader.read_u64::<LittleEndian>()?;
+                let mut section = reader.take(len);
+                match tag {
+                    SEGMENTS_SECTION => {
+                        segmented = section.read_u32::<LittleEndian>()? > 1;
+                        io::copy(&mut section, &mut io::sink())?;
+                    }
+                    MAP_SECTION if segmented => {
+                        let mut payload = Vec::with_capacity(len as usize);
+                        section.read_to_end(&mut payload)?;
+                        segments.push(scope.spawn(move |_| decode_segment::<K>(payload)));
+                    }
+                    MAP_SECTION | REQUESTS_SECTION => {
+                        let first_len = try_read_u32(&mut section)?;
+                        self.read_records(&mut section, first_len)
+                            .chain_err(|| format!("failed to read snapshot section {}", tag))?;
+                    }
+                    _ => {
+                        debug!("Skipping unknown snapshot section {} ({} bytes)", tag, len);
+                        io::copy(&mut section, &mut io::sink())?;
+                    }
+                }
+                if section.limit() > 0 {
+                    bail!("snapshot section {} is truncated", tag);
+                }
+            }
+
+            for segment in segments {
+                let mut segment = segment.join().unwrap()?;
+                if segment.eviction.is_some() || self.eviction.is_some() {
+                    self.track_recency();
+                    segment.track_recency();
+                }
+                self.map.append(segment.map);
+                if let (Some(eviction), Some(other)) = (&mut self.eviction, segment.eviction) {
+                    eviction.append(other);
+                }
+            }
+            Ok(())
+        })
+        .unwrap()
+    }
+
+    // The length of the first record is read by the caller.
+    fn read_records<T: Read>(&mut self, reader: &mut T, first_len: Option<u32>) -> Result<()> {
         let mut index = 0;
         let mut offset = 0;
+        let mut next_len = first_len;
 
-        while let Some(len) = try_read_u32(reader)? {
+        while let Some(len) = next_len {
             let mut buffer = vec![0; len as usize];
             reader.read_exact(&mut buffer)?;
 
-            let set = proto::SetRequest::decode(&buffer[..]).chain_err(|| {
+            let record = proto::SnapshotRecord::decode(&buffer[..]).chain_err(|| {
                 format!(
-                    "failed to decode mutation (index: {}, offset: {})",
+                    "failed to decode snapshot record (index: {}, offset: {})",
                     index, offset
                 )
             })?;
 
-            let key = set.key.into_boxed_slice();
-            let value = set.value.into_boxed_slice();
-            machine.map.insert(key, value);
+            match record.applied_request {
+                Some(request) => {
+                    let outcome = request.outcome.map(|outcome| match outcome {
+                        Outcome::Set(_) => MutationOutcome::Set,
+                        Outcome::Increment(reply) => MutationOutcome::Increment(reply.value),
+                        Outcome::Append(reply) => MutationOutcome::Append(reply.length),
+                        Outcome::Transaction(reply) => {
+                            MutationOutcome::Transaction(reply.committed)
+                        }
+                        Outcome::FlushNamespace(reply) => {
+                            MutationOutcome::FlushNamespace(reply.count)
+                        }
+                        Outcome::GetSet(reply) => MutationOutcome::GetSet(reply.value.into()),
+                        Outcome::Clear(reply) => MutationOutcome::Clear(reply.count),
+                        Outcome::DeletePrefix(reply) => MutationOutcome::DeletePrefix(reply.count),
+                        Outcome::Delete(reply) => MutationOutcome::Delete(reply.found),
+                    });
+                    self.record_request(request.request_id.into_boxed_slice(), outcome);
+                }
+                None => {
+                    let key = storage_key(&record.namespace, record.key);
+                    let value: Value = record.value.into();
+                    if record.tick > 0 {
+                        self.track_recency();
+                    }
+                    let tick = Some(record.tick);
+                    if record.removed {
+                        self.remove(&key);
+                    } else if record.compressed {
+                        self.insert_ticked(key, StoredValue::Zstd(value), tick);
+                    } else {
+                        self.insert_ticked(key, StoredValue::Plain(value), tick);
+                    }
+                }
+            }
 
             index += 1;
             offset += 4 + buffer.len();
+            next_len = try_read_u32(reader)?;
         }
 
-        Ok(machine)
+        Ok(())
+    }
+}
+
+// Prints what a full snapshot holds, for `rayd snapshot inspect`. A snapshot that fails to load
+// is read again without checking it, to tell how far its records are readable, and the error
+// it failed with is returned.
+pub fn inspect_snapshot<K: KvStore, W: Write>(
+    path: &Path,
+    sample: usize,
+    out: &mut W,
+) -> Result<()> {
+    if path.to_string_lossy().ends_with(".delta") {
+        bail!(
+            "{:?} is a delta, only full snapshots can be inspected",
+            path
+        );
+    }
+    let mut file = File::open(path).chain_err(|| format!("failed to open {:?}", path))?;
+    let err = match read_snapshot::<_, StorageMachine<K>>(&mut file) {
+        Ok((machine, epoch)) => {
+            writeln!(out, "Epoch: {}", epoch)?;
+            return machine.describe(sample, out);
+        }
+        Err(err) => err,
+    };
+
+    writeln!(out, "Snapshot fails to load, reading it unchecked")?;
+    let len = unchecked_payload_len(&mut file)?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = Crc64Reader::new(BufReader::new(file).take(len));
+    let epoch = reader
+        .read_u64::<LittleEndian>()
+        .chain_err(|| "failed to read the epoch")?;
+    writeln!(out, "Epoch: {}", epoch)?;
+
+    // Segments are only merged once all of them are read, so keys of a segmented snapshot
+    // are only counted if it is read to the end.
+    let mut machine = StorageMachine::<K>::default();
+    let result = read_format_version::<_, StorageMachine<K>>(&mut reader).and_then(
+        |(version, mut records)| {
+            writeln!(out, "Format version: {}", version)?;
+            machine.read_sections(&mut records, version)
+        },
+    );
+    match result {
+        Ok(()) => writeln!(out, "Records are readable up to the end ({} bytes)", len)?,
+        Err(err) => {
+            let causes: Vec<String> = err.iter().map(|cause| cause.to_string()).collect();
+            writeln!(
+                out,
+                "Records are readable up to offset {} of {}: {}",
+                reader.len(),
+                len,
+                causes.join(": ")
+            )?;
+        }
+    }
+    machine.describe(sample, out)?;
+    Err(err)
+}
+
+impl<K: KvStore> StorageMachine<K> {
+    fn describe<W: Write>(&self, sample: usize, out: &mut W) -> Result<()> {
+        let mut value_bytes = 0;
+        let mut compressed_count = 0;
+        let mut compressed_bytes = 0;
+        for (_, value) in self.map.iter() {
+            value_bytes += value.plain().len();
+            if value.is_compressed() {
+                compressed_count += 1;
+                compressed_bytes += value.stored().len();
+            }
+        }
+
+        writeln!(out, "Keys: {}", self.map.len())?;
+        writeln!(out, "Value bytes: {}", value_bytes)?;
+        if compressed_count > 0 {
+            writeln!(
+                out,
+                "Compressed values: {} ({} bytes compressed)",
+                compressed_count, compressed_bytes
+            )?;
+        }
+        writeln!(out, "Remembered requests: {}", self.requests.order.len())?;
+        if sample > 0 {
+            writeln!(out, "First keys:")?;
+        }
+        for (stored, value) in self.map.iter().take(sample) {
+            let (namespace, key) = split_key(stored);
+            write!(out, "  ")?;
+            if !namespace.is_empty() {
+                write!(out, "namespace={:?} ", ByteStr::new(namespace))?;
+            }
+            writeln!(
+                out,
+                "key={:?} value_len={}",
+                ByteStr::new(key),
+                value.plain().len()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn decode_segment<K: KvStore>(payload: Vec<u8>) -> Result<StorageMachine<K>> {
+    let mut reader = &payload[..];
+    let mut segment = StorageMachine::<K>::default();
+    let first_len = try_read_u32(&mut reader)?;
+    segment
+        .read_records(&mut reader, first_len)
+        .chain_err(|| format!("failed to read snapshot section {}", MAP_SECTION))?;
+    Ok(segment)
+}
+
+// The payload is written twice, first only to learn its length, so that sections as large as
+// the whole map need not be buffered.
+fn write_section<T, F>(writer: &mut T, tag: u32, write_payload: F) -> Result<()>
+where
+    T: Write,
+    F: Fn(&mut dyn Write) -> Result<()>,
+{
+    let mut counter = ByteCounter::default();
+    write_payload(&mut counter)?;
+
+    writer.write_u32::<LittleEndian>(tag)?;
+    writer.write_u64::<LittleEndian>(counter.len())?;
+    write_payload(writer)
+}
+
+// Compressed values are written as they are held, so they are not compressed anew.
+fn write_entry<T: Write + ?Sized>(
+    writer: &mut T,
+    namespace: &[u8],
+    key: &[u8],
+    value: &StoredValue,
+    tick: u64,
+) -> Result<()> {
+    write_record(
+        writer,
+        &proto::SnapshotRecord {
+            key: key.to_vec(),
+            value: value.stored().to_vec(),
+            namespace: namespace.to_vec(),
+            compressed: value.is_compressed(),
+            tick,
+            ..Default::default()
+        },
+    )
+}
+
+fn write_removal<T: Write + ?Sized>(writer: &mut T, namespace: &[u8], key: &[u8]) -> Result<()> {
+    write_record(
+        writer,
+        &proto::SnapshotRecord {
+            key: key.to_vec(),
+            namespace: namespace.to_vec(),
+            removed: true,
+            ..Default::default()
+        },
+    )
+}
+
+fn write_request<T: Write + ?Sized>(
+    writer: &mut T,
+    id: &[u8],
+    outcome: Option<MutationOutcome>,
+) -> Result<()> {
+    let outcome = outcome.map(|outcome| match outcome {
+        // Retries are told the epoch they are applied at, so it is not kept.
+        MutationOutcome::Set => Outcome::Set(proto::SetReply::default()),
+        MutationOutcome::Increment(value) => Outcome::Increment(proto::IncrementReply { value }),
+        MutationOutcome::Append(length) => Outcome::Append(proto::AppendReply { length }),
+        MutationOutcome::Transaction(committed) => {
+            Outcome::Transaction(proto::TransactionReply { committed })
+        }
+        MutationOutcome::FlushNamespace(count) => {
+            Outcome::FlushNamespace(proto::FlushNamespaceReply { count })
+        }
+        MutationOutcome::Clear(count) => Outcome::Clear(proto::ClearReply { count }),
+        MutationOutcome::DeletePrefix(count) => {
+            Outcome::DeletePrefix(proto::DeletePrefixReply { count })
+        }
+        MutationOutcome::Delete(found) => Outcome::Delete(proto::DeleteReply { found }),
+        MutationOutcome::GetSet(value) => Outcome::GetSet(proto::GetSetReply {
+            value: value.to_vec(),
+            ..Default::default()
+        }),
+    });
+    write_record(
+        writer,
+        &proto::SnapshotRecord {
+            applied_request: Some(proto::AppliedRequest {
+                request_id: id.to_vec(),
+                outcome,
+            }),
+            ..Default::default()
+        },
+    )
+}
+
+fn write_record<T: Write + ?Sized>(writer: &mut T, record: &proto::SnapshotRecord) -> Result<()> {
+    let len = record.encoded_len();
+    let mut buf = vec![0; len + 4];
+
+    assert!(len >> 32 == 0);
+    (&mut buf[..4])
+        .write_u32::<LittleEndian>(len as u32)
+        .unwrap();
+    record.encode(&mut &mut buf[4..])?;
+
+    writer.write_all(&buf)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::server::kv_store::{HashStore, OrderedStore};
+
+    use std::{io::Cursor, sync::Arc};
+
+    type TestMachine = StorageMachine<HashStore>;
+
+    fn set(key: &[u8], value: &[u8]) -> proto::Mutation {
+        proto::Mutation {
+            kind: Some(Kind::Set(proto::SetRequest {
+                key: key.to_vec(),
+                value: value.to_vec(),
+                ..Default::default()
+            })),
+        }
+    }
+
+    fn delete(key: &[u8]) -> proto::Mutation {
+        proto::Mutation {
+            kind: Some(Kind::Delete(proto::DeleteRequest {
+                key: key.to_vec(),
+                ..Default::default()
+            })),
+        }
+    }
+
+    fn increment(machine: &mut TestMachine, key: &[u8], delta: i64) -> Result<i64> {
+        increment_once(machine, key, delta, b"")
+    }
+
+    fn increment_mutation(key: &[u8], delta: i64, request_id: &[u8]) -> proto::Mutation {
+        proto::Mutation {
+            kind: Some(Kind::Increment(proto::IncrementRequest {
+                key: key.to_vec(),
+                delta,
+                request_id: request_id.to_vec(),
+            })),
+        }
+    }
+
+    // Applied only once for the request id, unless it is empty.
+    fn increment_once(
+        machine: &mut TestMachine,
+        key: &[u8],
+        delta: i64,
+        request_id: &[u8],
+    ) -> Result<i64> {
+        let mutation = increment_mutation(key, delta, request_id);
+        match machine.apply_mutation(mutation)? {
+            MutationOutcome::Increment(value) => Ok(value),
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+    }
+
+    fn append(
+        machine: &mut TestMachine,
+        key: &[u8],
+        suffix: &[u8],
+        max_value_size: u64,
+    ) -> Result<u64> {
+        let mutation = proto::Mutation {
+            kind: Some(Kind::Append(proto::AppendMutation {
+                key: key.to_vec(),
+                suffix: suffix.to_vec(),
+                max_value_size,
+                ..Default::default()
+            })),
+        };
+        match machine.apply_mutation(mutation)? {
+            MutationOutcome::Append(length) => Ok(length),
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+    }
+
+    fn get<K: KvStore>(machine: &StorageMachine<K>, key: &[u8]) -> Option<Vec<u8>> {
+        match machine.query_state(Query::Get(storage_key(&[], key.to_vec()))) {
+            Status::Value(value) => value.map(|value| value.to_vec()),
+            status => panic!("unexpected status: {:?}", status),
+        }
+    }
+
+    fn get_shared(machine: &TestMachine, key: &[u8]) -> Value {
+        match machine.query_state(Query::Get(storage_key(&[], key.to_vec()))) {
+            Status::Value(Some(value)) => value,
+            status => panic!("unexpected status: {:?}", status),
+        }
+    }
+
+    fn apply_delta_of<K: KvStore>(from: &StorageMachine<K>, to: &mut StorageMachine<K>) {
+        let mut delta = vec![];
+        from.write_delta(&mut delta).unwrap();
+        to.apply_delta(&mut Cursor::new(delta), 1).unwrap();
+    }
+
+    #[test]
+    fn changes_made_after_a_clone_outlive_its_snapshot() {
+        let mut machine = TestMachine::default();
+        machine.track_changes();
+        machine.apply_mutation(set(b"a", b"1")).unwrap();
+        let clone = machine.clone();
+        let change_count = machine.change_count();
+        machine.apply_mutation(set(b"b", b"2")).unwrap();
+
+        machine.clear_changes(change_count);
+        let mut delta = TestMachine::default();
+        apply_delta_of(&clone, &mut delta);
+        assert_eq!(get(&delta, b"a"), Some(b"1".to_vec()));
+        let mut delta = TestMachine::default();
+        apply_delta_of(&machine, &mut delta);
+        assert_eq!(get(&delta, b"a"), None);
+        assert_eq!(get(&delta, b"b"), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn changes_are_kept_until_cleared() {
+        let mut machine = TestMachine::default();
+        machine.track_changes();
+        machine.apply_mutation(set(b"a", b"1")).unwrap();
+        // The snapshot of a clone taken here fails, so nothing is cleared.
+        let _clone = machine.clone();
+        machine.apply_mutation(set(b"b", b"2")).unwrap();
+
+        let mut delta = TestMachine::default();
+        apply_delta_of(&machine, &mut delta);
+        assert_eq!(get(&delta, b"a"), Some(b"1".to_vec()));
+        assert_eq!(get(&delta, b"b"), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn increment_of_a_missing_key_starts_from_zero() {
+        let mut machine = TestMachine::default();
+        assert_eq!(increment(&mut machine, b"n", 5).unwrap(), 5);
+        assert_eq!(get(&machine, b"n"), Some(5i64.to_le_bytes().to_vec()));
+    }
+
+    #[test]
+    fn negative_increment_subtracts() {
+        let mut machine = TestMachine::default();
+        increment(&mut machine, b"n", 5).unwrap();
+        assert_eq!(increment(&mut machine, b"n", -8).unwrap(), -3);
+        assert_eq!(get(&machine, b"n"), Some((-3i64).to_le_bytes().to_vec()));
+    }
+
+    #[test]
+    fn increment_wraps_around_on_overflow() {
+        let mut machine = TestMachine::default();
+        increment(&mut machine, b"n", i64::MAX).unwrap();
+        assert_eq!(increment(&mut machine, b"n", 1).unwrap(), i64::MIN);
+        assert_eq!(increment(&mut machine, b"n", -1).unwrap(), i64::MAX);
+    }
+
+    #[test]
+    fn increment_of_a_non_integer_fails() {
+        let mut machine = TestMachine::default();
+        machine.apply_mutation(set(b"n", b"abc")).unwrap();
+        match increment(&mut machine, b"n", 1) {
+            Err(Error(ErrorKind::NotAnInteger(3), _)) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(get(&machine, b"n"), Some(b"abc".to_vec()));
+    }
+
+    #[test]
+    fn repeated_increment_is_applied_once() {
+        let mut machine = TestMachine::default();
+        assert_eq!(increment_once(&mut machine, b"n", 5, b"first").unwrap(), 5);
+        assert_eq!(increment_once(&mut machine, b"n", 5, b"first").unwrap(), 5);
+        assert_eq!(
+            increment_once(&mut machine, b"n", 5, b"second").unwrap(),
+            10
+        );
+        // The original reply comes back even after later changes.
+        assert_eq!(increment_once(&mut machine, b"n", 5, b"first").unwrap(), 5);
+        assert_eq!(get(&machine, b"n"), Some(10i64.to_le_bytes().to_vec()));
+
+        machine.apply_mutation(set(b"text", b"abc")).unwrap();
+        assert!(increment_once(&mut machine, b"text", 1, b"third").is_err());
+        match increment_once(&mut machine, b"text", 1, b"third") {
+            Err(Error(ErrorKind::FailedRequest, _)) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn request_ids_survive_snapshots_and_replay() {
+        let mut machine = TestMachine::default();
+        increment_once(&mut machine, b"n", 5, b"first").unwrap();
+        let mut snapshot = vec![];
+        machine.write_snapshot(&mut snapshot).unwrap();
+        let version = TestMachine::SNAPSHOT_VERSION;
+        let mut restored = TestMachine::from_snapshot(&mut Cursor::new(snapshot), version).unwrap();
+        assert_eq!(increment_once(&mut restored, b"n", 5, b"first").unwrap(), 5);
+        assert_eq!(get(&restored, b"n"), Some(5i64.to_le_bytes().to_vec()));
+
+        // A journal replaying a retried increment.
+        for &threads in &[1, 4] {
+            let journal = vec![
+                increment_mutation(b"n", 5, b"first"),
+                increment_mutation(b"n", 5, b"first"),
+                increment_mutation(b"n", 3, b""),
+            ];
+            let mut replayed = TestMachine::default();
+            replayed.apply_recovered(journal, threads);
+            let expected = Some(8i64.to_le_bytes().to_vec());
+            assert_eq!(get(&replayed, b"n"), expected, "threads: {}", threads);
+        }
+    }
+
+    fn condition(key: &[u8], value: Option<&[u8]>) -> proto::Condition {
+        proto::Condition {
+            key: key.to_vec(),
+            value: value.unwrap_or_default().to_vec(),
+            missing: value.is_none(),
+        }
+    }
+
+    fn transact(
+        machine: &mut TestMachine,
+        conditions: Vec<proto::Condition>,
+        mutations: Vec<proto::Mutation>,
+    ) -> Result<bool> {
+        let mutation = proto::Mutation {
+            kind: Some(Kind::Transaction(proto::TransactionRequest {
+                conditions,
+                mutations,
+                ..Default::default()
+            })),
+        };
+        match machine.apply_mutation(mutation)? {
+            MutationOutcome::Transaction(committed) => Ok(committed),
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn transaction_swaps_keys() {
+        let mut machine = TestMachine::default();
+        machine.apply_mutation(set(b"a", b"1")).unwrap();
+        machine.apply_mutation(set(b"b", b"2")).unwrap();
+        let conditions = vec![condition(b"a", Some(b"1")), condition(b"b", Some(b"2"))];
+        let mutations = vec![set(b"a", b"2"), set(b"b", b"1")];
+        assert!(transact(&mut machine, conditions, mutations).unwrap());
+        assert_eq!(get(&machine, b"a"), Some(b"2".to_vec()));
+        assert_eq!(get(&machine, b"b"), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn aborted_transaction_changes_nothing() {
+        let mut machine = TestMachine::default();
+        machine.apply_mutation(set(b"a", b"1")).unwrap();
+        let mutations = || vec![set(b"a", b"2"), delete(b"b"), set(b"c", b"3")];
+
+        // A condition that does not hold.
+        let conditions = vec![condition(b"a", Some(b"2"))];
+        assert!(!transact(&mut machine, conditions, mutations()).unwrap());
+
+        // A mutation that fails after others were staged.
+        machine.apply_mutation(set(b"b", b"2")).unwrap();
+        machine
+            .apply_mutation(set(b"n", b"not an integer"))
+            .unwrap();
+        let mut failing = mutations();
+        failing.push(increment_mutation(b"n", 1, b""));
+        transact(&mut machine, vec![], failing).unwrap_err();
+
+        assert_eq!(get(&machine, b"a"), Some(b"1".to_vec()));
+        assert_eq!(get(&machine, b"b"), Some(b"2".to_vec()));
+        assert_eq!(get(&machine, b"c"), None);
+    }
+
+    #[test]
+    fn transaction_conditions_on_missing_keys() {
+        let mut machine = TestMachine::default();
+        machine.apply_mutation(set(b"empty", b"")).unwrap();
+        // An empty value is not a missing key.
+        let conditions = vec![condition(b"empty", None)];
+        assert!(!transact(&mut machine, conditions, vec![set(b"a", b"1")]).unwrap());
+
+        let conditions = vec![condition(b"a", None)];
+        assert!(transact(&mut machine, conditions.clone(), vec![set(b"a", b"1")]).unwrap());
+        assert!(!transact(&mut machine, conditions, vec![set(b"a", b"2")]).unwrap());
+        assert_eq!(get(&machine, b"a"), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn gets_share_the_stored_value() {
+        let mut machine = TestMachine::default();
+        machine.apply_mutation(set(b"a", &[7; 1 << 20])).unwrap();
+
+        // Neither gets nor the clones snapshots are written from copy the value.
+        let value = get_shared(&machine, b"a");
+        assert!(Arc::ptr_eq(&value, &get_shared(&machine, b"a")));
+        let clone = machine.clone();
+        assert!(Arc::ptr_eq(&value, &get_shared(&clone, b"a")));
+
+        machine.apply_mutation(set(b"a", b"new")).unwrap();
+        assert_eq!(get(&clone, b"a"), Some(vec![7; 1 << 20]));
+        assert_eq!(get(&machine, b"a"), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn append_to_a_missing_key_sets_it() {
+        let mut machine = TestMachine::default();
+        assert_eq!(append(&mut machine, b"log", b"abc", 0).unwrap(), 3);
+        assert_eq!(get(&machine, b"log"), Some(b"abc".to_vec()));
+    }
+
+    #[test]
+    fn repeated_appends_accumulate() {
+        let mut machine = TestMachine::default();
+        machine.apply_mutation(set(b"log", b"a")).unwrap();
+        assert_eq!(append(&mut machine, b"log", b"bc", 0).unwrap(), 3);
+        assert_eq!(append(&mut machine, b"log", b"def", 0).unwrap(), 6);
+        assert_eq!(get(&machine, b"log"), Some(b"abcdef".to_vec()));
+    }
+
+    #[test]
+    fn append_over_the_size_limit_is_rejected() {
+        let mut machine = TestMachine::default();
+        assert_eq!(append(&mut machine, b"log", b"abc", 5).unwrap(), 3);
+        assert_eq!(append(&mut machine, b"log", b"de", 5).unwrap(), 5);
+        match append(&mut machine, b"log", b"f", 5) {
+            Err(Error(ErrorKind::ValueTooLarge(6, 5), _)) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(get(&machine, b"log"), Some(b"abcde".to_vec()));
+    }
+
+    #[test]
+    fn bloom_filter_has_no_false_negatives() {
+        // A filter sized for far fewer keys saturates, which must only cost false positives.
+        let mut config = MachineServiceConfig::default();
+        config.bloom_filter.enable = true;
+        config.bloom_filter.expected_keys = 16;
+        let mut machine = TestMachine::default();
+        machine.configure(&config);
+        let key = |index: u32| format!("key{}", index).into_bytes();
+        for index in 0..1000 {
+            machine.apply_mutation(set(&key(index), b"v")).unwrap();
+        }
+        for index in (0..1000).step_by(3) {
+            machine.apply_mutation(delete(&key(index))).unwrap();
+        }
+        for index in (0..1000).step_by(6) {
+            machine.apply_mutation(set(&key(index), b"w")).unwrap();
+        }
+        let expected = |index: u32| match index % 6 {
+            0 => Some(b"w".to_vec()),
+            3 => None,
+            _ => Some(b"v".to_vec()),
+        };
+        for index in 0..1000 {
+            assert_eq!(get(&machine, &key(index)), expected(index), "{}", index);
+        }
+
+        let mut snapshot = vec![];
+        machine.write_snapshot(&mut snapshot).unwrap();
+        let version = TestMachine::SNAPSHOT_VERSION;
+        let mut restored = TestMachine::from_snapshot(&mut Cursor::new(snapshot), version).unwrap();
+        restored.configure(&config);
+        for index in 0..1000 {
+            assert_eq!(get(&restored, &key(index)), expected(index), "{}", index);
+        }
+    }
+
+    // A journal of mixed mutations of few keys, so that most keys are written many times.
+    // Some carry request ids, a few of them repeated.
+    fn recovery_journal(count: u64) -> Vec<proto::Mutation> {
+        let mut state = 1u64;
+        let mut next = |modulo: u64| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1);
+            (state >> 33) % modulo
+        };
+        (0..count)
+            .map(|index| {
+                let key = format!("key{}", next(50)).into_bytes();
+                let value = index.to_le_bytes().to_vec();
+                let request_id = match next(4) {
+                    0 => format!("request{}", next(count / 2)).into_bytes(),
+                    _ => vec![],
+                };
+                let kind = match next(8) {
+                    0 => Kind::Increment(proto::IncrementRequest {
+                        key,
+                        delta: next(10) as i64 - 5,
+                        request_id,
+                    }),
+                    1 => Kind::Append(proto::AppendMutation {
+                        key,
+                        suffix: b"ab".to_vec(),
+                        max_value_size: 16,
+                        request_id,
+                    }),
+                    2 => Kind::GetSet(proto::GetSetRequest {
+                        key,
+                        value,
+                        request_id,
+                        ..Default::default()
+                    }),
+                    3 => Kind::Delete(proto::DeleteRequest {
+                        key,
+                        request_id,
+                        ..Default::default()
+                    }),
+                    4 => Kind::BatchSet(proto::BatchSetRequest {
+                        entries: (0..3)
+                            .map(|_| proto::KeyValue {
+                                key: format!("key{}", next(50)).into_bytes(),
+                                value: value.clone(),
+                            })
+                            .collect(),
+                        request_id,
+                    }),
+                    5 if next(10) == 0 => Kind::DeletePrefix(proto::DeletePrefixRequest {
+                        prefix: format!("key{}", next(5)).into_bytes(),
+                        request_id,
+                        ..Default::default()
+                    }),
+                    _ => Kind::Set(proto::SetRequest {
+                        key,
+                        value,
+                        request_id,
+                        ..Default::default()
+                    }),
+                };
+                proto::Mutation { kind: Some(kind) }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parallel_recovery_matches_serial_recovery() {
+        let snapshot_after = |threads: usize| {
+            let mut machine = StorageMachine::<OrderedStore>::default();
+            for chunk in recovery_journal(20_000).chunks(1000) {
+                machine.apply_recovered(chunk.to_vec(), threads);
+            }
+            let mut snapshot = vec![];
+            machine.write_snapshot(&mut snapshot).unwrap();
+            snapshot
+        };
+        let serial = snapshot_after(1);
+        for &threads in &[2, 4, 7] {
+            assert!(snapshot_after(threads) == serial, "threads: {}", threads);
+        }
+    }
+
+    // Shared by the stores, see the tests below.
+    fn check_gets_and_sets<K: KvStore>() {
+        let mut machine = StorageMachine::<K>::default();
+        assert_eq!(get(&machine, b"a"), None);
+        machine.apply_mutation(set(b"a", b"1")).unwrap();
+        machine.apply_mutation(set(b"b", b"2")).unwrap();
+        machine.apply_mutation(set(b"a", b"3")).unwrap();
+        machine.apply_mutation(delete(b"b")).unwrap();
+        machine.apply_mutation(set(b"c", b"")).unwrap();
+
+        assert_eq!(get(&machine, b"a"), Some(b"3".to_vec()));
+        assert_eq!(get(&machine, b"b"), None);
+        assert_eq!(get(&machine, b"c"), Some(vec![]));
+    }
+
+    fn check_snapshot_round_trip<K: KvStore>() {
+        let mut machine = StorageMachine::<K>::default();
+        for index in 0..100 {
+            let key = format!("key{}", index);
+            machine
+                .apply_mutation(set(key.as_bytes(), &[index]))
+                .unwrap();
+        }
+        machine.apply_mutation(delete(b"key7")).unwrap();
+
+        let mut snapshot = vec![];
+        machine.write_snapshot(&mut snapshot).unwrap();
+        let version = StorageMachine::<K>::SNAPSHOT_VERSION;
+        let restored =
+            StorageMachine::<K>::from_snapshot(&mut Cursor::new(snapshot), version).unwrap();
+        for index in 0..100 {
+            let key = format!("key{}", index);
+            let expected = if index == 7 { None } else { Some(vec![index]) };
+            assert_eq!(get(&restored, key.as_bytes()), expected, "{}", key);
+        }
+    }
+
+    #[test]
+    fn stores_get_and_set() {
+        check_gets_and_sets::<HashStore>();
+        check_gets_and_sets::<OrderedStore>();
+    }
+
+    #[test]
+    fn stores_round_trip_through_snapshots() {
+        check_snapshot_round_trip::<HashStore>();
+        check_snapshot_round_trip::<OrderedStore>();
+    }
+
+    fn snapshot_of(machine: &TestMachine) -> Vec<u8> {
+        let mut snapshot = vec![];
+        machine.write_snapshot(&mut snapshot).unwrap();
+        snapshot
+    }
+
+    fn restore(snapshot: Vec<u8>) -> TestMachine {
+        let version = TestMachine::SNAPSHOT_VERSION;
+        TestMachine::from_snapshot(&mut Cursor::new(snapshot), version).unwrap()
+    }
+
+    #[test]
+    fn every_section_round_trips() {
+        let mut machine = TestMachine::default();
+        for index in 0..20 {
+            machine.apply_mutation(set(&[index], &[index])).unwrap();
+        }
+        increment_once(&mut machine, b"n", 5, b"first").unwrap();
+
+        // The map written in one section or in segments, followed by the request ids.
+        for &segments in &[1, 3] {
+            machine.snapshot_segments = segments;
+            let mut restored = restore(snapshot_of(&machine));
+            for index in 0..20 {
+                assert_eq!(get(&restored, &[index]), Some(vec![index]));
+            }
+            assert_eq!(increment_once(&mut restored, b"n", 5, b"first").unwrap(), 5);
+        }
+    }
+
+    #[test]
+    fn compressed_values_round_trip_through_snapshots() {
+        let mut config = MachineServiceConfig::default();
+        config.compression.enable = true;
+        config.compression.min_value_size = 100;
+        let mut machine = TestMachine::default();
+        machine.configure(&config);
+        let large = vec![b'x'; 5000];
+        machine.apply_mutation(set(b"large", &large)).unwrap();
+        machine.apply_mutation(set(b"small", b"tiny")).unwrap();
+        let is_compressed = |machine: &TestMachine, key: &[u8]| {
+            machine
+                .map
+                .get(&storage_key(&[], key.to_vec())[..])
+                .unwrap()
+                .is_compressed()
+        };
+        assert!(is_compressed(&machine, b"large"));
+        assert!(!is_compressed(&machine, b"small"));
+        assert_eq!(get(&machine, b"large"), Some(large.clone()));
+        assert_eq!(get(&machine, b"small"), Some(b"tiny".to_vec()));
+
+        // Appending decompresses the value and compresses the result anew.
+        let length = append(&mut machine, b"large", b"!", 0).unwrap();
+        assert_eq!(length, large.len() as u64 + 1);
+        let mut appended = large.clone();
+        appended.push(b'!');
+        assert!(is_compressed(&machine, b"large"));
+
+        // Compressed values are loaded as they were written, whether or not compression is
+        // still enabled, in one section or in segments.
+        for &segments in &[1, 3] {
+            machine.snapshot_segments = segments;
+            let restored = restore(snapshot_of(&machine));
+            assert!(is_compressed(&restored, b"large"));
+            assert!(!is_compressed(&restored, b"small"));
+            assert_eq!(get(&restored, b"large"), Some(appended.clone()));
+            assert_eq!(get(&restored, b"small"), Some(b"tiny".to_vec()));
+        }
+    }
+
+    fn present_keys(machine: &TestMachine, keys: &[&[u8]]) -> Vec<Vec<u8>> {
+        keys.iter()
+            .filter(|key| get(machine, key).is_some())
+            .map(|key| key.to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn least_recently_written_keys_are_evicted() {
+        let mut config = MachineServiceConfig::default();
+        config.eviction.max_keys = 3;
+        let mut machine = TestMachine::default();
+        machine.configure(&config);
+        for key in &[b"z", b"y", b"x"] {
+            machine.apply_mutation(set(*key, b"v")).unwrap();
+        }
+        // Reads do not count, rewrites do.
+        get(&machine, b"y");
+        machine.apply_mutation(set(b"z", b"w")).unwrap();
+        machine.apply_mutation(set(b"a", b"v")).unwrap();
+        let keys: &[&[u8]] = &[b"a", b"x", b"y", b"z"];
+        assert_eq!(present_keys(&machine, keys), vec![b"a", b"x", b"z"]);
+
+        // Keys and values as stored, 2 bytes a key here.
+        let mut config = MachineServiceConfig::default();
+        config.eviction.max_bytes = 5;
+        machine.configure(&config);
+        assert_eq!(present_keys(&machine, keys), vec![b"a", b"z"]);
+    }
+
+    #[test]
+    fn eviction_is_the_same_after_a_reload() {
+        let mut config = MachineServiceConfig::default();
+        config.eviction.max_keys = 3;
+        let mut machine = TestMachine::default();
+        machine.configure(&config);
+        for key in &[b"z", b"y", b"x"] {
+            machine.apply_mutation(set(*key, b"v")).unwrap();
+        }
+        machine.apply_mutation(set(b"z", b"w")).unwrap();
+
+        // The order of writes is kept in snapshots, whether in one section or in segments, and
+        // not the order of keys.
+        let keys: &[&[u8]] = &[b"a", b"b", b"x", b"y", b"z"];
+        for &segments in &[1, 3] {
+            machine.snapshot_segments = segments;
+            let mut restored = restore(snapshot_of(&machine));
+            restored.configure(&config);
+            let mut original = machine.clone();
+            for target in &mut [&mut original, &mut restored] {
+                target.apply_mutation(set(b"a", b"v")).unwrap();
+                assert_eq!(present_keys(target, keys), vec![b"a", b"x", b"z"]);
+                target.apply_mutation(set(b"b", b"v")).unwrap();
+                assert_eq!(present_keys(target, keys), vec![b"a", b"b", b"z"]);
+            }
+        }
+
+        // Lowering the limit evicts on load.
+        config.eviction.max_keys = 2;
+        let mut restored = restore(snapshot_of(&machine));
+        restored.configure(&config);
+        assert_eq!(present_keys(&restored, keys), vec![b"x", b"z"]);
+    }
+
+    #[test]
+    fn segmented_snapshots_hold_the_same_state() {
+        let snapshot_with = |machine: &mut StorageMachine<OrderedStore>, segments| {
+            machine.snapshot_segments = segments;
+            let mut snapshot = vec![];
+            machine.write_snapshot(&mut snapshot).unwrap();
+            snapshot
+        };
+        for &count in &[0u8, 2, 20] {
+            let mut machine = StorageMachine::<OrderedStore>::default();
+            for index in 0..count {
+                machine.apply_mutation(set(&[index], &[index])).unwrap();
+            }
+            let single = snapshot_with(&mut machine, 1);
+
+            // More segments than keys leaves some of them empty.
+            for &segments in &[2, 3, 7] {
+                let snapshot = snapshot_with(&mut machine, segments);
+                let version = StorageMachine::<OrderedStore>::SNAPSHOT_VERSION;
+                let mut restored = StorageMachine::<OrderedStore>::from_snapshot(
+                    &mut Cursor::new(snapshot),
+                    version,
+                )
+                .unwrap();
+                assert_eq!(snapshot_with(&mut restored, 1), single, "{} keys", count);
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_sections_are_skipped() {
+        let mut machine = TestMachine::default();
+        machine.apply_mutation(set(b"a", b"1")).unwrap();
+        increment_once(&mut machine, b"n", 5, b"first").unwrap();
+        let snapshot = snapshot_of(&machine);
+
+        // As written by a newer version, with sections before and after the known ones.
+        let unknown_section = |newer: &mut Vec<u8>, tag| {
+            newer.write_u32::<LittleEndian>(tag).unwrap();
+            newer.write_u64::<LittleEndian>(3).unwrap();
+            newer.extend_from_slice(b"new");
+        };
+        let mut newer = vec![];
+        newer.write_u32::<LittleEndian>(SECTIONED).unwrap();
+        unknown_section(&mut newer, 100);
+        newer.extend_from_slice(&snapshot[4..]);
+        unknown_section(&mut newer, 101);
+
+        let mut restored = restore(newer);
+        assert_eq!(get(&restored, b"a"), Some(b"1".to_vec()));
+        assert_eq!(increment_once(&mut restored, b"n", 5, b"first").unwrap(), 5);
     }
 }
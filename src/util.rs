@@ -2,6 +2,8 @@ use crate::{errors::*, fatal};
 
 use byteorder::{LittleEndian, ReadBytesExt};
 
+use crc::crc64::{self, Hasher64};
+
 use tokio::sync::mpsc::{
     channel,
     error::{SendError, TryRecvError, TrySendError},
@@ -11,29 +13,50 @@ use tokio::sync::mpsc::{
 use uuid::Uuid;
 
 use std::{
-    io::{self, Read},
+    fmt::Write as _,
+    fs,
+    io::{self, Read, Write},
     panic::{catch_unwind, AssertUnwindSafe},
-    process::Command,
     sync::{
-        atomic::{AtomicI64, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
     },
+    time::{Duration, Instant},
 };
 
 #[derive(Clone, Debug)]
 pub struct Traced<T> {
     pub id: Uuid,
     pub payload: T,
+    // When the client stops waiting for the result, if it set a deadline.
+    pub deadline: Option<Instant>,
+    // Filled in by the services on the way, if the request is timed.
+    pub timings: Option<Arc<RequestTimings>>,
 }
 
 impl<T> Traced<T> {
     pub fn new(payload: T) -> Self {
         let id = Uuid::new_v4();
-        Self { id, payload }
+        Self::with_id(id, payload)
     }
 
     pub fn with_id(id: Uuid, payload: T) -> Self {
-        Self { id, payload }
+        Self {
+            id,
+            payload,
+            deadline: None,
+            timings: None,
+        }
+    }
+
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    pub fn with_timings(mut self, timings: Option<Arc<RequestTimings>>) -> Self {
+        self.timings = timings;
+        self
     }
 
     pub fn into_payload(self) -> T {
@@ -44,12 +67,70 @@ impl<T> Traced<T> {
         Traced::<U> {
             id: self.id,
             payload: func(self.payload),
+            deadline: self.deadline,
+            timings: self.timings,
         }
     }
 }
 
+// Where a request spent its time, to tell why it was slow. Times are in nanoseconds, since the
+// start for the persist time, and UNSET until the request gets there.
+#[derive(Debug)]
+pub struct RequestTimings {
+    start: Instant,
+    persisted: AtomicU64,
+    query_wait: AtomicU64,
+}
+
+const UNSET: u64 = u64::MAX;
+
+impl RequestTimings {
+    pub fn new(start: Instant) -> Self {
+        Self {
+            start,
+            persisted: AtomicU64::new(UNSET),
+            query_wait: AtomicU64::new(UNSET),
+        }
+    }
+
+    pub fn mark_persisted(&self, now: Instant) {
+        let nanos = now.saturating_duration_since(self.start).as_nanos() as u64;
+        self.persisted.store(nanos, Ordering::Relaxed);
+    }
+
+    // Time from the start until the mutation was persisted.
+    pub fn persisted(&self) -> Option<Duration> {
+        load_duration(&self.persisted)
+    }
+
+    pub fn record_query_wait(&self, wait: Duration) {
+        self.query_wait
+            .store(wait.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    // Time the query waited in the machine service for its epoch before it was served.
+    pub fn query_wait(&self) -> Option<Duration> {
+        load_duration(&self.query_wait)
+    }
+}
+
+fn load_duration(nanos: &AtomicU64) -> Option<Duration> {
+    match nanos.load(Ordering::Relaxed) {
+        UNSET => None,
+        nanos => Some(Duration::from_nanos(nanos)),
+    }
+}
+
+// Cleared as soon as any dedicated thread dies, while the process is still shutting down.
+static THREADS_ALIVE: AtomicBool = AtomicBool::new(true);
+
+pub fn threads_alive() -> bool {
+    THREADS_ALIVE.load(Ordering::Acquire)
+}
+
 pub fn do_and_die<F: FnOnce() -> Result<()>>(func: F) -> ! {
     let result = catch_unwind(AssertUnwindSafe(func));
+    THREADS_ALIVE.store(false, Ordering::Release);
     let thread_name = std::thread::current()
         .name()
         .unwrap_or("unknown")
@@ -83,48 +164,165 @@ pub fn try_read_u32<T: Read>(reader: &mut T) -> io::Result<Option<u32>> {
     Ok(Some(value))
 }
 
-fn run_shell_command(command: &str) -> Result<String> {
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(command)
-        .output()
-        .chain_err(|| format!("failed to run command '{}'", command))?;
-    if !output.status.success() {
-        bail!("command '{}' exited non-zero", command);
+pub fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+// Computes CRC-64 of everything written through it.
+pub struct Crc64Writer<'a, W: Write> {
+    inner: &'a mut W,
+    digest: crc64::Digest,
+    len: u64,
+}
+
+impl<'a, W: Write> Crc64Writer<'a, W> {
+    pub fn new(inner: &'a mut W) -> Self {
+        Self {
+            inner,
+            digest: crc64::Digest::new(crc64::ECMA),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn sum64(&self) -> u64 {
+        self.digest.sum64()
+    }
+
+    pub fn into_inner(self) -> &'a mut W {
+        self.inner
+    }
+}
+
+impl<'a, W: Write> Write for Crc64Writer<'a, W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(data)?;
+        Hasher64::write(&mut self.digest, &data[..written]);
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
-    let text =
-        std::str::from_utf8(&output.stdout).chain_err(|| "command output is not a valid utf-8")?;
-    Ok(text.trim().to_string())
+}
+
+// Discards everything written to it, only counting the bytes.
+#[derive(Default)]
+pub struct ByteCounter {
+    len: u64,
+}
+
+impl ByteCounter {
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+impl Write for ByteCounter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.len += data.len() as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Computes CRC-64 of everything read through it.
+pub struct Crc64Reader<R: Read> {
+    inner: R,
+    digest: crc64::Digest,
+    len: u64,
+}
+
+impl<R: Read> Crc64Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            digest: crc64::Digest::new(crc64::ECMA),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn sum64(&self) -> u64 {
+        self.digest.sum64()
+    }
+}
+
+impl<R: Read> Read for Crc64Reader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buffer)?;
+        Hasher64::write(&mut self.digest, &buffer[..read]);
+        self.len += read as u64;
+        Ok(read)
+    }
+}
+
+fn read_proc_file(path: &str) -> Result<String> {
+    fs::read_to_string(path).chain_err(|| format!("failed to read {}", path))
 }
 
 pub fn get_children_pids(parent_pid: u32) -> Result<Vec<u32>> {
-    let cmd = format!("ls /proc/{}/task", parent_pid);
-    let text = run_shell_command(&cmd)?;
-    let pids = text
-        .lines()
-        .filter_map(|line| Some(line.parse().ok()?))
+    let path = format!("/proc/{}/task", parent_pid);
+    let entries = fs::read_dir(&path).chain_err(|| format!("failed to list {}", path))?;
+    let pids = entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
         .collect();
     Ok(pids)
 }
 
 pub fn get_process_name(pid: u32) -> Result<String> {
-    let cmd = format!("cat /proc/{}/status | head -n1 | awk '{{print $2}}'", pid);
-    let text = run_shell_command(&cmd)?;
-    Ok(text)
+    let path = format!("/proc/{}/status", pid);
+    parse_status_name(&read_proc_file(&path)?).chain_err(|| format!("failed to parse {}", path))
 }
 
+// Microseconds the process has spent on a CPU.
 pub fn get_process_cpu_time(pid: u32) -> Result<u64> {
-    let cmd = format!(
-        "cat /proc/{}/sched | grep se.sum_exec_runtime | awk '{{print $3}}'",
-        pid
-    );
-    let text = run_shell_command(&cmd)?;
-    let value: f64 = text
+    let path = format!("/proc/{}/sched", pid);
+    parse_sched_cpu_time(&read_proc_file(&path)?).chain_err(|| format!("failed to parse {}", path))
+}
+
+// The name is everything after the "Name:" key, as thread names may contain spaces.
+fn parse_status_name(status: &str) -> Result<String> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Name:"))
+        .map(|name| name.trim().to_string())
+        .ok_or_else(|| "no Name field".into())
+}
+
+// The runtime is given in milliseconds with a fractional part, e.g.
+// "se.sum_exec_runtime                          :           118.482106", and returned in
+// microseconds. Rounded, as e.g. 1.001 ms is a hair below 1001 us as a float.
+fn parse_sched_cpu_time(sched: &str) -> Result<u64> {
+    let line = sched
+        .lines()
+        .find(|line| line.starts_with("se.sum_exec_runtime"))
+        .ok_or("no se.sum_exec_runtime field")?;
+    let value = match line.split_once(':') {
+        Some((_, value)) => value.trim(),
+        None => bail!("malformed line '{}'", line),
+    };
+    let millis: f64 = value
         .parse()
-        .chain_err(|| format!("command output '{}' is not a float", text))?;
-    Ok((value * 1000.) as u64)
+        .chain_err(|| format!("'{}' is not a float", value))?;
+    Ok((millis * 1000.).round() as u64)
 }
 
+#[derive(Clone)]
 pub struct ThreadCpuTimeInfo {
     pub pid: u32,
     pub name: String,
@@ -168,16 +366,46 @@ pub fn get_thread_cpu_times(main_pid: u32) -> Result<Vec<ThreadCpuTimeInfo>> {
     Ok(info)
 }
 
+// Thread cpu times, read at most once per refresh interval. Scrapes that come in while the
+// times are read wait for that reading instead of starting their own.
+pub struct ThreadCpuTimes {
+    main_pid: u32,
+    refresh_interval: Duration,
+    last: Mutex<Option<(Instant, Vec<ThreadCpuTimeInfo>)>>,
+}
+
+impl ThreadCpuTimes {
+    pub fn new(main_pid: u32, refresh_interval: Duration) -> Self {
+        Self {
+            main_pid,
+            refresh_interval,
+            last: Mutex::new(None),
+        }
+    }
+
+    pub fn get(&self) -> Result<Vec<ThreadCpuTimeInfo>> {
+        let mut last = self.last.lock().unwrap();
+        if let Some((read_at, ref times)) = *last {
+            if read_at.elapsed() < self.refresh_interval {
+                return Ok(times.clone());
+            }
+        }
+        let times = get_thread_cpu_times(self.main_pid)?;
+        *last = Some((Instant::now(), times.clone()));
+        Ok(times)
+    }
+}
+
 pub fn profiled_channel<T>(max_size: usize) -> (ProfiledSender<T>, ProfiledReceiver<T>) {
     let (sender, receiver) = channel(max_size);
-    let size = Arc::new(AtomicI64::new(0));
+    let stats = Arc::new(QueueStats::default());
     (
         ProfiledSender {
-            size: size.clone(),
+            stats: stats.clone(),
             inner: sender,
         },
         ProfiledReceiver {
-            size,
+            stats,
             inner: receiver,
         },
     )
@@ -186,23 +414,58 @@ pub fn profiled_channel<T>(max_size: usize) -> (ProfiledSender<T>, ProfiledRecei
 pub fn profiled_unbounded_channel<T>() -> (ProfiledUnboundedSender<T>, ProfiledUnboundedReceiver<T>)
 {
     let (sender, receiver) = unbounded_channel();
-    let size = Arc::new(AtomicI64::new(0));
+    let stats = Arc::new(QueueStats::default());
     (
         ProfiledUnboundedSender {
-            size: size.clone(),
+            stats: stats.clone(),
             inner: sender,
         },
         ProfiledUnboundedReceiver {
-            size,
+            stats,
             inner: receiver,
         },
     )
 }
 
-pub struct ProfiledSender<T> {
+// Shared by both ends of a profiled channel.
+#[derive(Default)]
+pub struct QueueStats {
     // Use i64, not u64, as size counter because it's updates are racy with respect to the
     // actual queue size, so it can be negative at some points of time.
-    size: Arc<AtomicI64>,
+    size: AtomicI64,
+    // Largest size since the last take_peak_size, so that spikes between two samples of the
+    // size are not missed.
+    peak_size: AtomicI64,
+    // Times try_send found a bounded channel full.
+    overflows: AtomicU64,
+}
+
+impl QueueStats {
+    fn push(&self) {
+        let size = self.size.fetch_add(1, Ordering::AcqRel) + 1;
+        self.peak_size.fetch_max(size, Ordering::AcqRel);
+    }
+
+    fn pop(&self) {
+        self.size.fetch_sub(1, Ordering::Release);
+    }
+
+    pub fn size(&self) -> i64 {
+        self.size.load(Ordering::Acquire)
+    }
+
+    // Returns the peak size and starts a new peak from the current size.
+    pub fn take_peak_size(&self) -> i64 {
+        self.peak_size.swap(self.size(), Ordering::AcqRel)
+    }
+
+    pub fn overflow_count(&self) -> u64 {
+        self.overflows.load(Ordering::Acquire)
+    }
+}
+
+pub struct ProfiledSender<T> {
+    stats: Arc<QueueStats>,
     inner: Sender<T>,
 }
 
@@ -210,7 +473,7 @@ pub struct ProfiledSender<T> {
 impl<T> Clone for ProfiledSender<T> {
     fn clone(&self) -> Self {
         Self {
-            size: self.size.clone(),
+            stats: self.stats.clone(),
             inner: self.inner.clone(),
         }
     }
@@ -220,8 +483,12 @@ impl<T> Clone for ProfiledSender<T> {
 impl<T> ProfiledSender<T> {
     pub fn try_send(&mut self, message: T) -> std::result::Result<(), TrySendError<T>> {
         let result = self.inner.try_send(message);
-        if result.is_ok() {
-            self.size.fetch_add(1, Ordering::Release);
+        match result {
+            Ok(()) => self.stats.push(),
+            Err(TrySendError::Full(_)) => {
+                self.stats.overflows.fetch_add(1, Ordering::Release);
+            }
+            Err(TrySendError::Closed(_)) => (),
         }
         result
     }
@@ -229,14 +496,14 @@ impl<T> ProfiledSender<T> {
     pub async fn send(&mut self, value: T) -> std::result::Result<(), SendError<T>> {
         let result = self.inner.send(value).await;
         if result.is_ok() {
-            self.size.fetch_add(1, Ordering::Release);
+            self.stats.push();
         }
         result
     }
 }
 
 pub struct ProfiledReceiver<T> {
-    size: Arc<AtomicI64>,
+    stats: Arc<QueueStats>,
     inner: Receiver<T>,
 }
 
@@ -249,7 +516,7 @@ impl<T> ProfiledReceiver<T> {
     pub async fn recv(&mut self) -> Option<T> {
         let result = self.inner.recv().await;
         if result.is_some() {
-            self.size.fetch_sub(1, Ordering::Release);
+            self.stats.pop();
         }
         result
     }
@@ -257,20 +524,22 @@ impl<T> ProfiledReceiver<T> {
     pub fn try_recv(&mut self) -> std::result::Result<T, TryRecvError> {
         let result = self.inner.try_recv();
         if result.is_ok() {
-            self.size.fetch_sub(1, Ordering::Release);
+            self.stats.pop();
         }
         result
     }
 
     pub fn approx_len(&self) -> i64 {
-        self.size.load(Ordering::Acquire)
+        self.stats.size()
+    }
+
+    pub fn stats(&self) -> Arc<QueueStats> {
+        self.stats.clone()
     }
 }
 
 pub struct ProfiledUnboundedSender<T> {
-    // Use i64, not u64, as size counter because it's updates are racy with respect to the
-    // actual queue size, so it can be negative at some points of time.
-    size: Arc<AtomicI64>,
+    stats: Arc<QueueStats>,
     inner: UnboundedSender<T>,
 }
 
@@ -278,7 +547,7 @@ pub struct ProfiledUnboundedSender<T> {
 impl<T> Clone for ProfiledUnboundedSender<T> {
     fn clone(&self) -> Self {
         Self {
-            size: self.size.clone(),
+            stats: self.stats.clone(),
             inner: self.inner.clone(),
         }
     }
@@ -288,14 +557,14 @@ impl<T> ProfiledUnboundedSender<T> {
     pub fn send(&self, value: T) -> std::result::Result<(), SendError<T>> {
         let result = self.inner.send(value);
         if result.is_ok() {
-            self.size.fetch_add(1, Ordering::Release);
+            self.stats.push();
         }
         result
     }
 }
 
 pub struct ProfiledUnboundedReceiver<T> {
-    size: Arc<AtomicI64>,
+    stats: Arc<QueueStats>,
     inner: UnboundedReceiver<T>,
 }
 
@@ -308,7 +577,7 @@ impl<T> ProfiledUnboundedReceiver<T> {
     pub async fn recv(&mut self) -> Option<T> {
         let result = self.inner.recv().await;
         if result.is_some() {
-            self.size.fetch_sub(1, Ordering::Release);
+            self.stats.pop();
         }
         result
     }
@@ -316,12 +585,67 @@ impl<T> ProfiledUnboundedReceiver<T> {
     pub fn try_recv(&mut self) -> std::result::Result<T, TryRecvError> {
         let result = self.inner.try_recv();
         if result.is_ok() {
-            self.size.fetch_sub(1, Ordering::Release);
+            self.stats.pop();
         }
         result
     }
 
     pub fn approx_len(&self) -> i64 {
-        self.size.load(Ordering::Acquire)
+        self.stats.size()
+    }
+
+    pub fn stats(&self) -> Arc<QueueStats> {
+        self.stats.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sched_cpu_time_is_read_in_microseconds() {
+        let sched = "rayd-journal (4242, #threads: 9)
+-------------------------------------------------------------------
+se.exec_start                                :        912345.123456
+se.vruntime                                  :            23.000001
+se.sum_exec_runtime                          :           118.482106
+se.nr_migrations                             :                    3
+";
+        assert_eq!(parse_sched_cpu_time(sched).unwrap(), 118_482);
+        let sched = "se.sum_exec_runtime                          :             1.001000\n";
+        assert_eq!(parse_sched_cpu_time(sched).unwrap(), 1_001);
+
+        assert!(parse_sched_cpu_time("se.vruntime : 1.0\n").is_err());
+        assert!(parse_sched_cpu_time("se.sum_exec_runtime 1.0\n").is_err());
+        assert!(parse_sched_cpu_time("se.sum_exec_runtime : n/a\n").is_err());
+    }
+
+    #[test]
+    fn status_name_keeps_embedded_spaces() {
+        let status = "Name:\tray bench worker \nUmask:\t0022\nState:\tS (sleeping)\n";
+        assert_eq!(parse_status_name(status).unwrap(), "ray bench worker");
+        assert_eq!(parse_status_name("Name:\trayd\n").unwrap(), "rayd");
+        assert!(parse_status_name("Umask:\t0022\n").is_err());
+    }
+
+    #[test]
+    fn peak_size_survives_a_drain_until_taken() {
+        let (mut sender, mut receiver) = profiled_channel(4);
+        for index in 0..6 {
+            let result = sender.try_send(index);
+            assert_eq!(result.is_ok(), index < 4);
+        }
+        while receiver.try_recv().is_ok() {}
+
+        let stats = receiver.stats();
+        assert_eq!(stats.size(), 0);
+        assert_eq!(stats.overflow_count(), 2);
+        assert_eq!(stats.take_peak_size(), 4);
+        // The next peak starts from the size at the time it was taken.
+        assert_eq!(stats.take_peak_size(), 0);
+        sender.try_send(6).unwrap();
+        assert_eq!(stats.take_peak_size(), 1);
+        assert_eq!(stats.take_peak_size(), 1);
     }
 }
@@ -0,0 +1,207 @@
+mod common;
+
+use common::{base_config, eventually, free_port, run_rayd, run_rayd_with_input, Server};
+
+use std::{fs, process::Output};
+
+#[test]
+fn check_config_accepts_a_valid_config() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("base.yml"), base_config(free_port())).unwrap();
+
+    let output = run_rayd(dir.path(), &["--check-config", "-c", "base.yml"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Config is OK\n");
+    // Nothing is created on disk, not even the journal and snapshot directories.
+    let mut names: Vec<_> = fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["base.yml"]);
+}
+
+#[test]
+fn check_config_reports_every_problem() {
+    let dir = tempfile::tempdir().unwrap();
+    let port = free_port();
+    fs::write(dir.path().join("base.yml"), base_config(port)).unwrap();
+    fs::write(dir.path().join("journal"), "not a directory").unwrap();
+    let invalid = format!(
+        "metrics:
+    enable: true
+    address: 127.0.0.1
+    port: {}
+journal_storage:
+    path: journal/inner
+",
+        port
+    );
+    fs::write(dir.path().join("invalid.yml"), invalid).unwrap();
+
+    let output = run_rayd(
+        dir.path(),
+        &["--check-config", "-c", "base.yml", "-c", "invalid.yml"],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("Config has 2 problem(s):"), "{}", stderr);
+    assert!(stderr.contains("listen on the same port"), "{}", stderr);
+    assert!(
+        stderr.contains("journal_storage.path is not usable"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn configs_are_merged_in_order_including_stdin() {
+    let dir = tempfile::tempdir().unwrap();
+    let port = free_port();
+    fs::write(dir.path().join("base.yml"), base_config(port)).unwrap();
+    // Metrics on the RPC port, which is only a problem if the override comes first.
+    let stdin = format!("metrics:\n    enable: true\n    port: {}\n", port);
+    fs::write(dir.path().join("over.yml"), "metrics:\n    enable: false\n").unwrap();
+
+    let args = [
+        "--check-config",
+        "-c",
+        "base.yml",
+        "-c",
+        "-",
+        "-c",
+        "over.yml",
+    ];
+    let output = run_rayd_with_input(dir.path(), &args, stdin.as_bytes());
+    assert!(output.status.success(), "{:?}", output);
+
+    let args = [
+        "--check-config",
+        "-c",
+        "base.yml",
+        "-c",
+        "over.yml",
+        "-c",
+        "-",
+    ];
+    let output = run_rayd_with_input(dir.path(), &args, stdin.as_bytes());
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("listen on the same port"), "{}", stderr);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn pid_file_is_written_once_up_and_removed_on_exit() {
+    let dir = tempfile::tempdir().unwrap();
+    // A stale file, naming a PID over the Linux maximum.
+    fs::write(dir.path().join("rayd.pid"), "4194305\n").unwrap();
+    let mut server = Server::start_in(dir, free_port(), "pid_file: rayd.pid\n");
+    server.client().await;
+    let pid_file = server.path("rayd.pid");
+    let contents = fs::read_to_string(&pid_file).unwrap();
+    assert_eq!(contents, format!("{}\n", server.pid()));
+
+    // The file of a running rayd is not taken over.
+    let other = tempfile::tempdir().unwrap();
+    fs::write(other.path().join("base.yml"), base_config(free_port())).unwrap();
+    let pid_path = pid_file.to_str().unwrap();
+    let output = run_rayd(other.path(), &["-c", "base.yml", "--pid-file", pid_path]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(fs::read_to_string(&pid_file).unwrap(), contents);
+
+    assert!(server.stop().success());
+    assert!(!pid_file.exists());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn journal_dump_reports_the_epoch_range_of_every_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = "journal_storage:\n    file_size_soft_limit: 100\n";
+    let mut server = Server::start_in(dir, free_port(), config);
+    let mut client = server.client().await;
+    for index in 0..10u8 {
+        client.set(vec![b'k', index], vec![index]).await.unwrap();
+    }
+    // Killed, so that no snapshot on the way out disposes of journal files.
+    server.kill();
+
+    let files = server.files("journal");
+    assert_eq!(files.len(), 3);
+    let summary = |output: &Output| {
+        assert!(output.status.success(), "{:?}", output);
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        assert!(stdout.contains("  offset 21: epoch 7, set key=b\"k\\x06\" value_len=1\n"));
+        let start = stdout.find("Summary:\n").unwrap();
+        stdout[start..].to_string()
+    };
+    let journal = server.path("journal");
+    let output = run_rayd(&journal, &["journal", "dump", "."]);
+    let expected = format!(
+        "Summary:\n  {}: 5 blobs, epochs 1 to 5\n  {}: 5 blobs, epochs 6 to 10\n  {}: 0 blobs, no epochs\n",
+        files[0], files[1], files[2]
+    );
+    assert_eq!(summary(&output), expected);
+
+    // An incomplete blob ends the file, which is left as it is.
+    let torn = journal.join(&files[1]);
+    let mut contents = fs::read(&torn).unwrap();
+    contents.extend_from_slice(&[50, 0, 0, 0, 1]);
+    fs::write(&torn, &contents).unwrap();
+    let output = run_rayd(&journal, &["journal", "dump", "."]);
+    let expected = expected.replace("to 10\n", "to 10, stopped at offset 105\n");
+    assert_eq!(summary(&output), expected);
+    assert_eq!(fs::read(&torn).unwrap(), contents);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn snapshot_inspect_reports_the_epoch_and_key_count() {
+    let mut server = Server::start("");
+    let mut client = server.client().await;
+    for index in 0..5u8 {
+        client.set(vec![b'k', index], vec![index]).await.unwrap();
+    }
+    client
+        .set_in(b"ns".to_vec(), b"key".to_vec(), b"v".to_vec())
+        .await
+        .unwrap();
+    client.trigger_snapshot().await.unwrap();
+    eventually("the snapshot", || {
+        server.snapshot_epochs(".snap").contains(&6)
+    })
+    .await;
+    server.kill();
+
+    let name = server
+        .files("snapshots")
+        .into_iter()
+        .find(|name| name.ends_with(".snap"))
+        .unwrap();
+    let snapshots = server.path("snapshots");
+    let output = run_rayd(&snapshots, &["snapshot", "inspect", &name, "--keys", "2"]);
+    assert!(output.status.success(), "{:?}", output);
+    let expected = "Epoch: 6
+Keys: 6
+Value bytes: 6
+Remembered requests: 0
+First keys:
+  namespace=b\"ns\" key=b\"key\" value_len=1
+  key=b\"k\\x00\" value_len=1
+";
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+
+    // A truncated copy is read as far as it goes and fails the command.
+    let contents = fs::read(snapshots.join(&name)).unwrap();
+    fs::write(snapshots.join("cut.snap"), &contents[..contents.len() / 2]).unwrap();
+    let output = run_rayd(&snapshots, &["snapshot", "inspect", "cut.snap"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("Snapshot fails to load, reading it unchecked\nEpoch: 6\n"));
+    assert!(
+        stdout.contains("Records are readable up to offset"),
+        "{}",
+        stdout
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("Failed to inspect snapshot: snapshot is truncated"));
+}
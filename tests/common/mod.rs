@@ -0,0 +1,227 @@
+// Runs rayd in a temporary directory for the duration of a test.
+
+#![allow(dead_code)]
+
+use ray::client::RayClient;
+
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+
+use tempfile::TempDir;
+
+use std::{
+    fs,
+    io::Write,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Output, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Server {
+    pub dir: TempDir,
+    pub port: u16,
+    child: Option<Child>,
+}
+
+impl Server {
+    // Starts rayd with a base config, see base_config, merged with the given YAML.
+    pub fn start(config: &str) -> Server {
+        let dir = tempfile::tempdir().unwrap();
+        let port = free_port();
+        Self::start_in(dir, port, config)
+    }
+
+    // Starts rayd in the directory of another one, e.g. a stopped primary, on a new port.
+    pub fn start_in(dir: TempDir, port: u16, config: &str) -> Server {
+        let mut server = Server {
+            dir,
+            port,
+            child: None,
+        };
+        server.restart(config);
+        server
+    }
+
+    // Starts rayd again, with the given config, after it was stopped.
+    pub fn restart(&mut self, config: &str) {
+        assert!(self.child.is_none(), "rayd is still running");
+        fs::write(self.path("base.yml"), base_config(self.port)).unwrap();
+        fs::write(self.path("test.yml"), config).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_rayd"))
+            .current_dir(self.dir.path())
+            .args(["-c", "base.yml", "-c", "test.yml"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        self.child = Some(child);
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.child.as_ref().expect("rayd is not running").id()
+    }
+
+    // Connects once rayd is done recovering.
+    pub async fn client(&self) -> RayClient {
+        self.client_at("127.0.0.1").await
+    }
+
+    // Same as client, but over the given address, e.g. unix:<path>.
+    pub async fn client_at(&self, address: &str) -> RayClient {
+        let deadline = Instant::now() + START_TIMEOUT;
+        loop {
+            if let Ok(mut client) = RayClient::connect(address, self.port).await {
+                if client.exists(b"-".to_vec()).await.is_ok() {
+                    return client;
+                }
+            }
+            assert!(
+                Instant::now() < deadline,
+                "rayd did not start, see {}",
+                self.log()
+            );
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+    }
+
+    pub fn signal(&self, signal: Signal) {
+        kill(Pid::from_raw(self.pid() as i32), signal).unwrap();
+    }
+
+    // Sends SIGTERM and waits for rayd to exit.
+    pub fn stop(&mut self) -> ExitStatus {
+        self.signal(Signal::SIGTERM);
+        self.wait()
+    }
+
+    pub fn wait(&mut self) -> ExitStatus {
+        let mut child = self.child.take().expect("rayd is not running");
+        let deadline = Instant::now() + START_TIMEOUT;
+        loop {
+            if let Some(status) = child.try_wait().unwrap() {
+                return status;
+            }
+            if Instant::now() > deadline {
+                let _ = child.kill();
+                panic!("rayd did not exit, see {}", self.log());
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    pub fn kill(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+
+    pub fn log(&self) -> String {
+        fs::read_to_string(self.path("rayd.log")).unwrap_or_default()
+    }
+
+    // Names of the files in a subdirectory, sorted.
+    pub fn files(&self, subdir: &str) -> Vec<String> {
+        let mut names: Vec<String> = match fs::read_dir(self.path(subdir)) {
+            Ok(entries) => entries
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect(),
+            Err(_) => vec![],
+        };
+        names.sort();
+        names
+    }
+
+    // Epochs of the snapshots with the extension, .snap or .delta, oldest first.
+    pub fn snapshot_epochs(&self, extension: &str) -> Vec<u64> {
+        let mut epochs: Vec<u64> = self
+            .files("snapshots")
+            .iter()
+            .filter_map(|name| name.strip_suffix(extension))
+            .map(|stem| stem[stem.len() - 20..].parse().unwrap())
+            .collect();
+        epochs.sort();
+        epochs
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+// Leaves out the metrics and health endpoints, whose fixed ports would clash between tests,
+// and logs to rayd.log in the directory of the server.
+pub fn base_config(port: u16) -> String {
+    format!(
+        "rpc:
+    threads: 2
+    address: 127.0.0.1
+    port: {}
+journal_storage:
+    path: journal
+snapshot_storage:
+    path: snapshots
+logging:
+    targets:
+      - target:
+            type: file
+            path: rayd.log
+        level: debug
+metrics:
+    enable: false
+health:
+    enable: false
+",
+        port
+    )
+}
+
+// Runs a rayd command that exits by itself, e.g. --check-config, in the directory.
+pub fn run_rayd(dir: &Path, args: &[&str]) -> Output {
+    run_rayd_with_input(dir, args, b"")
+}
+
+// Same as run_rayd, with the input given on stdin.
+pub fn run_rayd_with_input(dir: &Path, args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rayd"))
+        .current_dir(dir)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // rayd only reads stdin for a config of -, so it may be gone already.
+    let _ = child.stdin.take().unwrap().write_all(input);
+    child.wait_with_output().unwrap()
+}
+
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+// Polls the condition until it holds, for things rayd does in the background.
+pub async fn eventually<F: FnMut() -> bool>(what: &str, mut condition: F) {
+    let deadline = Instant::now() + START_TIMEOUT;
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+    }
+}
@@ -0,0 +1,100 @@
+mod common;
+
+use common::{base_config, free_port};
+
+use tempfile::TempDir;
+
+use std::{
+    env, fs,
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+mod proto {
+    tonic::include_proto!("counter");
+}
+
+use proto::{counter_client::CounterClient, AddRequest, GetRequest};
+
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Runs examples/counter.rs, which cargo builds along with the tests, in a temporary directory.
+struct Counter {
+    dir: TempDir,
+    port: u16,
+    child: Option<Child>,
+}
+
+impl Counter {
+    fn start() -> Self {
+        let mut counter = Self {
+            dir: tempfile::tempdir().unwrap(),
+            port: free_port(),
+            child: None,
+        };
+        counter.restart();
+        counter
+    }
+
+    fn restart(&mut self) {
+        // The tests are built in target/<profile>/deps, the examples in target/<profile>/examples.
+        let mut binary = env::current_exe().unwrap().parent().unwrap().to_path_buf();
+        binary.set_file_name("examples");
+        binary.push("counter");
+        fs::write(self.dir.path().join("base.yml"), base_config(self.port)).unwrap();
+        let child = Command::new(binary)
+            .current_dir(self.dir.path())
+            .arg("base.yml")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        self.child = Some(child);
+    }
+
+    // Connects once the counter is done recovering.
+    async fn client(&self) -> CounterClient<tonic::transport::Channel> {
+        let address = format!("http://127.0.0.1:{}", self.port);
+        let deadline = Instant::now() + START_TIMEOUT;
+        loop {
+            if let Ok(mut client) = CounterClient::connect(address.clone()).await {
+                if client.get(GetRequest {}).await.is_ok() {
+                    return client;
+                }
+            }
+            assert!(Instant::now() < deadline, "counter did not start");
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+    }
+
+    fn kill(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn counter_machine_is_served_and_recovered() {
+    let mut counter = Counter::start();
+    let mut client = counter.client().await;
+    for &(delta, value) in &[(5, 5), (-2, 3), (10, 13)] {
+        let reply = client.add(AddRequest { delta }).await.unwrap();
+        assert_eq!(reply.into_inner().value, value);
+    }
+
+    // Additions are journaled just like mutations of the key-value store.
+    counter.kill();
+    counter.restart();
+    let mut client = counter.client().await;
+    let reply = client.get(GetRequest {}).await.unwrap();
+    assert_eq!(reply.into_inner().value, 13);
+}
@@ -0,0 +1,328 @@
+mod common;
+
+use common::{eventually, Server};
+
+use ray::client::{is_not_ready, RayClient, RayClientConfig};
+
+use hmac_sha256::Hash;
+use tonic::Code;
+
+use std::{fs, time::Duration};
+
+#[tokio::test(threaded_scheduler)]
+async fn null_persistence_keeps_data_in_memory_only() {
+    let mut server = Server::start("persistence: none\n");
+    let mut client = server.client().await;
+    client
+        .set(b"key".to_vec(), b"value".to_vec())
+        .await
+        .unwrap();
+    client.sync().await.unwrap();
+    assert_eq!(client.get(b"key".to_vec()).await.unwrap(), b"value");
+    assert_eq!(client.info().await.unwrap().epoch, 1);
+    client.trigger_snapshot().await.unwrap();
+
+    assert!(server.stop().success());
+    assert_eq!(server.files("."), vec!["base.yml", "rayd.log", "test.yml"]);
+
+    server.restart("persistence: none\n");
+    let mut client = server.client().await;
+    assert_eq!(client.get_opt(b"key".to_vec()).await.unwrap(), None);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn synced_writes_survive_a_crash() {
+    let mut server = Server::start("");
+    let mut client = server.client().await;
+    for index in 0..10u8 {
+        client.set(vec![index], vec![index]).await.unwrap();
+    }
+    // Every write acknowledged before the sync is covered by its epoch.
+    assert_eq!(client.sync().await.unwrap(), 10);
+
+    server.kill();
+    server.restart("");
+    let mut client = server.client().await;
+    assert_eq!(client.info().await.unwrap().epoch, 10);
+    for index in 0..10u8 {
+        assert_eq!(client.get(vec![index]).await.unwrap(), vec![index]);
+    }
+}
+
+// Checks the hash chain of an audit log in text format and returns its epochs, or the number
+// of the first line that breaks the chain.
+fn verify_audit_chain(log: &str) -> Result<Vec<u64>, usize> {
+    let mut last_hash = [0; 32];
+    let mut epochs = vec![];
+    for (index, line) in log.lines().enumerate() {
+        let (record, hash) = line.rsplit_once(" hash=").ok_or(index + 1)?;
+        let mut hasher = Hash::new();
+        hasher.update(last_hash);
+        hasher.update(record);
+        last_hash = hasher.finalize();
+        let expected: String = last_hash
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        if hash != expected {
+            return Err(index + 1);
+        }
+
+        let epoch = record
+            .split(' ')
+            .find_map(|field| field.strip_prefix("epoch="));
+        epochs.push(epoch.unwrap().parse().unwrap());
+    }
+    Ok(epochs)
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn audit_log_chains_every_persisted_mutation() {
+    let config = "logging:\n    audit:\n        target:\n            type: file\n            path: audit.log\n";
+    let mut server = Server::start(config);
+    let mut client = server.client().await;
+    for index in 0..20u8 {
+        client.set(vec![index], vec![index]).await.unwrap();
+    }
+    assert!(server.stop().success());
+    let log = fs::read_to_string(server.path("audit.log")).unwrap();
+    assert_eq!(
+        verify_audit_chain(&log).unwrap(),
+        (1..=20).collect::<Vec<_>>()
+    );
+    assert!(log
+        .lines()
+        .next()
+        .unwrap()
+        .contains(" set key=\"\\x00\" value_len=1 "));
+
+    // The chain continues across a restart.
+    server.restart(config);
+    let mut client = server.client().await;
+    for index in 0..5u8 {
+        client.set(vec![index], vec![]).await.unwrap();
+    }
+    assert!(server.stop().success());
+    let log = fs::read_to_string(server.path("audit.log")).unwrap();
+    assert_eq!(
+        verify_audit_chain(&log).unwrap(),
+        (1..=25).collect::<Vec<_>>()
+    );
+
+    // Altering a record breaks the chain from there on.
+    let tampered = log.replacen("epoch=3 ", "epoch=4 ", 1);
+    assert_eq!(verify_audit_chain(&tampered), Err(3));
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn clear_is_refused_unless_allowed() {
+    let server = Server::start("");
+    let mut client = server.client().await;
+    client
+        .set(b"key".to_vec(), b"value".to_vec())
+        .await
+        .unwrap();
+    let status = client.clear().await.unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    assert_eq!(client.get(b"key".to_vec()).await.unwrap(), b"value");
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn clear_is_durable_across_a_restart() {
+    let config = "rpc:\n    allow_clear: true\n";
+    let mut server = Server::start(config);
+    let mut client = server.client().await;
+    for index in 0..10u8 {
+        client
+            .set_in(vec![index % 2], vec![index], vec![index])
+            .await
+            .unwrap();
+    }
+    assert_eq!(client.clear().await.unwrap(), 10);
+    client
+        .set(b"after".to_vec(), b"clear".to_vec())
+        .await
+        .unwrap();
+    client.sync().await.unwrap();
+    // A snapshot is taken at the clear, so that the journal before it can go.
+    eventually("the snapshot of the clear", || {
+        server.snapshot_epochs(".snap").contains(&11)
+    })
+    .await;
+
+    server.kill();
+    server.restart(config);
+    let mut client = server.client().await;
+    let info = client.info().await.unwrap();
+    assert_eq!((info.epoch, info.key_count), (12, 1));
+    assert_eq!(client.get(b"after".to_vec()).await.unwrap(), b"clear");
+    assert!(!client.exists_in(vec![0], vec![0]).await.unwrap());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn startup_tells_how_the_journal_lines_up_with_the_snapshot() {
+    let mut server = Server::start("");
+    let mut client = server.client().await;
+    assert!(server
+        .log()
+        .contains("Journal is empty and there is no snapshot, starting from scratch"));
+    for index in 0..3u8 {
+        client.set(vec![index], vec![index]).await.unwrap();
+    }
+
+    // The final snapshot covers the whole journal.
+    assert!(server.stop().success());
+    fs::remove_file(server.path("rayd.log")).unwrap();
+    server.restart("");
+    let mut client = server.client().await;
+    assert!(server
+        .log()
+        .contains("Journal ends at snapshot epoch 3, nothing to apply"));
+
+    // Writes past the snapshot are replayed on top of it.
+    for index in 3..5u8 {
+        client.set(vec![index], vec![index]).await.unwrap();
+    }
+    client.sync().await.unwrap();
+    server.kill();
+    fs::remove_file(server.path("rayd.log")).unwrap();
+    server.restart("");
+    let mut client = server.client().await;
+    assert!(server.log().contains(
+        "Journal continues the snapshot: epochs [4, 5] applied on top of snapshot epoch 3"
+    ));
+    assert_eq!(client.info().await.unwrap().epoch, 5);
+
+    // A lost journal looks like one that has just moved on to a new file.
+    server.kill();
+    fs::remove_dir_all(server.path("journal")).unwrap();
+    fs::remove_file(server.path("rayd.log")).unwrap();
+    server.restart("");
+    let mut client = server.client().await;
+    assert!(server
+        .log()
+        .contains("Journal is empty at snapshot epoch 3"));
+    assert_eq!(client.info().await.unwrap().epoch, 3);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn deleted_keys_do_not_come_back() {
+    let mut server = Server::start("");
+    let mut client = server.client().await;
+    client.set(b"a".to_vec(), b"1".to_vec()).await.unwrap();
+    client.set(b"b".to_vec(), b"2".to_vec()).await.unwrap();
+    assert_eq!(client.trigger_snapshot().await.unwrap(), 2);
+    assert!(client.delete(b"a".to_vec()).await.unwrap());
+    assert!(!client.delete(b"missing".to_vec()).await.unwrap());
+    client.sync().await.unwrap();
+
+    // The journal keeps the deletion over the snapshot holding the key.
+    server.kill();
+    server.restart("");
+    let mut client = server.client().await;
+    assert_eq!(client.get_opt(b"a".to_vec()).await.unwrap(), None);
+    assert_eq!(client.get(b"b".to_vec()).await.unwrap(), b"2");
+
+    // A snapshot past the deletion leaves the key out, so it no longer takes the journal.
+    assert_eq!(client.trigger_snapshot().await.unwrap(), 4);
+    server.kill();
+    fs::remove_dir_all(server.path("journal")).unwrap();
+    server.restart("");
+    let mut client = server.client().await;
+    assert_eq!(client.info().await.unwrap().epoch, 4);
+    assert_eq!(client.get_opt(b"a".to_vec()).await.unwrap(), None);
+    assert_eq!(client.get(b"b".to_vec()).await.unwrap(), b"2");
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn compacted_journals_are_recovered() {
+    let config = "journal_storage:\n    file_size_soft_limit: 1000\n    compact: true\n";
+    let mut server = Server::start(config);
+    let mut client = server.client().await;
+    // Snapshots taken while writes go on cover part of the oldest file kept.
+    let mut writers = vec![];
+    for writer in 0..8u32 {
+        let mut client = server.client().await;
+        writers.push(tokio::spawn(async move {
+            for index in 0..500u32 {
+                let key = (writer * 500 + index).to_be_bytes().to_vec();
+                client.set(key.clone(), vec![key[3]; 100]).await.unwrap();
+            }
+        }));
+    }
+    while !server.log().contains("Compacted ") && client.info().await.unwrap().epoch < 4000 {
+        client.trigger_snapshot().await.unwrap();
+    }
+    for writer in writers {
+        writer.await.unwrap();
+    }
+    assert!(server.log().contains("Compacted "));
+    client.sync().await.unwrap();
+
+    server.kill();
+    server.restart(config);
+    let mut client = server.client().await;
+    let info = client.info().await.unwrap();
+    assert_eq!((info.epoch, info.key_count), (4000, 4000));
+    for index in (0..4000u32).step_by(97) {
+        let key = index.to_be_bytes().to_vec();
+        assert_eq!(client.get(key.clone()).await.unwrap(), vec![key[3]; 100]);
+    }
+    let files = server.files("journal");
+    assert!(
+        files.iter().all(|name| name.ends_with(".jnl")),
+        "{:?}",
+        files
+    );
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn requests_during_recovery_are_retried() {
+    let mut server = Server::start("");
+    let mut client = server.client().await;
+    // Enough mutations that replaying them outlasts the first requests.
+    for batch in 0..40u32 {
+        let entries = (0..20_000u32)
+            .map(|index| ((batch * 20_000 + index).to_be_bytes().to_vec(), vec![0; 10]))
+            .collect();
+        client.batch_set(entries).await.unwrap();
+    }
+    client.sync().await.unwrap();
+    server.kill();
+    server.restart("");
+
+    let connect = |max_retries| {
+        let config = RayClientConfig {
+            max_retries,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+            ..Default::default()
+        };
+        RayClient::connect_with_config("127.0.0.1", server.port, config)
+    };
+    // Ping is answered during recovery, so it tells when rayd listens.
+    let mut impatient = loop {
+        if let Ok(mut client) = connect(0).await {
+            if client.ping().await.is_ok() {
+                break client;
+            }
+        }
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    };
+    let status = impatient
+        .set(b"key".to_vec(), b"value".to_vec())
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unavailable, "{}", status);
+    assert_eq!(status.message(), "recovering, retry");
+    assert!(is_not_ready(&status));
+
+    // Rejected requests were never applied, so even sets are sent again until rayd is ready.
+    let mut patient = connect(1000).await.unwrap();
+    patient
+        .set(b"key".to_vec(), b"value".to_vec())
+        .await
+        .unwrap();
+    assert_eq!(patient.get(b"key".to_vec()).await.unwrap(), b"value");
+    assert_eq!(patient.info().await.unwrap().key_count, 800_001);
+}
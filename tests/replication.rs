@@ -0,0 +1,216 @@
+mod common;
+
+use common::{eventually, Server};
+
+use ray::client::{RayClient, RayClientConfig};
+
+use nix::sys::signal::Signal;
+use tonic::Code;
+
+use std::{fs, time::Duration};
+
+async fn read_all(client: &mut RayClient, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+    let mut values = vec![];
+    for key in keys {
+        values.push(client.get_opt(key.to_vec()).await.unwrap());
+    }
+    values
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn replica_serves_the_reads_of_the_primary() {
+    let primary = Server::start("");
+    let mut writer = primary.client().await;
+    writer.set(b"a".to_vec(), b"1".to_vec()).await.unwrap();
+    writer.set(b"b".to_vec(), b"2".to_vec()).await.unwrap();
+
+    // The replica recovers the writes made so far from the journal, and tails the rest.
+    let replica = Server::start(&format!(
+        "role: replica
+journal_storage:
+    path: {}
+snapshot_storage:
+    path: {}
+",
+        primary.path("journal").display(),
+        primary.path("snapshots").display()
+    ));
+    let mut reader = replica.client().await;
+    writer.set(b"b".to_vec(), b"3".to_vec()).await.unwrap();
+    writer.delete(b"a".to_vec()).await.unwrap();
+    writer.increment(b"n".to_vec(), 5).await.unwrap();
+    writer.sync().await.unwrap();
+
+    reader.observe_epoch(writer.session_epoch());
+    let keys: &[&[u8]] = &[b"a", b"b", b"n", b"missing"];
+    let expected = read_all(&mut writer, keys).await;
+    assert_eq!(expected[1], Some(b"3".to_vec()));
+    assert_eq!(read_all(&mut reader, keys).await, expected);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn standby_is_promoted_with_its_state_preloaded() {
+    let mut primary = Server::start("");
+    let mut writer = primary.client().await;
+    writer.set(b"a".to_vec(), b"1".to_vec()).await.unwrap();
+    writer.trigger_snapshot().await.unwrap();
+    writer.set(b"b".to_vec(), b"2".to_vec()).await.unwrap();
+    writer.sync().await.unwrap();
+
+    let shared_storage = format!(
+        "journal_storage:
+    path: {}
+snapshot_storage:
+    path: {}
+",
+        primary.path("journal").display(),
+        primary.path("snapshots").display()
+    );
+    let mut standby = Server::start(&format!("role: standby\n{}", shared_storage));
+    eventually("the standby to catch up", || {
+        standby
+            .log()
+            .contains("Caught up with the journal (epoch: 2)")
+    })
+    .await;
+    // It serves nothing while standing by.
+    let config = RayClientConfig {
+        max_retries: 0,
+        ..Default::default()
+    };
+    let mut client = RayClient::connect_with_config("127.0.0.1", standby.port, config)
+        .await
+        .unwrap();
+    let status = client.get(b"a".to_vec()).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unavailable);
+
+    // Written while standing by, and followed from the journal.
+    writer.set(b"c".to_vec(), b"3".to_vec()).await.unwrap();
+    writer.sync().await.unwrap();
+    assert!(primary.stop().success());
+    fs::write(standby.path("test.yml"), &shared_storage).unwrap();
+    standby.signal(Signal::SIGHUP);
+
+    let mut client = standby.client().await;
+    eventually("the promotion to be logged", || {
+        standby.log().contains("Promoted to primary (epoch: 3)")
+    })
+    .await;
+    let keys: &[&[u8]] = &[b"a", b"b", b"c"];
+    let expected = vec![
+        Some(b"1".to_vec()),
+        Some(b"2".to_vec()),
+        Some(b"3".to_vec()),
+    ];
+    assert_eq!(read_all(&mut client, keys).await, expected);
+    client.set(b"d".to_vec(), b"4".to_vec()).await.unwrap();
+    assert_eq!(client.sync().await.unwrap(), 4);
+
+    // The promoted standby writes the journal like a primary.
+    assert!(standby.stop().success());
+    standby.restart(&shared_storage);
+    let mut client = standby.client().await;
+    assert_eq!(client.get(b"d".to_vec()).await.unwrap(), b"4");
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn replica_reads_wait_for_the_session_epoch() {
+    let primary = Server::start("");
+    let mut writer = primary.client().await;
+    writer.set(b"key".to_vec(), b"0".to_vec()).await.unwrap();
+    // Polled rarely, so that the replica lags behind every write below.
+    let replica = Server::start(&format!(
+        "role: replica
+psm:
+    journal_service:
+        poll_interval_ms: 1000
+journal_storage:
+    path: {}
+snapshot_storage:
+    path: {}
+",
+        primary.path("journal").display(),
+        primary.path("snapshots").display()
+    ));
+    let mut reader = replica.client().await;
+    let config = RayClientConfig {
+        read_your_writes: false,
+        ..Default::default()
+    };
+    let mut unaware = RayClient::connect_with_config("127.0.0.1", replica.port, config)
+        .await
+        .unwrap();
+
+    let mut stale_reads = 0;
+    for round in 1..=3u8 {
+        let value = vec![b'0' + round];
+        writer.set(b"key".to_vec(), value.clone()).await.unwrap();
+        let token = writer.session_epoch();
+        assert_eq!(token, 1 + round as u64);
+        if unaware.get(b"key".to_vec()).await.unwrap() != value {
+            stale_reads += 1;
+        }
+        reader.observe_epoch(token);
+        assert_eq!(reader.get(b"key".to_vec()).await.unwrap(), value);
+        assert!(reader.session_epoch() >= token);
+    }
+    // Reads without the token are served at whatever the replica has applied.
+    assert!(stale_reads > 0);
+
+    // The primary has applied every epoch it acknowledged, so it ignores bogus tokens, which
+    // a replica waits for until the deadline.
+    writer.observe_epoch(1000);
+    writer.get(b"key".to_vec()).await.unwrap();
+    let config = RayClientConfig {
+        request_timeout: Some(Duration::from_millis(200)),
+        max_retries: 0,
+        ..Default::default()
+    };
+    let mut bogus = RayClient::connect_with_config("127.0.0.1", replica.port, config)
+        .await
+        .unwrap();
+    bogus.observe_epoch(1000);
+    let status = bogus.get(b"key".to_vec()).await.unwrap_err();
+    assert_eq!(status.code(), Code::DeadlineExceeded);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn chunked_clients_read_their_writes_from_a_replica() {
+    let primary = Server::start("");
+    primary.client().await;
+    let replica = Server::start(&format!(
+        "role: replica
+psm:
+    journal_service:
+        poll_interval_ms: 1000
+journal_storage:
+    path: {}
+snapshot_storage:
+    path: {}
+",
+        primary.path("journal").display(),
+        primary.path("snapshots").display()
+    ));
+    replica.client().await;
+    let config = RayClientConfig {
+        chunk_size: Some(1000),
+        ..Default::default()
+    };
+    let mut writer = RayClient::connect_with_config("127.0.0.1", primary.port, config.clone())
+        .await
+        .unwrap();
+    let mut reader = RayClient::connect_with_config("127.0.0.1", replica.port, config)
+        .await
+        .unwrap();
+
+    // Small values and manifests alike replace the previous value with a get-set, whose epoch
+    // the session takes: the large value is set at epoch 7, after its 5 chunks.
+    let large: Vec<u8> = (0..5000).map(|index| index as u8).collect();
+    let values = [(b"small".to_vec(), 1), (large, 7), (b"again".to_vec(), 8)];
+    for (value, epoch) in values.iter() {
+        writer.set(b"key".to_vec(), value.clone()).await.unwrap();
+        assert_eq!(writer.session_epoch(), *epoch);
+        reader.observe_epoch(writer.session_epoch());
+        assert_eq!(&reader.get(b"key".to_vec()).await.unwrap(), value);
+    }
+}
@@ -0,0 +1,610 @@
+mod common;
+
+use common::{eventually, Server};
+
+use ray::{
+    client::{RayClient, RayClientConfig},
+    proto::{storage_client::StorageClient, GetSetRequest},
+};
+
+use nix::sys::signal::Signal;
+use tonic::Code;
+
+use std::{collections::HashSet, fs, time::Duration};
+
+fn key(index: usize) -> Vec<u8> {
+    format!("key{:05}", index).into_bytes()
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn dump_yields_every_pair() {
+    let server = Server::start("");
+    let mut client = server.client().await;
+    let count = 2500;
+    for chunk in (0..count).collect::<Vec<_>>().chunks(500) {
+        let entries = chunk
+            .iter()
+            .map(|&index| (key(index), index.to_string().into_bytes()))
+            .collect();
+        client.batch_set(entries).await.unwrap();
+    }
+
+    let mut dump = client.dump_keys(vec![]).await.unwrap();
+    let mut pairs = vec![];
+    while let Some(pair) = dump.message().await.unwrap() {
+        pairs.push((pair.key, pair.value));
+    }
+    let expected: Vec<_> = (0..count)
+        .map(|index| (key(index), index.to_string().into_bytes()))
+        .collect();
+    assert_eq!(pairs, expected);
+
+    // A resumed dump picks up after the last key received.
+    let mut dump = client.dump_keys(key(1999)).await.unwrap();
+    let mut resumed = 0;
+    while let Some(pair) = dump.message().await.unwrap() {
+        assert_eq!(pair.key, key(2000 + resumed));
+        resumed += 1;
+    }
+    assert_eq!(resumed, 500);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn info_reports_the_version_and_epoch() {
+    let server = Server::start("");
+    let mut client = server.client().await;
+    let info = client.info().await.unwrap();
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!((info.epoch, info.key_count), (0, 0));
+
+    for index in 0..3 {
+        client.set(key(index), b"value".to_vec()).await.unwrap();
+    }
+    client.set(key(0), b"other".to_vec()).await.unwrap();
+    let info = client.info().await.unwrap();
+    assert_eq!((info.epoch, info.key_count), (4, 3));
+    assert!(info.uptime_seconds < 60, "{:?}", info);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn requests_over_the_size_limit_are_rejected() {
+    let server = Server::start(
+        "rpc:
+    max_recv_message_size: 100
+",
+    );
+    let mut client = server.client().await;
+    // The limit covers the key and value bytes of a request.
+    client.set(b"k".to_vec(), vec![1; 99]).await.unwrap();
+    let status = client.set(b"k".to_vec(), vec![2; 100]).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted, "{}", status);
+    assert_eq!(client.get(b"k".to_vec()).await.unwrap(), vec![1; 99]);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn set_and_get_over_a_unix_socket() {
+    let config = "rpc:
+    address: unix:rayd.sock
+";
+    let mut server = Server::start(config);
+    let socket = server.path("rayd.sock");
+    let address = format!("unix:{}", socket.display());
+    let mut client = server.client_at(&address).await;
+    client
+        .set(b"key".to_vec(), b"value".to_vec())
+        .await
+        .unwrap();
+    assert_eq!(client.get(b"key".to_vec()).await.unwrap(), b"value");
+
+    // A killed rayd leaves its socket behind, to be replaced by the next one.
+    server.kill();
+    assert!(socket.exists());
+    server.restart(config);
+    let mut client = server.client_at(&address).await;
+    assert_eq!(client.get(b"key".to_vec()).await.unwrap(), b"value");
+    assert!(server.stop().success());
+    assert!(!socket.exists());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn oversized_keys_and_values_are_not_journaled() {
+    let config = "rpc:
+    max_key_size: 4
+    max_value_size: 8
+";
+    let mut server = Server::start(config);
+    let mut client = server.client().await;
+    client.set(b"abcd".to_vec(), vec![1; 8]).await.unwrap();
+    assert_eq!(client.info().await.unwrap().epoch, 1);
+
+    let status = client.set(b"abcde".to_vec(), vec![2; 8]).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument, "{}", status);
+    let status = client.set(b"abcd".to_vec(), vec![2; 9]).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument, "{}", status);
+    let entries = vec![(b"a".to_vec(), vec![3; 8]), (b"b".to_vec(), vec![3; 9])];
+    let status = client.batch_set(entries).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument, "{}", status);
+    assert_eq!(client.info().await.unwrap().epoch, 1);
+
+    // Replaying the journal finds only the accepted set.
+    server.kill();
+    server.restart(config);
+    let mut client = server.client().await;
+    let info = client.info().await.unwrap();
+    assert_eq!((info.epoch, info.key_count), (1, 1));
+    assert_eq!(client.get(b"abcd".to_vec()).await.unwrap(), vec![1; 8]);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn empty_keys_are_rejected() {
+    let server = Server::start("");
+    let mut client = server.client().await;
+    let status = client.set(vec![], b"value".to_vec()).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument, "{}", status);
+    assert_eq!(status.message(), "the key is empty");
+    let status = client.get(vec![]).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument, "{}", status);
+    assert_eq!(status.message(), "the key is empty");
+    let entries = vec![(b"a".to_vec(), b"1".to_vec()), (vec![], b"2".to_vec())];
+    let status = client.batch_set(entries).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument, "{}", status);
+    assert_eq!(client.info().await.unwrap().epoch, 0);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn bulk_set_streams_every_pair() {
+    let server = Server::start("");
+    let mut client = server.client().await;
+    let count = 10_000;
+    let entries = (0..count).map(|index| (key(index), index.to_string().into_bytes()));
+    let set = client
+        .bulk_set(futures::stream::iter(entries))
+        .await
+        .unwrap();
+    assert_eq!(set, count as u64);
+
+    let mut dump = client.dump_keys(vec![]).await.unwrap();
+    let mut read = 0;
+    while let Some(pair) = dump.message().await.unwrap() {
+        assert_eq!(pair.key, key(read));
+        assert_eq!(pair.value, read.to_string().into_bytes());
+        read += 1;
+    }
+    assert_eq!(read, count);
+}
+
+async fn dump_in(client: &mut RayClient, namespace: &[u8]) -> Vec<Vec<u8>> {
+    let mut dump = client
+        .dump_keys_in(namespace.to_vec(), vec![])
+        .await
+        .unwrap();
+    let mut keys = vec![];
+    while let Some(pair) = dump.message().await.unwrap() {
+        keys.push(pair.key);
+    }
+    keys.sort();
+    keys
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn namespaces_are_isolated_and_flushed_alone() {
+    for store in &["hash", "ordered"] {
+        let config = format!("psm:\n    machine_service:\n        store: {}\n", store);
+        let mut server = Server::start(&config);
+        let mut client = server.client().await;
+        for namespace in &[&b"a"[..], b"b", b""] {
+            for index in 0..3 {
+                let value = [namespace, &b"-"[..], &key(index)].concat();
+                client
+                    .set_in(namespace.to_vec(), key(index), value)
+                    .await
+                    .unwrap();
+            }
+        }
+        // A default-namespace key that looks like an encoded key of namespace a.
+        client
+            .set(b"\0\x01akey00000".to_vec(), b"plain".to_vec())
+            .await
+            .unwrap();
+
+        let value = client.get_in(b"a".to_vec(), key(0)).await.unwrap();
+        assert_eq!(value, b"a-key00000");
+        let value = client.get_in(b"b".to_vec(), key(0)).await.unwrap();
+        assert_eq!(value, b"b-key00000");
+        let keys: Vec<_> = (0..3).map(key).collect();
+        assert_eq!(dump_in(&mut client, b"a").await, keys);
+
+        assert_eq!(client.flush_namespace(b"a".to_vec()).await.unwrap(), 3);
+        assert!(dump_in(&mut client, b"a").await.is_empty());
+        assert!(!client.exists_in(b"a".to_vec(), key(0)).await.unwrap());
+        assert_eq!(dump_in(&mut client, b"b").await, keys);
+        assert_eq!(client.get(key(0)).await.unwrap(), b"-key00000");
+        assert_eq!(
+            client.get(b"\0\x01akey00000".to_vec()).await.unwrap(),
+            b"plain"
+        );
+
+        // The flush is replayed from the journal.
+        server.kill();
+        server.restart(&config);
+        let mut client = server.client().await;
+        assert!(dump_in(&mut client, b"a").await.is_empty(), "{}", store);
+        assert_eq!(dump_in(&mut client, b"b").await, keys);
+        assert_eq!(
+            client.get(b"\0\x01akey00000".to_vec()).await.unwrap(),
+            b"plain"
+        );
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn delete_prefix_removes_matching_keys_and_is_replayed() {
+    for store in &["hash", "ordered"] {
+        let config = format!("psm:\n    machine_service:\n        store: {}\n", store);
+        let mut server = Server::start(&config);
+        let mut client = server.client().await;
+        for tenant in &["t1", "t2"] {
+            for index in 0..3 {
+                let key = format!("{}:{}", tenant, index).into_bytes();
+                client.set(key, b"value".to_vec()).await.unwrap();
+            }
+        }
+        client.set(b"t1".to_vec(), b"value".to_vec()).await.unwrap();
+        client
+            .set_in(b"ns".to_vec(), b"t1:0".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+
+        // Only keys of the namespace that start with the prefix, not the prefix itself.
+        let deleted = client.delete_prefix(vec![], b"t1:".to_vec()).await.unwrap();
+        assert_eq!(deleted, 3, "{}", store);
+        let expected = vec![
+            b"t1".to_vec(),
+            b"t2:0".to_vec(),
+            b"t2:1".to_vec(),
+            b"t2:2".to_vec(),
+        ];
+        assert_eq!(dump_in(&mut client, b"").await, expected);
+        assert_eq!(dump_in(&mut client, b"ns").await, vec![b"t1:0".to_vec()]);
+        let deleted = client.delete_prefix(vec![], b"t3:".to_vec()).await.unwrap();
+        assert_eq!(deleted, 0);
+        let status = client.delete_prefix(vec![], vec![]).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        // The deletion is replayed from the journal.
+        server.kill();
+        server.restart(&config);
+        let mut client = server.client().await;
+        assert_eq!(dump_in(&mut client, b"").await, expected, "{}", store);
+        assert_eq!(dump_in(&mut client, b"ns").await, vec![b"t1:0".to_vec()]);
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn concurrent_get_sets_form_a_single_chain() {
+    let server = Server::start("");
+    let mut tasks = vec![];
+    for task in 0..8 {
+        let mut client = server.client().await;
+        tasks.push(tokio::spawn(async move {
+            let mut replaced = vec![];
+            for index in 0..50 {
+                let value = format!("{}-{}", task, index).into_bytes();
+                let old = client
+                    .get_set(b"key".to_vec(), value.clone())
+                    .await
+                    .unwrap();
+                replaced.push((value, old));
+            }
+            replaced
+        }));
+    }
+    let mut written = HashSet::new();
+    let mut old_values = HashSet::new();
+    for task in tasks {
+        for (value, old) in task.await.unwrap() {
+            written.insert(value);
+            // No two get-sets replaced the same value.
+            assert!(old_values.insert(old));
+        }
+    }
+
+    // Every value was replaced by exactly one other, except the last one written.
+    let mut client = server.client().await;
+    let last = client.get(b"key".to_vec()).await.unwrap();
+    assert!(written.remove(&last));
+    assert!(old_values.remove(&Vec::new()));
+    assert_eq!(old_values, written);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn get_sets_leave_out_replaced_values_over_the_limit() {
+    let server = Server::start("");
+    server.client().await;
+    let address = format!("http://127.0.0.1:{}", server.port);
+    let mut client = StorageClient::connect(address).await.unwrap();
+    let expected = [
+        (vec![b'a'; 100], vec![], false),
+        (b"b".to_vec(), vec![], true),
+        (b"c".to_vec(), b"b".to_vec(), false),
+    ];
+    for (epoch, (value, previous, omitted)) in (1..).zip(expected.iter()) {
+        let request = GetSetRequest {
+            key: b"key".to_vec(),
+            value: value.clone(),
+            max_previous_size: 10,
+            ..Default::default()
+        };
+        let reply = client.get_set(request).await.unwrap().into_inner();
+        assert_eq!(&reply.value, previous);
+        assert_eq!((reply.epoch, reply.previous_omitted), (epoch, *omitted));
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn reads_report_epochs_that_never_go_back() {
+    let server = Server::start("");
+    let mut client = server.client().await;
+    let reply = client.get_with_meta(vec![], b"key".to_vec()).await.unwrap();
+    assert_eq!((reply.found, reply.epoch, reply.size), (false, 0, 0));
+    client
+        .set(b"key".to_vec(), b"value".to_vec())
+        .await
+        .unwrap();
+    let reply = client.get_with_meta(vec![], b"key".to_vec()).await.unwrap();
+    assert_eq!((reply.found, reply.epoch, reply.size), (true, 1, 5));
+
+    let mut writers = vec![];
+    for _ in 0..4 {
+        let mut client = server.client().await;
+        writers.push(tokio::spawn(async move {
+            for _ in 0..30 {
+                client.increment(b"counter".to_vec(), 1).await.unwrap();
+            }
+        }));
+    }
+    // Every mutation since epoch 1 is an increment, so the counter tells the epoch it was
+    // read at.
+    let mut last_epoch = 1;
+    while last_epoch < 121 {
+        let reply = client
+            .get_with_meta(vec![], b"counter".to_vec())
+            .await
+            .unwrap();
+        assert!(
+            reply.epoch >= last_epoch,
+            "{} < {}",
+            reply.epoch,
+            last_epoch
+        );
+        let counter = if reply.found {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&reply.value);
+            i64::from_le_bytes(bytes)
+        } else {
+            0
+        };
+        assert_eq!(counter as u64, reply.epoch - 1);
+        last_epoch = reply.epoch;
+    }
+    for writer in writers {
+        writer.await.unwrap();
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn slow_requests_are_logged_unless_streamed() {
+    let server = Server::start("rpc:\n    slow_request_threshold_ms: 1\n");
+    let mut client = server.client().await;
+    // Far more than a millisecond to journal and apply.
+    let entries = || (0..50_000).map(|index| (key(index), vec![0; 100]));
+    client.batch_set(entries().collect()).await.unwrap();
+    let set = client
+        .bulk_set(futures::stream::iter(entries()))
+        .await
+        .unwrap();
+    assert_eq!(set, 50_000);
+
+    eventually("the slow request warning", || {
+        server.log().contains("Slow request: method batch_set")
+    })
+    .await;
+    let log = server.log();
+    let warning = log
+        .lines()
+        .find(|line| line.contains("Slow request: method batch_set"))
+        .unwrap();
+    assert!(warning.contains("(journal: "), "{}", warning);
+    assert!(!log.contains("Slow request: method bulk_set"), "{}", log);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn large_values_are_chunked_and_their_chunks_cleaned_up() {
+    let server = Server::start("rpc:\n    max_value_size: 4096\n");
+    let mut plain = server.client().await;
+    let config = RayClientConfig {
+        chunk_size: Some(3000),
+        ..Default::default()
+    };
+    let mut client = RayClient::connect_with_config("127.0.0.1", server.port, config)
+        .await
+        .unwrap();
+    let large = |seed: u8| {
+        (0..50_000)
+            .map(|index| (index % 251) as u8 ^ seed)
+            .collect()
+    };
+    let first: Vec<u8> = large(0);
+    let status = plain.set(b"key".to_vec(), first.clone()).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+
+    client.set(b"key".to_vec(), first.clone()).await.unwrap();
+    assert_eq!(client.get(b"key".to_vec()).await.unwrap(), first);
+    assert_eq!(dump_in(&mut client, b"ray.chunks").await.len(), 17);
+
+    // Only the chunks of the current value are kept.
+    let second: Vec<u8> = large(1);
+    client.set(b"key".to_vec(), second.clone()).await.unwrap();
+    assert_eq!(client.get(b"key".to_vec()).await.unwrap(), second);
+    assert_eq!(dump_in(&mut client, b"ray.chunks").await.len(), 17);
+    client
+        .set(b"key".to_vec(), b"small".to_vec())
+        .await
+        .unwrap();
+    assert_eq!(client.get(b"key".to_vec()).await.unwrap(), b"small");
+    assert!(dump_in(&mut client, b"ray.chunks").await.is_empty());
+    client.set(b"key".to_vec(), first.clone()).await.unwrap();
+    assert!(client.delete(b"key".to_vec()).await.unwrap());
+    assert_eq!(client.get_opt(b"key".to_vec()).await.unwrap(), None);
+    assert!(dump_in(&mut client, b"ray.chunks").await.is_empty());
+
+    // A value like a manifest is chunked as well, so that it reads back as it was.
+    let mut lookalike = b"\0ray-chunked\0".to_vec();
+    lookalike.resize(41, 1);
+    client
+        .set(b"key".to_vec(), lookalike.clone())
+        .await
+        .unwrap();
+    assert_eq!(client.get(b"key".to_vec()).await.unwrap(), lookalike);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn watchers_see_their_keys_in_epoch_order() {
+    let server = Server::start("psm:\n    machine_service:\n        watch_buffer_size: 2\n");
+    let mut client = server.client().await;
+    let mut watcher = client.watch(b"user:".to_vec()).await.unwrap();
+    client.set(b"user:a".to_vec(), b"1".to_vec()).await.unwrap();
+    client.set(b"other".to_vec(), b"2".to_vec()).await.unwrap();
+    client.set(b"user:b".to_vec(), b"3".to_vec()).await.unwrap();
+    assert!(client.delete(b"user:a".to_vec()).await.unwrap());
+
+    let first = watcher.message().await.unwrap().unwrap();
+    assert_eq!(
+        (&first.key[..], &first.value[..]),
+        (&b"user:a"[..], &b"1"[..])
+    );
+    assert!(!first.deleted);
+    // The unwatched key takes an epoch but delivers no event.
+    let second = watcher.message().await.unwrap().unwrap();
+    assert_eq!(
+        (&second.key[..], second.epoch),
+        (&b"user:b"[..], first.epoch + 2)
+    );
+    let third = watcher.message().await.unwrap().unwrap();
+    assert_eq!(
+        (&third.key[..], third.epoch),
+        (&b"user:a"[..], first.epoch + 3)
+    );
+    assert!(third.deleted && third.value.is_empty());
+
+    // Events too large for the stream to take at once pile up until the oldest are dropped.
+    let mut lagging = client.watch(b"big:".to_vec()).await.unwrap();
+    for index in 0..20 {
+        let key = format!("big:{:02}", index).into_bytes();
+        client.set(key, vec![index as u8; 200_000]).await.unwrap();
+    }
+    let (mut received, mut missed) = (vec![], 0);
+    while received.last().map(|key: &Vec<u8>| &key[..]) != Some(b"big:19") {
+        let event = lagging.message().await.unwrap().unwrap();
+        if event.missed > 0 {
+            assert!(event.key.is_empty());
+            missed += event.missed;
+        } else {
+            received.push(event.key);
+        }
+    }
+    assert!(missed > 0);
+    assert_eq!(received.len() as u64 + missed, 20);
+    let mut sorted = received.clone();
+    sorted.sort();
+    assert_eq!(received, sorted);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn cached_reads_miss_other_clients_writes_until_stale() {
+    let server = Server::start("");
+    let mut writer = server.client().await;
+    let staleness = Duration::from_secs(1);
+    let mut reader = server.client().await.with_cache(10, Some(staleness));
+    writer.set(b"key".to_vec(), b"1".to_vec()).await.unwrap();
+    assert_eq!(reader.get(b"key".to_vec()).await.unwrap(), b"1");
+
+    // Served from the cache.
+    writer.set(b"key".to_vec(), b"2".to_vec()).await.unwrap();
+    assert_eq!(reader.get(b"key".to_vec()).await.unwrap(), b"1");
+
+    // The reader's own writes are seen right away.
+    reader.set(b"key".to_vec(), b"3".to_vec()).await.unwrap();
+    assert_eq!(reader.get(b"key".to_vec()).await.unwrap(), b"3");
+
+    writer.set(b"key".to_vec(), b"4".to_vec()).await.unwrap();
+    assert_eq!(reader.get(b"key".to_vec()).await.unwrap(), b"3");
+    tokio::time::delay_for(staleness).await;
+    assert_eq!(reader.get(b"key".to_vec()).await.unwrap(), b"4");
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn empty_values_are_told_from_missing_keys() {
+    let server = Server::start("");
+    let mut client = server.client().await;
+    client.set(b"empty".to_vec(), vec![]).await.unwrap();
+    assert_eq!(
+        client.get_opt(b"empty".to_vec()).await.unwrap(),
+        Some(vec![])
+    );
+    assert_eq!(client.get_opt(b"missing".to_vec()).await.unwrap(), None);
+    // Both still read as an empty value.
+    assert!(client.get(b"empty".to_vec()).await.unwrap().is_empty());
+    assert!(client.get(b"missing".to_vec()).await.unwrap().is_empty());
+
+    // The read cache remembers which is which.
+    let mut cached = server.client().await.with_cache(10, None);
+    for _ in 0..2 {
+        assert_eq!(
+            cached.get_opt(b"empty".to_vec()).await.unwrap(),
+            Some(vec![])
+        );
+        assert_eq!(cached.get_opt(b"missing".to_vec()).await.unwrap(), None);
+    }
+}
+
+fn logging_at(level: &str) -> String {
+    format!(
+        "logging:
+    targets:
+      - target:
+            type: file
+            path: rayd.log
+        level: {}
+",
+        level
+    )
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn sighup_changes_the_log_level() {
+    let server = Server::start(&logging_at("info"));
+    let mut client = server.client().await;
+    client
+        .set(b"quiet".to_vec(), b"value".to_vec())
+        .await
+        .unwrap();
+
+    fs::write(server.path("test.yml"), logging_at("debug")).unwrap();
+    server.signal(Signal::SIGHUP);
+    eventually("the reload", || {
+        server.log().contains("Reloaded logging.targets.0.level")
+    })
+    .await;
+    client
+        .set(b"loud".to_vec(), b"value".to_vec())
+        .await
+        .unwrap();
+    eventually("the request to be logged", || {
+        server.log().contains("New request")
+    })
+    .await;
+    let log = server.log();
+    assert!(log.contains("loud"), "{}", log);
+    assert!(!log.contains("quiet"), "{}", log);
+}
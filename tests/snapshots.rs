@@ -0,0 +1,164 @@
+mod common;
+
+use common::{eventually, Server};
+
+use std::fs;
+
+#[tokio::test(threaded_scheduler)]
+async fn sigterm_takes_a_final_snapshot() {
+    let mut server = Server::start("");
+    let mut client = server.client().await;
+    for i in 0..10u8 {
+        client.set(vec![b'k', i], vec![i]).await.unwrap();
+    }
+    let epoch = client.sync().await.unwrap();
+    assert!(!server.snapshot_epochs(".snap").contains(&epoch));
+
+    assert!(server.stop().success());
+    assert_eq!(server.snapshot_epochs(".snap").last(), Some(&epoch));
+}
+
+#[tokio::test]
+async fn trigger_snapshot_writes_a_snapshot() {
+    let server = Server::start("");
+    let mut client = server.client().await;
+    client
+        .set(b"key".to_vec(), b"value".to_vec())
+        .await
+        .unwrap();
+    assert!(server.snapshot_epochs(".snap").is_empty());
+
+    let epoch = client.trigger_snapshot().await.unwrap();
+    assert_eq!(epoch, 1);
+    assert_eq!(server.snapshot_epochs(".snap"), vec![epoch]);
+}
+
+#[tokio::test]
+async fn deltas_are_recovered_on_top_of_the_full_snapshot() {
+    let config = "
+psm:
+    snapshot_service:
+        deltas_per_full: 3
+journal_storage:
+    file_size_soft_limit: 1
+";
+    let mut server = Server::start(config);
+    let mut client = server.client().await;
+    client.set(b"kept".to_vec(), b"1".to_vec()).await.unwrap();
+    client
+        .set(b"removed".to_vec(), b"2".to_vec())
+        .await
+        .unwrap();
+    client.trigger_snapshot().await.unwrap();
+
+    client.set(b"kept".to_vec(), b"3".to_vec()).await.unwrap();
+    client.set(b"added".to_vec(), b"4".to_vec()).await.unwrap();
+    assert!(client.delete(b"removed".to_vec()).await.unwrap());
+    let journal_files = server.files("journal").len();
+    let epoch = client.trigger_snapshot().await.unwrap();
+    assert_eq!(server.snapshot_epochs(".delta"), vec![epoch]);
+    // The journal is disposed of up to the delta, not just up to the full snapshot.
+    eventually("journal disposal", || {
+        server.files("journal").len() < journal_files
+    })
+    .await;
+
+    server.kill();
+    server.restart(config);
+    let mut client = server.client().await;
+    assert!(server.log().contains("Snapshot delta found"));
+    assert_eq!(client.get(b"kept".to_vec()).await.unwrap(), b"3");
+    assert_eq!(client.get(b"added".to_vec()).await.unwrap(), b"4");
+    assert_eq!(client.get_opt(b"removed".to_vec()).await.unwrap(), None);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn snapshots_after_a_parallel_recovery_hold_every_key() {
+    let config = "
+psm:
+    journal_service:
+        recovery_threads: 4
+    snapshot_service:
+        deltas_per_full: 3
+";
+    let mut server = Server::start(config);
+    let mut client = server.client().await;
+    let key = |index: u32| format!("key{}", index).into_bytes();
+    for index in 0..200 {
+        client.set(key(index), vec![1]).await.unwrap();
+    }
+    assert_eq!(client.trigger_snapshot().await.unwrap(), 200);
+    // Replayed over the snapshot in batches spread over the recovery threads.
+    for index in 100..300 {
+        client.set(key(index), vec![2]).await.unwrap();
+    }
+    for index in 0..50 {
+        assert!(client.delete(key(index)).await.unwrap());
+    }
+    client.sync().await.unwrap();
+    server.kill();
+    server.restart(config);
+    let mut client = server.client().await;
+    assert_eq!(client.info().await.unwrap().epoch, 450);
+
+    // Snapshots are written from the recovered machine, a full one and then a delta.
+    assert_eq!(client.trigger_snapshot().await.unwrap(), 450);
+    for index in 250..350 {
+        client.set(key(index), vec![3]).await.unwrap();
+    }
+    assert_eq!(client.trigger_snapshot().await.unwrap(), 550);
+    assert_eq!(server.snapshot_epochs(".snap"), vec![200, 450]);
+    assert_eq!(server.snapshot_epochs(".delta"), vec![550]);
+
+    // Without the journal, the state comes from the snapshots alone.
+    server.kill();
+    fs::remove_dir_all(server.path("journal")).unwrap();
+    server.restart(config);
+    let mut client = server.client().await;
+    let info = client.info().await.unwrap();
+    assert_eq!((info.epoch, info.key_count), (550, 300));
+    for index in 0..350 {
+        let expected = match index {
+            0..=49 => None,
+            50..=99 => Some(vec![1]),
+            100..=249 => Some(vec![2]),
+            _ => Some(vec![3]),
+        };
+        assert_eq!(
+            client.get_opt(key(index)).await.unwrap(),
+            expected,
+            "{}",
+            index
+        );
+    }
+}
+
+// Threads of the server named after the snapshot service.
+fn snapshot_threads(server: &Server) -> usize {
+    fs::read_dir(format!("/proc/{}/task", server.pid()))
+        .unwrap()
+        .filter_map(|task| fs::read_to_string(task.unwrap().path().join("comm")).ok())
+        .filter(|name| name == "rayd-snapshot\n")
+        .count()
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn snapshot_service_runs_on_its_worker_threads() {
+    // The dedicated thread alone, or along with the workers of a multi-threaded runtime.
+    for &(worker_threads, expected) in &[(0, 1), (3, 4)] {
+        let config = format!(
+            "psm:\n    snapshot_service:\n        worker_threads: {}\n",
+            worker_threads
+        );
+        let server = Server::start(&config);
+        let mut client = server.client().await;
+        assert_eq!(snapshot_threads(&server), expected);
+
+        client
+            .set(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(client.trigger_snapshot().await.unwrap(), 1);
+        assert_eq!(server.snapshot_epochs(".snap"), vec![1]);
+    }
+}